@@ -1,7 +1,7 @@
 use axum::Router;
 use anyhow::Context;
 use surrealdb::Surreal;
-use surrealdb::engine::remote::ws::Client;
+use surrealdb::engine::any::Any;
 use std::{
     net::{Ipv4Addr, SocketAddr},
     sync::Arc,
@@ -9,19 +9,33 @@ use std::{
 use tower_http::trace::TraceLayer;
 
 use crate::config::Config;
+use crate::db::encryption::Cipher;
 pub mod handlers;
 pub mod error;
 pub use error::Error;
-// pub mod extractor;
+pub mod etag;
+pub mod extractor;
+pub mod repository;
+pub mod transaction;
+#[cfg(test)]
+pub(crate) mod testing;
+
+use repository::{Repositories, SurrealRepository};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
-// TODO: Remove this when we implement auth and use config.
-#[allow(unused)]
 #[derive(Clone)]
 pub(crate) struct ApiContext {
     config: Arc<Config>,
-    db: Surreal<Client>,
+    db: Surreal<Any>,
+    /// The records of medications, stores, doses, reminders, notes and units. `db` is still used directly for
+    /// the accounts, sessions and grants.
+    repos: Repositories,
+    /// Decrypts the encrypted fields the handlers read from `db` directly, see `crate::db::encryption`.
+    cipher: Cipher,
+    /// A hash of a random password with the configured argon2 settings, checked against when a login names
+    /// an unknown user, see `handlers::user::verify_dummy_password()`.
+    dummy_password_hash: Arc<str>,
 }
 
 /// Serves the API using the given configuration and database client
@@ -29,25 +43,30 @@ pub(crate) struct ApiContext {
 /// # Arguments
 ///
 /// * `config` - A `Config` struct containing the configuration for the API
-/// * `db` - A `Surreal<Client>` struct representing the database client
+/// * `db` - A `Surreal<Any>` client for the database engine chosen in the `Config`, see `crate::db::connect()`
+/// * `cipher` - The `Cipher` of the encrypted fields, see `crate::db::encryption::unlock()`
 ///
 /// # Returns
 ///
 /// Returns `Ok(())` if the server was successfully started, otherwise returns an `anyhow::Error`
 ///
-// TODO: Alter the Surreal<Client> to Surreal<DB> for local file storage.
-// TODO: adjust comments for change in ws to db local.
-pub async fn serve(config: Config, db: Surreal<Client>) -> anyhow::Result<()> {
+pub async fn serve(config: Config, db: Surreal<Any>, cipher: Cipher) -> anyhow::Result<()> {
+    // Fail at startup rather than on the first login if the argon2 settings are out of range.
+    let dummy_password_hash = handlers::user::dummy_password_hash(&config)?;
     let api_context = ApiContext {
         config: Arc::new(config),
+        repos: Repositories::new(SurrealRepository::new(db.clone())).encrypted(cipher.clone()),
         db,
+        cipher,
+        dummy_password_hash: dummy_password_hash.into(),
     };
 
     let app = api_router(api_context);
 
     let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8080));
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        // The client address is needed to track failed logins per IP.
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .context("error running HTTP server")
 }
@@ -63,7 +82,13 @@ fn api_router(api_context: ApiContext) -> Router {
         .merge(handlers::note_router(api_context.clone()))
         .merge(handlers::store_router(api_context.clone()))
         .merge(handlers::uom_router(api_context.clone()))
-        // .merge(handlers::user_router(api_context.clone()))
+        .merge(handlers::user_router(api_context.clone()))
+        .merge(handlers::grant_router(api_context.clone()))
+        .merge(handlers::profile_router(api_context.clone()))
+        .merge(handlers::access_token_router(api_context.clone()))
+        .merge(handlers::audit_router(api_context.clone()))
+        .merge(handlers::backup_router(api_context.clone()))
+        .merge(handlers::trash_router(api_context.clone()))
         // Enables logging. Use `RUST_LOG=tower_http=debug`
         .layer(TraceLayer::new_for_http())
         .with_state(api_context)
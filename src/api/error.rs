@@ -1,10 +1,11 @@
-use axum::http::header::WWW_AUTHENTICATE;
+use axum::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::Json;
 use std::borrow::Cow;
 use std::collections::HashMap;
+use surrealdb::sql::Thing;
 
 
 #[derive(thiserror::Error, Debug)]
@@ -21,12 +22,26 @@ pub enum Error {
     #[error("request path not found")]
     NotFound,
 
+    /// Return `409 Conflict` with the records that keep a record from being deleted, see
+    /// `crate::api::repository::reference`.
+    #[error("the record has dependents, delete them first or cascade")]
+    Conflict { dependents: Vec<Thing> },
+
+    /// Return `412 Precondition Failed` when the `If-Match` of an update is missing or names an older version
+    /// of the record, see `crate::api::etag`.
+    #[error("the record was changed since it was read, or If-Match is missing")]
+    PreconditionFailed,
+
     /// Return `422 Unprocessable Entity`
     #[error("error in the request body")]
     UnprocessableEntity {
         errors: HashMap<Cow<'static, str>, Vec<Cow<'static, str>>>,
     },
 
+    /// Return `429 Too Many Requests` with a `Retry-After` header of `retry_after` seconds
+    #[error("too many failed attempts, try again later")]
+    TooManyRequests { retry_after: i64 },
+
     #[error("database error")]
     Db,
 
@@ -62,7 +77,10 @@ impl Error {
             Self::Unauthorized => StatusCode::UNAUTHORIZED,
             Self::Forbidden => StatusCode::FORBIDDEN,
             Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Conflict { .. } => StatusCode::CONFLICT,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
             Self::UnprocessableEntity { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
             Self::Db | Self::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -84,18 +102,33 @@ impl IntoResponse for Error {
 
                 return (StatusCode::UNPROCESSABLE_ENTITY, Json(Errors { errors })).into_response();
             }
+            Self::Conflict { dependents } => {
+                #[derive(serde::Serialize)]
+                struct Dependents {
+                    dependents: Vec<Thing>,
+                }
+
+                return (StatusCode::CONFLICT, Json(Dependents { dependents })).into_response();
+            }
             Self::Unauthorized => {
                 return (
                     self.status_code(),
                     // Include the `WWW-Authenticate` challenge required in the specification
                     // for the `401 Unauthorized` response code:
                     // https://developer.mozilla.org/en-US/docs/Web/HTTP/Status/401
-                    [(WWW_AUTHENTICATE, "Token")],
+                    [(WWW_AUTHENTICATE, "Bearer")],
+                    self.to_string(),
+                )
+                    .into_response();
+            }
+            Self::TooManyRequests { retry_after } => {
+                return (
+                    self.status_code(),
+                    [(RETRY_AFTER, retry_after.to_string())],
                     self.to_string(),
                 )
                     .into_response();
             }
-
 
             Self::Anyhow(ref e) => {
                 // TODO: we probably want to use `tracing` instead
@@ -0,0 +1,160 @@
+//! Optimistic concurrency for the records the clients edit.
+//!
+//! The version of a record is its `updated` timestamp. Reads return it as an `ETag`, e.g.
+//! `ETag: "2024-01-31T12:00:00.123456789Z"`, and updates must send it back in `If-Match`. An update only
+//! applies if the record is still at that version, otherwise it fails with `412 Precondition Failed` and the
+//! client reads the record again, rather than overwriting a change it hasn't seen.
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::header::{ETAG, IF_MATCH};
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use surrealdb::sql::Datetime;
+
+use crate::api::error::Error;
+use crate::api::repository::dose::Dose;
+use crate::api::repository::medication::Medication;
+use crate::api::repository::note::Note;
+use crate::api::repository::reminder::Reminder;
+use crate::api::repository::store::Store;
+use crate::api::repository::uom::UnitOfMeasure;
+
+/// A record with a version, see the module documentation.
+pub trait Versioned {
+    /// The `updated` of the record.
+    fn version(&self) -> &Datetime;
+}
+
+impl Versioned for Medication {
+    fn version(&self) -> &Datetime {
+        &self.updated
+    }
+}
+
+impl Versioned for Store {
+    fn version(&self) -> &Datetime {
+        &self.updated
+    }
+}
+
+impl Versioned for Dose {
+    fn version(&self) -> &Datetime {
+        &self.updated
+    }
+}
+
+impl Versioned for Reminder {
+    fn version(&self) -> &Datetime {
+        &self.updated
+    }
+}
+
+impl Versioned for Note {
+    fn version(&self) -> &Datetime {
+        &self.updated
+    }
+}
+
+impl Versioned for UnitOfMeasure {
+    fn version(&self) -> &Datetime {
+        &self.updated
+    }
+}
+
+/// Returns a record as JSON with its version in the `ETag` header.
+pub struct Tagged<T>(pub T);
+
+impl<T: Versioned + Serialize> IntoResponse for Tagged<T> {
+    fn into_response(self) -> Response {
+        ([(ETAG, format!("\"{}\"", self.0.version().to_raw()))], Json(self.0)).into_response()
+    }
+}
+
+/// Add this as a parameter to a handler function to require the `If-Match` header, holding the version of
+/// the record the client last read.
+///
+/// A missing header, or one that isn't an `ETag` given out by `Tagged`, is rejected with
+/// `Error::PreconditionFailed`. So is `If-Match: *`, as it would skip the check.
+pub struct IfMatch(pub Datetime);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IfMatch
+where
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let value = parts.headers
+            .get(IF_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .ok_or(Error::PreconditionFailed)?;
+        // Proxies may weaken an ETag, the version is the same.
+        let tag = value.trim().trim_start_matches("W/");
+        tag.strip_prefix('"')
+            .and_then(|tag| tag.strip_suffix('"'))
+            .and_then(|version| Datetime::try_from(version).ok())
+            .map(Self)
+            .ok_or(Error::PreconditionFailed)
+    }
+}
+
+/// The error for an update that matched no record. `current` is the record read again: if it still exists,
+/// it was changed since the client read it.
+pub fn rejected<T>(current: Option<T>) -> Error {
+    match current {
+        Some(_) => Error::PreconditionFailed,
+        None => Error::NotFound,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::api::testing::{id, TestApp};
+
+    #[tokio::test]
+    async fn a_change_needs_the_current_etag() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(&ann), Some(json!({ "name": "Aspirin" }))).await;
+        let uri = format!("/medications/{}", id(&medication));
+        let etag = app.etag(&uri, &ann).await;
+        let rename = |name: &str| Some(json!({ "name": name }));
+
+        let (status, _) = app.request(Method::PUT, &uri, Some(&ann), rename("Ibuprofen")).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        for wrong in ["*", "\"yesterday\"", etag.trim_matches('"')] {
+            let (status, _) = app.request_if_match(Method::PUT, &uri, &ann, wrong, rename("Ibuprofen")).await;
+            assert_eq!(status, StatusCode::PRECONDITION_FAILED, "{wrong}");
+        }
+
+        let (status, body) = app.request_if_match(Method::PUT, &uri, &ann, &etag, rename("Ibuprofen")).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, _) = app.request_if_match(Method::PUT, &uri, &ann, &etag, rename("Paracetamol")).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        let weak = format!("W/{}", app.etag(&uri, &ann).await);
+        let (status, body) = app.request_if_match(Method::PUT, &uri, &ann, &weak, rename("Paracetamol")).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[tokio::test]
+    async fn a_record_written_without_a_version_gets_one() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let unit = json!({ "name": "milligram", "abbreviation": "mg" });
+        let (_, unit) = app.request(Method::POST, "/uoms", Some(&ann), Some(unit)).await;
+
+        // As a restore of a backup from before every record had a version would.
+        app.db().query("UPDATE type::thing('unit_of_measure', $id) SET updated = NONE;")
+            .bind(("id", id(&unit)))
+            .bind(("verbatim", true))
+            .await.unwrap().check().unwrap();
+        app.etag(&format!("/uoms/{}", id(&unit)), &ann).await;
+    }
+}
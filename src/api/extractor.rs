@@ -1,34 +1,87 @@
-use crate::api::error::{Error}; // ResultExt};
+use crate::api::error::Error;
 use axum::extract::{FromRef, FromRequestParts};
 use axum::http::request::Parts;
+use axum::http::Extensions;
 
 use crate::api::ApiContext;
 use async_trait::async_trait;
 use axum::http::header::AUTHORIZATION;
-use axum::http::HeaderValue;
+use axum::http::{HeaderMap, HeaderValue, Method};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
 use hmac::{Hmac, Mac};
 use jwt::{SignWithKey, VerifyWithKey};
-use sha2::Sha384;
+use sha2::{Digest, Sha256, Sha384};
+use std::fmt::Write;
+use surrealdb::sql::Thing;
 use time::OffsetDateTime;
-// use uuid::Uuid;
 
+/// How long a session, and with it its refresh tokens, stays valid after login.
 const DEFAULT_SESSION_LENGTH: time::Duration = time::Duration::weeks(2);
 
-// Ideally the Realworld spec would use the `Bearer` scheme as that's relatively standard
-// and has parsers available, but it's really not that hard to parse anyway.
-const SCHEME_PREFIX: &str = "Token ";
+/// How long an access token (JWT) is valid. Clients exchange their refresh token for a new one after that.
+const ACCESS_TOKEN_LENGTH: time::Duration = time::Duration::minutes(15);
+
+// `Bearer` is the standard scheme, `Token` is what the Realworld spec this API started from uses.
+const SCHEME_PREFIXES: [&str; 2] = ["Bearer ", "Token "];
+
+/// The HttpOnly cookie holding the access JWT for browser frontends.
+const SESSION_COOKIE: &str = "medoxido_session";
+
+/// The HttpOnly cookie holding the refresh token, only sent to the refresh endpoint.
+const REFRESH_COOKIE: &str = "medoxido_refresh";
+const REFRESH_COOKIE_PATH: &str = "/users/refresh";
+
+/// The cookie holding the CSRF token. Unlike the other cookies it is readable by Javascript,
+/// so the frontend can echo it back in the `CSRF_HEADER`.
+const CSRF_COOKIE: &str = "medoxido_csrf";
+const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Personal access tokens start with this, so they can be told apart from JWTs in the
+/// `Authorization` header (and spotted by secret scanners).
+pub(in crate::api) const ACCESS_TOKEN_PREFIX: &str = "mdx_";
 
 /// Add this as a parameter to a handler function to require the user to be logged in.
 ///
-/// Parses a JWT from the `Authorization: Token <token>` header.
+/// Parses a short-lived access JWT from the `Authorization: Bearer <token>` header, or from the
+/// session cookie if there is no such header, and checks that the session it was issued for still
+/// exists in the `session` table.
+///
+/// Requests authenticated with the cookie that may change state, i.e. anything but `GET`, `HEAD`
+/// and `OPTIONS`, must also send the CSRF cookie value in the `X-CSRF-Token` header.
+///
+/// The `Authorization` header may also carry a personal access token instead of a JWT. Those are
+/// only accepted on routes that declare a `RequiredScope`, and only if the token holds that scope.
+///
+/// Sessions unlocked with the quick-unlock PIN are restricted: they are only accepted on routes
+/// marked with `AllowRestricted`, and end after `Config::pin_idle_timeout_minutes` without use.
 pub struct AuthUser {
     pub user_id: String,
+    /// The session the JWT was issued for. Empty for requests authenticated with a personal access
+    /// token, which never reach the routes that work with sessions.
+    pub session_id: String,
+    /// The profile the session is switched to, used when a request doesn't name one.
+    pub profile_id: Option<String>,
+    /// Whether the session was unlocked with the quick-unlock PIN rather than the password.
+    pub restricted: bool,
 }
 
+/// Marks a route as usable by a restricted session, i.e. one unlocked with the quick-unlock PIN.
+///
+/// Added to routes as an `Extension` layer in `handlers.rs`, like `RequiredScope`.
+#[derive(Clone, Copy, Debug)]
+pub struct AllowRestricted;
+
+/// The scope a personal access token needs for a route, e.g. `doses:write`.
+///
+/// Added to routes as an `Extension` layer in `handlers.rs`. Routes without one can't be used
+/// with a personal access token at all.
+#[derive(Clone, Copy, Debug)]
+pub struct RequiredScope(pub &'static str);
+
 /// Add this as a parameter to a handler function to optionally check if the user is logged in.
 ///
-/// If the `Authorization` header is absent then this will be `Self(None)`, otherwise it will
-/// validate the token.
+/// If neither the `Authorization` header nor the session cookie is present then this will be
+/// `Self(None)`, otherwise it will validate the token.
 ///
 /// This is in contrast to directly using `Option<AuthUser>`, which will be `None` if there
 /// is *any* error in deserializing, which isn't exactly what we want.
@@ -37,47 +90,279 @@ pub struct MaybeAuthUser(pub Option<AuthUser>);
 #[derive(serde::Serialize, serde::Deserialize)]
 struct AuthUserClaims {
     user_id: String,
+    session_id: String,
     /// Standard JWT `exp` claim.
     exp: i64,
 }
 
+/// A `refresh_token` record looked up by the hash of the token the client sent.
+#[derive(serde::Deserialize)]
+struct RefreshTokenRecord {
+    id: Thing,
+    user: Thing,
+    session: Thing,
+    profile: Option<Thing>,
+    // Missing on sessions started before quick unlock existed.
+    #[serde(default)]
+    restricted: bool,
+    used: bool,
+}
+
+/// An `access_token` record looked up by the hash of the token the client sent.
+#[derive(serde::Deserialize)]
+struct AccessTokenRecord {
+    id: Thing,
+    user: Thing,
+    profile: Option<Thing>,
+    scopes: Vec<String>,
+}
+
+/// The parts of a `session` record the `AuthUser` needs.
+#[derive(serde::Deserialize)]
+struct SessionRecord {
+    id: Thing,
+    profile: Option<Thing>,
+    // Missing on sessions started before quick unlock existed.
+    #[serde(default)]
+    restricted: bool,
+}
+
 impl AuthUser {
+    /// Starts a new server-side session for the given user and returns the `AuthUser` for it.
+    ///
+    /// The session starts on the first profile of the user. Expired sessions of the same user are
+    /// cleaned up along the way.
+    pub(in crate::api) async fn start_session(ctx: &ApiContext, user_id: String) -> Result<Self, Error> {
+        Self::create_session(ctx, user_id, DEFAULT_SESSION_LENGTH, false).await
+    }
+
+    /// Starts a restricted session for a user who unlocked with the quick-unlock PIN.
+    ///
+    /// The session expires after the idle timeout from the `Config`, which is pushed back every time
+    /// the session is used.
+    pub(in crate::api) async fn start_restricted_session(ctx: &ApiContext, user_id: String) -> Result<Self, Error> {
+        Self::create_session(ctx, user_id, pin_idle_timeout(ctx), true).await
+    }
+
+    async fn create_session(
+        ctx: &ApiContext,
+        user_id: String,
+        length: time::Duration,
+        restricted: bool,
+    ) -> Result<Self, Error> {
+        let expires = (OffsetDateTime::now_utc() + length).unix_timestamp();
+
+        let mut sql = ctx.db.query(
+            "DELETE session WHERE user = type::thing('user', $user) AND expires < time::now();
+            CREATE session SET user = type::thing('user', $user), expires = time::from::unix($expires),
+                restricted = $restricted,
+                profile = (SELECT id, created FROM profile WHERE user = type::thing('user', $user) ORDER BY created LIMIT 1)[0].id
+                RETURN id, profile, restricted;")
+            .bind(("user", &user_id))
+            .bind(("expires", expires))
+            .bind(("restricted", restricted))
+            .await?;
+        let session: Option<SessionRecord> = sql.take(1)?;
+        let session = session.ok_or_else(|| anyhow::anyhow!("CREATE session returned no record"))?;
+
+        Ok(Self {
+            user_id,
+            session_id: session.id.id.to_raw(),
+            profile_id: session.profile.map(|profile| profile.id.to_raw()),
+            restricted: session.restricted,
+        })
+    }
+
+    /// Ends the session of this `AuthUser`, so its token is no longer accepted.
+    pub(in crate::api) async fn end_session(&self, ctx: &ApiContext) -> Result<(), Error> {
+        ctx.db.query("DELETE type::thing('session', $session_id);")
+            .bind(("session_id", &self.session_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Ends every session of the given user, revokes their personal access tokens and removes their
+    /// quick-unlock PIN, in one transaction, so nothing someone else got hold of before a password change or
+    /// reset still lets them in.
+    pub(in crate::api) async fn revoke_all_access(ctx: &ApiContext, user_id: &str) -> Result<(), Error> {
+        ctx.db.query(
+            "BEGIN TRANSACTION;
+            DELETE session WHERE user = type::thing('user', $user);
+            DELETE access_token WHERE user = type::thing('user', $user);
+            UPDATE type::thing('user', $user) SET pin_hash = NONE, pin_failures = 0;
+            COMMIT TRANSACTION;")
+            .bind(("user", user_id))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Issues a new single-use refresh token for the session of this `AuthUser`.
+    ///
+    /// Only a SHA-256 hash of the token is stored, so a copy of the database can't be used to log in.
+    pub(in crate::api) async fn issue_refresh_token(&self, ctx: &ApiContext) -> Result<String, Error> {
+        let token = generate_token();
+
+        ctx.db.query(
+            "CREATE refresh_token SET session = type::thing('session', $session_id), token_hash = $token_hash;")
+            .bind(("session_id", &self.session_id))
+            .bind(("token_hash", hash_token(&token)))
+            .await?
+            .check()?;
+
+        Ok(token)
+    }
+
+    /// Exchanges a refresh token for the `AuthUser` of its session and marks the token as used.
+    ///
+    /// Every refresh token belongs to the family of tokens issued for one session. If a token that
+    /// was already used shows up again, either the client or an attacker holds a stolen copy, and we
+    /// can't tell which, so the whole family is revoked by deleting the session.
+    pub(in crate::api) async fn from_refresh_token(ctx: &ApiContext, token: &str) -> Result<Self, Error> {
+        let mut sql = ctx.db.query(
+            "SELECT id, session.user AS user, session, session.profile AS profile, session.restricted AS restricted, used
+            FROM refresh_token
+            WHERE token_hash = $token_hash AND session.expires > time::now();")
+            .bind(("token_hash", hash_token(token)))
+            .await?;
+        let record: Option<RefreshTokenRecord> = sql.take(0)?;
+        let record = record.ok_or_else(|| {
+            log::debug!("refresh token unknown, revoked or expired");
+            Error::Unauthorized
+        })?;
+
+        // Only flip `used` if nobody else did in the meantime, so two concurrent requests with the
+        // same token can't both succeed.
+        let mut sql = ctx.db.query("UPDATE $id SET used = true WHERE used = false RETURN id;")
+            .bind(("id", &record.id))
+            .await?;
+        let claimed: Option<Thing> = sql.take((0, "id"))?;
+
+        if record.used || claimed.is_none() {
+            log::warn!("refresh token reused, revoking {}", record.session);
+            // Deleting the session also deletes its refresh tokens through the `session_deleted` event.
+            ctx.db.query("DELETE $family;")
+                .bind(("family", &record.session))
+                .await?
+                .check()?;
+            return Err(Error::Unauthorized);
+        }
+
+        Ok(Self {
+            user_id: record.user.id.to_raw(),
+            session_id: record.session.id.to_raw(),
+            profile_id: record.profile.map(|profile| profile.id.to_raw()),
+            restricted: record.restricted,
+        })
+    }
+
     pub(in crate::api) fn to_jwt(&self, ctx: &ApiContext) -> String {
         let hmac = Hmac::<Sha384>::new_from_slice(ctx.config.hmac_key.as_bytes())
             .expect("HMAC-SHA-384 can accept any key length");
 
         AuthUserClaims {
-            user_id: self.user_id,
-            exp: (OffsetDateTime::now_utc() + DEFAULT_SESSION_LENGTH).unix_timestamp(),
+            user_id: self.user_id.clone(),
+            session_id: self.session_id.clone(),
+            exp: (OffsetDateTime::now_utc() + ACCESS_TOKEN_LENGTH).unix_timestamp(),
         }
         .sign_with_key(&hmac)
         .expect("HMAC signing should be infallible")
     }
 
     /// Attempt to parse `Self` from an `Authorization` header.
-    fn from_authorization(ctx: &ApiContext, auth_header: &HeaderValue) -> Result<Self, Error> {
+    async fn from_authorization(
+        ctx: &ApiContext,
+        auth_header: &HeaderValue,
+        extensions: &Extensions,
+    ) -> Result<Self, Error> {
         let auth_header = auth_header.to_str().map_err(|_| {
             log::debug!("Authorization header is not UTF-8");
             Error::Unauthorized
         })?;
 
-        if !auth_header.starts_with(SCHEME_PREFIX) {
-            log::debug!(
-                "Authorization header is using the wrong scheme: {:?}",
-                auth_header
-            );
-            return Err(Error::Unauthorized);
+        let token = SCHEME_PREFIXES
+            .iter()
+            .find_map(|prefix| auth_header.strip_prefix(prefix))
+            .ok_or_else(|| {
+                log::debug!(
+                    "Authorization header is using the wrong scheme: {:?}",
+                    auth_header
+                );
+                Error::Unauthorized
+            })?;
+
+        if token.starts_with(ACCESS_TOKEN_PREFIX) {
+            let required_scope = extensions.get::<RequiredScope>().copied();
+            return Self::from_access_token(ctx, token, required_scope).await;
+        }
+
+        Self::from_token(ctx, token, extensions).await
+    }
+
+    /// Looks up a personal access token, checks that it holds the scope of the route and records
+    /// that it was used.
+    async fn from_access_token(
+        ctx: &ApiContext,
+        token: &str,
+        required_scope: Option<RequiredScope>,
+    ) -> Result<Self, Error> {
+        let mut sql = ctx.db.query(
+            "SELECT id, user, profile, scopes FROM access_token WHERE token_hash = $token_hash;")
+            .bind(("token_hash", hash_token(token)))
+            .await?;
+        let record: Option<AccessTokenRecord> = sql.take(0)?;
+        let record = record.ok_or_else(|| {
+            log::debug!("personal access token unknown or revoked");
+            Error::Unauthorized
+        })?;
+
+        match required_scope {
+            Some(RequiredScope(scope)) if record.scopes.iter().any(|s| s == scope) => {}
+            Some(RequiredScope(scope)) => {
+                log::debug!("personal access token {} lacks scope {}", record.id, scope);
+                return Err(Error::Forbidden);
+            }
+            None => {
+                log::debug!("personal access tokens are not accepted on this route");
+                return Err(Error::Forbidden);
+            }
+        }
+
+        ctx.db.query("UPDATE $id SET last_used = time::now();")
+            .bind(("id", &record.id))
+            .await?
+            .check()?;
+
+        Ok(Self {
+            user_id: record.user.id.to_raw(),
+            session_id: String::new(),
+            profile_id: record.profile.map(|profile| profile.id.to_raw()),
+            restricted: false,
+        })
+    }
+
+    /// Attempt to parse `Self` from the session cookie.
+    ///
+    /// For methods that may change state the CSRF token is checked as well, see `verify_csrf()`.
+    async fn from_cookie(ctx: &ApiContext, parts: &Parts, jar: &CookieJar) -> Result<Self, Error> {
+        let token = jar.get(SESSION_COOKIE).ok_or(Error::Unauthorized)?.value();
+
+        if !matches!(parts.method, Method::GET | Method::HEAD | Method::OPTIONS) {
+            verify_csrf(&parts.headers, jar)?;
         }
 
-        let token = &auth_header[SCHEME_PREFIX.len()..];
+        Self::from_token(ctx, token, &parts.extensions).await
+    }
 
+    /// Verifies an access JWT and checks that its session is still active.
+    ///
+    /// A restricted session is only accepted if the route allows it, and each use pushes back its
+    /// idle timeout.
+    async fn from_token(ctx: &ApiContext, token: &str, extensions: &Extensions) -> Result<Self, Error> {
         let jwt =
             jwt::Token::<jwt::Header, AuthUserClaims, _>::parse_unverified(token).map_err(|e| {
-                log::debug!(
-                    "failed to parse Authorization header {:?}: {}",
-                    auth_header,
-                    e
-                );
+                log::debug!("failed to parse access token {:?}: {}", token, e);
                 Error::Unauthorized
             })?;
 
@@ -97,45 +382,150 @@ impl AuthUser {
 
         let (_header, claims) = jwt.into();
 
-        // Because JWTs are stateless, we don't really have any mechanism here to invalidate them
-        // besides expiration. You probably want to add more checks, like ensuring the user ID
-        // exists and has not been deleted/banned/deactivated.
-        //
-        // You could also use the user's password hash as part of the keying material for the HMAC,
-        // so changing their password invalidates their existing sessions.
-        //
-        // In practice, Launchbadge has abandoned using JWTs for authenticating long-lived sessions,
-        // instead storing session data in Redis, which can be accessed quickly and so adds less
-        // overhead to every request compared to hitting Postgres, and allows tracking and
-        // invalidating individual sessions by simply deleting them from Redis.
-        //
-        // Technically, the Realworld spec isn't all that adamant about using JWTs and there
-        // may be some flexibility in using other kinds of tokens, depending on whether the frontend
-        // is expected to parse the token or just treat it as an opaque string.
-        //
-        // Also, if the consumer of your API is a browser, you probably want to put your session
-        // token in a cookie instead of the response body. By setting the `HttpOnly` flag, the cookie
-        // isn't exposed in the response to Javascript at all which, along with setting `Domain` and
-        // `SameSite`, prevents all kinds of session hijacking exploits.
-        //
-        // This also has the benefit of avoiding having to deal with securely storing the session
-        // token on the frontend.
+        // JWTs on their own are stateless and can't be invalidated besides expiration, so every
+        // token carries the id of a row in the `session` table. Logging out, revoking a session or
+        // changing the password deletes the row, and the token stops working immediately even
+        // though its signature and `exp` are still valid.
 
         if claims.exp < OffsetDateTime::now_utc().unix_timestamp() {
             log::debug!("token expired");
             return Err(Error::Unauthorized);
         }
 
+        let mut sql = ctx.db.query(
+            "SELECT id, profile, restricted FROM type::thing('session', $session_id)
+            WHERE user = type::thing('user', $user) AND expires > time::now();")
+            .bind(("session_id", &claims.session_id))
+            .bind(("user", &claims.user_id))
+            .await?;
+        let session: Option<SessionRecord> = sql.take(0)?;
+        let session = session.ok_or_else(|| {
+            log::debug!("session revoked or expired");
+            Error::Unauthorized
+        })?;
+
+        if session.restricted {
+            if extensions.get::<AllowRestricted>().is_none() {
+                log::debug!("restricted session {} is not accepted on this route", session.id);
+                return Err(Error::Forbidden);
+            }
+
+            let expires = (OffsetDateTime::now_utc() + pin_idle_timeout(ctx)).unix_timestamp();
+            ctx.db.query("UPDATE $id SET expires = time::from::unix($expires);")
+                .bind(("id", &session.id))
+                .bind(("expires", expires))
+                .await?
+                .check()?;
+        }
+
         Ok(Self {
             user_id: claims.user_id,
+            session_id: claims.session_id,
+            profile_id: session.profile.map(|profile| profile.id.to_raw()),
+            restricted: session.restricted,
         })
     }
 }
 
+/// Adds the session, refresh and CSRF cookies for a browser frontend to `jar`.
+///
+/// The access and refresh tokens are `HttpOnly`, so scripts injected into the page can't steal
+/// them, and `SameSite=Strict`. The CSRF token is replaced along with them.
+pub(in crate::api) fn add_session_cookies(
+    ctx: &ApiContext,
+    jar: CookieJar,
+    token: String,
+    refresh_token: String,
+) -> CookieJar {
+    let secure = ctx.config.secure_cookies;
+    let cookie = |name: &'static str, value: String, max_age: time::Duration| {
+        Cookie::build(name, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .secure(secure)
+            .max_age(max_age)
+            .finish()
+    };
+
+    let mut refresh = cookie(REFRESH_COOKIE, refresh_token, DEFAULT_SESSION_LENGTH);
+    refresh.set_path(REFRESH_COOKIE_PATH);
+    let mut csrf = cookie(CSRF_COOKIE, generate_token(), DEFAULT_SESSION_LENGTH);
+    csrf.set_http_only(false);
+
+    jar.add(cookie(SESSION_COOKIE, token, ACCESS_TOKEN_LENGTH))
+        .add(refresh)
+        .add(csrf)
+}
+
+/// Removes the cookies added by `add_session_cookies()`.
+///
+/// The refresh cookie isn't sent along outside of its path, so `CookieJar::remove()` wouldn't know
+/// about it. Expired cookies are added in their place instead.
+pub(in crate::api) fn remove_session_cookies(jar: CookieJar) -> CookieJar {
+    [(SESSION_COOKIE, "/"), (REFRESH_COOKIE, REFRESH_COOKIE_PATH), (CSRF_COOKIE, "/")]
+        .into_iter()
+        .fold(jar, |jar, (name, path)| {
+            let mut cookie = Cookie::build(name, "").path(path).finish();
+            cookie.make_removal();
+            jar.add(cookie)
+        })
+}
+
+/// Returns the refresh token from its cookie, if the browser sent one.
+pub(in crate::api) fn refresh_cookie(jar: &CookieJar) -> Option<String> {
+    jar.get(REFRESH_COOKIE).map(|cookie| cookie.value().to_owned())
+}
+
+/// Checks the double-submit CSRF token: the `X-CSRF-Token` header must match the CSRF cookie.
+///
+/// Another site can make the browser send our cookies along, but can't read them, so it can't
+/// produce the header.
+pub(in crate::api) fn verify_csrf(headers: &HeaderMap, jar: &CookieJar) -> Result<(), Error> {
+    let cookie = jar.get(CSRF_COOKIE).map(|cookie| cookie.value());
+    let header = headers.get(CSRF_HEADER).and_then(|header| header.to_str().ok());
+
+    match (cookie, header) {
+        (Some(cookie), Some(header)) if constant_time_eq(cookie.as_bytes(), header.as_bytes()) => Ok(()),
+        _ => {
+            log::debug!("CSRF token missing or not matching");
+            Err(Error::Forbidden)
+        }
+    }
+}
+
+/// How long a restricted session lasts without being used.
+fn pin_idle_timeout(ctx: &ApiContext) -> time::Duration {
+    time::Duration::minutes(ctx.config.pin_idle_timeout_minutes)
+}
+
+/// Compares two byte strings without returning early, so the time taken doesn't reveal how much
+/// of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Generates a random opaque token, hex encoded.
+pub(in crate::api) fn generate_token() -> String {
+    to_hex(&rand::random::<[u8; 32]>())
+}
+
+/// Hashes an opaque token for storage. The tokens are random, so a fast hash is enough here.
+pub(in crate::api) fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+pub(in crate::api) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
 impl MaybeAuthUser {
     /// If this is `Self(Some(AuthUser))`, return `AuthUser::user_id`
     pub fn user_id(&self) -> Option<String> {
-        self.0.as_ref().map(|auth_user| auth_user.user_id)
+        self.0.as_ref().map(|auth_user| auth_user.user_id.clone())
     }
 }
 
@@ -157,13 +547,11 @@ where
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         let ctx: ApiContext = ApiContext::from_ref(state);
 
-        // Get the value of the `Authorization` header, if it was sent at all.
-        let auth_header = parts
-            .headers
-            .get(AUTHORIZATION)
-            .ok_or(Error::Unauthorized)?;
-
-        Self::from_authorization(&ctx, auth_header)
+        // API clients send the `Authorization` header, browsers the session cookie.
+        match parts.headers.get(AUTHORIZATION) {
+            Some(auth_header) => Self::from_authorization(&ctx, auth_header, &parts.extensions).await,
+            None => Self::from_cookie(&ctx, parts, &CookieJar::from_headers(&parts.headers)).await,
+        }
     }
 }
 
@@ -178,13 +566,83 @@ where
     async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
         let ctx: ApiContext = ApiContext::from_ref(state);
 
-        Ok(Self(
-            // Get the value of the `Authorization` header, if it was sent at all.
-            parts
-                .headers
-                .get(AUTHORIZATION)
-                .map(|auth_header| AuthUser::from_authorization(&ctx, auth_header))
-                .transpose()?,
-        ))
+        let jar = CookieJar::from_headers(&parts.headers);
+
+        // Get the value of the `Authorization` header or the session cookie, if either was sent at all.
+        let auth_user = match parts.headers.get(AUTHORIZATION) {
+            Some(auth_header) => Some(AuthUser::from_authorization(&ctx, auth_header, &parts.extensions).await?),
+            None if jar.get(SESSION_COOKIE).is_some() => Some(AuthUser::from_cookie(&ctx, parts, &jar).await?),
+            None => None,
+        };
+
+        Ok(Self(auth_user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header::{COOKIE, SET_COOKIE};
+    use axum::http::{HeaderMap, HeaderName, Method, StatusCode};
+    use serde_json::json;
+
+    use crate::api::testing::{TestApp, PASSWORD};
+
+    /// The value of the `name` cookie set by a response.
+    fn cookie(headers: &HeaderMap, name: &str) -> String {
+        headers.get_all(SET_COOKIE).iter()
+            .filter_map(|header| header.to_str().ok()?.split(';').next()?.split_once('='))
+            .find(|(cookie, _)| *cookie == name)
+            .map(|(_, value)| value.to_owned())
+            .unwrap_or_else(|| panic!("no {name} cookie in {headers:?}"))
+    }
+
+    /// Signs up `ann` and returns the cookies set for a browser.
+    async fn sign_up(app: &TestApp) -> HeaderMap {
+        let user = json!({ "user": { "username": "ann", "email": "ann@example.com", "password": PASSWORD } });
+        let (status, headers, body) = app.request_with_headers(Method::POST, "/users", &[], Some(user)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        headers
+    }
+
+    #[tokio::test]
+    async fn a_browser_is_authenticated_by_its_cookie_and_changes_need_the_csrf_token() {
+        let app = TestApp::new().await;
+        let headers = sign_up(&app).await;
+        let csrf = cookie(&headers, super::CSRF_COOKIE);
+        let cookies = format!(
+            "{}={}; {}={csrf}", super::SESSION_COOKIE, cookie(&headers, super::SESSION_COOKIE), super::CSRF_COOKIE);
+        let csrf_header = HeaderName::from_static(super::CSRF_HEADER);
+
+        let (status, _, _) = app.request_with_headers(Method::GET, "/user", &[(COOKIE, &cookies)], None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let update = json!({ "user": { "email": "ann@example.org" } });
+        let (status, _, _) =
+            app.request_with_headers(Method::PUT, "/user", &[(COOKIE, &cookies)], Some(update.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let wrong = [(COOKIE, cookies.as_str()), (csrf_header.clone(), "wrong")];
+        let (status, _, _) = app.request_with_headers(Method::PUT, "/user", &wrong, Some(update.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let right = [(COOKIE, cookies.as_str()), (csrf_header, csrf.as_str())];
+        let (status, _, body) = app.request_with_headers(Method::PUT, "/user", &right, Some(update)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["user"]["email"], "ann@example.org");
+    }
+
+    #[tokio::test]
+    async fn a_browser_refreshes_with_its_cookie_and_the_csrf_token() {
+        let app = TestApp::new().await;
+        let headers = sign_up(&app).await;
+        let csrf = cookie(&headers, super::CSRF_COOKIE);
+        let cookies = format!(
+            "{}={}; {}={csrf}", super::REFRESH_COOKIE, cookie(&headers, super::REFRESH_COOKIE), super::CSRF_COOKIE);
+
+        let (status, _, _) =
+            app.request_with_headers(Method::POST, "/users/refresh", &[(COOKIE, &cookies)], None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let right = [(COOKIE, cookies.as_str()), (HeaderName::from_static(super::CSRF_HEADER), csrf.as_str())];
+        let (status, headers, body) = app.request_with_headers(Method::POST, "/users/refresh", &right, None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(cookie(&headers, super::REFRESH_COOKIE), body["refresh_token"].as_str().unwrap());
     }
 }
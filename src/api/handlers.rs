@@ -1,33 +1,63 @@
 
-use axum::Router;
+use axum::extract::DefaultBodyLimit;
+use axum::{middleware, Extension, Router};
 use axum::routing::{delete, get, patch, post, put};
+use serde::Deserialize;
 use tower_http::trace::TraceLayer;
+pub(crate) mod access_token;
+pub(crate) mod account;
+pub(crate) mod audit;
+pub(crate) mod backup;
 pub(crate) mod dose;
+pub(crate) mod grant;
+pub(crate) mod history;
+pub(crate) mod login_attempt;
 pub(crate) mod medication;
 pub(crate) mod reminder;
 pub(crate) mod note;
+pub(crate) mod pin;
+pub(crate) mod profile;
+pub(crate) mod recovery_code;
 pub(crate) mod store;
+pub(crate) mod trash;
+pub(crate) mod two_factor;
 pub(crate) mod uom;
-// pub(crate) mod user;
+pub(crate) mod user;
 
-use crate::api::ApiContext;
+use crate::api::extractor::{AllowRestricted, RequiredScope};
+use crate::api::handlers::dose::DOSE;
+use crate::api::handlers::medication::MEDICATION;
+use crate::api::handlers::store::STORE;
+use crate::api::repository::dose::Dose;
+use crate::api::repository::medication::Medication;
+use crate::api::repository::note::Note;
+use crate::api::repository::reminder::Reminder;
+use crate::api::repository::store::Store;
+use crate::api::{ApiContext, Error, Result};
 
 /// Creates a router for the Dose API with the following routes:
-/// - POST /dose - creates a new dose
-/// - GET /dose/:id - reads a dose with the given ID
-/// - PUT /dose/:id - updates a dose with the given ID
-/// - DELETE /dose/:id - deletes a dose with the given ID
+/// - POST /doses - creates a new dose
+/// - GET /doses/:id - reads a dose with the given ID
+/// - PUT /doses/:id - updates a dose with the given ID
+/// - DELETE /doses/:id - deletes a dose with the given ID
+/// - GET /doses/:id/history - lists the earlier versions of a dose
+/// - POST /doses/:id/revert/:version - reverts a dose to an earlier version
 /// - GET /doses - lists all doses
+/// - GET /doses/medications - lists the doses of a medication
+/// - GET /doses/stores - lists the doses of a store
+///
 /// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the given ApiContext state.
 pub(crate) fn dose_router(api_context: ApiContext) -> Router<ApiContext> {
     Router::new()
-    .route("/doses", post(dose::create_dose))
-    .route("/doses/:id", get(dose::read_dose))
-    .route("/doses/:id", put(dose::update_dose))
-    .route("/doses/:id", delete(dose::delete_dose))
-    .route("/doses", get(dose::list_doses_for_user))
-    .route("/doses/medications", get(dose::list_doses_for_medication))
-    .route("/doses/stores", get(dose::list_doses_for_store))
+    .route("/doses", post(dose::create_dose).layer((require_scope("doses:write"), allow_restricted())))
+    .route("/doses/:id", get(dose::read_dose).layer(require_scope("doses:read")))
+    .route("/doses/:id", put(dose::update_dose).layer(require_scope("doses:write")))
+    .route("/doses/:id", delete(dose::delete_dose).layer(require_scope("doses:write")))
+    .route("/doses/:id/history", get(history::list_history::<Dose>).layer(require_scope("doses:read")))
+    .route("/doses/:id/revert/:version", post(history::revert::<Dose>).layer(require_scope("doses:write")))
+    .route("/doses", get(dose::list_doses_for_user).layer(require_scope("doses:read")))
+    .route("/doses/medications", get(dose::list_doses_for_medication).layer(require_scope("doses:read")))
+    .route("/doses/stores", get(dose::list_doses_for_store).layer(require_scope("doses:read")))
     .layer(TraceLayer::new_for_http())
     .with_state(api_context)
 }
@@ -45,19 +75,25 @@ pub(crate) fn dose_router(api_context: ApiContext) -> Router<ApiContext> {
 /// * POST `/medications` - Creates a new medication
 /// * GET `/medications/:id` - Retrieves a medication by ID
 /// * PUT `/medications/:id` - Updates a medication by ID
+/// * PATCH `/medications/deactivate` - Deactivates a medication
 /// * DELETE `/medications/:id` - Deletes a medication by ID
+/// * GET `/medications/:id/history` - Lists the earlier versions of a medication
+/// * POST `/medications/:id/revert/:version` - Reverts a medication to an earlier version
 /// * GET `/medications` - Retrieves a list of all medications
+/// * GET `/medications/status` - Retrieves a list of the active or inactive medications
 ///
-/// The router is also
+/// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the provided `api_context`.
 pub(crate) fn medication_router(api_context: ApiContext) -> Router<ApiContext> {
     Router::new()
-    .route("/medications", post(medication::create_med))
-    .route("/medications/:id", get(medication::read_med))
-    .route("/medications/:id", put(medication::update_med))
-    .route("/medications/deactivate", patch(medication::deactivate_med))
-    .route("/medications/:id", delete(medication::delete_med))
-    .route("/medications", get(medication::list_all_meds))
-    .route("/medications/status", get(medication::list_user_meds_by_status))
+    .route("/medications", post(medication::create_med).layer(require_scope("medications:write")))
+    .route("/medications/:id", get(medication::read_med).layer(require_scope("medications:read")))
+    .route("/medications/:id", put(medication::update_med).layer(require_scope("medications:write")))
+    .route("/medications/deactivate", patch(medication::deactivate_med).layer(require_scope("medications:write")))
+    .route("/medications/:id", delete(medication::delete_med).layer(require_scope("medications:write")))
+    .route("/medications/:id/history", get(history::list_history::<Medication>).layer(require_scope("medications:read")))
+    .route("/medications/:id/revert/:version", post(history::revert::<Medication>).layer(require_scope("medications:write")))
+    .route("/medications", get(medication::list_all_meds).layer(require_scope("medications:read")))
+    .route("/medications/status", get(medication::list_user_meds_by_status).layer(require_scope("medications:read")))
     .layer(TraceLayer::new_for_http())
     .with_state(api_context)
 }
@@ -70,21 +106,24 @@ pub(crate) fn medication_router(api_context: ApiContext) -> Router<ApiContext> {
 ///
 /// # Returns
 ///
-/// A `Router` instance with routes for creating, reading, updating, and deleting notes, as well as listing all notes.
+/// A `Router` instance with routes for creating, reading, updating, and deleting notes, listing and reverting to
+/// their earlier versions, as well as listing all notes.
 /// The router is also layered with `TraceLayer` for logging HTTP requests and responses.
 pub(crate) fn note_router(api_context: ApiContext) -> Router<ApiContext> {
     Router::new()
-    .route("/notes", post(note::create_note))
-    .route("/notes/:id", get(note::read_note))
-    .route("/notes/:id", put(note::update_note))
-    .route("/notes/:id", delete(note::delete_note))
-    .route("/notes", get(note::list_notes))
-    .route("/notes/dose", get(note::list_all_dose_notes))
-    .route("/notes/dose/:id", get(note::list_notes_for_dose))
-    .route("/notes/meds", get(note::list_all_medication_notes))
-    .route("/notes/med/:id", get(note::list_notes_for_medication))
-    .route("/notes/store", get(note::list_all_store_notes))
-    .route("/notes/store/:id", get(note::list_notes_for_store))
+    .route("/notes", post(note::create_note).layer(require_scope("notes:write")))
+    .route("/notes/:id", get(note::read_note).layer(require_scope("notes:read")))
+    .route("/notes/:id", put(note::update_note).layer(require_scope("notes:write")))
+    .route("/notes/:id", delete(note::delete_note).layer(require_scope("notes:write")))
+    .route("/notes/:id/history", get(history::list_history::<Note>).layer(require_scope("notes:read")))
+    .route("/notes/:id/revert/:version", post(history::revert::<Note>).layer(require_scope("notes:write")))
+    .route("/notes", get(note::list_notes).layer(require_scope("notes:read")))
+    .route("/notes/dose", get(note::list_all_dose_notes).layer(require_scope("notes:read")))
+    .route("/notes/dose/:id", get(note::list_notes_for_dose).layer(require_scope("notes:read")))
+    .route("/notes/meds", get(note::list_all_medication_notes).layer(require_scope("notes:read")))
+    .route("/notes/med/:id", get(note::list_notes_for_medication).layer(require_scope("notes:read")))
+    .route("/notes/store", get(note::list_all_store_notes).layer(require_scope("notes:read")))
+    .route("/notes/store/:id", get(note::list_notes_for_store).layer(require_scope("notes:read")))
     .layer(TraceLayer::new_for_http())
     .with_state(api_context)
 }
@@ -99,22 +138,28 @@ pub(crate) fn note_router(api_context: ApiContext) -> Router<ApiContext> {
 ///
 /// A `Router` instance with the following routes:
 ///
-/// * POST /reminder - Create a new reminder
-/// * GET /reminder/:id - Read a reminder by ID
-/// * PUT /reminder/:id - Update a reminder by ID
-/// * PATCH /reminder/:id - Deactivate a reminder by ID
-/// * DELETE /reminder/:id - Delete a reminder by ID
-/// * GET /reminders - List all reminders
-/// *
+/// * POST /reminders - Create a new reminder
+/// * GET /reminders/:id - Read a reminder by ID
+/// * PUT /reminders/:id - Update a reminder by ID
+/// * PATCH /reminders/:id - Deactivate a reminder by ID
+/// * DELETE /reminders/:id - Delete a reminder by ID
+/// * GET /reminders/:id/history - List the earlier versions of a reminder
+/// * POST /reminders/:id/revert/:version - Revert a reminder to an earlier version
+/// * POST /reminders/:id/acknowledge - Acknowledge a reminder by ID
+/// * GET /reminders/ - List all reminders
+/// * GET /activereminders/ - List the active reminders
 pub(crate) fn reminder_router(api_context: ApiContext) -> Router<ApiContext> {
     Router::new()
-    .route("/reminders", post(reminder::create_reminder))
-    .route("/reminders/:id", get(reminder::read_reminder))
-    .route("/reminders/:id", put(reminder::update_reminder))
-    .route("/reminders/:id", patch(reminder::deactivate_reminder))
-    .route("/reminders/:id", delete(reminder::delete_reminder))
-    .route("/reminders/", get(reminder::list_reminders))
-    .route("/activereminders/", get(reminder::list_active_reminders))
+    .route("/reminders", post(reminder::create_reminder).layer(require_scope("reminders:write")))
+    .route("/reminders/:id", get(reminder::read_reminder).layer(require_scope("reminders:read")))
+    .route("/reminders/:id", put(reminder::update_reminder).layer(require_scope("reminders:write")))
+    .route("/reminders/:id", patch(reminder::deactivate_reminder).layer(require_scope("reminders:write")))
+    .route("/reminders/:id", delete(reminder::delete_reminder).layer(require_scope("reminders:write")))
+    .route("/reminders/:id/history", get(history::list_history::<Reminder>).layer(require_scope("reminders:read")))
+    .route("/reminders/:id/revert/:version", post(history::revert::<Reminder>).layer(require_scope("reminders:write")))
+    .route("/reminders/:id/acknowledge", post(reminder::acknowledge_reminder).layer((require_scope("reminders:write"), allow_restricted())))
+    .route("/reminders/", get(reminder::list_reminders).layer(require_scope("reminders:read")))
+    .route("/activereminders/", get(reminder::list_active_reminders).layer((require_scope("reminders:read"), allow_restricted())))
     .layer(TraceLayer::new_for_http())
     .with_state(api_context)
 }
@@ -130,44 +175,223 @@ pub(crate) fn reminder_router(api_context: ApiContext) -> Router<ApiContext> {
 /// The router object with the store API endpoints and middleware added
 pub(crate) fn store_router(api_context: ApiContext) -> Router<ApiContext> {
     Router::new()
-    .route("/stores", post(store::create_store))
-    .route("/stores/:id", get(store::read_store))
-    .route("/stores/:id", put(store::update_store))
-    .route("/stores/:id", patch(store::deactivate_store))
-    .route("/stores/:id", delete(store::delete_store))
-    .route("/stores", get(store::list_stores))
-    .route("/stores/med", get(store::list_stores_for_medication))
-    .route("/stores/all", get(store::list_all_stores_for_medication))
+    .route("/stores", post(store::create_store).layer(require_scope("stores:write")))
+    .route("/stores/:id", get(store::read_store).layer(require_scope("stores:read")))
+    .route("/stores/:id", put(store::update_store).layer(require_scope("stores:write")))
+    .route("/stores/:id", patch(store::deactivate_store).layer(require_scope("stores:write")))
+    .route("/stores/:id", delete(store::delete_store).layer(require_scope("stores:write")))
+    .route("/stores/:id/refill", post(store::refill_store).layer(require_scope("stores:write")))
+    .route("/stores/:id/history", get(history::list_history::<Store>).layer(require_scope("stores:read")))
+    .route("/stores/:id/revert/:version", post(history::revert::<Store>).layer(require_scope("stores:write")))
+    .route("/stores", get(store::list_stores).layer(require_scope("stores:read")))
+    .route("/stores/med", get(store::list_stores_for_medication).layer((require_scope("stores:read"), allow_restricted())))
+    .route("/stores/all", get(store::list_all_stores_for_medication).layer(require_scope("stores:read")))
     .layer(TraceLayer::new_for_http())
     .with_state(api_context)
 }
 
 /// Returns a router for the UOM API with the following routes:
-/// - POST /uom - creates a new UOM
-/// - GET /uom/:id - reads a UOM by ID
-/// - PUT /uom/:id - updates a UOM by ID
-/// - DELETE /uom/:id - deletes a UOM by ID
+/// - POST /uoms - creates a new UOM
+/// - GET /uoms/:id - reads a UOM by ID
+/// - PUT /uoms/:id - updates a UOM by ID
+/// - DELETE /uoms/:id - deletes a UOM by ID
 /// - GET /uoms - lists all UOMs
+///
 /// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the provided `api_context`.
 pub(crate) fn uom_router(api_context: ApiContext) -> Router<ApiContext> {
     Router::new()
-    .route("/uoms", post(uom::create_uom))
-    .route("/uoms/:id", get(uom::read_uom))
-    .route("/uoms/:id", put(uom::update_uom))
-    .route("/uoms/:id", delete(uom::delete_uom))
-    .route("/uoms", get(uom::list_uoms))
+    .route("/uoms", post(uom::create_uom).layer(require_scope("uoms:write")))
+    .route("/uoms/:id", get(uom::read_uom).layer(require_scope("uoms:read")))
+    .route("/uoms/:id", put(uom::update_uom).layer(require_scope("uoms:write")))
+    .route("/uoms/:id", delete(uom::delete_uom).layer(require_scope("uoms:write")))
+    .route("/uoms", get(uom::list_uoms).layer(require_scope("uoms:read")))
+    .layer(TraceLayer::new_for_http())
+    .with_state(api_context)
+}
+
+/// Returns a router for the user API with the following routes:
+/// - POST /users - registers a new user and returns a token
+/// - POST /users/login - logs in an existing user and returns a token, or a 2FA challenge if 2FA is enabled
+/// - POST /users/login/2fa - completes a 2FA challenge with a TOTP or recovery code and returns a token
+/// - POST /users/password/reset - sets a new password with a password recovery code, and revokes the sessions, access
+///   tokens and PIN of the user
+/// - POST /users/unlock - unlocks with the quick-unlock PIN and returns a token for a restricted session
+/// - POST /users/refresh - exchanges a refresh token for a new access token and refresh token
+/// - GET /user - reads the current user
+/// - PUT /user - updates the email and/or username of the current user
+/// - DELETE /user - deletes the current user and every record linked to them
+/// - GET /user/export - exports every record of the current user as one JSON document
+/// - POST /user/import - imports such a document into the account of the current user
+/// - PUT /user/password - changes the password of the current user, and revokes their sessions, access tokens and PIN
+/// - POST /user/password/recovery-codes - replaces the password recovery codes of the current user
+/// - PUT /user/pin - sets the quick-unlock PIN of the current user
+/// - DELETE /user/pin - removes the quick-unlock PIN and ends the sessions unlocked with it
+/// - POST /user/logout - ends the current session
+/// - GET /user/sessions - lists the active sessions of the current user
+/// - DELETE /user/sessions/:id - revokes a session of the current user
+/// - POST /user/2fa - starts TOTP enrollment and returns the otpauth URI
+/// - DELETE /user/2fa - disables 2FA for the current user
+/// - POST /user/2fa/verify - confirms TOTP enrollment and returns the recovery codes
+/// - POST /user/2fa/recovery-codes - replaces the recovery codes of the current user
+///
+/// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the provided `api_context`.
+pub(crate) fn user_router(api_context: ApiContext) -> Router<ApiContext> {
+    Router::new()
+    .route("/users", post(user::create_user))
+    .route("/users/login", post(user::login_user))
+    .route("/users/login/2fa", post(two_factor::login_two_factor))
+    .route("/users/password/reset", post(user::reset_password))
+    .route("/users/unlock", post(pin::unlock_user))
+    .route("/users/refresh", post(user::refresh_token))
+    .route("/user", get(user::get_current_user).put(user::update_user).delete(account::delete_account))
+    .route("/user/export", get(account::export_account))
+    .route("/user/import", post(account::import_account))
+    .route("/user/password", put(user::update_password))
+    .route("/user/password/recovery-codes", post(user::regenerate_password_recovery_codes))
+    .route("/user/pin", put(pin::set_pin).delete(pin::remove_pin))
+    .route("/user/logout", post(user::logout_user).layer(allow_restricted()))
+    .route("/user/sessions", get(user::list_sessions))
+    .route("/user/sessions/:id", delete(user::revoke_session))
+    .route("/user/2fa", post(two_factor::enroll_two_factor).delete(two_factor::disable_two_factor))
+    .route("/user/2fa/verify", post(two_factor::verify_two_factor))
+    .route("/user/2fa/recovery-codes", post(two_factor::regenerate_recovery_codes))
+    .layer(TraceLayer::new_for_http())
+    .with_state(api_context)
+}
+
+/// Returns a router for the profile API with the following routes:
+/// - POST /profiles - creates a new profile
+/// - GET /profiles - lists the profiles of the current user, or of the user in the `owner` query parameter
+/// - PUT /profiles/:id - renames a profile
+/// - PUT /user/profile - switches the current session to one of the user's profiles
+///
+/// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the provided `api_context`.
+pub(crate) fn profile_router(api_context: ApiContext) -> Router<ApiContext> {
+    Router::new()
+    .route("/profiles", post(profile::create_profile).layer(require_scope("profiles:write")))
+    .route("/profiles", get(profile::list_profiles).layer(require_scope("profiles:read")))
+    .route("/profiles/:id", put(profile::update_profile).layer(require_scope("profiles:write")))
+    .route("/user/profile", put(profile::switch_profile))
+    .layer(TraceLayer::new_for_http())
+    .with_state(api_context)
+}
+
+/// Returns a router for the care grant API with the following routes:
+/// - POST /user/grants - shares the records of the current user with another user, or changes the permission
+/// - GET /user/grants - lists the grants the current user has given
+/// - GET /user/grants/received - lists the grants other users have given to the current user
+/// - DELETE /user/grants/:id - revokes a grant given by or to the current user
+///
+/// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the provided `api_context`.
+pub(crate) fn grant_router(api_context: ApiContext) -> Router<ApiContext> {
+    Router::new()
+    .route("/user/grants", post(grant::create_grant).get(grant::list_grants))
+    .route("/user/grants/received", get(grant::list_received_grants))
+    .route("/user/grants/:id", delete(grant::revoke_grant))
+    .layer(TraceLayer::new_for_http())
+    .with_state(api_context)
+}
+
+/// Returns a router for the personal access token API with the following routes:
+/// - POST /user/tokens - creates a token with the given scopes and returns it, the only time it is shown
+/// - GET /user/tokens - lists the tokens of the current user
+/// - DELETE /user/tokens/:id - revokes a token of the current user
+///
+/// These routes don't require a scope, so a personal access token can't be used to create more of them.
+///
+/// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the provided `api_context`.
+pub(crate) fn access_token_router(api_context: ApiContext) -> Router<ApiContext> {
+    Router::new()
+    .route("/user/tokens", post(access_token::create_access_token).get(access_token::list_access_tokens))
+    .route("/user/tokens/:id", delete(access_token::revoke_access_token))
     .layer(TraceLayer::new_for_http())
     .with_state(api_context)
 }
 
-// pub(crate) fn user_router(api_context: ApiContext) -> Router<ApiContext> {
-//     Router::new()
-//     .route("/users", post(user::create_user))
-//     .route("/users/login", post(user::login_user))
-//     .route("/user", get(user::get_current_user).put(user::update_user))
-//     .layer(TraceLayer::new_for_http())
-//     .with_state(api_context)
-// }
+/// Returns a router for the audit log with the following routes:
+/// - GET /audit - lists the changes to the records of the current user, filtered by record, table and time range
+///
+/// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the provided `api_context`.
+pub(crate) fn audit_router(api_context: ApiContext) -> Router<ApiContext> {
+    Router::new()
+    .route("/audit", get(audit::list_audit))
+    .layer(TraceLayer::new_for_http())
+    .with_state(api_context)
+}
+
+/// Returns a router for the backups of the whole database with the following routes:
+/// - GET /backup - downloads a backup of every table
+/// - POST /backup/restore - restores a backup into the empty database
+///
+/// Both need the backup key from the `Config` rather than a login, checked before the body is read. A backup is
+/// larger than the default body limit, so the restore takes up to `Config::backup_max_mib` instead.
+///
+/// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the provided `api_context`.
+pub(crate) fn backup_router(api_context: ApiContext) -> Router<ApiContext> {
+    let max_bytes = api_context.config.backup_max_mib as usize * 1024 * 1024;
+    Router::new()
+    .route("/backup", get(backup::create_backup))
+    .route("/backup/restore", post(backup::restore_backup).layer(DefaultBodyLimit::max(max_bytes)))
+    .route_layer(middleware::from_fn_with_state(api_context.clone(), backup::require_backup_key))
+    .layer(TraceLayer::new_for_http())
+    .with_state(api_context)
+}
+
+/// Returns a router for the trash of deleted records with the following routes:
+/// - GET /trash - lists the deleted records of the current user, most recently deleted first
+/// - POST /trash/:table/:id/restore - restores a deleted record
+/// - DELETE /trash/:table/:id - deletes a record in the trash for good
+///
+/// The router is also layered with a TraceLayer for HTTP tracing and is initialized with the provided `api_context`.
+pub(crate) fn trash_router(api_context: ApiContext) -> Router<ApiContext> {
+    Router::new()
+    .route("/trash", get(trash::list_trash))
+    .route("/trash/:table/:id/restore", post(trash::restore_trash))
+    .route("/trash/:table/:id", delete(trash::purge_trash))
+    .layer(TraceLayer::new_for_http())
+    .with_state(api_context)
+}
+
+/// Returns the layer that marks a route as usable with a personal access token holding `scope`.
+///
+/// `AuthUser` rejects personal access tokens on routes without one, and JWTs aren't affected by it.
+fn require_scope(scope: &'static str) -> Extension<RequiredScope> {
+    debug_assert!(access_token::SCOPES.contains(&scope), "unknown scope {scope}");
+    Extension(RequiredScope(scope))
+}
+
+/// Returns the layer that marks a route as usable with a restricted session, one unlocked with the
+/// quick-unlock PIN.
+///
+/// These are the routes needed to log a dose or acknowledge a reminder, and to log out again.
+fn allow_restricted() -> Extension<AllowRestricted> {
+    Extension(AllowRestricted)
+}
+
+/// The optional `cascade` query parameter of the routes that delete, restore or purge a record others depend
+/// on, see `crate::api::repository::reference`. Without it, such a record is left as it is and the request
+/// fails with `Error::Conflict`.
+#[derive(Deserialize)]
+pub struct CascadeQuery {
+    #[serde(default)]
+    cascade: bool,
+}
+
+/// Checks that the record `table:id` exists, isn't in the trash and belongs to the given user.
+///
+/// Used by handlers that link a record to another one (a dose to its store, a store to its medication, ...),
+/// so a user can't attach their records to someone else's.  A record owned by another user is reported as
+/// `Error::NotFound`, the same as a missing one, so the ids of other users are never confirmed. Only
+/// medications, stores and doses have records linked to them.
+pub(crate) async fn ensure_owned(ctx: &ApiContext, table: &str, id: &str, user_id: &str) -> Result<()> {
+    let found = match table {
+        MEDICATION => ctx.repos.medications.read(id, user_id).await?.is_some(),
+        STORE => ctx.repos.stores.read(id, user_id).await?.is_some(),
+        DOSE => ctx.repos.doses.read(id, user_id).await?.is_some(),
+        _ => false,
+    };
+    found.then_some(()).ok_or(Error::NotFound)
+}
 
 //TODO: Deal with any table index constraints in the client side ahead of time.
 //TODO: Revisit this approach of prechecks when we switch to embedded since it should have better error responses
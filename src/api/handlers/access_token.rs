@@ -0,0 +1,229 @@
+use axum::extract::{ State, Path };
+use axum::Json;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::error::Error;
+use crate::api::extractor::{self, AuthUser, ACCESS_TOKEN_PREFIX};
+use crate::api::handlers::profile::ensure_profile;
+use crate::api::{ApiContext, Result};
+
+/// Every scope a personal access token can hold. Each route that accepts tokens requires one of
+/// these, see `handlers.rs`.
+pub(crate) const SCOPES: [&str; 14] = [
+    "medications:read",
+    "medications:write",
+    "stores:read",
+    "stores:write",
+    "doses:read",
+    "doses:write",
+    "reminders:read",
+    "reminders:write",
+    "notes:read",
+    "notes:write",
+    "uoms:read",
+    "uoms:write",
+    "profiles:read",
+    "profiles:write",
+];
+
+/// A struct representing a personal access token
+///
+/// # Fields
+///
+/// * `id` - A `Thing` representing the ID of the token
+/// * `name` - A `String` naming what the token is used for
+/// * `scopes` - The scopes the token holds, e.g. `doses:write`
+/// * `profile` - The profile used when a request doesn't name one
+/// * `token` - The token itself, only returned once when it is created
+/// * `last_used` - A `Datetime` representing when the token was last used, if ever
+/// * `created` - A `Datetime` representing the date and time the token was created
+#[derive(Serialize, Deserialize)]
+pub struct AccessToken {
+    id: Thing,
+    name: String,
+    scopes: Vec<String>,
+    profile: Option<Thing>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    last_used: Option<Datetime>,
+    created: Datetime,
+}
+
+/// A wrapper matching the `{ "token": { ... } }` shape used by token requests and responses.
+#[derive(Serialize, Deserialize)]
+pub struct AccessTokenBody<T> {
+    token: T,
+}
+
+#[derive(Deserialize)]
+pub struct CreateAccessToken {
+    name: String,
+    scopes: Vec<String>,
+    profile: Option<String>,
+}
+
+const SELECT_ACCESS_TOKEN: &str = "SELECT id, name, scopes, profile, last_used, created FROM access_token";
+
+/// Creates a personal access token for the current user
+///
+/// Only a hash of the token is stored, so it can't be shown again later. Changing or resetting the
+/// password revokes every token of the user.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing the name and scopes of the token, and optionally the ID
+///   of the profile to use, which defaults to the profile the session is switched to
+///
+/// # Returns
+///
+/// A `Json` object containing the token, or `Error::UnprocessableEntity` if no scopes or unknown ones
+/// were requested.
+pub(crate) async fn create_access_token(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<AccessTokenBody<CreateAccessToken>>,
+) -> Result<Json<AccessTokenBody<AccessToken>>> {
+    let mut req = req.token;
+
+    if req.scopes.is_empty() {
+        return Err(Error::unprocessable_entity([("scopes", "must not be empty")]));
+    }
+    if let Some(scope) = req.scopes.iter().find(|scope| !SCOPES.contains(&scope.as_str())) {
+        return Err(Error::unprocessable_entity([("scopes", format!("unknown scope {scope}"))]));
+    }
+    req.scopes.sort();
+    req.scopes.dedup();
+
+    let profile = req.profile.or(auth_user.profile_id);
+    if let Some(profile) = &profile {
+        ensure_profile(&ctx, profile, &auth_user.user_id).await?;
+    }
+
+    let token = format!("{ACCESS_TOKEN_PREFIX}{}", extractor::generate_token());
+
+    let mut sql = ctx.db.query(
+        "CREATE access_token SET user = type::thing('user', $user), name = $name, scopes = $scopes,
+            profile = IF $profile != NONE THEN type::thing('profile', $profile) END, token_hash = $token_hash
+        RETURN id, name, scopes, profile, last_used, created;")
+        .bind(("user", &auth_user.user_id))
+        .bind(("name", req.name))
+        .bind(("scopes", req.scopes))
+        .bind(("profile", profile))
+        .bind(("token_hash", extractor::hash_token(&token)))
+        .await?;
+    let access_token: Option<AccessToken> = sql.take(0)?;
+    let access_token = access_token.ok_or_else(|| anyhow::anyhow!("CREATE access_token returned no record"))?;
+
+    Ok(Json(AccessTokenBody { token: AccessToken { token: Some(token), ..access_token } }))
+}
+
+/// Lists the personal access tokens of the current user, without the tokens themselves
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+///
+/// # Returns
+///
+/// A `Json` object containing a vector of `AccessToken` structs, oldest first.
+pub(crate) async fn list_access_tokens(auth_user: AuthUser, ctx: State<ApiContext>) -> Result<Json<Vec<AccessToken>>> {
+    let mut sql = ctx.db.query(format!(
+        "{SELECT_ACCESS_TOKEN} WHERE user = type::thing('user', $user) ORDER BY created;"))
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let access_tokens: Vec<AccessToken> = sql.take(0)?;
+    Ok(Json(access_tokens))
+}
+
+/// Revokes a personal access token of the current user
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `id` - A `Path` object containing the ID of the token to revoke
+///
+/// # Returns
+///
+/// `Error::NotFound` if the token does not exist or belongs to another user.
+pub(crate) async fn revoke_access_token(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<()> {
+    let mut sql = ctx.db.query(
+        "DELETE type::thing('access_token', $id) WHERE user = type::thing('user', $user) RETURN BEFORE;")
+        .bind(("id", &*id))
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let access_token: Option<Thing> = sql.take((0, "id"))?;
+    access_token.map(|_| ()).ok_or(Error::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+
+    use crate::api::testing::{id, TestApp};
+
+    async fn create(app: &TestApp, token: &str, scopes: Value) -> (StatusCode, Value) {
+        let access_token = json!({ "token": { "name": "script", "scopes": scopes } });
+        app.request(Method::POST, "/user/tokens", Some(token), Some(access_token)).await
+    }
+
+    #[tokio::test]
+    async fn a_token_is_accepted_only_on_the_routes_of_its_scopes() {
+        let app = TestApp::new().await;
+        let session = app.sign_up("ann").await;
+        let (status, body) = create(&app, &session, json!(["medications:read"])).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let access_token = body["token"]["token"].as_str().unwrap().to_owned();
+
+        let (status, body) = app.request(Method::GET, "/medications", Some(&access_token), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let medication = json!({ "name": "Aspirin" });
+        let (status, _) = app.request(Method::POST, "/medications", Some(&access_token), Some(medication)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = app.request(Method::GET, "/doses", Some(&access_token), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = create(&app, &access_token, json!(["medications:write"])).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = app.request(Method::GET, "/user", Some(&access_token), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (_, tokens) = app.request(Method::GET, "/user/tokens", Some(&session), None).await;
+        assert!(tokens[0]["last_used"].is_string(), "{tokens}");
+        assert!(tokens[0].get("token").is_none(), "{tokens}");
+    }
+
+    #[tokio::test]
+    async fn a_token_needs_known_scopes() {
+        let app = TestApp::new().await;
+        let session = app.sign_up("ann").await;
+
+        let (status, _) = create(&app, &session, json!([])).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (status, _) = create(&app, &session, json!(["everything"])).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn a_revoked_token_is_refused() {
+        let app = TestApp::new().await;
+        let session = app.sign_up("ann").await;
+        let (_, body) = create(&app, &session, json!(["medications:read"])).await;
+        let access_token = body["token"]["token"].as_str().unwrap().to_owned();
+
+        let uri = format!("/user/tokens/{}", id(&body["token"]));
+        let (status, _) = app.request(Method::DELETE, &uri, Some(&session), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = app.request(Method::GET, "/medications", Some(&access_token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
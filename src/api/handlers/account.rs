@@ -0,0 +1,532 @@
+//! Export, import and deletion of a whole account.
+//!
+//! # Export format
+//!
+//! `GET /user/export` returns a single JSON document, which `POST /user/import` accepts again:
+//!
+//! ```json
+//! {
+//!   "format": "medoxido-export",
+//!   "version": 1,
+//!   "exported": "2024-01-31T12:00:00Z",
+//!   "user": { "username": "...", "email": "...", "created": "..." },
+//!   "profiles": [{ "id": "...", "name": "...", "created": "..." }],
+//!   "units_of_measure": [{ "id": "...", "name": "...", "abbreviation": "...", "active": true, "created": "..." }],
+//!   "medications": [{ "id": "...", "profile": "...", "name": "...", "active": true, "created": "..." }],
+//!   "stores": [{ "id": "...", "profile": "...", "medication": "...", "production_date": "...",
+//!                "expiration_date": "...", "lot_number": "...", "quantity": 30.0, "unit": "...",
+//!                "active": true, "created": "..." }],
+//!   "doses": [{ "id": "...", "profile": "...", "store": "...", "quantity": 1.0, "unit": "...", "created": "..." }],
+//!   "reminders": [{ "id": "...", "profile": "...", "medication": "...", "start": "...", "end": "...",
+//!                   "days": "1111111", "times": ["08:00"], "active": true, "created": "..." }],
+//!   "notes": [{ "id": "...", "profile": "...", "note_table": "dose", "note_thing": "...", "content": "...",
+//!               "created": "...", "target": { "table": "dose", "id": "...", "medication": "..." } }]
+//! }
+//! ```
+//!
+//! IDs are the bare record IDs without the table name, and every reference (`profile`, `medication`,
+//! `store`, `note_thing`) points at another record in the same document. Dates are RFC 3339. The
+//! `target` of a note is the record it was written about, resolved to the medication it concerns;
+//! it is `null` if that record no longer exists. `target` is informational and ignored on import.
+//!
+//! Password hashes, sessions, tokens, 2FA secrets, recovery codes, care grants, the audit log and the
+//! version history are not exported, nor are the records in the trash and the records that depend on them.
+
+use axum::extract::State;
+use axum::http::header::CONTENT_DISPOSITION;
+use axum::Json;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use surrealdb::sql::{ Datetime, Id, Thing };
+
+use crate::api::error::Error;
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::user::{self, UpdatePassword, UserBody};
+use crate::api::{ApiContext, Result};
+
+/// Identifies a medoxido export document.
+const EXPORT_FORMAT: &str = "medoxido-export";
+
+/// The version of the export format. Bump it when the format changes incompatibly.
+const EXPORT_VERSION: u32 = 1;
+
+/// A complete export of the records of one user, see the module documentation for the format.
+#[derive(Serialize, Deserialize)]
+pub struct Export {
+    format: String,
+    version: u32,
+    exported: Datetime,
+    user: ExportUser,
+    profiles: Vec<ExportProfile>,
+    units_of_measure: Vec<ExportUnitOfMeasure>,
+    medications: Vec<ExportMedication>,
+    stores: Vec<ExportStore>,
+    doses: Vec<ExportDose>,
+    reminders: Vec<ExportReminder>,
+    notes: Vec<ExportNote>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportUser {
+    username: String,
+    email: String,
+    created: Datetime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportProfile {
+    id: String,
+    name: String,
+    created: Datetime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportUnitOfMeasure {
+    id: String,
+    name: String,
+    abbreviation: String,
+    active: bool,
+    created: Datetime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportMedication {
+    id: String,
+    profile: String,
+    name: String,
+    active: bool,
+    created: Datetime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportStore {
+    id: String,
+    profile: String,
+    medication: String,
+    production_date: Datetime,
+    expiration_date: Option<Datetime>,
+    lot_number: String,
+    quantity: f32,
+    unit: String,
+    active: bool,
+    created: Datetime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportDose {
+    id: String,
+    profile: String,
+    store: String,
+    quantity: f32,
+    unit: String,
+    created: Datetime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportReminder {
+    id: String,
+    profile: String,
+    medication: String,
+    start: Datetime,
+    end: Datetime,
+    days: String,
+    times: Vec<String>,
+    active: bool,
+    created: Datetime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportNote {
+    id: String,
+    profile: String,
+    note_table: String,
+    note_thing: String,
+    content: String,
+    created: Datetime,
+    #[serde(default)]
+    target: Option<NoteTarget>,
+}
+
+/// The record a note was written about.
+///
+/// # Fields
+///
+/// * `table` - The table of the record: `medication`, `store` or `dose`
+/// * `id` - The ID of the record in the export
+/// * `medication` - The name of the medication the record is, or belongs to
+#[derive(Serialize, Deserialize)]
+pub struct NoteTarget {
+    table: String,
+    id: String,
+    medication: String,
+}
+
+/// How many records of each kind an import added.
+#[derive(Serialize, Deserialize)]
+pub struct ImportSummary {
+    profiles: usize,
+    units_of_measure: usize,
+    medications: usize,
+    stores: usize,
+    doses: usize,
+    reminders: usize,
+    notes: usize,
+}
+
+/// An existing profile of the importing user, which imported profiles of the same name are merged into.
+#[derive(Deserialize)]
+struct ExistingProfile {
+    id: String,
+    name: String,
+}
+
+/// Exports every record of the current user as a single JSON document
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+///
+/// # Returns
+///
+/// A `Json` object containing the `Export`, served as a `medoxido-export.json` attachment.
+pub(crate) async fn export_account(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+) -> Result<([(axum::http::HeaderName, &'static str); 1], Json<Export>)> {
+    let mut sql = ctx.db.query(
+        "SELECT username, email, created FROM type::thing('user', $user);
+        SELECT meta::id(id) AS id, name, created FROM profile
+            WHERE user = type::thing('user', $user) ORDER BY created;
+        SELECT meta::id(id) AS id, name, abbreviation, active, created FROM unit_of_measure
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE ORDER BY created;
+        SELECT meta::id(id) AS id, meta::id(profile) AS profile, name, active, created FROM medication
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE ORDER BY created;
+        SELECT meta::id(id) AS id, meta::id(profile) AS profile, meta::id(medication) AS medication,
+            production_date, expiration_date, lot_number, quantity, unit, active, created FROM store
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND medication.deleted_at = NONE
+            ORDER BY created;
+        SELECT meta::id(id) AS id, meta::id(profile) AS profile, meta::id(store) AS store, quantity, unit, created
+            FROM dose WHERE user = type::thing('user', $user) AND deleted_at = NONE AND store.deleted_at = NONE
+            AND store.medication.deleted_at = NONE ORDER BY created;
+        SELECT meta::id(id) AS id, meta::id(profile) AS profile, meta::id(medication) AS medication,
+            start, end, days, times, active, created FROM reminder
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND medication.deleted_at = NONE
+            ORDER BY created;
+        SELECT meta::id(id) AS id, meta::id(profile) AS profile, note_table, note_thing, content, created
+            FROM note WHERE user = type::thing('user', $user) AND deleted_at = NONE ORDER BY created;")
+        .bind(("user", &auth_user.user_id))
+        .await?;
+
+    let user: Option<ExportUser> = sql.take(0)?;
+    let mut export = Export {
+        format: EXPORT_FORMAT.to_owned(),
+        version: EXPORT_VERSION,
+        exported: Datetime::default(),
+        user: user.ok_or(Error::NotFound)?,
+        profiles: sql.take(1)?,
+        units_of_measure: sql.take(2)?,
+        medications: sql.take(3)?,
+        stores: sql.take(4)?,
+        doses: sql.take(5)?,
+        reminders: sql.take(6)?,
+        notes: sql.take(7)?,
+    };
+
+    for profile in &mut export.profiles {
+        profile.name = ctx.cipher.decrypt(&profile.name)?;
+    }
+    for medication in &mut export.medications {
+        medication.name = ctx.cipher.decrypt(&medication.name)?;
+    }
+    for store in &mut export.stores {
+        store.lot_number = ctx.cipher.decrypt(&store.lot_number)?;
+        store.unit = ctx.cipher.decrypt(&store.unit)?;
+    }
+    for dose in &mut export.doses {
+        dose.unit = ctx.cipher.decrypt(&dose.unit)?;
+    }
+    for note in &mut export.notes {
+        note.content = ctx.cipher.decrypt(&note.content)?;
+    }
+
+    let medications: HashMap<&str, &str> =
+        export.medications.iter().map(|m| (m.id.as_str(), m.name.as_str())).collect();
+    let stores: HashMap<&str, &str> =
+        export.stores.iter().map(|s| (s.id.as_str(), s.medication.as_str())).collect();
+    let doses: HashMap<&str, &str> =
+        export.doses.iter().map(|d| (d.id.as_str(), d.store.as_str())).collect();
+
+    for note in &mut export.notes {
+        let medication = match note.note_table.as_str() {
+            "medication" => Some(note.note_thing.as_str()),
+            "store" => stores.get(note.note_thing.as_str()).copied(),
+            "dose" => doses.get(note.note_thing.as_str()).and_then(|store| stores.get(store)).copied(),
+            _ => None,
+        };
+        note.target = medication.and_then(|id| medications.get(id)).map(|name| NoteTarget {
+            table: note.note_table.clone(),
+            id: note.note_thing.clone(),
+            medication: (*name).to_owned(),
+        });
+    }
+
+    Ok((
+        [(CONTENT_DISPOSITION, "attachment; filename=\"medoxido-export.json\"")],
+        Json(export),
+    ))
+}
+
+/// Imports an export into the account of the current user
+///
+/// Every record gets a new ID, and the references between them are rewritten to match. Imported
+/// profiles are merged into existing profiles of the same name, units of measure the user already
+/// has are skipped, and notes whose target is not part of the export are left out. The `user`
+/// section of the export is ignored. Everything is added in one transaction, so a failed import
+/// leaves no partial data behind.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(export)` - A `Json` object containing an `Export`
+///
+/// # Returns
+///
+/// A `Json` object counting the added records, or `Error::UnprocessableEntity` if the document is not a
+/// supported export, references a record it doesn't contain, or a medication already exists in the
+/// profile it is imported into.
+pub(crate) async fn import_account(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(mut export): Json<Export>,
+) -> Result<Json<ImportSummary>> {
+    if export.format != EXPORT_FORMAT {
+        return Err(Error::unprocessable_entity([("format", format!("must be {EXPORT_FORMAT}"))]));
+    }
+    if export.version != EXPORT_VERSION {
+        return Err(Error::unprocessable_entity([("version", format!("{} is not supported", export.version))]));
+    }
+
+    let mut sql = ctx.db.query(
+        "SELECT meta::id(id) AS id, name FROM profile WHERE user = type::thing('user', $user);
+        SELECT VALUE [name, abbreviation] FROM unit_of_measure
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE;
+        SELECT VALUE [meta::id(profile), name] FROM medication
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE;")
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let existing_profiles: Vec<ExistingProfile> = sql.take(0)?;
+    let existing_units: Vec<(String, String)> = sql.take(1)?;
+    let existing_medications: Vec<(String, String)> = sql.take(2)?;
+
+    let existing_profiles: HashMap<String, String> = existing_profiles
+        .into_iter()
+        .map(|profile| Ok((ctx.cipher.decrypt(&profile.name)?, profile.id)))
+        .collect::<anyhow::Result<_>>()?;
+    let existing_units: HashSet<(String, String)> = existing_units.into_iter().collect();
+    let existing_medications: HashSet<(String, String)> = existing_medications
+        .into_iter()
+        .map(|(profile, name)| Ok((profile, ctx.cipher.decrypt(&name)?)))
+        .collect::<anyhow::Result<_>>()?;
+
+    // Old ID -> new ID, per table.
+    let mut profiles = HashMap::new();
+    export.profiles.retain_mut(|profile| {
+        let existing = existing_profiles.get(&profile.name);
+        let id = existing.cloned().unwrap_or_else(new_id);
+        profiles.insert(std::mem::replace(&mut profile.id, id.clone()), id);
+        existing.is_none()
+    });
+
+    export.units_of_measure.retain_mut(|unit| {
+        unit.id = new_id();
+        !existing_units.contains(&(unit.name.clone(), unit.abbreviation.clone()))
+    });
+
+    let mut medications = HashMap::new();
+    for medication in &mut export.medications {
+        medication.profile = remap(&profiles, "medications", &medication.profile)?;
+        if existing_medications.contains(&(medication.profile.clone(), medication.name.clone())) {
+            return Err(Error::unprocessable_entity([(
+                "medications",
+                format!("{} already exists in the profile", medication.name),
+            )]));
+        }
+        let id = new_id();
+        medications.insert(std::mem::replace(&mut medication.id, id.clone()), id);
+    }
+
+    let mut stores = HashMap::new();
+    for store in &mut export.stores {
+        store.profile = remap(&profiles, "stores", &store.profile)?;
+        store.medication = remap(&medications, "stores", &store.medication)?;
+        let id = new_id();
+        stores.insert(std::mem::replace(&mut store.id, id.clone()), id);
+    }
+
+    let mut doses = HashMap::new();
+    for dose in &mut export.doses {
+        dose.profile = remap(&profiles, "doses", &dose.profile)?;
+        dose.store = remap(&stores, "doses", &dose.store)?;
+        let id = new_id();
+        doses.insert(std::mem::replace(&mut dose.id, id.clone()), id);
+    }
+
+    for reminder in &mut export.reminders {
+        reminder.id = new_id();
+        reminder.profile = remap(&profiles, "reminders", &reminder.profile)?;
+        reminder.medication = remap(&medications, "reminders", &reminder.medication)?;
+    }
+
+    let mut notes = Vec::with_capacity(export.notes.len());
+    for mut note in export.notes {
+        let targets = match note.note_table.as_str() {
+            "medication" => &medications,
+            "store" => &stores,
+            "dose" => &doses,
+            _ => continue,
+        };
+        let Some(target) = targets.get(&note.note_thing) else { continue };
+        note.note_thing = target.clone();
+        note.profile = remap(&profiles, "notes", &note.profile)?;
+        note.id = new_id();
+        notes.push(note);
+    }
+
+    // The records are bound as they are, so their encrypted fields are encrypted here.
+    for profile in &mut export.profiles {
+        profile.name = ctx.cipher.encrypt(&profile.name);
+    }
+    for medication in &mut export.medications {
+        medication.name = ctx.cipher.encrypt(&medication.name);
+    }
+    for store in &mut export.stores {
+        store.lot_number = ctx.cipher.encrypt(&store.lot_number);
+        store.unit = ctx.cipher.encrypt(&store.unit);
+    }
+    for dose in &mut export.doses {
+        dose.unit = ctx.cipher.encrypt(&dose.unit);
+    }
+    for note in &mut notes {
+        note.content = ctx.cipher.encrypt(&note.content);
+    }
+
+    let summary = ImportSummary {
+        profiles: export.profiles.len(),
+        units_of_measure: export.units_of_measure.len(),
+        medications: export.medications.len(),
+        stores: export.stores.len(),
+        doses: export.doses.len(),
+        reminders: export.reminders.len(),
+        notes: notes.len(),
+    };
+
+    ctx.db.query(
+        "BEGIN TRANSACTION;
+        FOR $r IN $profiles {
+            CREATE type::thing('profile', $r.id) SET user = $user, name = $r.name, created = $r.created;
+        };
+        FOR $r IN $units_of_measure {
+            CREATE type::thing('unit_of_measure', $r.id) SET user = $user, name = $r.name,
+                abbreviation = $r.abbreviation, active = $r.active, created = $r.created;
+        };
+        FOR $r IN $medications {
+            CREATE type::thing('medication', $r.id) SET user = $user, profile = type::thing('profile', $r.profile),
+                name = $r.name, active = $r.active, created = $r.created;
+        };
+        FOR $r IN $stores {
+            CREATE type::thing('store', $r.id) SET user = $user, profile = type::thing('profile', $r.profile),
+                medication = type::thing('medication', $r.medication), production_date = $r.production_date,
+                expiration_date = $r.expiration_date, lot_number = $r.lot_number, quantity = $r.quantity,
+                unit = $r.unit, active = $r.active, created = $r.created;
+        };
+        FOR $r IN $doses {
+            CREATE type::thing('dose', $r.id) SET user = $user, profile = type::thing('profile', $r.profile),
+                store = type::thing('store', $r.store), quantity = $r.quantity, unit = $r.unit, created = $r.created;
+        };
+        FOR $r IN $reminders {
+            CREATE type::thing('reminder', $r.id) SET user = $user, profile = type::thing('profile', $r.profile),
+                medication = type::thing('medication', $r.medication), start = $r.start, end = $r.end,
+                days = $r.days, times = $r.times, active = $r.active, created = $r.created;
+        };
+        FOR $r IN $notes {
+            CREATE type::thing('note', $r.id) SET user = $user, profile = type::thing('profile', $r.profile),
+                note_table = $r.note_table, note_thing = $r.note_thing, content = $r.content, created = $r.created;
+        };
+        COMMIT TRANSACTION;")
+        .bind(("actor", &auth_user.user_id))
+        .bind(("user", Thing::from(("user", auth_user.user_id.as_str()))))
+        .bind(("profiles", export.profiles))
+        .bind(("units_of_measure", export.units_of_measure))
+        .bind(("medications", export.medications))
+        .bind(("stores", export.stores))
+        .bind(("doses", export.doses))
+        .bind(("reminders", export.reminders))
+        .bind(("notes", notes))
+        .await?
+        .check()?;
+
+    Ok(Json(summary))
+}
+
+/// Deletes the account of the current user along with every record linked to it
+///
+/// The clinical records and their version history, profiles, sessions, tokens, recovery codes, the audit log
+/// of the records and the care grants given by or to the user are all deleted in one transaction, so either
+/// nothing or everything is gone. Changes the user made to the records of others stay in their audit log,
+/// without the user as actor.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing the current password
+///
+/// # Returns
+///
+/// `Error::Unauthorized` if the password is wrong.
+pub(crate) async fn delete_account(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<UserBody<UpdatePassword>>,
+) -> Result<()> {
+    let password = req.user.password
+        .ok_or_else(|| Error::unprocessable_entity([("password", "is required")]))?;
+
+    let mut sql = ctx.db.query("SELECT VALUE password_hash FROM type::thing('user', $user);")
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let password_hash: Option<String> = sql.take(0)?;
+    user::verify_password(password, password_hash.ok_or(Error::NotFound)?).await?;
+
+    // Deleting the sessions also deletes their refresh tokens through the `session_deleted` event.
+    ctx.db.query(
+        "BEGIN TRANSACTION;
+        DELETE note, dose, reminder, store, medication, unit_of_measure, profile WHERE user = $user;
+        DELETE care_grant WHERE owner = $user OR grantee = $user;
+        DELETE session, access_token, login_challenge, totp_recovery_code, password_recovery_code WHERE user = $user;
+        DELETE audit WHERE owner = $user;
+        DELETE history WHERE user = $user;
+        UPDATE audit SET actor = NONE WHERE actor = $user;
+        DELETE $user;
+        COMMIT TRANSACTION;")
+        .bind(("user", Thing::from(("user", auth_user.user_id.as_str()))))
+        .await?
+        .check()?;
+
+    Ok(())
+}
+
+fn new_id() -> String {
+    Id::rand().to_raw()
+}
+
+/// Looks up the new ID of a record referenced in an export, or returns `Error::UnprocessableEntity`
+/// naming the section with the dangling reference.
+fn remap(ids: &HashMap<String, String>, section: &'static str, id: &str) -> Result<String> {
+    ids.get(id)
+        .cloned()
+        .ok_or_else(|| Error::unprocessable_entity([(section, format!("reference to unknown record {id}"))]))
+}
@@ -0,0 +1,177 @@
+use axum::extract::{ State, Query };
+use axum::Json;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::error::Error;
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::grant::{authorize_scope, Permission, ScopeQuery};
+use crate::api::{ApiContext, Result};
+use crate::db::encryption::ENCRYPTED_FIELDS;
+
+/// The tables whose changes are recorded in the audit log, see `015_audit.surql`.
+const AUDITED_TABLES: [&str; 6] = ["unit_of_measure", "medication", "store", "dose", "reminder", "note"];
+
+/// A struct representing an entry of the audit log
+///
+/// # Fields
+///
+/// * `id` - A `Thing` representing the ID of the entry
+/// * `owner` - A `Thing` representing the user the changed record belongs to
+/// * `actor` - A `Thing` representing the user who made the change, if known
+/// * `action` - `CREATE`, `UPDATE` or `DELETE`
+/// * `table` - The table of the changed record
+/// * `record` - A `Thing` representing the changed record
+/// * `before` - The record before the change, `None` for `CREATE`
+/// * `after` - The record after the change, `None` for `DELETE`
+/// * `time` - A `Datetime` representing when the change was made
+#[derive(Serialize, Deserialize)]
+pub struct AuditEntry {
+    id: Thing,
+    owner: Thing,
+    actor: Option<Thing>,
+    action: String,
+    table: String,
+    record: Thing,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+    time: Datetime,
+}
+
+/// The filters of the audit log, all optional.
+///
+/// `owner` and `profile` select whose records the entries are about, as for the other lists. `record`
+/// is the ID of a record in `table`, and needs `table` to be set. `from` and `to` are RFC 3339 dates,
+/// `from` is inclusive and `to` exclusive.
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    #[serde(flatten)]
+    scope: ScopeQuery,
+    table: Option<String>,
+    record: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+/// Lists the audit log of the records of the current user, or with a grant those of another user
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user, who needs at least the `View` permission for the records
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `query` - A `Query` object containing the `AuditQuery` filters
+///
+/// # Returns
+///
+/// A `Json` object containing a vector of `AuditEntry` structs, oldest first, or
+/// `Error::UnprocessableEntity` if a filter is not valid.
+pub(crate) async fn list_audit(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    query: Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEntry>>> {
+    let Query(query) = query;
+    let scope = authorize_scope(&ctx, &auth_user, &query.scope, Permission::View).await?;
+
+    if let Some(table) = &query.table {
+        if !AUDITED_TABLES.contains(&table.as_str()) {
+            return Err(Error::unprocessable_entity([("table", "is not audited")]));
+        }
+    }
+    let record = match (&query.table, query.record) {
+        (_, None) => None,
+        (Some(table), Some(record)) => Some(Thing::from((table.as_str(), record.as_str()))),
+        (None, Some(_)) => return Err(Error::unprocessable_entity([("table", "is required with record")])),
+    };
+    let from = parse_datetime("from", query.from)?;
+    let to = parse_datetime("to", query.to)?;
+
+    // Units of measure don't belong to a profile, so their entries show up for every profile.
+    let mut sql = ctx.db.query(
+        "SELECT * FROM audit WHERE owner = type::thing('user', $owner)
+            AND ($profile = NONE OR (after.profile ?? before.profile) = NONE
+                OR (after.profile ?? before.profile) = type::thing('profile', $profile))
+            AND ($table = NONE OR table = $table)
+            AND ($record = NONE OR record = $record)
+            AND ($from = NONE OR time >= $from)
+            AND ($to = NONE OR time < $to)
+            ORDER BY time;")
+        .bind(("owner", &scope.owner))
+        .bind(("profile", &scope.profile))
+        .bind(("table", query.table))
+        .bind(("record", record))
+        .bind(("from", from))
+        .bind(("to", to))
+        .await?;
+    let mut entries: Vec<AuditEntry> = sql.take(0)?;
+
+    // The copies of the records hold their encrypted fields as they were stored.
+    for entry in &mut entries {
+        for (_, field) in ENCRYPTED_FIELDS.iter().filter(|(table, _)| *table == entry.table) {
+            for record in [&mut entry.before, &mut entry.after].into_iter().flatten() {
+                if let Some(serde_json::Value::String(value)) = record.get_mut(*field) {
+                    *value = ctx.cipher.decrypt(value)?;
+                }
+            }
+        }
+    }
+    Ok(Json(entries))
+}
+
+fn parse_datetime(field: &'static str, value: Option<String>) -> Result<Option<Datetime>> {
+    value
+        .map(|value| Datetime::try_from(value.as_str()))
+        .transpose()
+        .map_err(|_| Error::unprocessable_entity([(field, "is not a valid date")]))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::api::testing::{id, TestApp};
+
+    #[tokio::test]
+    async fn each_change_is_recorded_with_its_actor_and_decrypted() {
+        let app = TestApp::with_args(&["--encryption-passphrase", "secret"]).await;
+        let ann = app.sign_up("ann").await;
+        let bob = app.sign_up("bob").await;
+        let grant = json!({ "grant": { "username": "bob", "permission": "manage" } });
+        let (_, grant) = app.request(Method::POST, "/user/grants", Some(&ann), Some(grant)).await;
+        let (owner, grantee) = (&grant["grant"]["owner"], &grant["grant"]["grantee"]);
+
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(&ann), Some(json!({ "name": "Aspirin" }))).await;
+        let uri = format!("/medications/{}", id(&medication));
+        let etag = app.etag(&uri, &bob).await;
+        let (status, body) =
+            app.request_if_match(Method::PUT, &uri, &bob, &etag, Some(json!({ "name": "Ibuprofen" }))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let uri = format!(
+            "/audit?owner={}&table=medication&record={}", owner["id"]["String"].as_str().unwrap(), id(&medication));
+        let (status, entries) = app.request(Method::GET, &uri, Some(&bob), None).await;
+        assert_eq!(status, StatusCode::OK, "{entries}");
+        let [create, update] = entries.as_array().unwrap().as_slice() else {
+            panic!("expected two entries in {entries}");
+        };
+        assert_eq!(create["action"], "CREATE");
+        assert_eq!(create["actor"], *owner);
+        assert_eq!(create["after"]["name"], "Aspirin");
+        assert_eq!(update["action"], "UPDATE");
+        assert_eq!(update["actor"], *grantee);
+        assert_eq!(update["before"]["name"], "Aspirin");
+        assert_eq!(update["after"]["name"], "Ibuprofen");
+    }
+
+    #[tokio::test]
+    async fn the_audit_log_is_refused_for_tables_it_does_not_cover() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+
+        let (status, _) = app.request(Method::GET, "/audit?table=user", Some(&ann), None).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
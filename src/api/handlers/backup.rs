@@ -0,0 +1,127 @@
+use axum::extract::State;
+use axum::http::header::{AUTHORIZATION, CONTENT_DISPOSITION};
+use axum::http::{HeaderMap, Request};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::api::error::Error;
+use crate::api::{ApiContext, Result};
+use crate::db::backup::{self, Backup};
+
+/// The number of records restored from a backup.
+#[derive(Serialize)]
+pub struct RestoreResult {
+    restored: usize,
+}
+
+/// Checks the `Authorization: Bearer <key>` header against the configured backup key before the request
+/// reaches the handler, so the body of a restore isn't read for a client without the key.
+///
+/// The backup routes don't exist without a key, so they return `Error::NotFound` then.
+pub(crate) async fn require_backup_key<B>(
+    ctx: State<ApiContext>,
+    headers: HeaderMap,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response> {
+    authorize_backup(&ctx, &headers)?;
+    Ok(next.run(request).await)
+}
+
+fn authorize_backup(ctx: &ApiContext, headers: &HeaderMap) -> Result<()> {
+    let key = ctx.config.backup_key.as_deref().ok_or(Error::NotFound)?;
+    let given = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::Unauthorized)?;
+
+    // Comparing the hashes rather than the keys doesn't tell how much of a guess was right.
+    if Sha256::digest(given.as_bytes()) != Sha256::digest(key.as_bytes()) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(())
+}
+
+/// Makes a backup of the whole database
+///
+/// # Arguments
+///
+/// * `ctx` - A `State` object containing the `ApiContext` struct
+///
+/// # Returns
+///
+/// A `Json` object containing the `Backup`, served as an attachment named after the time it was made.
+/// The key is checked by `require_backup_key()`.
+pub(crate) async fn create_backup(
+    ctx: State<ApiContext>,
+) -> Result<impl IntoResponse> {
+    let backup = backup::create(&ctx.db).await?;
+    Ok((
+        [(CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", backup.file_name()))],
+        Json(backup),
+    ))
+}
+
+/// Restores a backup into the database, which must be empty
+///
+/// # Arguments
+///
+/// * `ctx` - A `State` object containing the `ApiContext` struct
+/// * `Json(backup)` - A `Json` object containing a `Backup`, of at most `Config::backup_max_mib`
+///
+/// # Returns
+///
+/// A `Json` object with the number of restored records. `Error::UnprocessableEntity` if the backup is
+/// damaged or not supported, the database already has records, or either of them is encrypted. The key is
+/// checked by `require_backup_key()`.
+pub(crate) async fn restore_backup(
+    ctx: State<ApiContext>,
+    Json(backup): Json<Backup>,
+) -> Result<Json<RestoreResult>> {
+    let records = backup
+        .records()
+        .map_err(|error| Error::unprocessable_entity([("backup", error.to_string())]))?;
+    // The key of an encrypted database is checked at startup, so it is restored before the server starts.
+    if backup.is_encrypted() || ctx.cipher.is_enabled() {
+        return Err(Error::unprocessable_entity([("backup", "of an encrypted database is restored from the command line")]));
+    }
+    if !backup::is_empty(&ctx.db).await? {
+        return Err(Error::unprocessable_entity([("database", "is not empty")]));
+    }
+
+    let restored = backup::restore(&ctx.db, records).await?;
+    Ok(Json(RestoreResult { restored }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::api::testing::TestApp;
+
+    #[tokio::test]
+    async fn the_key_is_checked_before_the_backup_is_read() {
+        let app = TestApp::new().await;
+        let (status, _) = app.request(Method::POST, "/backup/restore", Some("key"), Some(json!("not a backup"))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let app = TestApp::with_args(&["--backup-key", "key", "--backup-max-mib", "1"]).await;
+        let (status, _) = app.request(Method::POST, "/backup/restore", Some("guess"), Some(json!("not a backup"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app.request(Method::POST, "/backup/restore", Some("key"), Some(json!("not a backup"))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn a_backup_over_the_limit_is_refused() {
+        let app = TestApp::with_args(&["--backup-key", "key", "--backup-max-mib", "1"]).await;
+        let backup = json!("x".repeat(2 * 1024 * 1024));
+        let (status, _) = app.request(Method::POST, "/backup/restore", Some("key"), Some(backup)).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -3,80 +3,49 @@ use axum::extract::{ State, Path, Query };
 use axum::Json;
 use serde::Deserialize;
 use serde::Serialize;
-use surrealdb::sql::{ Thing, Datetime };
 
 use crate::api::error::Error;
+use crate::api::etag::{rejected, IfMatch, Tagged};
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::grant::{authorize_record, authorize_scope, Permission, ScopeQuery};
+use crate::api::handlers::ensure_owned;
+use crate::api::handlers::store::STORE;
+use crate::api::repository::dose::{CreateDose, Dose, DoseList};
 use crate::api::ApiContext;
 
-const DOSE: &str = "dose";
+pub(crate) const DOSE: &str = "dose";
 
-/// A struct representing a dose of a certain medication
-///
-/// # Fields
-///
-/// * `id` - A `Thing` representing the unique identifier of the dose
-/// * `store` - A `Thing` representing the medication that the dose is for
-/// * `quantity` - A `f32` representing the amount of medication in the dose
-/// * `unit` - A `String` representing the unit of measurement for the medication in the dose
-/// * `created` - A `Datetime` representing the date and time the dose was created
-/// * `updated` - A `Datetime` representing the date and time the dose was last updated
-#[derive(Serialize, Deserialize)]
-pub struct Dose {
-    id: Thing,
-    user: Thing,
-    store: Thing,
-    quantity: f32,
-    unit: String,
-    created: Datetime,
-    updated: Datetime,
-}
-
-/// A struct representing a dose to be created
-///
-/// # Fields
-///
-/// * `id` - An optional `String` representing the ID of the dose
-/// * `store` - A `String` representing the store where the dose is located
-/// * `quantity` - A `f32` representing the quantity of the dose
-/// * `unit` - A `String` representing the unit of the dose
-#[derive(Serialize, Deserialize)]
-pub struct CreateDose {
-    id: Option<String>,
-    user: String,
-    store: String,
-    quantity: f32,
-    unit: String,
-}
 #[derive(Serialize, Deserialize)]
 pub struct DoseQuery {
     id: Option<String>,
     store: Option<String>,
-    user: String,
 }
 
 /// Creates a new dose with the given store, quantity, and unit and returns the created dose as a JSON object
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user logging the dose for themselves or, with a grant, for another user
 /// * `ctx` - A `State` object containing the API context
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 /// * `Json(dose)` - A `Json` object containing the dose information to be created
 ///
 /// # Returns
 ///
 /// A `Json` object containing the created dose, or `None` if the dose could not be created.
+/// Returns `Error::NotFound` if the store does not belong to the user, or `Error::UnprocessableEntity` if the
+/// unit isn't that of the store or the store has less left than the quantity. The dose is written and taken
+/// from the store in one transaction, and a store it uses up is deactivated.
 //TODO: Find fix for quantity f32 issue - temp changed all to f32, when decimal is implemented, change
 pub(crate) async fn create_dose(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     Json(dose): Json<CreateDose>,
 ) -> Result<Json<Option<Dose>>, Error> {
-    let mut sql = ctx.db.query(
-        "CREATE dose SET user = type::thing('user', $user), store = type::thing('store', $store), quantity = $quantity, unit = $unit;")
-        .bind(("user", dose.user))
-        .bind(("store", dose.store))
-        .bind(("quantity", dose.quantity))
-        .bind(("unit", dose.unit))
-        .await?;
-    let dose: Option<Dose> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::LogDoses).await?;
+    ensure_owned(&ctx, STORE, &dose.store, &scope.owner).await?;
+    let dose = ctx.repos.doses.create(&auth_user.user_id, &scope.owner, dose).await?;
     Ok(Json(dose))
 }
 
@@ -84,120 +53,179 @@ pub(crate) async fn create_dose(
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the dose
 /// * `ctx` - The API context containing the database connection
 /// * `id` - The ID of the dose to read
 ///
 /// # Returns
 ///
-/// Returns a `Json` object containing the dose with the given ID, or `None` if no dose was found. If an error occurs while reading from the database, an `Error` is returned.
-pub(crate) async fn read_dose(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Dose>>, Error> {
-    let dose = ctx.db.select((DOSE, &*id)).await?;
-    Ok(Json(dose))
+/// Returns a `Json` object containing the dose with the given ID and its version as the `ETag`, or `Error::NotFound` if no dose of the user was found. If an error occurs while reading from the database, an `Error` is returned.
+pub(crate) async fn read_dose(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Tagged<Dose>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, DOSE, &id, Permission::View).await?;
+    let dose = ctx.repos.doses.read(&id, &owner).await?;
+    Ok(Tagged(dose.ok_or(Error::NotFound)?))
 }
 
-/// Updates the dose with the given id with the new quantity, unit, and store. Returns the updated dose if it exists, otherwise None.
+/// Updates the dose with the given id with the new quantity, unit, and store. Returns the updated dose if it exists
+/// and belongs to the user, otherwise `Error::NotFound`. The `If-Match` header must hold the `ETag` of the dose as
+/// the client last read it, or the update fails with `Error::PreconditionFailed`. The store is checked as when
+/// the dose was logged, failing with `Error::UnprocessableEntity`.
 pub(crate) async fn update_dose(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
     id: Path<String>,
+    if_match: IfMatch,
     Json(dose): Json<CreateDose>,
-) -> Result<Json<Option<Dose>>, Error> {
-    let mut sql = ctx.db.query(
-        "UPDATE type::thing('dose', $id) SET quantity = $quantity, unit = $unit, store = type::thing('store', $store),
-        user = type::thing('user', $user);")
-        .bind(("id", &*id))
-        .bind(("quantity", dose.quantity))
-        .bind(("unit", dose.unit))
-        .bind(("store", dose.store))
-        .bind(("user", dose.user))
-        .await?;
-    let dose: Option<Dose> = sql.take(0)?;
-    Ok(Json(dose))
+) -> Result<Tagged<Dose>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, DOSE, &id, Permission::LogDoses).await?;
+    ensure_owned(&ctx, STORE, &dose.store, &owner).await?;
+    let dose = ctx.repos.doses.update(&auth_user.user_id, &id, &owner, &if_match.0, dose).await?;
+    match dose {
+        Some(dose) => Ok(Tagged(dose)),
+        None => Err(rejected(ctx.repos.doses.read(&id, &owner).await?)),
+    }
 }
 
 /// Deletes a dose from the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the dose
 /// * `ctx` - A `State` object containing the `ApiContext` struct
 /// * `id` - A `Path` object containing the ID of the dose to be deleted
 ///
 /// # Returns
 ///
-/// A `Json` object containing the deleted dose, or `None` if the dose was not found in the database.
-pub(crate) async fn delete_dose(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Dose>>, Error> {
-    let dose = ctx.db.delete((DOSE, &*id)).await?;
-    Ok(Json(dose))
+/// A `Json` object containing the deleted dose, or `Error::NotFound` if no dose of the user was found in the database.
+pub(crate) async fn delete_dose(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Json<Dose>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, DOSE, &id, Permission::LogDoses).await?;
+    let dose = ctx.repos.doses.delete(&auth_user.user_id, &id, &owner).await?;
+    Ok(Json(dose.ok_or(Error::NotFound)?))
 }
 
-/// Retrieves a list of all doses from the database and returns them as a JSON object
+/// Retrieves a list of all doses of the current profile, or of all profiles of an owner, and returns them
+/// as a JSON object
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user listing their own doses or, with a grant, those of another user
 /// * `ctx` - A `State` object containing the `ApiContext` struct
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 ///
 /// # Returns
 ///
-/// A `Json` object containing a `Vec` of `Dose` structs, representing all doses in the database. If there is an error retrieving the doses from the database, an `Error` is returned.
+/// A `Json` object containing a `Vec` of `DoseList` structs, representing all doses of the user. If there is an error retrieving the doses from the database, an `Error` is returned.
 pub(crate) async fn list_doses_for_user(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
-    query: Query<DoseQuery>,
+    scope_query: Query<ScopeQuery>,
 ) -> Result<Json<Vec<DoseList>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_doses_for_user($user);")
-        .bind(("user", &query.user))
-        .await?;
-    let notes: Vec<DoseList> = sql.take(0)?;
-    Ok(Json(notes))
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct DoseList {
-    created: Datetime,
-    id: Thing,
-    dose_quantity: f32,
-    dose_unit: String,
-    medication_id: Thing,
-    medication_name: String,
-    store_active: bool,
-    store_created: Datetime,
-    store_id: Thing,
-    store_production_date: Datetime,
-    store_start_quantity: f32,
-    store_unit: String,
-    store_updated: Datetime,
-    updated: Datetime,
-    user: Thing,
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let doses = ctx.repos.doses.list(&scope.owner, scope.profile.as_deref()).await?;
+    Ok(Json(doses))
 }
 
 pub(crate) async fn list_doses_for_medication(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     query: Query<DoseQuery>,
 ) -> Result<Json<Vec<DoseList>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_doses_for_medication($id, $user);")
-        .bind(("id", &query.id))
-        .bind(("user", &query.user))
-        .await?;
-    let notes: Vec<DoseList> = sql.take(0)?;
-    Ok(Json(notes))
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let medication = query.id.as_deref().ok_or_else(|| Error::unprocessable_entity([("id", "is required")]))?;
+    let doses = ctx.repos.doses.list_for_medication(&scope.owner, medication).await?;
+    Ok(Json(doses))
 }
 
 pub(crate) async fn list_doses_for_store(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     query: Query<DoseQuery>,
 ) -> Result<Json<Vec<DoseList>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_doses_for_store($id, $user);")
-        .bind(("id", &query.id))
-        .bind(("user", &query.user))
-        .await?;
-    let notes: Vec<DoseList> = sql.take(0)?;
-    Ok(Json(notes))
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let store = query.id.as_deref().ok_or_else(|| Error::unprocessable_entity([("id", "is required")]))?;
+    let doses = ctx.repos.doses.list_for_store(&scope.owner, store).await?;
+    Ok(Json(doses))
 }
 
-//TODO: Add tests for dose handlers
-
-//TODO: Add user to all queries where id not used (update, delete, read)
 //TODO: add function to get summary data on doses - stats for graphing, ot other reports
 //TODO: add function to get summary data on dose timings and other patterns
 
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+
+    use crate::api::testing::{id, TestApp};
+
+    /// Makes a store of `quantity` mg of a new medication named `name` and returns its ID.
+    async fn store(app: &TestApp, token: &str, name: &str, quantity: f32) -> String {
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(token), Some(json!({ "name": name }))).await;
+        let store = json!({
+            "medication": id(&medication),
+            "production_date": "2026-01-01T00:00:00Z",
+            "lot_number": "A1",
+            "quantity": quantity,
+            "unit": "mg",
+        });
+        let (status, store) = app.request(Method::POST, "/stores", Some(token), Some(store)).await;
+        assert_eq!(status, StatusCode::OK, "{store}");
+        id(&store)
+    }
+
+    /// Asserts that the request was refused with a `422 Unprocessable Entity` on `field`.
+    fn assert_refused((status, body): (StatusCode, Value), field: &str) {
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{body}");
+        assert!(body["errors"][field].is_array(), "no error on {field} in {body}");
+    }
+
+    async fn active(app: &TestApp, token: &str, store: &str) -> bool {
+        let (_, store) = app.request(Method::GET, &format!("/stores/{store}"), Some(token), None).await;
+        store["active"].as_bool().unwrap_or_else(|| panic!("no active in {store}"))
+    }
+
+    #[tokio::test]
+    async fn a_dose_is_refused_unless_it_fits_in_its_store() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let store = store(&app, &ann, "Aspirin", 10.0).await;
+        let dose = |quantity: f32, unit: &str| Some(json!({ "store": store, "quantity": quantity, "unit": unit }));
+
+        assert_refused(app.request(Method::POST, "/doses", Some(&ann), dose(11.0, "mg")).await, "quantity");
+        assert_refused(app.request(Method::POST, "/doses", Some(&ann), dose(1.0, "ml")).await, "unit");
+
+        // The dose using up the store deactivates it, and no other dose is taken from it.
+        let (status, body) = app.request(Method::POST, "/doses", Some(&ann), dose(10.0, "mg")).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(!active(&app, &ann, &store).await);
+        assert_refused(app.request(Method::POST, "/doses", Some(&ann), dose(0.0, "mg")).await, "store");
+    }
+
+    #[tokio::test]
+    async fn moving_a_used_up_dose_reactivates_its_former_store() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let (first, second) = (store(&app, &ann, "Aspirin", 10.0).await, store(&app, &ann, "Ibuprofen", 10.0).await);
+        let dose = json!({ "store": first, "quantity": 10.0, "unit": "mg" });
+        let (_, dose) = app.request(Method::POST, "/doses", Some(&ann), Some(dose)).await;
+        assert!(!active(&app, &ann, &first).await);
+
+        let uri = format!("/doses/{}", id(&dose));
+        let etag = app.etag(&uri, &ann).await;
+        let moved = json!({ "store": second, "quantity": 10.0, "unit": "mg" });
+        let (status, body) = app.request_if_match(Method::PUT, &uri, &ann, &etag, Some(moved)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(active(&app, &ann, &first).await);
+        assert!(!active(&app, &ann, &second).await);
+    }
+}
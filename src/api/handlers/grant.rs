@@ -0,0 +1,374 @@
+use axum::extract::{ State, Path };
+use axum::Json;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::error::Error;
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::profile::PROFILE;
+use crate::api::{ApiContext, Result};
+
+/// What a care grant allows the grantee to do with the records of the owner.
+///
+/// Each permission includes the ones before it, so they can be compared with `>=`.
+///
+/// * `View` - Read medications, stores, doses, reminders and notes
+/// * `LogDoses` - Also create, update and delete doses, and acknowledge reminders
+/// * `Manage` - Also create, update and delete medications, stores, reminders and notes
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    View,
+    LogDoses,
+    Manage,
+}
+
+/// A struct representing a care grant
+///
+/// # Fields
+///
+/// * `id` - A `Thing` representing the ID of the grant
+/// * `owner` - A `Thing` representing the user whose records are shared
+/// * `owner_username` - The username of the owner
+/// * `grantee` - A `Thing` representing the user the records are shared with
+/// * `grantee_username` - The username of the grantee
+/// * `permission` - The `Permission` of the grantee
+/// * `created` - A `Datetime` representing the date and time the grant was created
+/// * `updated` - A `Datetime` representing the date and time the grant was last changed
+#[derive(Serialize, Deserialize)]
+pub struct Grant {
+    id: Thing,
+    owner: Thing,
+    owner_username: String,
+    grantee: Thing,
+    grantee_username: String,
+    permission: Permission,
+    created: Datetime,
+    updated: Datetime,
+}
+
+/// A wrapper matching the `{ "grant": { ... } }` shape used by grant requests and responses.
+#[derive(Serialize, Deserialize)]
+pub struct GrantBody<T> {
+    grant: T,
+}
+
+#[derive(Deserialize)]
+pub struct CreateGrant {
+    username: String,
+    permission: Permission,
+}
+
+/// The optional `owner` and `profile` query parameters of routes that create or list records.
+///
+/// Set `profile` to the ID of a profile to use its records, or `owner` to the ID of another user to
+/// use the records of all their profiles under a grant. Without either, the profile the session is
+/// switched to is used.
+#[derive(Deserialize)]
+pub struct ScopeQuery {
+    owner: Option<String>,
+    profile: Option<String>,
+}
+
+/// The records a create or list request acts on, as resolved by `authorize_scope()`.
+///
+/// # Fields
+///
+/// * `owner` - The ID of the user who owns the records
+/// * `profile` - The ID of the profile of the records, or `None` for all profiles of the owner
+pub(crate) struct Scope {
+    pub(crate) owner: String,
+    pub(crate) profile: Option<String>,
+}
+
+impl Scope {
+    /// Returns the profile new records are created in, or `Error::UnprocessableEntity` if the
+    /// request doesn't name one.
+    pub(crate) fn required_profile(&self) -> Result<&str> {
+        self.profile
+            .as_deref()
+            .ok_or_else(|| Error::unprocessable_entity([("profile", "is required")]))
+    }
+}
+
+const SELECT_GRANT: &str =
+    "SELECT id, owner, owner.username AS owner_username, grantee, grantee.username AS grantee_username,
+    permission, created, updated FROM care_grant";
+
+/// Grants another user access to the records of the current user, or changes the permission of an
+/// existing grant
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user who shares their records
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing the username of the grantee and the permission
+///
+/// # Returns
+///
+/// A `Json` object containing the grant, or `Error::UnprocessableEntity` if the username does not
+/// exist or is the current user.
+pub(crate) async fn create_grant(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<GrantBody<CreateGrant>>,
+) -> Result<Json<GrantBody<Grant>>> {
+    let mut sql = ctx.db.query("SELECT VALUE id FROM user WHERE username = $username;")
+        .bind(("username", &*req.grant.username))
+        .await?;
+    let grantee: Option<Thing> = sql.take(0)?;
+    let grantee = grantee.ok_or_else(|| Error::unprocessable_entity([("username", "does not exist")]))?;
+
+    if grantee.id.to_raw() == auth_user.user_id {
+        return Err(Error::unprocessable_entity([("username", "is the current user")]));
+    }
+
+    let mut sql = ctx.db.query(
+        "UPDATE care_grant SET permission = $permission
+        WHERE owner = type::thing('user', $user) AND grantee = $grantee RETURN id;")
+        .bind(("permission", req.grant.permission))
+        .bind(("grantee", &grantee))
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let mut id: Option<Thing> = sql.take((0, "id"))?;
+
+    if id.is_none() {
+        let mut sql = ctx.db.query(
+            "CREATE care_grant SET owner = type::thing('user', $user), grantee = $grantee, permission = $permission
+            RETURN id;")
+            .bind(("permission", req.grant.permission))
+            .bind(("grantee", &grantee))
+            .bind(("user", &auth_user.user_id))
+            .await?;
+        id = sql.take((0, "id"))?;
+    }
+
+    let mut sql = ctx.db.query(format!("{SELECT_GRANT} WHERE id = $id;"))
+        .bind(("id", id))
+        .await?;
+    let grant: Option<Grant> = sql.take(0)?;
+    let grant = grant.ok_or_else(|| anyhow::anyhow!("care grant was not saved"))?;
+
+    Ok(Json(GrantBody { grant }))
+}
+
+/// Lists the grants the current user has given to others
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+///
+/// # Returns
+///
+/// A `Json` object containing a vector of `Grant` structs.
+pub(crate) async fn list_grants(auth_user: AuthUser, ctx: State<ApiContext>) -> Result<Json<Vec<Grant>>> {
+    let mut sql = ctx.db.query(format!(
+        "{SELECT_GRANT} WHERE owner = type::thing('user', $user) ORDER BY created;"))
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let grants: Vec<Grant> = sql.take(0)?;
+    Ok(Json(grants))
+}
+
+/// Lists the grants other users have given to the current user
+///
+/// The `owner` of each grant is the value to pass as the `owner` query parameter when creating or
+/// listing their records.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+///
+/// # Returns
+///
+/// A `Json` object containing a vector of `Grant` structs.
+pub(crate) async fn list_received_grants(auth_user: AuthUser, ctx: State<ApiContext>) -> Result<Json<Vec<Grant>>> {
+    let mut sql = ctx.db.query(format!(
+        "{SELECT_GRANT} WHERE grantee = type::thing('user', $user) ORDER BY created;"))
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let grants: Vec<Grant> = sql.take(0)?;
+    Ok(Json(grants))
+}
+
+/// Revokes a grant. Both the owner and the grantee may do this.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `id` - A `Path` object containing the ID of the grant to revoke
+///
+/// # Returns
+///
+/// `Error::NotFound` if the grant does not exist or the current user is not part of it.
+pub(crate) async fn revoke_grant(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<()> {
+    let mut sql = ctx.db.query(
+        "DELETE type::thing('care_grant', $id)
+        WHERE owner = type::thing('user', $user) OR grantee = type::thing('user', $user) RETURN BEFORE;")
+        .bind(("id", &*id))
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let grant: Option<Thing> = sql.take((0, "id"))?;
+    grant.map(|_| ()).ok_or(Error::NotFound)
+}
+
+/// Returns the owner and profile of the records a create or list request acts on, after checking
+/// that the current user holds `permission` for them.
+///
+/// # Arguments
+///
+/// * `ctx` - The `ApiContext`
+/// * `auth_user` - The authenticated user
+/// * `scope_query` - The `owner` and `profile` query parameters of the request
+/// * `permission` - The permission the request needs
+///
+/// # Returns
+///
+/// The `Scope` of the request. Returns `Error::Forbidden` if the current user has no grant from the
+/// owner or the grant is not enough, and `Error::NotFound` for an unknown profile.
+pub(crate) async fn authorize_scope(
+    ctx: &ApiContext,
+    auth_user: &AuthUser,
+    scope_query: &ScopeQuery,
+    permission: Permission,
+) -> Result<Scope> {
+    if let Some(profile) = &scope_query.profile {
+        let owner = authorize_record(ctx, auth_user, PROFILE, profile, permission).await?;
+        return Ok(Scope { owner, profile: Some(profile.clone()) });
+    }
+
+    let owner = match &scope_query.owner {
+        Some(owner) if *owner != auth_user.user_id => owner,
+        _ => {
+            return Ok(Scope {
+                owner: auth_user.user_id.clone(),
+                profile: auth_user.profile_id.clone(),
+            })
+        }
+    };
+
+    match granted_permission(ctx, owner, &auth_user.user_id).await? {
+        Some(granted) if granted >= permission => Ok(Scope { owner: owner.clone(), profile: None }),
+        _ => Err(Error::Forbidden),
+    }
+}
+
+/// Returns the ID of the user who owns a record, after checking that the current user is the owner
+/// or holds `permission` for them.
+///
+/// # Arguments
+///
+/// * `ctx` - The `ApiContext`
+/// * `auth_user` - The authenticated user
+/// * `table` - The table of the record
+/// * `id` - The ID of the record
+/// * `permission` - The permission the request needs
+///
+/// # Returns
+///
+/// The ID of the owner. Returns `Error::NotFound` if the record does not exist or the current user has
+/// no grant from its owner, so the IDs of other users' records aren't revealed, and `Error::Forbidden`
+/// if the grant is not enough.
+pub(crate) async fn authorize_record(
+    ctx: &ApiContext,
+    auth_user: &AuthUser,
+    table: &str,
+    id: &str,
+    permission: Permission,
+) -> Result<String> {
+    let mut sql = ctx.db.query("SELECT VALUE user FROM type::thing($table, $id);")
+        .bind(("table", table))
+        .bind(("id", id))
+        .await?;
+    let owner: Option<Thing> = sql.take(0)?;
+    let owner = owner.ok_or(Error::NotFound)?.id.to_raw();
+
+    if owner == auth_user.user_id {
+        return Ok(owner);
+    }
+
+    match granted_permission(ctx, &owner, &auth_user.user_id).await? {
+        Some(granted) if granted >= permission => Ok(owner),
+        Some(_) => Err(Error::Forbidden),
+        None => Err(Error::NotFound),
+    }
+}
+
+async fn granted_permission(ctx: &ApiContext, owner: &str, grantee: &str) -> Result<Option<Permission>> {
+    let mut sql = ctx.db.query(
+        "SELECT VALUE permission FROM care_grant
+        WHERE owner = type::thing('user', $owner) AND grantee = type::thing('user', $grantee);")
+        .bind(("owner", owner))
+        .bind(("grantee", grantee))
+        .await?;
+    Ok(sql.take(0)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::api::testing::{id, TestApp};
+
+    /// Grants `bob` the `permission` to the records of the user with `token`, and returns the ID of that user.
+    async fn grant(app: &TestApp, token: &str, permission: &str) -> String {
+        let grant = json!({ "grant": { "username": "bob", "permission": permission } });
+        let (status, body) = app.request(Method::POST, "/user/grants", Some(token), Some(grant)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        body["grant"]["owner"]["id"]["String"].as_str().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn a_record_is_not_found_without_a_grant_and_forbidden_with_too_little_of_one() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let bob = app.sign_up("bob").await;
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(&ann), Some(json!({ "name": "Aspirin" }))).await;
+        let uri = format!("/medications/{}", id(&medication));
+
+        let (status, _) = app.request(Method::GET, &uri, Some(&bob), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = app.request(Method::GET, "/medications/unknown", Some(&bob), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        grant(&app, &ann, "view").await;
+        let etag = app.etag(&uri, &bob).await;
+        let update = json!({ "name": "Ibuprofen" });
+        let (status, _) = app.request_if_match(Method::PUT, &uri, &bob, &etag, Some(update.clone())).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        grant(&app, &ann, "manage").await;
+        let (status, body) = app.request_if_match(Method::PUT, &uri, &bob, &etag, Some(update)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["name"], "Ibuprofen");
+    }
+
+    #[tokio::test]
+    async fn the_records_of_another_user_are_listed_only_with_a_grant() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let bob = app.sign_up("bob").await;
+        let carol = app.sign_up("carol").await;
+        app.request(Method::POST, "/medications", Some(&ann), Some(json!({ "name": "Aspirin" }))).await;
+        let owner = grant(&app, &ann, "view").await;
+        let uri = format!("/medications?owner={owner}");
+
+        let (status, body) = app.request(Method::GET, &uri, Some(&bob), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        let (status, _) = app.request(Method::POST, &uri, Some(&bob), Some(json!({ "name": "Ibuprofen" }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = app.request(Method::GET, &uri, Some(&carol), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
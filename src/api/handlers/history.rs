@@ -0,0 +1,189 @@
+use async_trait::async_trait;
+use axum::extract::{ State, Path };
+use axum::Json;
+use serde::Serialize;
+use surrealdb::sql::Datetime;
+
+use crate::api::error::Error;
+use crate::api::etag::{rejected, IfMatch, Tagged, Versioned};
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::dose::DOSE;
+use crate::api::handlers::grant::{authorize_record, Permission};
+use crate::api::handlers::medication::MEDICATION;
+use crate::api::handlers::note::NOTE;
+use crate::api::handlers::reminder::REMINDER;
+use crate::api::handlers::store::STORE;
+use crate::api::repository::dose::Dose;
+use crate::api::repository::history::Version;
+use crate::api::repository::medication::Medication;
+use crate::api::repository::note::Note;
+use crate::api::repository::reminder::Reminder;
+use crate::api::repository::store::Store;
+use crate::api::{ApiContext, Result};
+
+/// A record with a version history, see `crate::api::repository::history`.
+#[async_trait]
+pub(crate) trait Historic: Versioned + Serialize + Sized + Send + 'static {
+    /// The table of the records.
+    const TABLE: &'static str;
+    /// The permission needed to update a record, the same as for its update handler.
+    const PERMISSION: Permission;
+
+    /// Reads the current version of the record.
+    async fn read(ctx: &ApiContext, id: &str, owner: &str) -> Result<Option<Self>>;
+
+    /// Lists the earlier versions of the record.
+    async fn history(ctx: &ApiContext, id: &str, owner: &str) -> Result<Vec<Version<Self>>>;
+
+    /// Reverts the record to its version `to` if it is still at `version`.
+    async fn revert(ctx: &ApiContext, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Self>>;
+}
+
+/// Implements `Historic` for the model of a repository in `ApiContext::repos`.
+macro_rules! historic {
+    ($model:ty, $table:expr, $permission:expr, $repository:ident) => {
+        #[async_trait]
+        impl Historic for $model {
+            const TABLE: &'static str = $table;
+            const PERMISSION: Permission = $permission;
+
+            async fn read(ctx: &ApiContext, id: &str, owner: &str) -> Result<Option<Self>> {
+                ctx.repos.$repository.read(id, owner).await
+            }
+
+            async fn history(ctx: &ApiContext, id: &str, owner: &str) -> Result<Vec<Version<Self>>> {
+                ctx.repos.$repository.history(id, owner).await
+            }
+
+            async fn revert(ctx: &ApiContext, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+                -> Result<Option<Self>> {
+                ctx.repos.$repository.revert(actor, id, owner, version, to).await
+            }
+        }
+    };
+}
+
+historic!(Medication, MEDICATION, Permission::Manage, medications);
+historic!(Store, STORE, Permission::Manage, stores);
+historic!(Reminder, REMINDER, Permission::Manage, reminders);
+historic!(Dose, DOSE, Permission::LogDoses, doses);
+historic!(Note, NOTE, Permission::Manage, notes);
+
+/// Lists the earlier versions of a record
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user, who needs at least the `View` permission for the record
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `id` - A `Path` object with the ID of the record
+///
+/// # Returns
+///
+/// A `Json` object containing a vector of `Version` structs, oldest first, without the current version, or
+/// `Error::NotFound` if the record doesn't exist or is in the trash.
+pub(crate) async fn list_history<T: Historic>(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Json<Vec<Version<T>>>> {
+    let owner = authorize_record(&ctx, &auth_user, T::TABLE, &id, Permission::View).await?;
+    T::read(&ctx, &id, &owner).await?.ok_or(Error::NotFound)?;
+
+    Ok(Json(T::history(&ctx, &id, &owner).await?))
+}
+
+/// Reverts a record to an earlier version
+///
+/// The version it replaces is kept in the history like that of any other update, so a revert can be reverted.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user, who needs the permission to update the record
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `path` - A `Path` object with the ID of the record and the version to revert to, as listed by
+///   `list_history()`
+/// * `if_match` - The `ETag` of the record as the client last read it
+///
+/// # Returns
+///
+/// A `Json` object containing the reverted record and its new `ETag`, `Error::NotFound` if the record or the
+/// version doesn't exist, `Error::PreconditionFailed` if the record was changed since the client read it, or
+/// `Error::UnprocessableEntity` if the version isn't valid or no longer is, e.g. because its medication was
+/// deleted since.
+pub(crate) async fn revert<T: Historic>(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Path((id, version)): Path<(String, String)>,
+    if_match: IfMatch,
+) -> Result<Tagged<T>> {
+    let owner = authorize_record(&ctx, &auth_user, T::TABLE, &id, T::PERMISSION).await?;
+    // The version is listed as a datetime and given out as an ETag, so take it either way.
+    let version = Datetime::try_from(version.trim_matches('"'))
+        .map_err(|_| Error::unprocessable_entity([("version", "is not a valid version")]))?;
+
+    match T::revert(&ctx, &auth_user.user_id, &id, &owner, &if_match.0, &version).await? {
+        Some(record) => Ok(Tagged(record)),
+        None => Err(rejected(T::read(&ctx, &id, &owner).await?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::api::testing::{id, TestApp};
+
+    #[tokio::test]
+    async fn a_medication_is_reverted_to_an_earlier_version() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(&ann), Some(json!({ "name": "Aspirin" }))).await;
+        let uri = format!("/medications/{}", id(&medication));
+        let first = app.etag(&uri, &ann).await;
+        let renamed = json!({ "name": "Ibuprofen" });
+        let (status, body) = app.request_if_match(Method::PUT, &uri, &ann, &first, Some(renamed)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (status, history) = app.request(Method::GET, &format!("{uri}/history"), Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK, "{history}");
+        let versions = history.as_array().unwrap_or_else(|| panic!("no versions in {history}"));
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0]["record"]["name"], "Aspirin");
+        let version = versions[0]["version"].as_str().unwrap_or_else(|| panic!("no version in {history}"));
+        let revert = format!("{uri}/revert/{version}");
+
+        // The ETag read before the rename no longer matches.
+        let (status, body) = app.request_if_match(Method::POST, &revert, &ann, &first, None).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED, "{body}");
+
+        let current = app.etag(&uri, &ann).await;
+        let (status, body) = app.request_if_match(Method::POST, &revert, &ann, &current, None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["name"], "Aspirin");
+        assert_ne!(app.etag(&uri, &ann).await, current);
+
+        // The version the revert replaced is kept, so the revert can be reverted in turn.
+        let (_, history) = app.request(Method::GET, &format!("{uri}/history"), Some(&ann), None).await;
+        assert_eq!(history.as_array().map(Vec::len), Some(2));
+        assert_eq!(history[1]["record"]["name"], "Ibuprofen");
+    }
+
+    #[tokio::test]
+    async fn reverting_to_an_unknown_version_is_not_found() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(&ann), Some(json!({ "name": "Aspirin" }))).await;
+        let uri = format!("/medications/{}", id(&medication));
+        let etag = app.etag(&uri, &ann).await;
+
+        let revert = format!("{uri}/revert/2020-01-01T00:00:00Z");
+        let (status, _) = app.request_if_match(Method::POST, &revert, &ann, &etag, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = app.request_if_match(Method::POST, &format!("{uri}/revert/soon"), &ann, &etag, None).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
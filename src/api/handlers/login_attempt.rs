@@ -0,0 +1,179 @@
+use std::net::IpAddr;
+
+use time::OffsetDateTime;
+
+use crate::api::error::Error;
+use crate::api::{ApiContext, Result};
+
+/// How many failed attempts a key gets before it is locked out.
+const FREE_ATTEMPTS: i64 = 5;
+
+/// How long the first lockout lasts. Each further failure doubles it, up to `MAX_LOCKOUT`.
+const BASE_LOCKOUT: time::Duration = time::Duration::seconds(30);
+const MAX_LOCKOUT: time::Duration = time::Duration::hours(1);
+
+/// Failures are forgotten once there has been none for this long.
+const FAILURE_WINDOW: time::Duration = time::Duration::days(1);
+
+/// The `login_attempt` keys a login is tracked under: the username it tries and the client IP it
+/// comes from.
+///
+/// Tracking the username stops guessing one user's password from many addresses, tracking the IP
+/// stops one client from trying a common password against many users. Loopback addresses aren't
+/// tracked: every login to the local desktop app comes from one, so a few typos would lock out every
+/// account on the machine.
+pub(crate) struct LoginKeys {
+    username: String,
+    ip: Option<String>,
+}
+
+impl LoginKeys {
+    pub(crate) fn new(username: &str, ip: IpAddr) -> Self {
+        Self {
+            username: format!("username:{}", username.to_lowercase()),
+            ip: (!ip.to_canonical().is_loopback()).then(|| format!("ip:{ip}")),
+        }
+    }
+
+    fn all(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.username.as_str()).chain(self.ip.as_deref())
+    }
+}
+
+/// Returns `Error::TooManyRequests` if the username or the IP of the login is locked out.
+pub(crate) async fn check_lockout(ctx: &ApiContext, keys: &LoginKeys) -> Result<()> {
+    let mut locked_until = None;
+    for key in keys.all() {
+        let mut sql = ctx.db.query(
+            "SELECT VALUE time::unix(locked_until) FROM type::thing('login_attempt', $key)
+            WHERE locked_until != NONE AND locked_until > time::now();")
+            .bind(("key", key))
+            .await?;
+        let until: Option<i64> = sql.take(0)?;
+        locked_until = locked_until.max(until);
+    }
+
+    match locked_until {
+        Some(locked_until) => {
+            log::debug!("login locked out for {} or {:?}", keys.username, keys.ip);
+            let retry_after = locked_until - OffsetDateTime::now_utc().unix_timestamp();
+            Err(Error::TooManyRequests { retry_after: retry_after.max(1) })
+        }
+        None => Ok(()),
+    }
+}
+
+/// Counts a failed login against the username and the IP, and locks out those with too many.
+pub(crate) async fn record_failure(ctx: &ApiContext, keys: &LoginKeys) -> Result<()> {
+    let since = (OffsetDateTime::now_utc() - FAILURE_WINDOW).unix_timestamp();
+
+    for key in keys.all() {
+        let mut sql = ctx.db.query(
+            "UPDATE type::thing('login_attempt', $key) SET
+                failures = IF last_failure != NONE AND last_failure > time::from::unix($since) THEN failures + 1 ELSE 1 END,
+                last_failure = time::now()
+            RETURN VALUE failures;")
+            .bind(("key", key))
+            .bind(("since", since))
+            .await?;
+        let failures: Option<i64> = sql.take(0)?;
+        let failures = failures.unwrap_or_default();
+
+        if failures > FREE_ATTEMPTS {
+            let doublings = (failures - FREE_ATTEMPTS - 1).min(16) as u32;
+            let lockout = (BASE_LOCKOUT * 2_i32.pow(doublings)).min(MAX_LOCKOUT);
+            log::warn!("{key} locked out for {lockout} after {failures} failed logins");
+
+            ctx.db.query("UPDATE type::thing('login_attempt', $key) SET locked_until = time::from::unix($until);")
+                .bind(("key", key))
+                .bind(("until", (OffsetDateTime::now_utc() + lockout).unix_timestamp()))
+                .await?
+                .check()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Forgets the failed logins of the username after a successful one.
+///
+/// The failures of the IP are kept, so logging into an account of one's own doesn't reset them.
+pub(crate) async fn clear(ctx: &ApiContext, keys: &LoginKeys) -> Result<()> {
+    ctx.db.query("DELETE type::thing('login_attempt', $username);")
+        .bind(("username", &keys.username))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use super::FREE_ATTEMPTS;
+    use crate::api::testing::{TestApp, CLIENT, PASSWORD};
+
+    const LOOPBACK: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 40000);
+    const OTHER_CLIENT: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), 40000);
+
+    async fn fail(app: &TestApp, client: SocketAddr, username: &str, times: i64) {
+        for _ in 0..times {
+            let (status, body) = app.log_in_from(client, username, "wrong").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn too_many_failures_lock_out_the_right_password() {
+        let app = TestApp::new().await;
+        app.sign_up("ann").await;
+
+        fail(&app, CLIENT, "ann", FREE_ATTEMPTS + 1).await;
+        let (status, _) = app.log_in_from(CLIENT, "ann", PASSWORD).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // The username is locked out from any address.
+        let (status, _) = app.log_in_from(OTHER_CLIENT, "ANN", PASSWORD).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn too_many_failures_lock_out_the_ip_for_every_user() {
+        let app = TestApp::new().await;
+        app.sign_up("ann").await;
+        app.sign_up("ben").await;
+
+        fail(&app, CLIENT, "ann", FREE_ATTEMPTS + 1).await;
+        let (status, _) = app.log_in_from(CLIENT, "ben", PASSWORD).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, body) = app.log_in_from(OTHER_CLIENT, "ben", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[tokio::test]
+    async fn loopback_failures_only_lock_out_the_username() {
+        let app = TestApp::new().await;
+        app.sign_up("ann").await;
+        app.sign_up("ben").await;
+
+        fail(&app, LOOPBACK, "ann", FREE_ATTEMPTS + 1).await;
+        let (status, _) = app.log_in_from(LOOPBACK, "ann", PASSWORD).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, body) = app.log_in_from(LOOPBACK, "ben", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[tokio::test]
+    async fn a_successful_login_forgets_the_failures_of_the_username() {
+        let app = TestApp::new().await;
+        app.sign_up("ann").await;
+
+        fail(&app, LOOPBACK, "ann", FREE_ATTEMPTS).await;
+        let (status, body) = app.log_in_from(LOOPBACK, "ann", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        fail(&app, LOOPBACK, "ann", FREE_ATTEMPTS).await;
+        let (status, body) = app.log_in_from(LOOPBACK, "ann", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+}
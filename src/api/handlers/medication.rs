@@ -3,71 +3,46 @@ use axum::extract::{ State, Path, Query };
 use axum::Json;
 use serde::Deserialize;
 use serde::Serialize;
-use surrealdb::sql::{ Thing, Datetime };
 
 use crate::api::error::Error;
+use crate::api::etag::{rejected, IfMatch, Tagged};
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::grant::{authorize_record, authorize_scope, Permission, ScopeQuery};
+use crate::api::handlers::CascadeQuery;
+use crate::api::repository::medication::{CreateMedication, Medication};
 use crate::api::ApiContext;
 
-const MEDICATION: &str = "medication";
-
-/// A struct representing a medication
-///
-/// # Fields
-///
-/// * `id` - An optional `Thing` representing the ID of the medication
-/// * `user` - A `Thing` representing the ID of the user who created the medication
-/// * `name` - A `String` representing the name of the medication
-/// * `created` - An optional `Datetime` representing the date and time the medication was created
-/// * `updated` - An optional `Datetime` representing the date and time the medication was last updated
-/// * `active` - An optional `bool` representing whether the medication is currently active or not
-#[derive(Serialize, Deserialize)]
-pub struct Medication {
-    id: Thing,
-    user: Thing,
-    name: String,
-    created: Option<Datetime>,
-    updated: Option<Datetime>,
-    active: Option<bool>,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct CreateMedication {
-    user: String,
-    name: String,
-    created: Option<Datetime>,
-    updated: Option<Datetime>,
-    active: Option<bool>,
-}
+pub(crate) const MEDICATION: &str = "medication";
 
 #[derive(Serialize, Deserialize)]
 pub struct MedicationBool {
     active: Option<bool>,
     id: Option<String>,
-    user: String,
 }
 
 /// Creates a new medication record in the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user creating the medication for themselves or, with a grant, for another user
 /// * `ctx` - A `State` object containing the `ApiContext` struct
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 /// * `Json(medication)` - A JSON object containing the medication data
 ///
 /// # Returns
 ///
 /// A `Json` object containing the newly created medication record, wrapped in an `Option` object.
 /// Creates a new medication and returns it as JSON
-
 pub(crate) async fn create_med(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     Json(medication): Json<CreateMedication>,
 ) -> Result<Json<Option<Medication>>, Error> {
-    let mut sql = ctx.db.query(
-        "CREATE medication SET user = type::thing('user', $user), name = $name;")
-        .bind(("user", medication.user))
-        .bind(("name", medication.name))
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::Manage).await?;
+    let medication = ctx.repos.medications
+        .create(&auth_user.user_id, &scope.owner, scope.required_profile()?, medication)
         .await?;
-    let medication: Option<Medication> = sql.take(0)?;
     Ok(Json(medication))
 }
 
@@ -75,56 +50,72 @@ pub(crate) async fn create_med(
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the medication
 /// * `ctx` - The API context containing the database connection
 /// * `id` - The ID of the medication to read
 ///
 /// # Returns
 ///
-/// A `Json` object containing the medication with the given ID, or `None` if it does not exist in the database.
-/// If an error occurs while accessing the database, an `Error` is returned.
-pub(crate) async fn read_med(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Medication>>, Error> {
-    let medication = ctx.db.select((MEDICATION, &*id)).await?;
-    Ok(Json(medication))
+/// A `Json` object containing the medication with the given ID and its version as the `ETag`, or `Error::NotFound` if it does not exist
+/// or belongs to another user. If an error occurs while accessing the database, an `Error` is returned.
+pub(crate) async fn read_med(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Tagged<Medication>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, MEDICATION, &id, Permission::View).await?;
+    let medication = ctx.repos.medications.read(&id, &owner).await?;
+    Ok(Tagged(medication.ok_or(Error::NotFound)?))
 }
 
 /// Updates a medication with the given ID in the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the medication
 /// * `ctx` - A `State` object containing the `ApiContext`
 /// * `id` - A `Path` object containing the ID of the medication to update
+/// * `if_match` - The `ETag` of the medication as the client last read it
 /// * `medication` - A `Json` object containing the updated medication information
 ///
 /// # Returns
 ///
-/// A `Json` object containing the updated medication information, wrapped in an `Option`, or an `Error` if the update fails.
+/// A `Json` object containing the updated medication information with its new `ETag`, `Error::NotFound` if the
+/// medication does not exist or belongs to another user, or `Error::PreconditionFailed` if it was changed since
+/// the client read it.
 pub(crate) async fn update_med(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
     id: Path<String>,
+    if_match: IfMatch,
     Json(medication): Json<CreateMedication>,
-) -> Result<Json<Option<Medication>>, Error> {
-    let mut sql = ctx.db.query(
-        "UPDATE type::thing('medication', $id) SET user = type::thing('user', $user), name = $name;")
-        .bind(("id", &*id))
-        .bind(("user", medication.user))
-        .bind(("name", medication.name))
+) -> Result<Tagged<Medication>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, MEDICATION, &id, Permission::Manage).await?;
+    let medication = ctx.repos.medications
+        .update(&auth_user.user_id, &id, &owner, &if_match.0, medication)
         .await?;
-    let medication: Option<Medication> = sql.take(0)?;
-    Ok(Json(medication))
+    match medication {
+        Some(medication) => Ok(Tagged(medication)),
+        None => Err(rejected(ctx.repos.medications.read(&id, &owner).await?)),
+    }
 }
 
+/// Deactivates the medication with the ID given in the body, if it wasn't changed since the client read it
+/// with the `ETag` in `If-Match`. Returns `Error::PreconditionFailed` otherwise.
 pub(crate) async fn deactivate_med(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
     // id: Path<String>,
+    if_match: IfMatch,
     Json(medication): Json<MedicationBool>,
-) -> Result<Json<Option<Medication>>, Error> {
-    let mut sql = ctx.db.query(
-        "UPDATE type::thing('medication', $id) SET active = false WHERE user = type::thing('user', $user);")
-        .bind(("id", medication.id))
-        .bind(("user", medication.user))
-        .await?;
-    let medication: Option<Medication> = sql.take(0)?;
-    Ok(Json(medication))
+) -> Result<Tagged<Medication>, Error> {
+    let id = medication.id.ok_or(Error::NotFound)?;
+    let owner = authorize_record(&ctx, &auth_user, MEDICATION, &id, Permission::Manage).await?;
+    let medication = ctx.repos.medications.deactivate(&auth_user.user_id, &id, &owner, &if_match.0).await?;
+    match medication {
+        Some(medication) => Ok(Tagged(medication)),
+        None => Err(rejected(ctx.repos.medications.read(&id, &owner).await?)),
+    }
 }
 
 
@@ -132,36 +123,47 @@ pub(crate) async fn deactivate_med(
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the medication
 /// * `ctx` - A `State` object that holds the `ApiContext` struct
 /// * `id` - A `Path` object that holds the ID of the medication to be deleted
+/// * `cascade_query` - A `Query` object with `cascade`, to delete the stores and reminders of the medication
+///   and the doses of those stores along with it
 ///
 /// # Returns
 ///
-/// A `Json` object that holds an `Option` of the deleted medication or an `Error` if the operation fails.
-pub(crate) async fn delete_med(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Medication>>, Error> {
-    let medication = ctx.db.delete((MEDICATION, &*id)).await?;
-    Ok(Json(medication))
+/// A `Json` object that holds the deleted medication, `Error::NotFound` if the medication does not exist
+/// or belongs to another user, or `Error::Conflict` listing its stores, doses and reminders if it has any
+/// and `cascade` isn't set. The notes about all of them are deleted along with it.
+pub(crate) async fn delete_med(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+    cascade_query: Query<CascadeQuery>,
+) -> Result<Json<Medication>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, MEDICATION, &id, Permission::Manage).await?;
+    let medication = ctx.repos.medications.delete(&auth_user.user_id, &id, &owner, cascade_query.cascade).await?;
+    Ok(Json(medication.ok_or(Error::NotFound)?))
 }
 
-/// Retrieves a list of all medications from the database and returns them as a JSON object
+/// Retrieves a list of all medications of the current profile, or of all profiles of an owner, and returns
+/// them as a JSON object
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user listing their own medications or, with a grant, those of another user
 /// * `ctx` - A `State` object containing the `ApiContext` struct
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 ///
 /// # Returns
 ///
 /// A `Json` object containing a vector of `Medication` structs, or an `Error` if the database query fails.
 pub(crate) async fn list_all_meds(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
-    // user: Path<String>,
-    query: Query<MedicationBool>,
+    scope_query: Query<ScopeQuery>,
 ) -> Result<Json<Vec<Medication>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_user_medications($user);")
-        .bind(("user", &*query.user))
-        .await?;
-    let medications: Vec<Medication> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let medications = ctx.repos.medications.list(&scope.owner, scope.profile.as_deref(), None).await?;
     Ok(Json(medications))
 }
 
@@ -170,19 +172,78 @@ pub(crate) async fn list_all_meds(
 /// # Arguments
 ///
 /// * `ctx` - A `State` object containing the `ApiContext` struct
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 ///
 /// # Returns
 ///
 /// A `Json` object containing a vector of `Medication` structs, or an `Error` if the database query fails.
 pub(crate) async fn list_user_meds_by_status(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     query: Query<MedicationBool>,
 ) -> Result<Json<Vec<Medication>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_user_medications_by_status($active, $user);")
-        .bind(("user", &*query.user))
-        .bind(("active", query.active))
-        .await?;
-    let medications: Vec<Medication> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let medications = ctx.repos.medications.list(&scope.owner, scope.profile.as_deref(), query.active).await?;
     Ok(Json(medications))
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+
+    use crate::api::testing::{id, TestApp};
+
+    /// The tables of the records listed in a `409 Conflict`, sorted.
+    fn dependents(body: &Value) -> Vec<&str> {
+        let dependents = body["dependents"].as_array().unwrap_or_else(|| panic!("no dependents in {body}"));
+        let mut tables: Vec<&str> = dependents.iter()
+            .map(|dependent| dependent["tb"].as_str().unwrap())
+            .collect();
+        tables.sort();
+        tables
+    }
+
+    #[tokio::test]
+    async fn a_medication_with_dependents_is_deleted_only_with_cascade() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(&ann), Some(json!({ "name": "Aspirin" }))).await;
+        let store = json!({
+            "medication": id(&medication),
+            "production_date": "2026-01-01T00:00:00Z",
+            "lot_number": "A1",
+            "quantity": 10.0,
+            "unit": "mg",
+        });
+        let (_, store) = app.request(Method::POST, "/stores", Some(&ann), Some(store)).await;
+        let dose = json!({ "store": id(&store), "quantity": 1.0, "unit": "mg" });
+        let (status, dose) = app.request(Method::POST, "/doses", Some(&ann), Some(dose)).await;
+        assert_eq!(status, StatusCode::OK, "{dose}");
+        let note = json!({ "note_table": "medication", "note_thing": id(&medication), "content": "with food" });
+        let (status, note) = app.request(Method::POST, "/notes", Some(&ann), Some(note)).await;
+        assert_eq!(status, StatusCode::OK, "{note}");
+        let uri = format!("/medications/{}", id(&medication));
+
+        let (status, body) = app.request(Method::DELETE, &uri, Some(&ann), None).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{body}");
+        assert_eq!(dependents(&body), ["dose", "store"]);
+        let (status, _) = app.request(Method::GET, &uri, Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = app.request(Method::DELETE, &format!("{uri}?cascade=true"), Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let deleted = [
+            uri,
+            format!("/stores/{}", id(&store)),
+            format!("/doses/{}", id(&dose)),
+            format!("/notes/{}", id(&note)),
+        ];
+        for uri in deleted {
+            let (status, _) = app.request(Method::GET, &uri, Some(&ann), None).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+        }
+    }
+}
@@ -3,44 +3,24 @@ use axum::extract::{ State, Path, Query };
 use axum::Json;
 use serde::Deserialize;
 use serde::Serialize;
-use surrealdb::sql::{ Thing, Datetime };
 
 use crate::api::error::Error;
+use crate::api::etag::{rejected, IfMatch, Tagged};
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::grant::{authorize_record, authorize_scope, Permission, ScopeQuery};
+use crate::api::handlers::ensure_owned;
+use crate::api::repository::note::{CreateNote, DoseNote, MedicationNote, Note, StoreNote};
+use crate::api::repository::reference::NOTE_TABLES;
 use crate::api::ApiContext;
 
-const NOTE: &str = "note";
+pub(crate) const NOTE: &str = "note";
 
-/// A struct representing a note that can relate to other objects. Used to store notes on
-/// medications, stores, and other objects. The `note_table` and `note_thing` fields are used to
-/// identify the object the note relates to. The `content` field is used to store the note itself.
-///
-/// # Fields
-///
-/// * `id` - The unique identifier of the note
-/// * `note_table` - The table the note relates to
-/// * `note_thing` - The thing the note relates to
-/// * `content` - The content of the note
-/// * `created` - The date the note was created
-/// * `updated` - The date the note was last updated
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Note {
-    id: Option<Thing>,
-    user: Option<Thing>,
-    note_table: String,
-    note_thing: String,
-    content: String,
-    created: Option<Datetime>,
-    updated: Option<Datetime>,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct CreateNote {
-    user: String,
-    note_table: String,
-    note_thing: String,
-    content: String,
-    created: Option<Datetime>,
-    updated: Option<Datetime>,
+/// Checks that a note is about a record of a table notes can be about, see `NOTE_TABLES`, and of its owner.
+pub(crate) async fn ensure_about(ctx: &ApiContext, note: &CreateNote, owner: &str) -> Result<(), Error> {
+    if !NOTE_TABLES.contains(&note.note_table.as_str()) {
+        return Err(Error::unprocessable_entity([("note_table", "must be medication, store or dose")]));
+    }
+    ensure_owned(ctx, &note.note_table, &note.note_thing, owner).await
 }
 
 #[derive(Serialize, Deserialize)]
@@ -48,32 +28,32 @@ pub struct NoteQuery {
     id: Option<String>,
     note_table: Option<String>,
     note_thing: Option<String>,
-    user: String,
 }
 
 /// Creates a new note in the database with the provided content
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user creating the note for themselves or, with a grant, for another user
 /// * `ctx` - A `State` object containing the `ApiContext`
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 /// * `Json(note)` - A `Json` object containing the content of the note to be created
 ///
 /// # Returns
 ///
 /// A `Json` object containing the newly created note, wrapped in an `Option`.
-/// If the note was not created successfully, returns an `Error`.
+/// If the note was not created successfully, returns an `Error`, `Error::NotFound` if the record
+/// the note relates to does not belong to the user, or `Error::UnprocessableEntity` if notes can't be about
+/// records of `note_table`.
 pub(crate) async fn create_note(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     Json(note): Json<CreateNote>,
 ) -> Result<Json<Option<Note>>, Error> {
-    let mut sql = ctx.db.query(
-        "CREATE note SET user = type::thing('user', $user), note_table = $note_table, note_thing = $note_thing, content = $content;")
-        .bind(("user", note.user))
-        .bind(("note_table", note.note_table))
-        .bind(("note_thing", note.note_thing))
-        .bind(("content", note.content))
-        .await?;
-    let note: Option<Note> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::Manage).await?;
+    ensure_about(&ctx, &note, &scope.owner).await?;
+    let note = ctx.repos.notes.create(&auth_user.user_id, &scope.owner, note).await?;
     Ok(Json(note))
 }
 
@@ -81,75 +61,93 @@ pub(crate) async fn create_note(
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the note.
 /// * `ctx` - A `State` object containing the `ApiContext` struct.
 /// * `id` - A `Path` object containing the ID of the note to be read.
 ///
 /// # Returns
 ///
-/// Returns a `Json` object containing the note with the given ID, or `None` if no note was found.
+/// Returns a `Json` object containing the note with the given ID, and its version as the `ETag`.
 ///
 /// # Errors
 ///
-/// Returns an `Error` if there was an issue with the database query.
-pub(crate) async fn read_note(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Note>>, Error> {
-    let note = ctx.db.select((NOTE, &*id)).await?;
-    Ok(Json(note))
+/// Returns `Error::NotFound` if no note of the user was found, or an `Error` if there was an issue with the
+/// database query.
+pub(crate) async fn read_note(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Tagged<Note>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, NOTE, &id, Permission::View).await?;
+    let note = ctx.repos.notes.read(&id, &owner).await?;
+    Ok(Tagged(note.ok_or(Error::NotFound)?))
 }
 
 /// Updates the note with the given ID in the database with the new content provided in the request body.
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the note and the record it relates to.
 /// * `ctx` - A `State` object containing the `ApiContext` struct.
 /// * `id` - A `Path` object containing the ID of the note to be updated.
+/// * `if_match` - The `ETag` of the note as the client last read it.
 /// * `Json(note)` - A `Json` object containing the new content of the note.
 ///
 /// # Returns
 ///
-/// A `Json` object containing the updated note, or `None` if the note was not found in the database.
+/// A `Json` object containing the updated note, with its new `ETag`.
 ///
 /// # Errors
 ///
-/// Returns an `Error` if there was an issue updating the note in the database.
+/// Returns `Error::NotFound` if the note or the record it relates to does not belong to the user,
+/// `Error::UnprocessableEntity` if notes can't be about records of `note_table`, `Error::PreconditionFailed` if
+/// the note was changed since the client read it, or an `Error` if there was an issue updating the note in the
+/// database.
 pub(crate) async fn update_note(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
     id: Path<String>,
+    if_match: IfMatch,
     Json(note): Json<CreateNote>,
-) -> Result<Json<Option<Note>>, Error> {
-    let mut sql = ctx.db.query(
-        "UPDATE type::thing('note', $id) SET user = type::thing('user', $user), note_table = $note_table, note_thing = $note_thing, content = $content;")
-        .bind(("id", &*id))
-        .bind(("user", note.user))
-        .bind(("note_table", note.note_table))
-        .bind(("note_thing", note.note_thing))
-        .bind(("content", note.content))
-        .await?;
-    let note: Option<Note> = sql.take(0)?;
-    dbg!(&note);
-    Ok(Json(note))
+) -> Result<Tagged<Note>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, NOTE, &id, Permission::Manage).await?;
+    ensure_about(&ctx, &note, &owner).await?;
+    let note = ctx.repos.notes.update(&auth_user.user_id, &id, &owner, &if_match.0, note).await?;
+    match note {
+        Some(note) => Ok(Tagged(note)),
+        None => Err(rejected(ctx.repos.notes.read(&id, &owner).await?)),
+    }
 }
 
 /// Deletes a note with the given ID from the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the note
 /// * `ctx` - A `State` object containing the `ApiContext` struct
 /// * `id` - A `Path` object containing the ID of the note to be deleted
 ///
 /// # Returns
 ///
-/// A `Json` object containing the deleted note, or `None` if the note was not found
+/// A `Json` object containing the deleted note, or `Error::NotFound` if no note of the user was found
 /// in the database. If an error occurs during the deletion process, an `Error` object is returned.
-pub(crate) async fn delete_note(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Note>>, Error> {
-    let note = ctx.db.delete((NOTE, &*id)).await?;
-    Ok(Json(note))
+pub(crate) async fn delete_note(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Json<Note>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, NOTE, &id, Permission::Manage).await?;
+    let note = ctx.repos.notes.delete(&auth_user.user_id, &id, &owner).await?;
+    Ok(Json(note.ok_or(Error::NotFound)?))
 }
 
-/// Lists all notes in the database
+/// Lists all notes of the current profile, or of all profiles of an owner
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user listing their own notes or, with a grant, those of another user
 /// * `ctx` - A `State` object containing the `ApiContext` instance
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 ///
 /// # Returns
 ///
@@ -158,125 +156,76 @@ pub(crate) async fn delete_note(ctx: State<ApiContext>, id: Path<String>) -> Res
 /// # Errors
 ///
 /// * Returns an `Error` if the database query fails.
-pub(crate) async fn list_notes(ctx: State<ApiContext>,) -> Result<Json<Vec<Note>>, Error> {
-    let notes = ctx.db.select(NOTE).await?;
+pub(crate) async fn list_notes(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
+) -> Result<Json<Vec<Note>>, Error> {
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let notes = ctx.repos.notes.list(&scope.owner, scope.profile.as_deref()).await?;
     Ok(Json(notes))
 }
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DoseNote {
-    content: String,
-    created: Datetime,
-    dose_created: String,
-    dose_id: Thing,
-    dose_quantity: f32,
-    dose_updated: String,
-    id: Thing,
-    medication_id: Thing,
-    medication_name: String,
-    note_table: String,
-    note_thing: String,
-    store_id: Thing,
-    store_production_date: Datetime,
-    store_start_quantity: f32,
-    unit: String,
-    updated: Datetime,
-    user: Thing,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct MedicationNote {
-    id: Thing,
-    content: String,
-    created: Datetime,
-    medication_active: bool,
-    medication_id: Thing,
-    medication_name: String,
-    note_table: String,
-    note_thing: String,
-    updated: Datetime,
-    user: Thing,
-}
-#[derive(Serialize, Deserialize, Debug)]
-pub struct StoreNote {
-    id: Thing,
-    content: String,
-    created: Datetime,
-    medication_id: Thing,
-    medication_name: String,
-    note_table: String,
-    note_thing: String,
-    store_active: bool,
-    store_created: String,
-    store_id: Thing,
-    store_production_date: Datetime,
-    store_start_quantity: f32,
-    store_updated: String,
-    unit: String,
-    updated: Datetime,
-    user: Thing,
-}
-
-pub(crate) async fn list_all_dose_notes(ctx: State<ApiContext>,) -> Result<Json<Vec<DoseNote>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_all_dose_notes();")
-        .await?;
-    let notes: Vec<DoseNote> = sql.take(0)?;
-    dbg!(&notes);
+pub(crate) async fn list_all_dose_notes(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
+) -> Result<Json<Vec<DoseNote>>, Error> {
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let notes = ctx.repos.notes.list_dose_notes(&scope.owner, scope.profile.as_deref()).await?;
     Ok(Json(notes))
 }
 
-pub(crate) async fn list_notes_for_dose(ctx: State<ApiContext>, id: Path<String>) ->
-Result<Json<Vec<DoseNote>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_notes_for_dose($id);")
-        .bind(("id", &*id))
-        .await?;
-    let notes: Vec<DoseNote> = sql.take(0)?;
+pub(crate) async fn list_notes_for_dose(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
+    id: Path<String>,
+) -> Result<Json<Vec<DoseNote>>, Error> {
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let notes = ctx.repos.notes.list_notes_for_dose(&scope.owner, &id).await?;
     Ok(Json(notes))
 }
 
 pub(crate) async fn list_all_medication_notes(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
-    query: Query<NoteQuery>,
+    scope_query: Query<ScopeQuery>,
 ) -> Result<Json<Vec<MedicationNote>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_all_medication_notes($user);")
-        .bind(("id", &query.user))
-        .await?;
-    let notes: Vec<MedicationNote> = sql.take(0)?;
-    dbg!(&notes);
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let notes = ctx.repos.notes.list_medication_notes(&scope.owner, scope.profile.as_deref()).await?;
     Ok(Json(notes))
 }
 
 pub(crate) async fn list_notes_for_medication(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     query: Query<NoteQuery>,
 ) -> Result<Json<Vec<MedicationNote>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_notes_for_medication($id);")
-        .bind(("id", &query.id))
-        .bind(("id", &query.user))
-        .await?;
-    let notes: Vec<MedicationNote> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let medication = query.id.as_deref().ok_or_else(|| Error::unprocessable_entity([("id", "is required")]))?;
+    let notes = ctx.repos.notes.list_notes_for_medication(&scope.owner, medication).await?;
     Ok(Json(notes))
 }
 
-pub(crate) async fn list_all_store_notes(ctx: State<ApiContext>,) -> Result<Json<Vec<StoreNote>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_all_store_notes();")
-        .await?;
-    let notes: Vec<StoreNote> = sql.take(0)?;
-    dbg!(&notes);
+pub(crate) async fn list_all_store_notes(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
+) -> Result<Json<Vec<StoreNote>>, Error> {
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let notes = ctx.repos.notes.list_store_notes(&scope.owner, scope.profile.as_deref()).await?;
     Ok(Json(notes))
 }
 
-pub(crate) async fn list_notes_for_store(ctx: State<ApiContext>, id: Path<String>) ->
-Result<Json<Vec<StoreNote>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_notes_for_store($id);")
-        .bind(("id", &*id))
-        .await?;
-    let notes: Vec<StoreNote> = sql.take(0)?;
+pub(crate) async fn list_notes_for_store(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
+    id: Path<String>,
+) -> Result<Json<Vec<StoreNote>>, Error> {
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let notes = ctx.repos.notes.list_notes_for_store(&scope.owner, &id).await?;
     Ok(Json(notes))
 }
 //TODO: Add function to list notes by tables and things (objects)
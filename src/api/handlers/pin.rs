@@ -0,0 +1,272 @@
+use axum::extract::{ ConnectInfo, State };
+use axum::Json;
+use axum_extra::extract::CookieJar;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use surrealdb::sql::Thing;
+
+use crate::api::error::Error;
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::login_attempt::{self, LoginKeys};
+use crate::api::handlers::user::{self, UpdatePassword, User, UserBody};
+use crate::api::{ApiContext, Result};
+
+/// How many wrong PINs in a row wipe the PIN, so the user has to log in with the password and set a new one.
+const MAX_PIN_ATTEMPTS: i64 = 5;
+
+/// The allowed length of a PIN, in digits.
+const PIN_LENGTH: std::ops::RangeInclusive<usize> = 4..=8;
+
+/// A new quick-unlock PIN, set with the current password.
+#[derive(Deserialize)]
+pub struct SetPin {
+    password: String,
+    pin: String,
+}
+
+/// A quick unlock of the local desktop app.
+#[derive(Deserialize)]
+pub struct UnlockUser {
+    username: String,
+    pin: String,
+}
+
+/// The fields of a `user` record needed to verify an unlock attempt.
+#[derive(Deserialize)]
+struct PinUser {
+    id: Thing,
+    username: String,
+    email: String,
+    pin_hash: Option<String>,
+}
+
+/// Sets or replaces the quick-unlock PIN of the current user
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing the current password and the new PIN
+///
+/// # Returns
+///
+/// `Error::Unauthorized` if the password is wrong, or `Error::UnprocessableEntity` if the PIN is not
+/// 4 to 8 digits.
+pub(crate) async fn set_pin(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<UserBody<SetPin>>,
+) -> Result<()> {
+    let pin = req.user.pin;
+    if !PIN_LENGTH.contains(&pin.len()) || !pin.chars().all(|c| c.is_ascii_digit()) {
+        return Err(Error::unprocessable_entity([("pin", "must be 4 to 8 digits")]));
+    }
+
+    verify_current_password(&ctx, &auth_user, req.user.password).await?;
+
+    let pin_hash = user::hash_password(&ctx, pin).await?;
+    ctx.db.query("UPDATE type::thing('user', $user) SET pin_hash = $pin_hash, pin_failures = 0;")
+        .bind(("pin_hash", pin_hash))
+        .bind(("user", &*auth_user.user_id))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+/// Removes the quick-unlock PIN of the current user and ends the sessions unlocked with it
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing the current password
+///
+/// # Returns
+///
+/// `Error::Unauthorized` if the password is wrong.
+pub(crate) async fn remove_pin(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<UserBody<UpdatePassword>>,
+) -> Result<()> {
+    let password = req.user.password
+        .ok_or_else(|| Error::unprocessable_entity([("password", "is required")]))?;
+    verify_current_password(&ctx, &auth_user, password).await?;
+
+    ctx.db.query(
+        "UPDATE type::thing('user', $user) SET pin_hash = NONE, pin_failures = 0;
+        DELETE session WHERE user = type::thing('user', $user) AND restricted = true;")
+        .bind(("user", &*auth_user.user_id))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+/// Unlocks the local desktop app with the PIN of the user and returns a restricted session
+///
+/// The session can only log doses and acknowledge reminders, and ends after
+/// `Config::pin_idle_timeout_minutes` without use. The PIN doesn't ask for the second factor, which
+/// is why anything else still needs a login with the password.
+///
+/// # Arguments
+///
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `addr` - The address of the client, for tracking failed attempts
+/// * `jar` - The cookies of the request
+/// * `Json(req)` - A `Json` object containing the username and the PIN
+///
+/// # Returns
+///
+/// A `Json` object containing the user and the tokens of the restricted session, along with the
+/// session cookies, or `Error::Unauthorized` if the username is unknown, the user has no PIN or the
+/// PIN is wrong. Wrong PINs count as failed logins, see `user::login_user()`, and after
+/// `MAX_PIN_ATTEMPTS` of them in a row the PIN is removed.
+pub(crate) async fn unlock_user(
+    ctx: State<ApiContext>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    jar: CookieJar,
+    Json(req): Json<UserBody<UnlockUser>>,
+) -> Result<(CookieJar, Json<UserBody<User>>)> {
+    let keys = LoginKeys::new(&req.user.username, addr.ip());
+    login_attempt::check_lockout(&ctx, &keys).await?;
+
+    let mut sql = ctx.db.query(
+        "SELECT id, email, username, pin_hash FROM user WHERE username = $username;")
+        .bind(("username", &*req.user.username))
+        .await?;
+    let user: Option<PinUser> = sql.take(0)?;
+    // An unknown username, or a user without a PIN, gets the same answer as a wrong PIN, so it doesn't tell
+    // which usernames exist.
+    let Some(PinUser { id, username, email, pin_hash: Some(pin_hash) }) = user else {
+        login_attempt::record_failure(&ctx, &keys).await?;
+        return Err(Error::Unauthorized);
+    };
+
+    match user::verify_password(req.user.pin, pin_hash).await {
+        Ok(()) => {}
+        Err(Error::Unauthorized) => {
+            login_attempt::record_failure(&ctx, &keys).await?;
+
+            let mut sql = ctx.db.query(
+                "UPDATE $user SET pin_failures += 1 RETURN VALUE pin_failures;")
+                .bind(("user", &id))
+                .await?;
+            let failures: Option<i64> = sql.take(0)?;
+            if failures.unwrap_or_default() >= MAX_PIN_ATTEMPTS {
+                log::warn!("too many wrong PINs for {}, removing the PIN", id);
+                ctx.db.query("UPDATE $user SET pin_hash = NONE, pin_failures = 0;")
+                    .bind(("user", &id))
+                    .await?
+                    .check()?;
+            }
+            return Err(Error::Unauthorized);
+        }
+        Err(e) => return Err(e),
+    }
+
+    login_attempt::clear(&ctx, &keys).await?;
+    ctx.db.query("UPDATE $user SET pin_failures = 0;")
+        .bind(("user", &id))
+        .await?
+        .check()?;
+
+    let auth_user = AuthUser::start_restricted_session(&ctx, id.id.to_raw()).await?;
+    user::session_user(&ctx, jar, auth_user, email, username).await
+}
+
+async fn verify_current_password(ctx: &ApiContext, auth_user: &AuthUser, password: String) -> Result<()> {
+    let mut sql = ctx.db.query("SELECT VALUE password_hash FROM type::thing('user', $user);")
+        .bind(("user", &*auth_user.user_id))
+        .await?;
+    let password_hash: Option<String> = sql.take(0)?;
+    user::verify_password(password, password_hash.ok_or(Error::NotFound)?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::api::testing::{id, token, TestApp, PASSWORD};
+
+    /// Signs up `ann` with the PIN 1234, and returns the token of the session.
+    async fn sign_up_with_pin(app: &TestApp) -> String {
+        let token = app.sign_up("ann").await;
+        let pin = json!({ "user": { "password": PASSWORD, "pin": "1234" } });
+        let (status, body) = app.request(Method::PUT, "/user/pin", Some(&token), Some(pin)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        token
+    }
+
+    async fn unlock(app: &TestApp, pin: &str) -> (StatusCode, serde_json::Value) {
+        let unlock = json!({ "user": { "username": "ann", "pin": pin } });
+        app.request(Method::POST, "/users/unlock", None, Some(unlock)).await
+    }
+
+    #[tokio::test]
+    async fn a_restricted_session_can_log_doses_and_acknowledge_reminders_only() {
+        let app = TestApp::new().await;
+        let full = sign_up_with_pin(&app).await;
+
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(&full), Some(json!({ "name": "Aspirin" }))).await;
+        let store = json!({
+            "medication": id(&medication),
+            "production_date": "2026-01-01T00:00:00Z",
+            "lot_number": "A1",
+            "quantity": 10.0,
+            "unit": "mg",
+        });
+        let (status, store) = app.request(Method::POST, "/stores", Some(&full), Some(store)).await;
+        assert_eq!(status, StatusCode::OK, "{store}");
+        let reminder = json!({
+            "medication": id(&medication),
+            "end": "2030-01-01T00:00:00Z",
+            "days": "1111111",
+            "times": ["08:00"],
+        });
+        let (status, reminder) = app.request(Method::POST, "/reminders", Some(&full), Some(reminder)).await;
+        assert_eq!(status, StatusCode::OK, "{reminder}");
+
+        let (status, body) = unlock(&app, "1234").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let restricted = token(&body);
+
+        let dose = json!({ "store": id(&store), "quantity": 1.0, "unit": "mg" });
+        let (status, body) = app.request(Method::POST, "/doses", Some(&restricted), Some(dose)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let uri = format!("/reminders/{}/acknowledge", id(&reminder));
+        let (status, body) = app.request(Method::POST, &uri, Some(&restricted), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, body) = app.request(Method::GET, "/activereminders/", Some(&restricted), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (status, _) = app.request(Method::GET, "/medications", Some(&restricted), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let pin = json!({ "user": { "password": PASSWORD, "pin": "5678" } });
+        let (status, _) = app.request(Method::PUT, "/user/pin", Some(&restricted), Some(pin)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn a_wrong_pin_is_refused() {
+        let app = TestApp::new().await;
+        sign_up_with_pin(&app).await;
+
+        let (status, _) = unlock(&app, "4321").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn too_many_wrong_pins_remove_the_pin() {
+        let app = TestApp::new().await;
+        sign_up_with_pin(&app).await;
+
+        for _ in 0..super::MAX_PIN_ATTEMPTS {
+            let (status, _) = unlock(&app, "4321").await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let (status, _) = unlock(&app, "1234").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
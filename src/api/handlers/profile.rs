@@ -0,0 +1,249 @@
+use axum::extract::{ State, Path, Query };
+use axum::Json;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::error::Error;
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::grant::{authorize_record, authorize_scope, Permission, ScopeQuery};
+use crate::api::repository::encrypted::Decrypt;
+use crate::api::ApiContext;
+use crate::db::encryption::Cipher;
+
+pub(crate) const PROFILE: &str = "profile";
+
+/// A struct representing a patient profile, which all clinical records belong to
+///
+/// # Fields
+///
+/// * `id` - A `Thing` representing the ID of the profile
+/// * `user` - A `Thing` representing the user who owns the profile
+/// * `name` - A `String` representing the name of the patient
+/// * `created` - A `Datetime` representing the date and time the profile was created
+/// * `updated` - A `Datetime` representing the date and time the profile was last updated
+/// * `current` - Whether the session of the request is switched to this profile
+#[derive(Serialize, Deserialize)]
+pub struct Profile {
+    id: Thing,
+    user: Thing,
+    name: String,
+    created: Datetime,
+    updated: Datetime,
+    #[serde(default)]
+    current: bool,
+}
+
+/// The name of the patient is encrypted, see `crate::db::encryption`.
+impl Decrypt for Profile {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.name = cipher.decrypt(&self.name)?;
+        Ok(self)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateProfile {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SwitchProfile {
+    profile: String,
+}
+
+/// Creates a new profile
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user creating the profile for themselves or, with a grant, for another user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `scope_query` - A `Query` object with the optional owner of the profile
+/// * `Json(profile)` - A `Json` object containing the name of the profile
+///
+/// # Returns
+///
+/// A `Json` object containing the new profile, wrapped in an `Option`.
+pub(crate) async fn create_profile(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
+    Json(profile): Json<CreateProfile>,
+) -> Result<Json<Option<Profile>>, Error> {
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::Manage).await?;
+    let mut sql = ctx.db.query(
+        "CREATE profile SET user = type::thing('user', $user), name = $name;")
+        .bind(("user", &scope.owner))
+        .bind(("name", ctx.cipher.encrypt(&profile.name)))
+        .await?;
+    let profile: Option<Profile> = sql.take(0)?;
+    Ok(Json(profile.decrypt(&ctx.cipher)?))
+}
+
+/// Renames a profile
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the profile
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `id` - A `Path` object containing the ID of the profile to update
+/// * `Json(profile)` - A `Json` object containing the new name of the profile
+///
+/// # Returns
+///
+/// A `Json` object containing the updated profile, or `Error::NotFound` if the profile does not exist
+/// or belongs to another user.
+pub(crate) async fn update_profile(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+    Json(profile): Json<CreateProfile>,
+) -> Result<Json<Profile>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, PROFILE, &id, Permission::Manage).await?;
+    let mut sql = ctx.db.query(
+        "UPDATE type::thing('profile', $id) SET name = $name WHERE user = type::thing('user', $user);")
+        .bind(("id", &*id))
+        .bind(("user", &owner))
+        .bind(("name", ctx.cipher.encrypt(&profile.name)))
+        .await?;
+    let profile: Option<Profile> = sql.take(0)?;
+    Ok(Json(profile.ok_or(Error::NotFound)?.decrypt(&ctx.cipher)?))
+}
+
+/// Lists the profiles of the current user, or with a grant those of another user
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `scope_query` - A `Query` object with the optional owner of the profiles
+///
+/// # Returns
+///
+/// A `Json` object containing a vector of `Profile` structs, oldest first.
+pub(crate) async fn list_profiles(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
+) -> Result<Json<Vec<Profile>>, Error> {
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let current = auth_user.profile_id.as_deref().map(|id| Thing::from((PROFILE, id)));
+    let mut sql = ctx.db.query(
+        "SELECT *, id = $current AS current FROM profile WHERE user = type::thing('user', $user) ORDER BY created;")
+        .bind(("current", current))
+        .bind(("user", &scope.owner))
+        .await?;
+    let profiles: Vec<Profile> = sql.take(0)?;
+    Ok(Json(profiles.decrypt(&ctx.cipher)?))
+}
+
+/// Switches the session of the request to one of the profiles of the current user
+///
+/// Creating and listing records without naming a profile uses the profile the session is switched to.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user who must own the profile
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing the ID of the profile
+///
+/// # Returns
+///
+/// A `Json` object containing the profile, or `Error::NotFound` if the profile does not exist or
+/// belongs to another user.
+pub(crate) async fn switch_profile(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<SwitchProfile>,
+) -> Result<Json<Profile>, Error> {
+    ensure_profile(&ctx, &req.profile, &auth_user.user_id).await?;
+    let mut sql = ctx.db.query(
+        "UPDATE type::thing('session', $session_id) SET profile = type::thing('profile', $profile);
+        SELECT *, true AS current FROM type::thing('profile', $profile);")
+        .bind(("session_id", &auth_user.session_id))
+        .bind(("profile", &req.profile))
+        .await?;
+    let profile: Option<Profile> = sql.take(1)?;
+    Ok(Json(profile.ok_or(Error::NotFound)?.decrypt(&ctx.cipher)?))
+}
+
+/// Checks that the profile exists and belongs to the given user, reporting one of another user as
+/// `Error::NotFound` like a missing one, see `crate::api::handlers::ensure_owned()`.
+pub(crate) async fn ensure_profile(ctx: &ApiContext, id: &str, user_id: &str) -> Result<(), Error> {
+    let mut sql = ctx.db.query(
+        "SELECT VALUE id FROM type::thing('profile', $id) WHERE user = type::thing('user', $user);")
+        .bind(("id", id))
+        .bind(("user", user_id))
+        .await?;
+    let found: Option<Thing> = sql.take(0)?;
+    found.map(|_| ()).ok_or(Error::NotFound)
+}
+
+/// Creates the first profile of a new user, named after them.
+pub(crate) async fn create_default_profile(ctx: &ApiContext, user_id: &str, name: &str) -> Result<(), Error> {
+    ctx.db.query("CREATE profile SET user = type::thing('user', $user), name = $name;")
+        .bind(("user", user_id))
+        .bind(("name", ctx.cipher.encrypt(name)))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::api::testing::{id, token, TestApp, CLIENT, PASSWORD};
+
+    #[tokio::test]
+    async fn login_starts_the_session_on_the_first_profile() {
+        let app = TestApp::new().await;
+        app.sign_up("ann").await;
+
+        let (status, body) = app.log_in_from(CLIENT, "ann", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let token = token(&body);
+
+        let (status, profiles) = app.request(Method::GET, "/profiles", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK, "{profiles}");
+        assert_eq!(profiles.as_array().map(Vec::len), Some(1));
+        assert_eq!(profiles[0]["current"], json!(true));
+
+        let (status, medication) =
+            app.request(Method::POST, "/medications", Some(&token), Some(json!({ "name": "Aspirin" }))).await;
+        assert_eq!(status, StatusCode::OK, "{medication}");
+        assert_eq!(medication["profile"], profiles[0]["id"]);
+    }
+
+    #[tokio::test]
+    async fn switching_the_profile_scopes_the_new_records() {
+        let app = TestApp::new().await;
+        let token = app.sign_up("ann").await;
+
+        let (status, profile) =
+            app.request(Method::POST, "/profiles", Some(&token), Some(json!({ "name": "Ben" }))).await;
+        assert_eq!(status, StatusCode::OK, "{profile}");
+        let switch = json!({ "profile": id(&profile) });
+        let (status, body) = app.request(Method::PUT, "/user/profile", Some(&token), Some(switch)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(&token), Some(json!({ "name": "Aspirin" }))).await;
+        assert_eq!(medication["profile"], profile["id"]);
+        let (_, medications) = app.request(Method::GET, "/medications", Some(&token), None).await;
+        assert_eq!(medications.as_array().map(Vec::len), Some(1));
+    }
+
+    #[tokio::test]
+    async fn switching_to_a_profile_of_another_user_is_refused() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let ben = app.sign_up("ben").await;
+
+        let (_, profiles) = app.request(Method::GET, "/profiles", Some(&ben), None).await;
+        let switch = json!({ "profile": id(&profiles[0]) });
+        let (status, _) = app.request(Method::PUT, "/user/profile", Some(&ann), Some(switch)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,104 @@
+use serde::Deserialize;
+use surrealdb::sql::Thing;
+
+use crate::api::error::Error;
+use crate::api::extractor;
+use crate::api::handlers::user;
+use crate::api::{ApiContext, Result};
+
+/// How many recovery codes are handed out at a time.
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// The table of the single-use codes for a lost authenticator.
+pub(crate) const TOTP_RECOVERY_CODE: &str = "totp_recovery_code";
+
+/// The table of the single-use codes for a forgotten password.
+pub(crate) const PASSWORD_RECOVERY_CODE: &str = "password_recovery_code";
+
+#[derive(Deserialize)]
+struct RecoveryCode {
+    id: Thing,
+    code_hash: String,
+}
+
+/// Generates new recovery codes for the user in `table`, replacing the old ones, and stores their
+/// argon2 hashes.
+///
+/// Returns the codes themselves, which are only ever shown to the user this once.
+pub(crate) async fn replace_recovery_codes(ctx: &ApiContext, table: &str, user_id: &str) -> Result<Vec<String>> {
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = extractor::to_hex(&rand::random::<[u8; 5]>());
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+
+    let mut hashes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for code in &recovery_codes {
+        hashes.push(user::hash_password(ctx, normalize_recovery_code(code)).await?);
+    }
+
+    ctx.db.query(
+        "DELETE type::table($table) WHERE user = type::thing('user', $user);
+        FOR $hash IN $hashes {
+            CREATE type::table($table) SET user = type::thing('user', $user), code_hash = $hash;
+        };")
+        .bind(("table", table))
+        .bind(("hashes", hashes))
+        .bind(("user", user_id))
+        .await?
+        .check()?;
+
+    Ok(recovery_codes)
+}
+
+/// Uses up the recovery code if it is one of the user's in `table`.
+pub(crate) async fn use_recovery_code(
+    ctx: &ApiContext,
+    table: &str,
+    user_id: &str,
+    recovery_code: String,
+) -> Result<bool> {
+    let mut sql = ctx.db.query(
+        "SELECT id, code_hash FROM type::table($table) WHERE user = type::thing('user', $user);")
+        .bind(("table", table))
+        .bind(("user", user_id))
+        .await?;
+    let codes: Vec<RecoveryCode> = sql.take(0)?;
+    let recovery_code = normalize_recovery_code(&recovery_code);
+
+    for code in codes {
+        match user::verify_password(recovery_code.clone(), code.code_hash).await {
+            Ok(()) => {
+                // Only the request that actually deletes the code gets to use it.
+                let mut sql = ctx.db.query("DELETE $code RETURN BEFORE;")
+                    .bind(("code", &code.id))
+                    .await?;
+                let deleted: Option<Thing> = sql.take((0, "id"))?;
+                return Ok(deleted.is_some());
+            }
+            Err(Error::Unauthorized) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    log::debug!("recovery code does not match");
+    Ok(false)
+}
+
+/// Checks the recovery code against as many dummy hashes as a user has codes, see
+/// `user::verify_dummy_password()`, so an unknown username takes as long as a wrong code.
+pub(crate) async fn verify_dummy_recovery_codes(ctx: &ApiContext, recovery_code: String) {
+    let recovery_code = normalize_recovery_code(&recovery_code);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        user::verify_dummy_password(ctx, recovery_code.clone()).await;
+    }
+}
+
+/// Recovery codes are compared without the dash and case-insensitively, as people type them off paper.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
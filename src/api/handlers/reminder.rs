@@ -1,94 +1,39 @@
 
 use axum::extract::{ State, Path, Query };
 use axum::Json;
-use serde::Deserialize;
-use serde::Serialize;
-use surrealdb::sql::{ Thing, Datetime };
 use crate::api::error::Error;
+use crate::api::etag::{rejected, IfMatch, Tagged};
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::grant::{authorize_record, authorize_scope, Permission, ScopeQuery};
+use crate::api::handlers::ensure_owned;
+use crate::api::handlers::medication::MEDICATION;
+use crate::api::repository::reminder::{CreateReminder, Reminder};
 use crate::api::ApiContext;
 
-const REMINDER: &str = "reminder";
-
-/// A struct representing a reminder for taking a medication with the following fields:
-/// * `id`: A unique identifier for the reminder
-/// * `medication`: The medication for which the reminder is set
-/// * `start`: The start date and time of the reminder
-/// * `end`: The end date and time of the reminder
-/// * `days`: A string representing the days on which the reminder should be active
-/// * `times`: A vector of strings representing the times at which the reminder should be active
-/// * `active`: A boolean indicating whether the reminder is currently active
-/// * `user`: An optional string representing the user who created the reminder
-/// * `created`: The date and time when the reminder was created
-#[derive(Serialize, Deserialize)]
-pub struct Reminder {
-    active: bool,
-    created: Datetime,
-    days: String,
-    end: Datetime,
-    id: Thing,
-    medication: Thing,
-    start: Datetime,
-    times: Vec<String>,
-    updated: Datetime,
-    user: Option<Thing>,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct QueryUser {
-    active: Option<bool>,
-    created: Option<Datetime>,
-    days: Option<String>,
-    end: Option<Datetime>,
-    id: Option<String>,
-    medication: Option<String>,
-    start: Option<Datetime>,
-    user: Option<String>,
-}
-
-/// Creates a new note in the database with the provided content
-///
-/// # Arguments
-///
-/// * `ctx` - A `State` object containing the `ApiContext`
-/// * `Json(note)` - A `Json` object containing the content of the note to be created
-///
-/// # Returns
-///
-/// A `Json` object containing the newly created note, wrapped in an `Option`. If the note was not created successfully, returns an `Error`.
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct CreateReminder {
-    user: String,
-    medication: String,
-    // start: String,
-    end: Datetime,
-    days: String,
-    times: Vec<String>,
-}
+pub(crate) const REMINDER: &str = "reminder";
 
 /// Creates a new reminder in the database with the given parameters
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user creating the reminder for themselves or, with a grant, for another user
 /// * `ctx` - A `State` object containing the `ApiContext`
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 /// * `Json(reminder)` - A `Json` object containing the parameters for the new reminder
 ///
 /// # Returns
 ///
 /// A `Json` object containing the newly created reminder, or `None` if the creation failed.
+/// Returns `Error::NotFound` if the medication does not belong to the user.
 pub(crate) async fn create_reminder(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     Json(reminder): Json<CreateReminder>,
 ) -> Result<Json<Option<Reminder>>, Error> {
-    let mut sql = ctx.db.query(
-        "CREATE reminder SET user = type::thing('user', $user), medication = type::thing('medication', $medication), end = $end, days = $days, times = $times;")
-        .bind(("user", reminder.user))
-        .bind(("medication", reminder.medication))
-        .bind(("end", reminder.end))
-        .bind(("days", reminder.days))
-        .bind(("times", reminder.times))
-        .await?;
-    let reminder: Option<Reminder> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::Manage).await?;
+    ensure_owned(&ctx, MEDICATION, &reminder.medication, &scope.owner).await?;
+    let reminder = ctx.repos.reminders.create(&auth_user.user_id, &scope.owner, reminder).await?;
     Ok(Json(reminder))
 }
 
@@ -96,91 +41,141 @@ pub(crate) async fn create_reminder(
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the reminder.
 /// * `ctx` - A `State` object containing the `ApiContext` struct.
 /// * `id` - A `Path` object containing the ID of the reminder to be read.
 ///
 /// # Returns
 ///
-/// Returns a `Json` object containing the reminder with the given ID, or `None` if no such reminder exists.
+/// Returns a `Json` object containing the reminder with the given ID, and its version as the `ETag`.
 ///
 /// # Errors
 ///
-/// Returns an `Error` if there is an issue with the database connection or query.
-pub(crate) async fn read_reminder(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Reminder>>, Error> {
-    let reminder = ctx.db.select((REMINDER, &*id)).await?;
-    Ok(Json(reminder))
+/// Returns `Error::NotFound` if no such reminder of the user exists, or an `Error` if there is an issue with
+/// the database connection or query.
+pub(crate) async fn read_reminder(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Tagged<Reminder>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, REMINDER, &id, Permission::View).await?;
+    let reminder = ctx.repos.reminders.read(&id, &owner).await?;
+    Ok(Tagged(reminder.ok_or(Error::NotFound)?))
 }
 
 /// Updates the reminder with the given id with the provided information
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the reminder and its medication
 /// * `ctx` - The API context
 /// * `id` - The id of the reminder to update
+/// * `if_match` - The `ETag` of the reminder as the client last read it
 /// * `reminder` - The new information to update the reminder with
 ///
 /// # Returns
 ///
-/// * `Json<Option<Reminder>>` - The updated reminder wrapped in an `Option` and then wrapped in a `Json` object
-/// * `Error` - An error that occurred while updating the reminder, if any.
+/// * `Json<Reminder>` - The updated reminder wrapped in a `Json` object, with its new `ETag`
+/// * `Error` - An error that occurred while updating the reminder, if any. `Error::NotFound` if the reminder or
+///   the medication does not belong to the user, `Error::PreconditionFailed` if the reminder was changed since
+///   the client read it.
 pub(crate) async fn update_reminder(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
     id: Path<String>,
+    if_match: IfMatch,
     Json(reminder): Json<CreateReminder>,
-) -> Result<Json<Option<Reminder>>, Error> {
-    let mut sql = ctx.db.query(
-        "UPDATE type::thing('reminder', $id) SET user = type::thing('user', $user), medication = type::thing('medication', $medication), end = $end, days = $days, times = $times;")
-        .bind(("id", &*id))
-        .bind(("user", reminder.user))
-        .bind(("medication", reminder.medication))
-        .bind(("end", reminder.end))
-        .bind(("days", reminder.days))
-        .bind(("times", reminder.times))
-        .await?;
-    let reminder: Option<Reminder> = sql.take(0)?;
-    Ok(Json(reminder))
+) -> Result<Tagged<Reminder>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, REMINDER, &id, Permission::Manage).await?;
+    ensure_owned(&ctx, MEDICATION, &reminder.medication, &owner).await?;
+    let reminder = ctx.repos.reminders.update(&auth_user.user_id, &id, &owner, &if_match.0, reminder).await?;
+    match reminder {
+        Some(reminder) => Ok(Tagged(reminder)),
+        None => Err(rejected(ctx.repos.reminders.read(&id, &owner).await?)),
+    }
 }
 
 /// Deactivates a reminder with the given ID by setting its `active` field to `false` in the database.
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the reminder.
 /// * `ctx` - A `State` object containing the `ApiContext` struct.
 /// * `id` - A `Path` object containing the ID of the reminder to be deactivated.
+/// * `if_match` - The `ETag` of the reminder as the client last read it.
 ///
 /// # Returns
 ///
-/// Returns a `Json` object containing an `Option` of the deactivated `Reminder` object, or an `Error` if the operation fails.
+/// Returns a `Json` object containing the deactivated `Reminder` object, `Error::NotFound` if no reminder of the
+/// user was found, `Error::PreconditionFailed` if it was changed since the client read it, or an `Error` if the
+/// operation fails.
 pub(crate) async fn deactivate_reminder(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
     id: Path<String>,
-) -> Result<Json<Option<Reminder>>, Error> {
-    let mut sql = ctx.db.query(
-        "UPDATE type::thing('reminder', $id) SET active = false;").bind(("id", &*id)).await?;
-    let reminder: Option<Reminder> = sql.take(0)?;
-    Ok(Json(reminder))
+    if_match: IfMatch,
+) -> Result<Tagged<Reminder>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, REMINDER, &id, Permission::Manage).await?;
+    let reminder = ctx.repos.reminders.deactivate(&auth_user.user_id, &id, &owner, &if_match.0).await?;
+    match reminder {
+        Some(reminder) => Ok(Tagged(reminder)),
+        None => Err(rejected(ctx.repos.reminders.read(&id, &owner).await?)),
+    }
+}
+
+/// Acknowledges a reminder with the given ID by setting its `acknowledged` field to the current time.
+///
+/// Acknowledging is part of logging doses, so the `LogDoses` permission is enough, and it is one of the
+/// few things a session unlocked with the quick-unlock PIN may do.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the reminder.
+/// * `ctx` - A `State` object containing the `ApiContext` struct.
+/// * `id` - A `Path` object containing the ID of the reminder to be acknowledged.
+///
+/// # Returns
+///
+/// Returns a `Json` object containing the acknowledged `Reminder` object, or `Error::NotFound` if no reminder of the
+/// user was found.
+pub(crate) async fn acknowledge_reminder(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Tagged<Reminder>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, REMINDER, &id, Permission::LogDoses).await?;
+    let reminder = ctx.repos.reminders.acknowledge(&auth_user.user_id, &id, &owner).await?;
+    Ok(Tagged(reminder.ok_or(Error::NotFound)?))
 }
 
 /// Deletes a reminder with the given ID from the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the reminder
 /// * `ctx` - A `State` object containing the `ApiContext` struct
 /// * `id` - A `Path` object containing the ID of the reminder to be deleted
 ///
 /// # Returns
 ///
-/// A `Json` object containing the deleted reminder, or `None` if the reminder was not found in the database.
-pub(crate) async fn delete_reminder(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Reminder>>, Error> {
-    let reminder = ctx.db.delete((REMINDER, &*id)).await?;
-    Ok(Json(reminder))
+/// A `Json` object containing the deleted reminder, or `Error::NotFound` if no reminder of the user was found in the database.
+pub(crate) async fn delete_reminder(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Json<Reminder>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, REMINDER, &id, Permission::Manage).await?;
+    let reminder = ctx.repos.reminders.delete(&auth_user.user_id, &id, &owner).await?;
+    Ok(Json(reminder.ok_or(Error::NotFound)?))
 }
 
-/// Lists all reminders from the database
+/// Lists all reminders of the current profile, or of all profiles of an owner, from the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user listing their own reminders or, with a grant, those of another user
 /// * `ctx` - A `State` object containing the `ApiContext` struct
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 ///
 /// # Returns
 ///
@@ -190,22 +185,22 @@ pub(crate) async fn delete_reminder(ctx: State<ApiContext>, id: Path<String>) ->
 ///
 /// * Returns an `Error` if there is an issue with the database query or connection.
 pub(crate) async fn list_reminders(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
-    query: Query<QueryUser>,
+    scope_query: Query<ScopeQuery>,
 ) -> Result<Json<Vec<Reminder>>, Error> {
-    let mut sql = ctx.db.query(
-        "SELECT * from reminder where user = type::thing('user', $user);")
-        .bind(("user", &query.user))
-        .await?;
-    let reminders: Vec<Reminder> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let reminders = ctx.repos.reminders.list(&scope.owner, scope.profile.as_deref(), None).await?;
     Ok(Json(reminders))
 }
 
-/// Lists all active reminders from the database
+/// Lists all active reminders of the current profile, or of all profiles of an owner, from the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user listing their own reminders or, with a grant, those of another user
 /// * `ctx` - A `State` object containing the `ApiContext` struct
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 ///
 /// # Returns
 ///
@@ -215,13 +210,11 @@ pub(crate) async fn list_reminders(
 ///
 /// Returns an `Error` if there is an issue with the database query or if the query returns no results.
 pub(crate) async fn list_active_reminders(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
-    query: Query<QueryUser>,
+    scope_query: Query<ScopeQuery>,
     ) -> Result<Json<Vec<Reminder>>, Error> {
-    let mut sql = ctx.db.query(
-        "SELECT * from reminder where active == true and user = type::thing('user', $user);")
-        .bind(("user", &query.user))
-        .await?;
-    let reminders: Vec<Reminder> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let reminders = ctx.repos.reminders.list(&scope.owner, scope.profile.as_deref(), Some(true)).await?;
     Ok(Json(reminders))
 }
@@ -1,84 +1,35 @@
 
 use axum::extract::Path;
-use axum::extract::State;
+use axum::extract::{ State, Query };
 use axum::Json;
 use serde::Deserialize;
 use serde::Serialize;
-use surrealdb::sql::{ Thing, Datetime };
 
 use crate::api::error::Error;
+use crate::api::etag::{rejected, IfMatch, Tagged};
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::grant::{authorize_record, authorize_scope, Permission, ScopeQuery};
+use crate::api::handlers::{ensure_owned, CascadeQuery};
+use crate::api::handlers::medication::MEDICATION;
+use crate::api::repository::store::{CreateStore, RefillStore, Store, StoreList};
 use crate::api::ApiContext;
 
-const STORE: &str = "store";
-
-/// A struct representing a store of medication
-///
-/// # Fields
-///
-/// * `id` - The unique identifier of the store
-/// * `medication` - The medication stored in the store
-/// * `production_date` - The date the medication was produced
-/// * `expiration_date` - The date the medication will expire
-/// * `lot_number` - The lot number of the medication
-/// * `quantity` - The quantity of medication stored
-/// * `unit` - The unit of measurement for the quantity
-/// * `created` - The date the store was created
-/// * `updated` - The date the store was last updated
-#[derive(Serialize, Deserialize)]
-pub struct Store {
-    id: Thing,
-    user: Thing,
-    medication: Thing,
-    production_date: Datetime,
-    expiration_date: Option<Datetime>,
-    lot_number: String,
-    quantity: f32,
-    unit: String,
-    created: Datetime,
-    updated: Datetime,
-    active: bool,
-}
-
-/// A struct representing the creation of a store with the following fields:
-///
-/// * `medication` - a `String` representing the name of the medication
-/// * `production_date` - a `Datetime` representing the date of production
-/// * `expiration_date` - a `Datetime` representing the date of expiration
-/// * `lot_number` - a `String` representing the lot number of the medication
-/// * `quantity` - a `f32` representing the quantity of the medication
-/// * `unit` - a `String` representing the unit of measurement for the medication quantity.
-#[derive(Serialize, Deserialize)]
-pub struct CreateStore {
-    user: String,
-    medication: String,
-    production_date: Datetime,
-    expiration_date: Option<Datetime>,
-    lot_number: String,
-    quantity: f32,
-    unit: String,
-}
+pub(crate) const STORE: &str = "store";
 
 /// Creates a new store in the database with the given medication, production date,
 /// expiration date, lot number, quantity, and unit. Returns the created store as a JSON object
 /// wrapped in a Result. If the store creation is successful, the JSON object will contain the
-/// created store. If not, it will be None.
+/// created store. If not, it will be None. Returns `Error::NotFound` if the medication does not
+/// belong to the user.
 pub(crate) async fn create_store(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     Json(store): Json<CreateStore>,
 ) -> Result<Json<Option<Store>>, Error> {
-    //TODO: Evaluate if the <decimal> function here on quantity is necessary
-    let mut sql = ctx.db.query(
-        "CREATE store SET  user = type::thing('user', $user), medication = type::thing('medication', $medication), production_date = $production_date,
-        expiration_date = $expiration_date, lot_number = $lot_number , quantity = <decimal> $quantity, unit = $unit;")
-        .bind(("user", store.user))
-        .bind(("medication", store.medication))
-        .bind(("production_date", store.production_date))
-        .bind(("expiration_date", store.expiration_date))
-        .bind(("lot_number", store.lot_number))
-        .bind(("quantity", store.quantity))
-        .bind(("unit", store.unit))
-        .await?;
-    let store: Option<Store> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::Manage).await?;
+    ensure_owned(&ctx, MEDICATION, &store.medication, &scope.owner).await?;
+    let store = ctx.repos.stores.create(&auth_user.user_id, &scope.owner, store).await?;
     Ok(Json(store))
 }
 
@@ -86,140 +37,179 @@ pub(crate) async fn create_store(
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the store.
 /// * `ctx` - A `State` object containing the `ApiContext`.
 /// * `id` - A `Path` object containing the ID of the store to be read.
 ///
 /// # Returns
 ///
-/// Returns a `Json` object containing the store data if the store is found in the database, otherwise returns an `Error`.
-pub(crate) async fn read_store(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Store>>, Error> {
-    let store = ctx.db.select((STORE, &*id)).await?;
-    Ok(Json(store))
+/// Returns a `Json` object containing the store data, with its version as the `ETag`, if a store of the user is found in the database, otherwise returns `Error::NotFound`.
+pub(crate) async fn read_store(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Tagged<Store>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, STORE, &id, Permission::View).await?;
+    let store = ctx.repos.stores.read(&id, &owner).await?;
+    Ok(Tagged(store.ok_or(Error::NotFound)?))
 }
 
 /// Updates the store with the given id with the provided store information
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the store and its medication
 /// * `ctx` - The API context
 /// * `id` - The id of the store to update
+/// * `if_match` - The `ETag` of the store as the client last read it
 /// * `Json(store)` - The store information to update
 ///
 /// # Returns
 ///
-/// Returns a JSON object containing the updated store information if successful, otherwise returns an error.
+/// Returns a JSON object containing the updated store information and its new `ETag` if successful, otherwise
+/// returns an error. Returns `Error::NotFound` if the store or the medication does not belong to the user, and
+/// `Error::PreconditionFailed` if the store was changed since the client read it.
 pub(crate) async fn update_store(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
     id: Path<String>,
+    if_match: IfMatch,
     Json(store): Json<CreateStore>,
-) -> Result<Json<Option<Store>>, Error> {
-    let mut sql = ctx.db.query(
-        "UPDATE type::thing('store', $id) SET  user = type::thing('user', $user), medication = type::thing('medication', $medication), production_date = $production_date,
-        expiration_date = $expiration_date, lot_number = $lot_number , quantity = $quantity, unit = $unit;")
-        .bind(("id", &*id))
-        .bind(("user", store.user))
-        .bind(("medication", store.medication))
-        .bind(("production_date", store.production_date))
-        .bind(("expiration_date", store.expiration_date))
-        .bind(("lot_number", store.lot_number))
-        .bind(("quantity", store.quantity))
-        .bind(("unit", store.unit))
-        .await?;
-    let store: Option<Store> = sql.take(0)?;
-    Ok(Json(store))
+) -> Result<Tagged<Store>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, STORE, &id, Permission::Manage).await?;
+    ensure_owned(&ctx, MEDICATION, &store.medication, &owner).await?;
+    let store = ctx.repos.stores.update(&auth_user.user_id, &id, &owner, &if_match.0, store).await?;
+    match store {
+        Some(store) => Ok(Tagged(store)),
+        None => Err(rejected(ctx.repos.stores.read(&id, &owner).await?)),
+    }
 }
 
+/// Deactivates the store with the given id, if it wasn't changed since the client read it with the `ETag` in
+/// `If-Match`. Returns `Error::PreconditionFailed` otherwise.
 pub(crate) async fn deactivate_store(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
     id: Path<String>,
+    if_match: IfMatch,
     // Json(store): Json<Store>,
-) -> Result<Json<Option<Store>>, Error> {
-    let mut sql = ctx.db.query(
-        "UPDATE type::thing('store', $id) SET  active = false;")
-        .bind(("id", &*id))
-        .await?;
-    let store: Option<Store> = sql.take(0)?;
-    Ok(Json(store))
+) -> Result<Tagged<Store>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, STORE, &id, Permission::Manage).await?;
+    let store = ctx.repos.stores.deactivate(&auth_user.user_id, &id, &owner, &if_match.0).await?;
+    match store {
+        Some(store) => Ok(Tagged(store)),
+        None => Err(rejected(ctx.repos.stores.read(&id, &owner).await?)),
+    }
+}
+
+/// Refills the store with the given id: it is deactivated and replaced by a new store of the same medication in
+/// one step, if it wasn't changed since the client read it with the `ETag` in `If-Match`
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the store
+/// * `ctx` - A `State` object containing the `ApiContext` struct
+/// * `id` - A `Path` object containing the `id` of the store to be refilled
+/// * `if_match` - The `ETag` of the store as the client last read it
+/// * `Json(refill)` - The production date, expiration date, lot number, quantity and unit of the new batch
+///
+/// # Returns
+///
+/// A `Json` object containing the new store and its `ETag`, `Error::NotFound` if no store of the user was found,
+/// or `Error::PreconditionFailed` if the store was changed since the client read it.
+pub(crate) async fn refill_store(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+    if_match: IfMatch,
+    Json(refill): Json<RefillStore>,
+) -> Result<Tagged<Store>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, STORE, &id, Permission::Manage).await?;
+    let store = ctx.repos.stores.refill(&auth_user.user_id, &id, &owner, &if_match.0, refill).await?;
+    match store {
+        Some(store) => Ok(Tagged(store)),
+        None => Err(rejected(ctx.repos.stores.read(&id, &owner).await?)),
+    }
 }
 
 /// Deletes a store from the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own, or hold a grant for, the store
 /// * `ctx` - A `State` object containing the `ApiContext` struct
 /// * `id` - A `Path` object containing the `id` of the store to be deleted
+/// * `cascade_query` - A `Query` object with `cascade`, to delete the doses of the store along with it
 ///
 /// # Returns
 ///
-/// A `Json` object containing the deleted store, wrapped in an `Option` object. If the store was not found, returns `None`.
+/// A `Json` object containing the deleted store. The notes about it and its doses are deleted along with it.
 ///
 /// # Errors
 ///
-/// Returns an `Error` if there was an issue deleting the store from the database.
-pub(crate) async fn delete_store(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<Store>>, Error> {
-    let store = ctx.db.delete((STORE, &*id)).await?;
-    Ok(Json(store))
+/// Returns `Error::NotFound` if no store of the user was found, `Error::Conflict` listing its doses if it has
+/// any and `cascade` isn't set, or an `Error` if there was an issue deleting the store from the database.
+pub(crate) async fn delete_store(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+    cascade_query: Query<CascadeQuery>,
+) -> Result<Json<Store>, Error> {
+    let owner = authorize_record(&ctx, &auth_user, STORE, &id, Permission::Manage).await?;
+    let store = ctx.repos.stores.delete(&auth_user.user_id, &id, &owner, cascade_query.cascade).await?;
+    Ok(Json(store.ok_or(Error::NotFound)?))
 }
 
-/// Lists all stores in the database
+/// Lists all stores of the current profile, or of all profiles of an owner
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user listing their own stores or, with a grant, those of another user
 /// * `ctx` - A `State` object containing the `ApiContext` struct
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
 ///
 /// # Returns
 ///
 /// A `Json` object containing a vector of `Store` structs, or an `Error` if the database query fails.
-pub(crate) async fn list_stores(ctx: State<ApiContext>,) -> Result<Json<Vec<Store>>, Error> {
-    let stores = ctx.db.select(STORE).await?;
+pub(crate) async fn list_stores(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
+) -> Result<Json<Vec<Store>>, Error> {
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let stores = ctx.repos.stores.list(&scope.owner, scope.profile.as_deref()).await?;
     Ok(Json(stores))
 }
 
-//TODO: Need to review and assess use of user for all queries to keep records isolated in case of multuiple users
 #[derive(Serialize, Deserialize)]
 pub struct StoreBool {
     active: Option<bool>,
     medication: String,
-    user: String,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct StoreList {
-    medication_id: Thing,
-    medication_name: String,
-    store_active: bool,
-    store_created: Datetime,
-    store_expiration_date: Option<Datetime>,
-    store_id: Thing,
-    store_lot_number: String,
-    store_production_date: Datetime,
-    store_start_quantity: f32,
-    store_unit: String,
-    store_updated: Datetime,
-    user: Thing,
-}
-
-/// Lists all stores for a given medication and user based on the active status of the store
+/// Lists all stores for a given medication and user based on the active status of the store, or all of them
+/// if no status is given
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user listing their own stores or, with a grant, those of another user
 /// * `ctx` - A `State` object containing the `ApiContext`
-/// * `Json(store_bool)` - A JSON object containing the medication ID, active status, and user ID
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
+/// * `Json(store_bool)` - A JSON object containing the medication ID and active status
 ///
 /// # Returns
 ///
 /// A JSON object containing a list of stores that match the given medication ID and active status.
 pub(crate) async fn list_stores_for_medication(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     Json(store_bool): Json<StoreBool>,
 ) -> Result<Json<Vec<StoreList>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_stores_for_medication($id, $bool, $user);")
-        .bind(("id", store_bool.medication))
-        .bind(("bool", store_bool.active))
-        .bind(("user", store_bool.user))
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let stores = ctx.repos.stores
+        .list_for_medication(&scope.owner, &store_bool.medication, store_bool.active)
         .await?;
-    let stores: Vec<StoreList> = sql.take(0)?;
     Ok(Json(stores))
 }
 
@@ -227,8 +217,10 @@ pub(crate) async fn list_stores_for_medication(
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user listing their own stores or, with a grant, those of another user
 /// * `ctx` - The API context
-/// * `store_bool` - A JSON object containing the medication ID and user ID
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
+/// * `store_bool` - A JSON object containing the medication ID
 ///
 /// # Returns
 ///
@@ -238,14 +230,12 @@ pub(crate) async fn list_stores_for_medication(
 ///
 /// Returns an error if the query fails.
 pub(crate) async fn list_all_stores_for_medication(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
     Json(store_bool): Json<StoreBool>,
 ) -> Result<Json<Vec<StoreList>>, Error> {
-    let mut sql = ctx.db.query(
-        "RETURN fn::list_all_stores_for_medication($id, $user);")
-        .bind(("id", store_bool.medication))
-        .bind(("user", store_bool.user))
-        .await?;
-    let stores: Vec<StoreList> = sql.take(0)?;
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+    let stores = ctx.repos.stores.list_for_medication(&scope.owner, &store_bool.medication, None).await?;
     Ok(Json(stores))
 }
@@ -0,0 +1,260 @@
+use axum::extract::{ State, Path, Query };
+use axum::Json;
+
+use crate::api::error::Error;
+use crate::api::extractor::AuthUser;
+use crate::api::handlers::grant::{authorize_record, authorize_scope, Permission, ScopeQuery};
+use crate::api::handlers::dose::DOSE;
+use crate::api::handlers::uom::UNIT_OF_MEASURE;
+use crate::api::handlers::CascadeQuery;
+use crate::api::repository::trash::TrashItem;
+use crate::api::{ApiContext, Result};
+use crate::db::trash::TRASH_TABLES;
+
+/// Checks that `table` is one whose records go to the trash.
+fn trash_table(table: &str) -> Result<&str> {
+    TRASH_TABLES
+        .iter()
+        .find(|known| **known == table)
+        .copied()
+        .ok_or_else(|| Error::unprocessable_entity([("table", "has no trash")]))
+}
+
+/// Returns the ID of the user who owns a record in the trash, after checking that the current user may restore
+/// or purge it: with the permission its delete handler needs, see `grant::authorize_record()`. Units of measure
+/// aren't shared through grants, so only their owner can.
+async fn authorize_trash(ctx: &ApiContext, auth_user: &AuthUser, table: &str, id: &str) -> Result<String> {
+    let permission = match table {
+        DOSE => Permission::LogDoses,
+        _ => Permission::Manage,
+    };
+    let owner = authorize_record(ctx, auth_user, table, id, permission).await?;
+    if table == UNIT_OF_MEASURE && owner != auth_user.user_id {
+        return Err(Error::NotFound);
+    }
+    Ok(owner)
+}
+
+/// Lists the deleted records of the current user, or with a grant those of another user
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user, who needs at least the `View` permission for the records
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `scope_query` - A `Query` object with the optional owner or profile of the records
+///
+/// # Returns
+///
+/// A `Json` object containing a vector of `TrashItem` structs, most recently deleted first. Units of
+/// measure don't belong to a profile, so they are listed for every profile, but only to their owner, as
+/// they aren't shared through grants, see `authorize_trash()`.
+pub(crate) async fn list_trash(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    scope_query: Query<ScopeQuery>,
+) -> Result<Json<Vec<TrashItem>>> {
+    let scope = authorize_scope(&ctx, &auth_user, &scope_query, Permission::View).await?;
+
+    let mut items = ctx.repos.trash.list(&scope.owner, scope.profile.as_deref()).await?;
+    if scope.owner != auth_user.user_id {
+        items.retain(|item| item.table != UNIT_OF_MEASURE);
+    }
+    Ok(Json(items))
+}
+
+/// Restores a deleted record from the trash
+///
+/// A record is only restored after the records it references, e.g. a dose after its store. The notes about it
+/// that were deleted along with it are restored with it, and with `cascade` so are the other records deleted
+/// along with it, see `crate::api::repository::reference`.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user restoring their own record or, with a grant, one of another user, see
+///   `authorize_trash()`
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `path` - A `Path` object with the table and ID of the record
+/// * `cascade_query` - A `Query` object with `cascade`, to restore the records that were deleted along with it
+///
+/// # Returns
+///
+/// A `Json` object containing the restored record, `Error::NotFound` if it isn't in the trash,
+/// `Error::Conflict` listing the records it references that are in the trash, or
+/// `Error::UnprocessableEntity` if the table has no trash or a record with the same name was created
+/// since it was deleted, see `crate::api::transaction`.
+pub(crate) async fn restore_trash(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Path((table, id)): Path<(String, String)>,
+    cascade_query: Query<CascadeQuery>,
+) -> Result<Json<serde_json::Value>> {
+    let table = trash_table(&table)?;
+    let owner = authorize_trash(&ctx, &auth_user, table, &id).await?;
+
+    let record = ctx.repos.trash.restore(&auth_user.user_id, table, &id, &owner, cascade_query.cascade).await?;
+    Ok(Json(record.ok_or(Error::NotFound)?))
+}
+
+/// Deletes a record in the trash for good
+///
+/// The notes about it go along, and with `cascade` so do the records depending on it, see
+/// `crate::api::repository::reference`.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user purging their own record or, with a grant, one of another user, see
+///   `authorize_trash()`
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `path` - A `Path` object with the table and ID of the record
+/// * `cascade_query` - A `Query` object with `cascade`, to purge the records depending on it along with it
+///
+/// # Returns
+///
+/// A `Json` object containing the purged record, `Error::NotFound` if it isn't in the trash,
+/// `Error::Conflict` listing the records depending on it if there are any and `cascade` isn't set, or
+/// `Error::UnprocessableEntity` if the table has no trash.
+pub(crate) async fn purge_trash(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Path((table, id)): Path<(String, String)>,
+    cascade_query: Query<CascadeQuery>,
+) -> Result<Json<serde_json::Value>> {
+    let table = trash_table(&table)?;
+    let owner = authorize_trash(&ctx, &auth_user, table, &id).await?;
+
+    let record = ctx.repos.trash.purge(&auth_user.user_id, table, &id, &owner, cascade_query.cascade).await?;
+    Ok(Json(record.ok_or(Error::NotFound)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+
+    use crate::api::testing::{id, TestApp};
+
+    /// Creates a record at `uri` and deletes it again, and returns it.
+    async fn create_deleted(app: &TestApp, token: &str, uri: &str, record: Value) -> Value {
+        let (status, record) = app.request(Method::POST, uri, Some(token), Some(record)).await;
+        assert_eq!(status, StatusCode::OK, "{record}");
+        let (status, body) = app.request(Method::DELETE, &format!("{uri}/{}", id(&record)), Some(token), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        record
+    }
+
+    /// The tables of the records in a listing of the trash.
+    fn tables(items: &Value) -> Vec<&str> {
+        items.as_array().unwrap().iter().map(|item| item["table"].as_str().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn a_grantee_is_not_shown_the_units_in_the_trash() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let bob = app.sign_up("bob").await;
+        create_deleted(&app, &ann, "/uoms", json!({ "name": "milligram", "abbreviation": "mg" })).await;
+        create_deleted(&app, &ann, "/medications", json!({ "name": "Aspirin" })).await;
+        let grant = json!({ "grant": { "username": "bob", "permission": "manage" } });
+        let (_, grant) = app.request(Method::POST, "/user/grants", Some(&ann), Some(grant)).await;
+        let owner = grant["grant"]["owner"]["id"]["String"].as_str().unwrap();
+
+        let (status, items) = app.request(Method::GET, "/trash", Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK, "{items}");
+        assert_eq!(tables(&items), ["medication", "unit_of_measure"]);
+        let (status, items) = app.request(Method::GET, &format!("/trash?owner={owner}"), Some(&bob), None).await;
+        assert_eq!(status, StatusCode::OK, "{items}");
+        assert_eq!(tables(&items), ["medication"]);
+    }
+
+    /// Creates a medication with a store, and deletes both with the medication. Returns their IDs.
+    async fn delete_medication_with_store(app: &TestApp, token: &str) -> (String, String) {
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(token), Some(json!({ "name": "Aspirin" }))).await;
+        let store = json!({
+            "medication": id(&medication),
+            "production_date": "2026-01-01T00:00:00Z",
+            "lot_number": "A1",
+            "quantity": 10.0,
+            "unit": "mg",
+        });
+        let (status, store) = app.request(Method::POST, "/stores", Some(token), Some(store)).await;
+        assert_eq!(status, StatusCode::OK, "{store}");
+        let uri = format!("/medications/{}?cascade=true", id(&medication));
+        let (status, body) = app.request(Method::DELETE, &uri, Some(token), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        (id(&medication), id(&store))
+    }
+
+    #[tokio::test]
+    async fn a_record_is_restored_after_the_records_it_references() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let (medication, store) = delete_medication_with_store(&app, &ann).await;
+        let (status, _) = app.request(Method::GET, &format!("/medications/{medication}"), Some(&ann), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let restore_store = format!("/trash/store/{store}/restore");
+        let (status, body) = app.request(Method::POST, &restore_store, Some(&ann), None).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{body}");
+        let uri = format!("/trash/medication/{medication}/restore");
+        let (status, body) = app.request(Method::POST, &uri, Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["name"], "Aspirin");
+        let (_, items) = app.request(Method::GET, "/trash", Some(&ann), None).await;
+        assert_eq!(tables(&items), ["store"]);
+
+        let (status, body) = app.request(Method::POST, &restore_store, Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, _) = app.request(Method::GET, &format!("/stores/{store}"), Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, items) = app.request(Method::GET, "/trash", Some(&ann), None).await;
+        assert_eq!(tables(&items), Vec::<&str>::new());
+    }
+
+    #[tokio::test]
+    async fn a_cascading_restore_brings_back_the_records_deleted_along() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let (medication, store) = delete_medication_with_store(&app, &ann).await;
+
+        let uri = format!("/trash/medication/{medication}/restore?cascade=true");
+        let (status, body) = app.request(Method::POST, &uri, Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, _) = app.request(Method::GET, &format!("/stores/{store}"), Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn a_purge_needs_cascade_for_the_records_depending_on_it() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let (medication, store) = delete_medication_with_store(&app, &ann).await;
+
+        let uri = format!("/trash/medication/{medication}");
+        let (status, body) = app.request(Method::DELETE, &uri, Some(&ann), None).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{body}");
+        let (status, body) = app.request(Method::DELETE, &format!("{uri}?cascade=true"), Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (_, items) = app.request(Method::GET, "/trash", Some(&ann), None).await;
+        assert_eq!(tables(&items), Vec::<&str>::new());
+        let (status, _) = app.request(Method::POST, &format!("/trash/store/{store}/restore"), Some(&ann), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_restores_a_unit() {
+        let app = TestApp::new().await;
+        let ann = app.sign_up("ann").await;
+        let bob = app.sign_up("bob").await;
+        let unit = create_deleted(&app, &ann, "/uoms", json!({ "name": "milligram", "abbreviation": "mg" })).await;
+        let grant = json!({ "grant": { "username": "bob", "permission": "manage" } });
+        app.request(Method::POST, "/user/grants", Some(&ann), Some(grant)).await;
+        let uri = format!("/trash/unit_of_measure/{}/restore", id(&unit));
+
+        let (status, _) = app.request(Method::POST, &uri, Some(&bob), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, body) = app.request(Method::POST, &uri, Some(&ann), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+}
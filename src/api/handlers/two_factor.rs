@@ -0,0 +1,435 @@
+use anyhow::Context;
+use axum::extract::State;
+use axum::Json;
+use axum_extra::extract::CookieJar;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::Thing;
+use time::OffsetDateTime;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::api::error::Error;
+use crate::api::extractor::{self, AuthUser};
+use crate::api::handlers::recovery_code::{replace_recovery_codes, use_recovery_code, TOTP_RECOVERY_CODE};
+use crate::api::handlers::user::{self, User, UserBody};
+use crate::api::{ApiContext, Result};
+
+const TOTP_ISSUER: &str = "medoxido";
+const TOTP_DIGITS: usize = 6;
+const TOTP_STEP: u64 = 30;
+
+/// How long a login may wait between the password and the second factor.
+const CHALLENGE_LENGTH: time::Duration = time::Duration::minutes(5);
+
+/// How many wrong codes a login challenge takes before it is thrown away and the password is needed again.
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+
+/// A wrapper matching the `{ "two_factor": { ... } }` shape used by every 2FA request and response.
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorBody<T> {
+    two_factor: T,
+}
+
+/// The secret of a new TOTP enrollment, to be added to an authenticator app.
+///
+/// # Fields
+///
+/// * `otpauth_uri` - The `otpauth://` URI, usually shown as a QR code
+/// * `secret` - The base32 encoded secret, for entering by hand
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorEnrollment {
+    otpauth_uri: String,
+    secret: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorCode {
+    code: String,
+}
+
+/// The recovery codes of the user. They are only ever shown here, the database keeps their hashes.
+#[derive(Serialize, Deserialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Returned by the login instead of the user when the second factor is still missing.
+#[derive(Serialize, Deserialize)]
+pub struct TwoFactorChallenge {
+    challenge: String,
+}
+
+/// The second login step. One of `code` and `recovery_code` is required.
+#[derive(Deserialize)]
+pub struct TwoFactorLogin {
+    challenge: String,
+    code: Option<String>,
+    recovery_code: Option<String>,
+}
+
+/// The 2FA state of a `user` record.
+#[derive(Deserialize)]
+struct TwoFactorUser {
+    username: String,
+    email: String,
+    totp_secret: Option<String>,
+    totp_pending_secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LoginChallenge {
+    id: Thing,
+    user: Thing,
+    attempts: i64,
+}
+
+/// Starts TOTP enrollment for the current user
+///
+/// 2FA is not enabled until a code from the new secret is confirmed with `verify_two_factor()`.
+/// Starting again replaces a secret that was not confirmed yet.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+///
+/// # Returns
+///
+/// A `Json` object containing the `otpauth://` URI and the secret, or `Error::UnprocessableEntity`
+/// if 2FA is already enabled.
+pub(crate) async fn enroll_two_factor(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+) -> Result<Json<TwoFactorBody<TwoFactorEnrollment>>> {
+    let user = two_factor_user(&ctx, &auth_user.user_id).await?;
+    if user.totp_secret.is_some() {
+        return Err(Error::unprocessable_entity([("two_factor", "is already enabled")]));
+    }
+
+    let secret = Secret::Raw(rand::random::<[u8; 20]>().to_vec()).to_encoded().to_string();
+    let otpauth_uri = totp(&secret, user.username)?.get_url();
+
+    ctx.db.query("UPDATE type::thing('user', $user) SET totp_pending_secret = $secret;")
+        .bind(("secret", &secret))
+        .bind(("user", &auth_user.user_id))
+        .await?
+        .check()?;
+
+    Ok(Json(TwoFactorBody {
+        two_factor: TwoFactorEnrollment { otpauth_uri, secret },
+    }))
+}
+
+/// Confirms TOTP enrollment with a code from the authenticator app and enables 2FA
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing the current code
+///
+/// # Returns
+///
+/// A `Json` object containing the recovery codes, or `Error::UnprocessableEntity` if there is no
+/// enrollment to confirm or the code is wrong.
+pub(crate) async fn verify_two_factor(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<TwoFactorBody<TwoFactorCode>>,
+) -> Result<Json<TwoFactorBody<RecoveryCodes>>> {
+    let user = two_factor_user(&ctx, &auth_user.user_id).await?;
+    let secret = user.totp_pending_secret
+        .ok_or_else(|| Error::unprocessable_entity([("two_factor", "enrollment was not started")]))?;
+
+    if !check_totp(&ctx, &auth_user.user_id, &secret, user.username, &req.two_factor.code).await? {
+        return Err(Error::unprocessable_entity([("code", "is invalid")]));
+    }
+
+    ctx.db.query(
+        "UPDATE type::thing('user', $user) SET totp_secret = totp_pending_secret, totp_pending_secret = NONE;")
+        .bind(("user", &auth_user.user_id))
+        .await?
+        .check()?;
+
+    let recovery_codes = replace_recovery_codes(&ctx, TOTP_RECOVERY_CODE, &auth_user.user_id).await?;
+
+    Ok(Json(TwoFactorBody {
+        two_factor: RecoveryCodes { recovery_codes },
+    }))
+}
+
+/// Disables 2FA for the current user and deletes the recovery codes
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing a current code or a recovery code
+///
+/// # Returns
+///
+/// `Error::UnprocessableEntity` if 2FA is not enabled or the code is wrong.
+pub(crate) async fn disable_two_factor(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<TwoFactorBody<TwoFactorCode>>,
+) -> Result<()> {
+    let user = two_factor_user(&ctx, &auth_user.user_id).await?;
+    let secret = user.totp_secret
+        .ok_or_else(|| Error::unprocessable_entity([("two_factor", "is not enabled")]))?;
+
+    let code = req.two_factor.code;
+    if !check_totp(&ctx, &auth_user.user_id, &secret, user.username, &code).await?
+        && !use_recovery_code(&ctx, TOTP_RECOVERY_CODE, &auth_user.user_id, code).await?
+    {
+        return Err(Error::unprocessable_entity([("code", "is invalid")]));
+    }
+
+    ctx.db.query(
+        "UPDATE type::thing('user', $user) SET totp_secret = NONE, totp_pending_secret = NONE, totp_last_step = NONE;
+        DELETE totp_recovery_code WHERE user = type::thing('user', $user);")
+        .bind(("user", &auth_user.user_id))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+/// Replaces the recovery codes of the current user, e.g. when most of them are used up
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing a current code
+///
+/// # Returns
+///
+/// A `Json` object containing the new recovery codes, or `Error::UnprocessableEntity` if 2FA is
+/// not enabled or the code is wrong.
+pub(crate) async fn regenerate_recovery_codes(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<TwoFactorBody<TwoFactorCode>>,
+) -> Result<Json<TwoFactorBody<RecoveryCodes>>> {
+    let user = two_factor_user(&ctx, &auth_user.user_id).await?;
+    let secret = user.totp_secret
+        .ok_or_else(|| Error::unprocessable_entity([("two_factor", "is not enabled")]))?;
+
+    if !check_totp(&ctx, &auth_user.user_id, &secret, user.username, &req.two_factor.code).await? {
+        return Err(Error::unprocessable_entity([("code", "is invalid")]));
+    }
+
+    let recovery_codes = replace_recovery_codes(&ctx, TOTP_RECOVERY_CODE, &auth_user.user_id).await?;
+
+    Ok(Json(TwoFactorBody {
+        two_factor: RecoveryCodes { recovery_codes },
+    }))
+}
+
+/// Completes a login with the second factor and returns the user with a login token
+///
+/// # Arguments
+///
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `jar` - The cookies of the request
+/// * `Json(req)` - A `Json` object containing the challenge from the login and a code or recovery code
+///
+/// # Returns
+///
+/// A `Json` object containing the user and a token along with the session cookies, or
+/// `Error::Unauthorized` if the challenge or the code is not valid.
+pub(crate) async fn login_two_factor(
+    ctx: State<ApiContext>,
+    jar: CookieJar,
+    Json(req): Json<TwoFactorBody<TwoFactorLogin>>,
+) -> Result<(CookieJar, Json<UserBody<User>>)> {
+    let req = req.two_factor;
+
+    let mut sql = ctx.db.query(
+        "SELECT id, user, attempts FROM login_challenge WHERE token_hash = $token_hash AND expires > time::now();")
+        .bind(("token_hash", extractor::hash_token(&req.challenge)))
+        .await?;
+    let challenge: Option<LoginChallenge> = sql.take(0)?;
+    let challenge = challenge.ok_or_else(|| {
+        log::debug!("login challenge unknown or expired");
+        Error::Unauthorized
+    })?;
+
+    let user_id = challenge.user.id.to_raw();
+    let user = two_factor_user(&ctx, &user_id).await?;
+    let secret = user.totp_secret.context("login challenge for a user without 2FA")?;
+
+    let passed = match (req.code, req.recovery_code) {
+        (Some(code), _) => check_totp(&ctx, &user_id, &secret, user.username.clone(), &code).await?,
+        (None, Some(recovery_code)) => use_recovery_code(&ctx, TOTP_RECOVERY_CODE, &user_id, recovery_code).await?,
+        (None, None) => return Err(Error::unprocessable_entity([("code", "is required")])),
+    };
+
+    if !passed {
+        // Wrong guesses use up the challenge, after which the password has to be entered again.
+        let query = if challenge.attempts + 1 >= MAX_CHALLENGE_ATTEMPTS {
+            "DELETE $challenge;"
+        } else {
+            "UPDATE $challenge SET attempts += 1;"
+        };
+        ctx.db.query(query).bind(("challenge", &challenge.id)).await?.check()?;
+        return Err(Error::Unauthorized);
+    }
+
+    ctx.db.query("DELETE $challenge;").bind(("challenge", &challenge.id)).await?.check()?;
+
+    user::logged_in_user(&ctx, jar, user_id, user.email, user.username).await
+}
+
+/// Starts the second login step for a user with 2FA enabled, after the password was verified.
+pub(crate) async fn start_challenge(ctx: &ApiContext, user_id: &str) -> Result<TwoFactorBody<TwoFactorChallenge>> {
+    let challenge = extractor::generate_token();
+    let expires = (OffsetDateTime::now_utc() + CHALLENGE_LENGTH).unix_timestamp();
+
+    ctx.db.query(
+        "DELETE login_challenge WHERE expires < time::now();
+        CREATE login_challenge SET user = type::thing('user', $user), token_hash = $token_hash,
+            expires = time::from::unix($expires);")
+        .bind(("user", user_id))
+        .bind(("token_hash", extractor::hash_token(&challenge)))
+        .bind(("expires", expires))
+        .await?
+        .check()?;
+
+    Ok(TwoFactorBody {
+        two_factor: TwoFactorChallenge { challenge },
+    })
+}
+
+async fn two_factor_user(ctx: &ApiContext, user_id: &str) -> Result<TwoFactorUser> {
+    let mut sql = ctx.db.query(
+        "SELECT username, email, totp_secret, totp_pending_secret FROM type::thing('user', $user);")
+        .bind(("user", user_id))
+        .await?;
+    let user: Option<TwoFactorUser> = sql.take(0)?;
+    user.ok_or(Error::NotFound)
+}
+
+fn totp(secret: &str, username: String) -> Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_owned())
+        .to_bytes()
+        .map_err(|e| anyhow::anyhow!("invalid TOTP secret: {:?}", e))?;
+
+    Ok(TOTP::new(Algorithm::SHA1, TOTP_DIGITS, 1, TOTP_STEP, secret, Some(TOTP_ISSUER.to_owned()), username)
+        .map_err(|e| anyhow::anyhow!("invalid TOTP parameters: {:?}", e))?)
+}
+
+/// Checks a TOTP code, allowing one step of clock drift either way.
+///
+/// Each accepted code moves `totp_last_step` of the user forward, so the same code, or an older
+/// one, can't be used a second time.
+async fn check_totp(ctx: &ApiContext, user_id: &str, secret: &str, username: String, code: &str) -> Result<bool> {
+    let totp = totp(secret, username)?;
+    let now = OffsetDateTime::now_utc().unix_timestamp() as u64;
+
+    let step = [now - TOTP_STEP, now, now + TOTP_STEP]
+        .into_iter()
+        .find(|&time| totp.generate(time) == code.trim())
+        .map(|time| (time / TOTP_STEP) as i64);
+    let Some(step) = step else {
+        log::debug!("TOTP code does not match");
+        return Ok(false);
+    };
+
+    let mut sql = ctx.db.query(
+        "UPDATE type::thing('user', $user) SET totp_last_step = $step
+        WHERE totp_last_step = NONE OR totp_last_step < $step RETURN id;")
+        .bind(("step", step))
+        .bind(("user", user_id))
+        .await?;
+    let claimed: Option<Thing> = sql.take((0, "id"))?;
+
+    if claimed.is_none() {
+        log::debug!("TOTP code was already used");
+    }
+    Ok(claimed.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::{json, Value};
+    use time::OffsetDateTime;
+
+    use crate::api::testing::{TestApp, CLIENT, PASSWORD};
+
+    /// Signs up `ann` with 2FA enabled, and returns the TOTP secret and the recovery codes.
+    async fn sign_up_with_two_factor(app: &TestApp) -> (String, Value) {
+        let token = app.sign_up("ann").await;
+        let (status, body) = app.request(Method::POST, "/user/2fa", Some(&token), None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let secret = body["two_factor"]["secret"].as_str().unwrap().to_owned();
+
+        let verify = json!({ "two_factor": { "code": code(&secret, 0) } });
+        let (status, body) = app.request(Method::POST, "/user/2fa/verify", Some(&token), Some(verify)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        (secret, body["two_factor"]["recovery_codes"].clone())
+    }
+
+    /// The TOTP code `steps` steps from now.
+    fn code(secret: &str, steps: i64) -> String {
+        let time = OffsetDateTime::now_utc().unix_timestamp() + steps * super::TOTP_STEP as i64;
+        super::totp(secret, "ann".to_owned()).unwrap().generate(time as u64)
+    }
+
+    /// Logs in `ann` with the password and returns the challenge for the second factor.
+    async fn challenge(app: &TestApp) -> String {
+        let (status, body) = app.log_in_from(CLIENT, "ann", PASSWORD).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(body.get("user").is_none(), "{body}");
+        body["two_factor"]["challenge"].as_str().unwrap().to_owned()
+    }
+
+    async fn complete(app: &TestApp, challenge: &str, second_factor: Value) -> (StatusCode, Value) {
+        let mut login = json!({ "two_factor": { "challenge": challenge } });
+        login["two_factor"].as_object_mut().unwrap().extend(second_factor.as_object().unwrap().clone());
+        app.request(Method::POST, "/users/login/2fa", None, Some(login)).await
+    }
+
+    #[tokio::test]
+    async fn a_login_needs_a_code_that_was_not_used_before() {
+        let app = TestApp::new().await;
+        let (secret, _) = sign_up_with_two_factor(&app).await;
+        let challenge = challenge(&app).await;
+
+        let (status, _) = complete(&app, &challenge, json!({ "code": code(&secret, 0) })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = complete(&app, &challenge, json!({ "code": code(&secret, 1) })).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert!(body["user"]["token"].is_string(), "{body}");
+
+        let (status, _) = complete(&app, &challenge, json!({ "code": code(&secret, 1) })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn a_recovery_code_works_once() {
+        let app = TestApp::new().await;
+        let (_, recovery_codes) = sign_up_with_two_factor(&app).await;
+        let recovery_code = json!({ "recovery_code": recovery_codes[0] });
+
+        let (status, body) = complete(&app, &challenge(&app).await, recovery_code.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, _) = complete(&app, &challenge(&app).await, recovery_code).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn too_many_wrong_codes_use_up_the_challenge() {
+        let app = TestApp::new().await;
+        let (secret, _) = sign_up_with_two_factor(&app).await;
+        let challenge = challenge(&app).await;
+
+        for _ in 0..super::MAX_CHALLENGE_ATTEMPTS {
+            let (status, _) = complete(&app, &challenge, json!({ "code": "000000" })).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+        }
+        let (status, _) = complete(&app, &challenge, json!({ "code": code(&secret, 1) })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
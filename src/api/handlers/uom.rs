@@ -2,39 +2,20 @@
 use axum::extract::Path;
 use axum::extract::State;
 use axum::Json;
-use serde::Deserialize;
-use serde::Serialize;
-use surrealdb::sql::{ Thing, Datetime };
 
 use crate::api::error::Error;
+use crate::api::etag::{rejected, IfMatch, Tagged};
+use crate::api::extractor::AuthUser;
+use crate::api::repository::uom::{CreateUnitOfMeasure, UnitOfMeasure};
 use crate::api::ApiContext;
 
-const UNITOFMEASURE: &str = "unit_of_measure";
-
-/// A unit of measure to be used by the client for medications and doses.
-///
-/// # Fields
-///
-/// * `id` - An optional unique identifier for the unit of measure.
-/// * `name` - The name of the unit of measure.
-/// * `abbreviation` - The abbreviation of the unit of measure.
-/// * `created` - An optional timestamp indicating when the unit of measure was created.
-/// * `updated` - An optional timestamp indicating when the unit of measure was last updated.
-/// * `active` - An optional boolean indicating whether the unit of measure is currently active.
-#[derive(Serialize, Deserialize)]
-pub struct UnitOfMeasure {
-    id: Option<Thing>,
-    name: String,
-    abbreviation: String,
-    created: Option<Datetime>,
-    updated: Option<Datetime>,
-    active: Option<bool>,
-}
+pub(crate) const UNIT_OF_MEASURE: &str = "unit_of_measure";
 
 /// Creates a new unit of measure and returns it as a JSON object
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who will own the unit of measure
 /// * `ctx` - A `State` object containing the `ApiContext`
 /// * `Json(unitofmeasure)` - A JSON object containing the unit of measure to be created
 ///
@@ -46,15 +27,11 @@ pub struct UnitOfMeasure {
 ///
 /// Returns an `Error` if the creation of the unit of measure fails.
 pub(crate) async fn create_uom(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
-    Json(unitofmeasure): Json<UnitOfMeasure>,
+    Json(unitofmeasure): Json<CreateUnitOfMeasure>,
 ) -> Result<Json<Option<UnitOfMeasure>>, Error> {
-    let mut sql = ctx.db.query(
-        "CREATE unit_of_measure set name = $name, abbreviation = $abbreviation;")
-        .bind(("name", unitofmeasure.name))
-        .bind(("abbreviation", unitofmeasure.abbreviation))
-        .await?;
-    let uom: Option<UnitOfMeasure> = sql.take(0)?;
+    let uom = ctx.repos.units.create(&auth_user.user_id, &auth_user.user_id, unitofmeasure).await?;
     Ok(Json(uom))
 }
 
@@ -62,60 +39,84 @@ pub(crate) async fn create_uom(
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own the unit of measure
 /// * `ctx` - A `State` object containing the application context
 /// * `id` - The ID of the unit of measure to read
 ///
 /// # Returns
 ///
-/// A `Json` object containing the unit of measure if it exists, otherwise `None`
+/// A `Json` object containing the unit of measure if it exists, with its version as the `ETag`
 ///
 /// # Errors
 ///
-/// Returns an `Error` if there was an issue reading from the database.
-pub(crate) async fn read_uom(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<UnitOfMeasure>>, Error> {
-    let unitofmeasure = ctx.db.select((UNITOFMEASURE, &*id)).await?;
-    Ok(Json(unitofmeasure))
+/// Returns `Error::NotFound` if no unit of measure of the user exists, or an `Error` if there was an issue
+/// reading from the database.
+pub(crate) async fn read_uom(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Tagged<UnitOfMeasure>, Error> {
+    let unitofmeasure = ctx.repos.units.read(&id, &auth_user.user_id).await?;
+    Ok(Tagged(unitofmeasure.ok_or(Error::NotFound)?))
 }
 
 /// Updates a unit of measure with the given ID in the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own the unit of measure
 /// * `ctx` - A `State` object containing the `ApiContext`
 /// * `id` - A `Path` object containing the ID of the unit of measure to update
+/// * `if_match` - The `ETag` of the unit of measure as the client last read it
 /// * `Json(unitofmeasure)` - A `Json` object containing the updated unit of measure
 ///
 /// # Returns
 ///
-/// A `Json` object containing the updated unit of measure, wrapped in an `Option`, or an `Error` if the update fails.
+/// A `Json` object containing the updated unit of measure with its new `ETag`, `Error::NotFound` if no unit of
+/// measure of the user exists, `Error::PreconditionFailed` if it was changed since the client read it, or an
+/// `Error` if the update fails.
 pub(crate) async fn update_uom(
+    auth_user: AuthUser,
     ctx: State<ApiContext>,
     id: Path<String>,
-    Json(unitofmeasure): Json<UnitOfMeasure>,
-) -> Result<Json<Option<UnitOfMeasure>>, Error> {
-    let unitofmeasure = ctx.db.update((UNITOFMEASURE, &*id)).content(unitofmeasure).await?;
-    Ok(Json(unitofmeasure))
+    if_match: IfMatch,
+    Json(unitofmeasure): Json<CreateUnitOfMeasure>,
+) -> Result<Tagged<UnitOfMeasure>, Error> {
+    let unitofmeasure = ctx.repos.units
+        .update(&auth_user.user_id, &id, &auth_user.user_id, &if_match.0, unitofmeasure)
+        .await?;
+    match unitofmeasure {
+        Some(unitofmeasure) => Ok(Tagged(unitofmeasure)),
+        None => Err(rejected(ctx.repos.units.read(&id, &auth_user.user_id).await?)),
+    }
 }
 
 /// Deletes a unit of measure from the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user who must own the unit of measure
 /// * `ctx` - A `State` object that holds the `ApiContext` struct
 /// * `id` - A `Path` object that holds the `id` of the unit of measure to be deleted
 ///
 /// # Returns
 ///
-/// * `Json<Option<UnitOfMeasure>>` - A JSON object that holds the deleted unit of measure, if it exists.
-pub(crate) async fn delete_uom(ctx: State<ApiContext>, id: Path<String>) -> Result<Json<Option<UnitOfMeasure>>, Error> {
-    let unitofmeasure = ctx.db.delete((UNITOFMEASURE, &*id)).await?;
-    Ok(Json(unitofmeasure))
+/// * `Json<UnitOfMeasure>` - A JSON object that holds the deleted unit of measure, or `Error::NotFound` if no
+///   unit of measure of the user exists.
+pub(crate) async fn delete_uom(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<Json<UnitOfMeasure>, Error> {
+    let unitofmeasure = ctx.repos.units.delete(&auth_user.user_id, &id, &auth_user.user_id).await?;
+    Ok(Json(unitofmeasure.ok_or(Error::NotFound)?))
 }
 
-/// Lists all the unit of measures from the database
+/// Lists all the unit of measures of the current user from the database
 ///
 /// # Arguments
 ///
+/// * `auth_user` - The authenticated user whose units of measure are listed
 /// * `ctx` - A `State` object containing the `ApiContext` instance
 ///
 /// # Returns
@@ -125,7 +126,7 @@ pub(crate) async fn delete_uom(ctx: State<ApiContext>, id: Path<String>) -> Resu
 /// # Errors
 ///
 /// * Returns an `Error` if the database query fails.
-pub(crate) async fn list_uoms(ctx: State<ApiContext>,) -> Result<Json<Vec<UnitOfMeasure>>, Error> {
-    let unitofmeasures = ctx.db.select(UNITOFMEASURE).await?;
+pub(crate) async fn list_uoms(auth_user: AuthUser, ctx: State<ApiContext>,) -> Result<Json<Vec<UnitOfMeasure>>, Error> {
+    let unitofmeasures = ctx.repos.units.list(&auth_user.user_id).await?;
     Ok(Json(unitofmeasures))
 }
@@ -1,151 +1,283 @@
-
-// use axum::extract::Path;
-use axum::extract::State;
+use anyhow::Context;
+use axum::extract::{ ConnectInfo, State, Path };
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Response};
 use axum::Json;
+use axum_extra::extract::CookieJar;
 use argon2::password_hash::SaltString;
-use argon2::{Argon2, PasswordHash};
+use argon2::{Algorithm, Argon2, Params, PasswordHash, Version};
 use serde::Deserialize;
 use serde::Serialize;
-use surrealdb::sql::{ Thing, Datetime, Op };
-// use anyhow::Context;
-// use axum::routing::{get, post};
+use std::net::SocketAddr;
+use surrealdb::sql::{ Thing, Datetime };
 
 use crate::api::error::Error;
 use crate::api::{ApiContext, Result};
-use crate::api::extractor::AuthUser;
-
-// pub(crate) fn router() -> Router<ApiContext> {
-
-//     Router::new()
-//         .route("/api/users", post(create_user))
-//         .route("/api/users/login", post(login_user))
-//         .route("/api/user", get(get_current_user).put(update_user))
-// }
+use crate::config::Config;
+use crate::api::extractor::{self, AuthUser};
+use crate::api::handlers::recovery_code::{replace_recovery_codes, use_recovery_code, verify_dummy_recovery_codes,
+    PASSWORD_RECOVERY_CODE};
+use crate::api::handlers::login_attempt::{self, LoginKeys};
+use crate::api::handlers::{profile, two_factor};
 
 const USER: &str = "user";
 
+/// A wrapper matching the `{ "user": { ... } }` shape used by every user request and response.
 #[derive(Serialize, Deserialize)]
-struct UserBody<T> {
-    user: T,
+pub struct UserBody<T> {
+    pub(crate) user: T,
 }
 
 #[derive(Deserialize)]
-struct NewUser {
+pub struct NewUser {
     username: String,
     email: String,
     password: String,
 }
 
 #[derive(Deserialize)]
-struct LoginUser {
+pub struct LoginUser {
     username: String,
     password: String,
 }
 
+/// The fields of a `user` record needed to verify a login attempt.
 #[derive(Deserialize)]
 struct PassUser {
+    id: Thing,
     username: String,
+    email: String,
     password_hash: String,
-    user_id: String,
-    email: Option<String>
+    two_factor: bool,
+}
+
+/// The public fields of a `user` record as stored in the database.
+#[derive(Deserialize)]
+struct UserRecord {
+    email: String,
+    username: String,
 }
 
 #[derive(Deserialize, Default, PartialEq, Eq)]
 #[serde(default)] // fill in any missing fields with `..UpdateUser::default()`
-struct UpdateUser {
+pub struct UpdateUser {
     email: Option<String>,
     username: Option<String>,
-    // password: Option<String>,
 }
 
 #[derive(Deserialize, Default)]
-#[serde(default)] // fill in any missing fields with `..UpdateUser::default()`
-struct UpdatePassword {
-    email: Option<String>,
-    username: Option<String>,
-    password: Option<String>,
+#[serde(default)] // fill in any missing fields with `..UpdatePassword::default()`
+pub struct UpdatePassword {
+    pub(crate) password: Option<String>,
+}
+
+/// A forgotten password being replaced with one of the user's password recovery codes.
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    username: String,
+    recovery_code: String,
+    password: String,
 }
 
+/// The password recovery codes of the user. They are only ever shown here, the database keeps their hashes.
 #[derive(Serialize, Deserialize)]
-struct UserId {
-    user_id: String,
+pub struct PasswordRecoveryCodes {
+    recovery_codes: Vec<String>,
 }
 
+/// The user returned to the client.
+///
+/// `token` and `refresh_token` are only set when a new session was started, i.e. on registration,
+/// login and password change. `recovery_codes` are only set on registration.
 #[derive(Serialize, Deserialize)]
-struct User {
+pub struct User {
     email: String,
-    token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
     username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_codes: Option<Vec<String>>,
 }
 
+/// The refresh token sent by API clients. Browser frontends send it in a cookie instead.
+#[derive(Deserialize)]
+pub struct RefreshToken {
+    refresh_token: String,
+}
+
+/// A new short-lived access token together with the refresh token that replaces the one used.
+#[derive(Serialize, Deserialize)]
+pub struct TokenPair {
+    token: String,
+    refresh_token: String,
+}
+
+/// An active login session of the current user.
+///
+/// # Fields
+///
+/// * `id` - The id of the session, used to revoke it
+/// * `created` - The date and time the user logged in
+/// * `expires` - The date and time the session expires
+/// * `current` - Whether this is the session the request was made with
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    id: Thing,
+    created: Datetime,
+    expires: Datetime,
+    current: bool,
+}
+
+/// Registers a new user with a first profile and returns it with a login token
+///
+/// The response also carries the password recovery codes of the new user. This is the only time
+/// they are shown, so the user should write them down.
+///
+/// # Arguments
+///
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `jar` - The cookies of the request
+/// * `Json(req)` - A `Json` object containing the username, email and password of the new user
+///
+/// # Returns
+///
+/// A `Json` object containing the new user, a token for the `Authorization` header and the recovery
+/// codes, along with the session cookies for browser frontends.
 pub(crate) async fn create_user(
     ctx: State<ApiContext>,
+    jar: CookieJar,
     Json(req): Json<UserBody<NewUser>>,
-) -> Result<Json<UserBody<User>>> {
-    let password_hash = hash_password(req.user.password).await?;
+) -> Result<(CookieJar, Json<UserBody<User>>)> {
+    let password_hash = hash_password(&ctx, req.user.password).await?;
 
     // front end will validate username and email for uniqueness prior to calling create
     // once Surreal has better error codes and info, we can incorporate unique check return here
     let mut sql = ctx.db.query(
-        "CREATE user SET email = $email, username = $username, password_hash = $password_hash RETURN id AS user_id;")
+        "CREATE user SET email = $email, username = $username, password_hash = $password_hash RETURN id;")
         .bind(("email", &*req.user.email))
         .bind(("username", &*req.user.username))
         .bind(("password_hash", password_hash))
         .await?;
-    let my_user_id: Option<UserId>  = sql.take((0, "user_id"))?;
-
-    Ok(Json(UserBody {
-        user: User {
-            email: req.user.email,
-            token: AuthUser { user_id: my_user_id.expect("Failed to get user_id").user_id  }.to_jwt(&ctx),
-            username: req.user.username,
-        },
-    }))
+    let user_id: Option<Thing> = sql.take((0, "id"))?;
+    let user_id = user_id.context("CREATE user returned no record")?;
+    profile::create_default_profile(&ctx, &user_id.id.to_raw(), &req.user.username).await?;
+    let recovery_codes = replace_recovery_codes(&ctx, PASSWORD_RECOVERY_CODE, &user_id.id.to_raw()).await?;
+
+    let (jar, Json(mut body)) =
+        logged_in_user(&ctx, jar, user_id.id.to_raw(), req.user.email, req.user.username).await?;
+    body.user.recovery_codes = Some(recovery_codes);
+    Ok((jar, Json(body)))
 }
 
+/// Verifies a username and password and returns the user with a login token
+///
+/// # Arguments
+///
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `addr` - The address of the client, for tracking failed logins
+/// * `jar` - The cookies of the request
+/// * `Json(req)` - A `Json` object containing the username and password
+///
+/// # Returns
+///
+/// A `Json` object containing the user and a token along with the session cookies, or
+/// `Error::Unauthorized` if the username or the password is wrong.
+///
+/// If the user has 2FA enabled, a `two_factor` challenge is returned instead, to be completed with
+/// a code at `/users/login/2fa`.
+///
+/// After too many failed logins for the username or from the client IP, `Error::TooManyRequests` is
+/// returned for an exponentially growing time. A password hash made with weaker settings than
+/// configured is replaced once the password checks out.
 pub(crate) async fn login_user(
     ctx: State<ApiContext>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    jar: CookieJar,
     Json(req): Json<UserBody<LoginUser>>,
-) -> Result<Json<UserBody<User>>> {
+) -> Result<Response> {
+    let keys = LoginKeys::new(&req.user.username, addr.ip());
+    login_attempt::check_lockout(&ctx, &keys).await?;
+
     let mut sql = ctx.db.query(
-        "select id as user_id, email, username, password_hash
-        from user where username = $1;")
-        .bind(("username", req.user.username))
+        "SELECT id, email, username, password_hash, totp_secret != NONE AS two_factor FROM user
+        WHERE username = $username;")
+        .bind(("username", &*req.user.username))
         .await?;
+    let user: Option<PassUser> = sql.take(0)?;
+    // An unknown username gets the same answer as a wrong password, after as long, so it doesn't tell
+    // which usernames exist.
+    let Some(user) = user else {
+        verify_dummy_password(&ctx, req.user.password).await;
+        login_attempt::record_failure(&ctx, &keys).await?;
+        return Err(Error::Unauthorized);
+    };
 
-    let user: PassUser = sql.take(0)?;
-    let dog = user.email;
-
+    if let Err(e) = verify_password(req.user.password.clone(), user.password_hash.clone()).await {
+        if matches!(e, Error::Unauthorized) {
+            login_attempt::record_failure(&ctx, &keys).await?;
+        }
+        return Err(e);
+    }
+    login_attempt::clear(&ctx, &keys).await?;
+
+    if needs_rehash(&ctx.config, &user.password_hash) {
+        let password_hash = hash_password(&ctx, req.user.password).await?;
+        ctx.db.query("UPDATE $user SET password_hash = $password_hash;")
+            .bind(("password_hash", password_hash))
+            .bind(("user", &user.id))
+            .await?
+            .check()?;
+    }
 
-    verify_password(req.user.password, user.password_hash).await?;
+    if user.two_factor {
+        let challenge = two_factor::start_challenge(&ctx, &user.id.id.to_raw()).await?;
+        return Ok(Json(challenge).into_response());
+    }
 
-    Ok(Json(UserBody {
-        user: User {
-            email: user.email,
-            token: AuthUser {
-                user_id: user.user_id,
-            }
-            .to_jwt(&ctx),
-            username: user.username,
-        },
-    }))
+    Ok(logged_in_user(&ctx, jar, user.id.id.to_raw(), user.email, user.username).await?.into_response())
 }
 
+/// Returns the user identified by the token in the `Authorization` header
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+///
+/// # Returns
+///
+/// A `Json` object containing the current user.
 pub(crate) async fn get_current_user(
     auth_user: AuthUser,
     ctx: State<ApiContext>,
 ) -> Result<Json<UserBody<User>>> {
-    let user: User = ctx.db.select((USER, &*auth_user.user_id)).await?;
+    let user: Option<UserRecord> = ctx.db.select((USER, &*auth_user.user_id)).await?;
+    let user = user.ok_or(Error::NotFound)?;
 
     Ok(Json(UserBody {
         user: User {
             email: user.email,
-            token: auth_user.to_jwt(&ctx),
+            token: None,
+            refresh_token: None,
             username: user.username,
+            recovery_codes: None,
         },
     }))
 }
 
+/// Updates the email and/or username of the current user
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing the fields to change; missing fields are left as they are
+///
+/// # Returns
+///
+/// A `Json` object containing the updated user.
 pub(crate) async fn update_user(
     auth_user: AuthUser,
     ctx: State<ApiContext>,
@@ -156,66 +288,315 @@ pub(crate) async fn update_user(
         return get_current_user(auth_user, ctx).await;
     }
 
-    // WTB `Option::map_async()`
-    let password_hash = if let Some(password) = req.user.password {
-        Some(hash_password(password).await?)
-    } else {
-        None
-    };
-
     let mut sql = ctx.db.query(
-        "UPDATE user SET email = $email, username = $username WHERE id = type::thing('user', $user);")
+        "UPDATE type::thing('user', $user) SET email = $email ?? email, username = $username ?? username
+        RETURN email, username;")
         .bind(("email", req.user.email))
         .bind(("username", req.user.username))
-        .bind(("user", auth_user.user_id))
+        .bind(("user", &*auth_user.user_id))
         .await?;
-    let user: Option<User> = sql.take(0)?;
+    let user: Option<UserRecord> = sql.take(0)?;
+    let user = user.ok_or(Error::NotFound)?;
 
     Ok(Json(UserBody {
         user: User {
             email: user.email,
-            token: auth_user.to_jwt(&ctx),
+            token: None,
+            refresh_token: None,
             username: user.username,
+            recovery_codes: None,
         },
     }))
 }
 
-
+/// Replaces the password of the current user with a new argon2 hash
+///
+/// Every existing session of the user, including the current one, is ended, their personal access tokens
+/// are revoked and their quick-unlock PIN is removed, see `AuthUser::revoke_all_access()`. A new session is
+/// started for the caller, scripts need new tokens.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `jar` - The cookies of the request
+/// * `Json(req)` - A `Json` object containing the new password
+///
+/// # Returns
+///
+/// A `Json` object containing the user and the tokens for the new session, along with the session
+/// cookies.
 pub(crate) async fn update_password(
     auth_user: AuthUser,
     ctx: State<ApiContext>,
+    jar: CookieJar,
     Json(req): Json<UserBody<UpdatePassword>>,
-) -> Result<Json<UserBody<User>>> {
+) -> Result<(CookieJar, Json<UserBody<User>>)> {
+    let password = req.user.password
+        .ok_or_else(|| Error::unprocessable_entity([("password", "is required")]))?;
+    let password_hash = hash_password(&ctx, password).await?;
+
+    let mut sql = ctx.db.query(
+        "UPDATE type::thing('user', $user) SET password_hash = $password_hash RETURN email, username;")
+        .bind(("password_hash", password_hash))
+        .bind(("user", &*auth_user.user_id))
+        .await?;
+    let user: Option<UserRecord> = sql.take(0)?;
+    let user = user.ok_or(Error::NotFound)?;
+
+    AuthUser::revoke_all_access(&ctx, &auth_user.user_id).await?;
 
-    let password_hash = if let Some(password) = req.user.password {
-        Some(hash_password(password).await?)
-    } else {
-        None
+    logged_in_user(&ctx, jar, auth_user.user_id, user.email, user.username).await
+}
+
+/// Sets a new password for a user who forgot theirs, using up one of their password recovery codes
+///
+/// Every existing session and personal access token of the user is revoked and the quick-unlock PIN is
+/// removed, as someone else may have got hold of them along with the old password. No new session is started,
+/// the user logs in with the new password afterwards, which still asks for the second factor if 2FA is
+/// enabled.
+///
+/// # Arguments
+///
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `addr` - The address of the client, for tracking failed attempts
+/// * `Json(req)` - A `Json` object containing the username, a recovery code and the new password
+///
+/// # Returns
+///
+/// `Error::Unauthorized` if the username is unknown or the recovery code is wrong or was already
+/// used, after as long either way. Wrong codes count as failed logins, see `login_user()`.
+pub(crate) async fn reset_password(
+    ctx: State<ApiContext>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(req): Json<UserBody<ResetPassword>>,
+) -> Result<()> {
+    let keys = LoginKeys::new(&req.user.username, addr.ip());
+    login_attempt::check_lockout(&ctx, &keys).await?;
+
+    let mut sql = ctx.db.query("SELECT VALUE id FROM user WHERE username = $username;")
+        .bind(("username", &*req.user.username))
+        .await?;
+    let user_id: Option<Thing> = sql.take(0)?;
+    let Some(user_id) = user_id else {
+        verify_dummy_recovery_codes(&ctx, req.user.recovery_code).await;
+        login_attempt::record_failure(&ctx, &keys).await?;
+        return Err(Error::Unauthorized);
     };
+    let user_id = user_id.id.to_raw();
 
-    let mut sql = ctx.db.query(
-        "UPDATE user SET password_hash = $password_hash WHERE id = type::thing('user', $user);")
+    if !use_recovery_code(&ctx, PASSWORD_RECOVERY_CODE, &user_id, req.user.recovery_code).await? {
+        login_attempt::record_failure(&ctx, &keys).await?;
+        return Err(Error::Unauthorized);
+    }
+    login_attempt::clear(&ctx, &keys).await?;
+
+    let password_hash = hash_password(&ctx, req.user.password).await?;
+    ctx.db.query("UPDATE type::thing('user', $user) SET password_hash = $password_hash;")
         .bind(("password_hash", password_hash))
-        .bind(("user", auth_user.user_id))
+        .bind(("user", &user_id))
+        .await?
+        .check()?;
+
+    AuthUser::revoke_all_access(&ctx, &user_id).await?;
+    Ok(())
+}
+
+/// Replaces the password recovery codes of the current user, e.g. when most of them are used up
+///
+/// The current password is required, so a stolen session can't be turned into a way to take over
+/// the account.
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `Json(req)` - A `Json` object containing the current password
+///
+/// # Returns
+///
+/// A `Json` object containing the new recovery codes, or `Error::Unauthorized` if the password is wrong.
+pub(crate) async fn regenerate_password_recovery_codes(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    Json(req): Json<UserBody<UpdatePassword>>,
+) -> Result<Json<UserBody<PasswordRecoveryCodes>>> {
+    let password = req.user.password
+        .ok_or_else(|| Error::unprocessable_entity([("password", "is required")]))?;
+
+    let mut sql = ctx.db.query("SELECT VALUE password_hash FROM type::thing('user', $user);")
+        .bind(("user", &*auth_user.user_id))
         .await?;
-    let user = sql.take(0)?;
+    let password_hash: Option<String> = sql.take(0)?;
+    verify_password(password, password_hash.ok_or(Error::NotFound)?).await?;
+
+    let recovery_codes = replace_recovery_codes(&ctx, PASSWORD_RECOVERY_CODE, &auth_user.user_id).await?;
 
     Ok(Json(UserBody {
+        user: PasswordRecoveryCodes { recovery_codes },
+    }))
+}
+
+/// Exchanges a refresh token for a new access token and a new refresh token
+///
+/// Each refresh token can only be used once. Presenting a used one again revokes the whole session.
+///
+/// Without a request body the refresh token is taken from its cookie, which, like any other
+/// cookie-authenticated change, needs the CSRF token in the `X-CSRF-Token` header.
+///
+/// # Arguments
+///
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `headers` - The headers of the request, for the CSRF token
+/// * `jar` - The cookies of the request
+/// * `req` - An optional `Json` object containing the refresh token
+///
+/// # Returns
+///
+/// A `Json` object containing the new token pair along with the updated session cookies, or
+/// `Error::Unauthorized` if the refresh token is not valid.
+pub(crate) async fn refresh_token(
+    ctx: State<ApiContext>,
+    headers: HeaderMap,
+    jar: CookieJar,
+    req: Option<Json<RefreshToken>>,
+) -> Result<(CookieJar, Json<TokenPair>)> {
+    let token = match req {
+        Some(Json(req)) => req.refresh_token,
+        None => {
+            extractor::verify_csrf(&headers, &jar)?;
+            extractor::refresh_cookie(&jar).ok_or(Error::Unauthorized)?
+        }
+    };
+
+    let auth_user = AuthUser::from_refresh_token(&ctx, &token).await?;
+    let token = auth_user.to_jwt(&ctx);
+    let refresh_token = auth_user.issue_refresh_token(&ctx).await?;
+    let jar = extractor::add_session_cookies(&ctx, jar, token.clone(), refresh_token.clone());
+
+    Ok((jar, Json(TokenPair { token, refresh_token })))
+}
+
+/// Logs out the current user by ending the session the request was made with
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `jar` - The cookies of the request
+///
+/// # Returns
+///
+/// Expired session cookies, so the browser drops them.
+pub(crate) async fn logout_user(auth_user: AuthUser, ctx: State<ApiContext>, jar: CookieJar) -> Result<CookieJar> {
+    auth_user.end_session(&ctx).await?;
+    Ok(extractor::remove_session_cookies(jar))
+}
+
+/// Lists the active sessions of the current user
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+///
+/// # Returns
+///
+/// A `Json` object containing a vector of `Session` structs, oldest first.
+pub(crate) async fn list_sessions(auth_user: AuthUser, ctx: State<ApiContext>) -> Result<Json<Vec<Session>>> {
+    let mut sql = ctx.db.query(
+        "SELECT id, created, expires, id = type::thing('session', $session_id) AS current FROM session
+        WHERE user = type::thing('user', $user) AND expires > time::now() ORDER BY created;")
+        .bind(("session_id", &auth_user.session_id))
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let sessions: Vec<Session> = sql.take(0)?;
+    Ok(Json(sessions))
+}
+
+/// Revokes one session of the current user, e.g. a login on a lost device
+///
+/// # Arguments
+///
+/// * `auth_user` - The authenticated user
+/// * `ctx` - A `State` object containing the `ApiContext`
+/// * `id` - A `Path` object containing the ID of the session to revoke
+///
+/// # Returns
+///
+/// `Error::NotFound` if the session does not exist or belongs to another user.
+pub(crate) async fn revoke_session(
+    auth_user: AuthUser,
+    ctx: State<ApiContext>,
+    id: Path<String>,
+) -> Result<()> {
+    let mut sql = ctx.db.query(
+        "DELETE type::thing('session', $id) WHERE user = type::thing('user', $user) RETURN BEFORE;")
+        .bind(("id", &*id))
+        .bind(("user", &auth_user.user_id))
+        .await?;
+    let session: Option<Thing> = sql.take((0, "id"))?;
+    session.map(|_| ()).ok_or(Error::NotFound)
+}
+
+/// Starts a new session for the user and returns it with the access and refresh tokens of that session,
+/// both in the body and as cookies.
+pub(crate) async fn logged_in_user(
+    ctx: &ApiContext,
+    jar: CookieJar,
+    user_id: String,
+    email: String,
+    username: String,
+) -> Result<(CookieJar, Json<UserBody<User>>)> {
+    let auth_user = AuthUser::start_session(ctx, user_id).await?;
+    session_user(ctx, jar, auth_user, email, username).await
+}
+
+/// Returns the user with the access and refresh tokens of a session just started for them, both in
+/// the body and as cookies.
+pub(crate) async fn session_user(
+    ctx: &ApiContext,
+    jar: CookieJar,
+    auth_user: AuthUser,
+    email: String,
+    username: String,
+) -> Result<(CookieJar, Json<UserBody<User>>)> {
+    let token = auth_user.to_jwt(ctx);
+    let refresh_token = auth_user.issue_refresh_token(ctx).await?;
+    let jar = extractor::add_session_cookies(ctx, jar, token.clone(), refresh_token.clone());
+
+    Ok((jar, Json(UserBody {
         user: User {
-            email: user.email,
-            token: auth_user.to_jwt(&ctx),
-            username: user.username,
+            email,
+            token: Some(token),
+            refresh_token: Some(refresh_token),
+            username,
+            recovery_codes: None,
         },
-    }))
+    })))
 }
 
-async fn hash_password(password: String) -> Result<String> {
+/// Returns an `Argon2` hasher with the memory, iteration and parallelism settings from the `Config`,
+/// or an error if they are out of range.
+pub(crate) fn argon2(config: &Config) -> anyhow::Result<Argon2<'static>> {
+    let params = Params::new(
+        config.argon2_memory_kib,
+        config.argon2_iterations,
+        config.argon2_parallelism,
+        None,
+    )
+    .map_err(|e| anyhow::anyhow!("invalid argon2 settings: {}", e))?;
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+}
+
+pub(crate) async fn hash_password(ctx: &ApiContext, password: String) -> Result<String> {
+    let argon2 = argon2(&ctx.config)?;
     // Argon2 hashing is designed to be computationally intensive,
     // so we need to do this on a blocking thread.
     tokio::task::spawn_blocking(move || -> Result<String> {
         let salt = SaltString::generate(rand::thread_rng());
         Ok(
-            PasswordHash::generate(Argon2::default(), password, &salt)
+            PasswordHash::generate(argon2, password, &salt)
                 .map_err(|e| anyhow::anyhow!("failed to generate password hash: {}", e))?
                 .to_string(),
         )
@@ -224,7 +605,40 @@ async fn hash_password(password: String) -> Result<String> {
     .context("panic in generating password hash")?
 }
 
-async fn verify_password(password: String, password_hash: String) -> Result<()> {
+/// Hashes a random password with the argon2 settings from the `Config`, for `verify_dummy_password()`,
+/// or returns an error if the settings are out of range.
+pub(crate) fn dummy_password_hash(config: &Config) -> anyhow::Result<String> {
+    let salt = SaltString::generate(rand::thread_rng());
+    let password = extractor::to_hex(&rand::random::<[u8; 16]>());
+    Ok(PasswordHash::generate(argon2(config)?, password, &salt)
+        .map_err(|e| anyhow::anyhow!("failed to generate password hash: {}", e))?
+        .to_string())
+}
+
+/// Checks the password against the dummy hash of the `ApiContext`, which never matches. It stands in for
+/// the check of a user's password when the username is unknown, so both take as long.
+pub(crate) async fn verify_dummy_password(ctx: &ApiContext, password: String) {
+    let _ = verify_password(password, ctx.dummy_password_hash.to_string()).await;
+}
+
+/// Whether a password hash is older or weaker than what the `Config` asks for: not Argon2id, an
+/// older version of it, or lower memory, iteration or parallelism settings.
+fn needs_rehash(config: &Config, password_hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(password_hash) else {
+        return true;
+    };
+    let Ok(params) = Params::try_from(&hash) else {
+        return true;
+    };
+
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || params.m_cost() < config.argon2_memory_kib
+        || params.t_cost() < config.argon2_iterations
+        || params.p_cost() < config.argon2_parallelism
+}
+
+pub(crate) async fn verify_password(password: String, password_hash: String) -> Result<()> {
     tokio::task::spawn_blocking(move || -> Result<()> {
         let hash = PasswordHash::new(&password_hash)
             .map_err(|e| anyhow::anyhow!("invalid password hash: {}", e))?;
@@ -238,3 +652,112 @@ async fn verify_password(password: String, password_hash: String) -> Result<()>
     .await
     .context("panic in verifying password hash")?
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use crate::api::testing::{token, TestApp, CLIENT, PASSWORD};
+
+    #[tokio::test]
+    async fn an_unknown_username_is_checked_against_a_hash_with_the_configured_settings() {
+        let app = TestApp::new().await;
+        assert!(!super::needs_rehash(&app.ctx.config, &app.ctx.dummy_password_hash));
+
+        let login = json!({ "user": { "username": "nobody", "password": PASSWORD } });
+        let (status, _) = app.request(Method::POST, "/users/login", None, Some(login)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn a_password_reset_uses_up_the_code_and_revokes_the_sessions_tokens_and_pin() {
+        let app = TestApp::new().await;
+        let user = json!({ "user": { "username": "ann", "email": "ann@example.com", "password": PASSWORD } });
+        let (_, body) = app.request(Method::POST, "/users", None, Some(user)).await;
+        let session = token(&body);
+        let recovery_code = body["user"]["recovery_codes"][0].as_str().unwrap().to_owned();
+
+        let access_token = json!({ "token": { "name": "script", "scopes": ["doses:read"] } });
+        let (status, body) = app.request(Method::POST, "/user/tokens", Some(&session), Some(access_token)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let access_token = body["token"]["token"].as_str().unwrap().to_owned();
+        let pin = json!({ "user": { "password": PASSWORD, "pin": "1234" } });
+        let (status, body) = app.request(Method::PUT, "/user/pin", Some(&session), Some(pin)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let reset = |recovery_code: &str| json!({
+            "user": { "username": "ann", "recovery_code": recovery_code, "password": "battery staple" }
+        });
+        let (status, _) = app.request(Method::POST, "/users/password/reset", None, Some(reset("00000-00000"))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = app.request(Method::POST, "/users/password/reset", None, Some(reset(&recovery_code))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let (status, _) = app.request(Method::POST, "/users/password/reset", None, Some(reset(&recovery_code))).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = app.request(Method::GET, "/user", Some(&session), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app.request(Method::GET, "/doses", Some(&access_token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let unlock = json!({ "user": { "username": "ann", "pin": "1234" } });
+        let (status, _) = app.request(Method::POST, "/users/unlock", None, Some(unlock)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _) = app.log_in_from(CLIENT, "ann", PASSWORD).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = app.log_in_from(CLIENT, "ann", "battery staple").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+    }
+
+    #[tokio::test]
+    async fn a_password_reset_for_an_unknown_username_is_refused() {
+        let app = TestApp::new().await;
+
+        let reset = json!({ "user": { "username": "nobody", "recovery_code": "00000-00000", "password": "x" } });
+        let (status, _) = app.request(Method::POST, "/users/password/reset", None, Some(reset)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn a_password_change_revokes_the_access_tokens() {
+        let app = TestApp::new().await;
+        let session = app.sign_up("ann").await;
+        let access_token = json!({ "token": { "name": "script", "scopes": ["doses:read"] } });
+        let (_, body) = app.request(Method::POST, "/user/tokens", Some(&session), Some(access_token)).await;
+        let access_token = body["token"]["token"].as_str().unwrap().to_owned();
+        let (status, _) = app.request(Method::GET, "/doses", Some(&access_token), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let password = json!({ "user": { "password": "battery staple" } });
+        let (status, body) = app.request(Method::PUT, "/user/password", Some(&session), Some(password)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let (status, _) = app.request(Method::GET, "/doses", Some(&access_token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app.request(Method::GET, "/user", Some(&token(&body)), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn a_refresh_token_is_replaced_on_use_and_reusing_it_revokes_the_session() {
+        let app = TestApp::new().await;
+        let user = json!({ "user": { "username": "ann", "email": "ann@example.com", "password": PASSWORD } });
+        let (_, body) = app.request(Method::POST, "/users", None, Some(user)).await;
+        let first = json!({ "refresh_token": body["user"]["refresh_token"] });
+
+        let (status, pair) = app.request(Method::POST, "/users/refresh", None, Some(first.clone())).await;
+        assert_eq!(status, StatusCode::OK, "{pair}");
+        assert_ne!(pair["refresh_token"], first["refresh_token"]);
+        let (status, _) = app.request(Method::GET, "/user", pair["token"].as_str(), None).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = app.request(Method::POST, "/users/refresh", None, Some(first)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let second = json!({ "refresh_token": pair["refresh_token"] });
+        let (status, _) = app.request(Method::POST, "/users/refresh", None, Some(second)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app.request(Method::GET, "/user", pair["token"].as_str(), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
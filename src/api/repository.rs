@@ -0,0 +1,89 @@
+//! Typed access to the records of the aggregates behind the handlers: medications, stores, doses, reminders,
+//! notes and units of measure, and of the trash they are deleted to.
+//!
+//! Each aggregate has a trait with its models in a submodule. The SurrealQL behind them lives in `surreal`,
+//! and `fake` keeps the records in memory, for unit tests of code that takes a `Repositories`. `encrypted`
+//! wraps either of them to encrypt the sensitive fields.
+//!
+//! The repositories don't check permissions. The handlers authorize the request first, see
+//! `handlers::grant`, and then pass the owner of the records, so every method is scoped to one user.
+//! Methods that write also take the actor, the user making the request, which is recorded in the audit log.
+//!
+//! Deleting a record moves it to the trash, see `crate::db::trash`, along with the records depending on it, see
+//! `reference`. Only `TrashRepository` sees the records in the trash, every other method treats them as if they
+//! didn't exist.
+use std::sync::Arc;
+
+use crate::db::encryption::Cipher;
+
+pub mod dose;
+pub mod encrypted;
+pub mod fake;
+pub mod history;
+pub mod medication;
+pub mod note;
+pub mod reference;
+pub mod reminder;
+pub mod store;
+pub mod surreal;
+pub mod trash;
+pub mod uom;
+
+pub use dose::DoseRepository;
+pub use encrypted::Encrypted;
+pub use fake::FakeRepository;
+pub use medication::MedicationRepository;
+pub use note::NoteRepository;
+pub use reminder::ReminderRepository;
+pub use store::StoreRepository;
+pub use surreal::SurrealRepository;
+pub use trash::TrashRepository;
+pub use uom::UnitOfMeasureRepository;
+
+/// The repositories of all the aggregates, as kept in the `ApiContext`.
+#[derive(Clone)]
+pub struct Repositories {
+    pub medications: Arc<dyn MedicationRepository>,
+    pub stores: Arc<dyn StoreRepository>,
+    pub doses: Arc<dyn DoseRepository>,
+    pub reminders: Arc<dyn ReminderRepository>,
+    pub notes: Arc<dyn NoteRepository>,
+    pub units: Arc<dyn UnitOfMeasureRepository>,
+    pub trash: Arc<dyn TrashRepository>,
+}
+
+impl Repositories {
+    /// Uses one implementation for all the aggregates, usually a `SurrealRepository` or a `FakeRepository`.
+    pub fn new<R>(repository: R) -> Self
+    where
+        R: MedicationRepository + StoreRepository + DoseRepository + ReminderRepository + NoteRepository
+            + UnitOfMeasureRepository + TrashRepository + 'static,
+    {
+        let repository = Arc::new(repository);
+        Self {
+            medications: repository.clone(),
+            stores: repository.clone(),
+            doses: repository.clone(),
+            reminders: repository.clone(),
+            notes: repository.clone(),
+            units: repository.clone(),
+            trash: repository,
+        }
+    }
+
+    /// Encrypts the sensitive fields of the medications, stores, doses and notes before they are stored, and
+    /// decrypts them again when they are read. Does nothing if the `cipher` has no key.
+    pub fn encrypted(self, cipher: Cipher) -> Self {
+        if !cipher.is_enabled() {
+            return self;
+        }
+        Self {
+            medications: Arc::new(Encrypted::new(self.medications, cipher.clone())),
+            stores: Arc::new(Encrypted::new(self.stores, cipher.clone())),
+            doses: Arc::new(Encrypted::new(self.doses, cipher.clone())),
+            notes: Arc::new(Encrypted::new(self.notes, cipher.clone())),
+            trash: Arc::new(Encrypted::new(self.trash, cipher)),
+            ..self
+        }
+    }
+}
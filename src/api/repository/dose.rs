@@ -0,0 +1,111 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::Result;
+use crate::api::repository::history::Version;
+
+/// A struct representing a dose of a certain medication
+///
+/// # Fields
+///
+/// * `id` - A `Thing` representing the unique identifier of the dose
+/// * `profile` - A `Thing` representing the profile the dose belongs to, the same as that of its store
+/// * `store` - A `Thing` representing the medication that the dose is for
+/// * `quantity` - A `f32` representing the amount of medication in the dose
+/// * `unit` - A `String` representing the unit of measurement for the medication in the dose
+/// * `created` - A `Datetime` representing the date and time the dose was created
+/// * `updated` - A `Datetime` representing the date and time the dose was last updated
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Dose {
+    pub id: Thing,
+    pub user: Thing,
+    pub profile: Thing,
+    pub store: Thing,
+    pub quantity: f32,
+    pub unit: String,
+    pub created: Datetime,
+    pub updated: Datetime,
+}
+
+/// A struct representing a dose to be created
+///
+/// # Fields
+///
+/// * `id` - An optional `String` representing the ID of the dose
+/// * `store` - A `String` representing the store where the dose is located
+/// * `quantity` - A `f32` representing the quantity of the dose
+/// * `unit` - A `String` representing the unit of the dose
+#[derive(Serialize, Deserialize)]
+pub struct CreateDose {
+    pub id: Option<String>,
+    pub store: String,
+    pub quantity: f32,
+    pub unit: String,
+}
+
+/// A dose joined with its store and medication.
+#[derive(Serialize, Deserialize)]
+pub struct DoseList {
+    pub created: Datetime,
+    pub id: Thing,
+    pub dose_quantity: f32,
+    pub dose_unit: String,
+    pub medication_id: Thing,
+    pub medication_name: String,
+    pub store_active: bool,
+    pub store_created: Datetime,
+    pub store_id: Thing,
+    pub store_production_date: Datetime,
+    pub store_start_quantity: f32,
+    pub store_unit: String,
+    pub store_updated: Datetime,
+    pub updated: Datetime,
+    pub user: Thing,
+}
+
+/// The doses taken by a user.
+///
+/// A dose takes the profile of its store, which the caller must have checked belongs to the owner.
+#[async_trait]
+pub trait DoseRepository: Send + Sync {
+    /// Logs a dose of `owner`, taking it from its store in the same transaction, see `crate::api::transaction`.
+    /// Fails with `Error::UnprocessableEntity` if the store isn't active, its unit isn't that of the store, or the
+    /// store has less left than its quantity. A store the dose uses up is deactivated, keeping
+    /// its version.
+    async fn create(&self, actor: &str, owner: &str, dose: CreateDose) -> Result<Option<Dose>>;
+
+    /// Returns the dose, or `None` if it doesn't exist or belongs to another user.
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Dose>>;
+
+    /// Replaces the fields of the dose if it is still at `version`, its `updated`. Returns `None` if it doesn't
+    /// exist, belongs to another user or was changed since. It is taken from its store again as by `create`,
+    /// after giving back what it took before. A dose moved to another store gives its quantity back to the store it
+    /// leaves, which is reactivated, keeping its version, if the dose had used it up.
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, dose: CreateDose)
+        -> Result<Option<Dose>>;
+
+    /// Lists the earlier versions of the dose, oldest first, see `super::history`. Empty if it has none or
+    /// belongs to another user.
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Dose>>>;
+
+    /// Reverts the dose to its earlier version `to` if it is still at `version`, returning `None` like
+    /// `update()`. Fails with `Error::NotFound` if it has no such version. Fails with
+    /// `Error::UnprocessableEntity` if its store no longer exists or has too little left, as for `create()`. It gives
+    /// back to the store it leaves as by `update()`.
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Dose>>;
+
+    /// Moves the dose to the trash and returns it as it was, see `crate::db::trash`. The notes about it go along.
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Dose>>;
+
+    /// Lists the doses of a profile, or of all profiles of `owner`, oldest first.
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<DoseList>>;
+
+    /// Lists the doses of `owner` taken from the stores of a medication, oldest first.
+    async fn list_for_medication(&self, owner: &str, medication: &str) -> Result<Vec<DoseList>>;
+
+    /// Lists the doses of `owner` taken from a store, oldest first.
+    async fn list_for_store(&self, owner: &str, store: &str) -> Result<Vec<DoseList>>;
+}
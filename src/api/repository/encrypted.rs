@@ -0,0 +1,369 @@
+//! Repositories that encrypt the sensitive fields on the way in and decrypt them on the way out, around
+//! the ones that store them, see `Repositories::encrypted()` and `crate::db::encryption`.
+use async_trait::async_trait;
+use std::sync::Arc;
+use surrealdb::sql::Datetime;
+
+use crate::api::Result;
+use crate::db::encryption::{Cipher, ENCRYPTED_FIELDS};
+
+use super::dose::{CreateDose, Dose, DoseList, DoseRepository};
+use super::history::Version;
+use super::medication::{CreateMedication, Medication, MedicationRepository};
+use super::note::{CreateNote, DoseNote, MedicationNote, Note, NoteRepository, StoreNote};
+use super::store::{CreateStore, RefillStore, Store, StoreList, StoreRepository};
+use super::trash::{TrashItem, TrashRepository};
+
+/// Wraps the repository of an aggregate with encrypted fields.
+pub struct Encrypted<R: ?Sized> {
+    inner: Arc<R>,
+    cipher: Cipher,
+}
+
+impl<R: ?Sized> Encrypted<R> {
+    pub fn new(inner: Arc<R>, cipher: Cipher) -> Self {
+        Self { inner, cipher }
+    }
+}
+
+/// A model read back from a repository, with the encrypted fields it has.
+pub(crate) trait Decrypt: Sized {
+    fn decrypt(self, cipher: &Cipher) -> anyhow::Result<Self>;
+}
+
+impl<T: Decrypt> Decrypt for Option<T> {
+    fn decrypt(self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.map(|model| model.decrypt(cipher)).transpose()
+    }
+}
+
+impl<T: Decrypt> Decrypt for Vec<T> {
+    fn decrypt(self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.into_iter().map(|model| model.decrypt(cipher)).collect()
+    }
+}
+
+impl Decrypt for Medication {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.name = cipher.decrypt(&self.name)?;
+        Ok(self)
+    }
+}
+
+impl Decrypt for Store {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.lot_number = cipher.decrypt(&self.lot_number)?;
+        self.unit = cipher.decrypt(&self.unit)?;
+        Ok(self)
+    }
+}
+
+impl Decrypt for Dose {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.unit = cipher.decrypt(&self.unit)?;
+        Ok(self)
+    }
+}
+
+impl<T: Decrypt> Decrypt for Version<T> {
+    fn decrypt(self, cipher: &Cipher) -> anyhow::Result<Self> {
+        Ok(Self { record: self.record.decrypt(cipher)?, ..self })
+    }
+}
+
+impl Decrypt for StoreList {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.medication_name = cipher.decrypt(&self.medication_name)?;
+        self.store_lot_number = cipher.decrypt(&self.store_lot_number)?;
+        self.store_unit = cipher.decrypt(&self.store_unit)?;
+        Ok(self)
+    }
+}
+
+impl Decrypt for DoseList {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.medication_name = cipher.decrypt(&self.medication_name)?;
+        self.dose_unit = cipher.decrypt(&self.dose_unit)?;
+        self.store_unit = cipher.decrypt(&self.store_unit)?;
+        Ok(self)
+    }
+}
+
+impl Decrypt for Note {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.content = cipher.decrypt(&self.content)?;
+        Ok(self)
+    }
+}
+
+impl Decrypt for DoseNote {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.content = cipher.decrypt(&self.content)?;
+        self.medication_name = cipher.decrypt(&self.medication_name)?;
+        self.unit = cipher.decrypt(&self.unit)?;
+        Ok(self)
+    }
+}
+
+impl Decrypt for MedicationNote {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.content = cipher.decrypt(&self.content)?;
+        self.medication_name = cipher.decrypt(&self.medication_name)?;
+        Ok(self)
+    }
+}
+
+impl Decrypt for StoreNote {
+    fn decrypt(mut self, cipher: &Cipher) -> anyhow::Result<Self> {
+        self.content = cipher.decrypt(&self.content)?;
+        self.medication_name = cipher.decrypt(&self.medication_name)?;
+        self.unit = cipher.decrypt(&self.unit)?;
+        Ok(self)
+    }
+}
+
+#[async_trait]
+impl MedicationRepository for Encrypted<dyn MedicationRepository> {
+    async fn create(&self, actor: &str, owner: &str, profile: &str, mut medication: CreateMedication)
+        -> Result<Option<Medication>>
+    {
+        medication.name = self.cipher.encrypt(&medication.name);
+        Ok(self.inner.create(actor, owner, profile, medication).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Medication>> {
+        Ok(self.inner.read(id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, mut medication: CreateMedication)
+        -> Result<Option<Medication>>
+    {
+        medication.name = self.cipher.encrypt(&medication.name);
+        Ok(self.inner.update(actor, id, owner, version, medication).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn deactivate(&self, actor: &str, id: &str, owner: &str, version: &Datetime)
+        -> Result<Option<Medication>>
+    {
+        Ok(self.inner.deactivate(actor, id, owner, version).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Medication>>> {
+        Ok(self.inner.history(id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Medication>>
+    {
+        Ok(self.inner.revert(actor, id, owner, version, to).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str, cascade: bool) -> Result<Option<Medication>> {
+        Ok(self.inner.delete(actor, id, owner, cascade).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>, active: Option<bool>) -> Result<Vec<Medication>> {
+        Ok(self.inner.list(owner, profile, active).await?.decrypt(&self.cipher)?)
+    }
+}
+
+#[async_trait]
+impl StoreRepository for Encrypted<dyn StoreRepository> {
+    async fn create(&self, actor: &str, owner: &str, mut store: CreateStore) -> Result<Option<Store>> {
+        store.lot_number = self.cipher.encrypt(&store.lot_number);
+        store.unit = self.cipher.encrypt(&store.unit);
+        Ok(self.inner.create(actor, owner, store).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Store>> {
+        Ok(self.inner.read(id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, mut store: CreateStore)
+        -> Result<Option<Store>>
+    {
+        store.lot_number = self.cipher.encrypt(&store.lot_number);
+        store.unit = self.cipher.encrypt(&store.unit);
+        Ok(self.inner.update(actor, id, owner, version, store).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn deactivate(&self, actor: &str, id: &str, owner: &str, version: &Datetime) -> Result<Option<Store>> {
+        Ok(self.inner.deactivate(actor, id, owner, version).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn refill(&self, actor: &str, id: &str, owner: &str, version: &Datetime, mut refill: RefillStore)
+        -> Result<Option<Store>>
+    {
+        refill.lot_number = self.cipher.encrypt(&refill.lot_number);
+        refill.unit = self.cipher.encrypt(&refill.unit);
+        Ok(self.inner.refill(actor, id, owner, version, refill).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Store>>> {
+        Ok(self.inner.history(id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Store>>
+    {
+        Ok(self.inner.revert(actor, id, owner, version, to).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str, cascade: bool) -> Result<Option<Store>> {
+        Ok(self.inner.delete(actor, id, owner, cascade).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<Store>> {
+        Ok(self.inner.list(owner, profile).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list_for_medication(&self, owner: &str, medication: &str, active: Option<bool>)
+        -> Result<Vec<StoreList>>
+    {
+        Ok(self.inner.list_for_medication(owner, medication, active).await?.decrypt(&self.cipher)?)
+    }
+}
+
+#[async_trait]
+impl DoseRepository for Encrypted<dyn DoseRepository> {
+    async fn create(&self, actor: &str, owner: &str, mut dose: CreateDose) -> Result<Option<Dose>> {
+        dose.unit = self.cipher.encrypt(&dose.unit);
+        Ok(self.inner.create(actor, owner, dose).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Dose>> {
+        Ok(self.inner.read(id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, mut dose: CreateDose)
+        -> Result<Option<Dose>>
+    {
+        dose.unit = self.cipher.encrypt(&dose.unit);
+        Ok(self.inner.update(actor, id, owner, version, dose).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Dose>>> {
+        Ok(self.inner.history(id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Dose>>
+    {
+        Ok(self.inner.revert(actor, id, owner, version, to).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Dose>> {
+        Ok(self.inner.delete(actor, id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<DoseList>> {
+        Ok(self.inner.list(owner, profile).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list_for_medication(&self, owner: &str, medication: &str) -> Result<Vec<DoseList>> {
+        Ok(self.inner.list_for_medication(owner, medication).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list_for_store(&self, owner: &str, store: &str) -> Result<Vec<DoseList>> {
+        Ok(self.inner.list_for_store(owner, store).await?.decrypt(&self.cipher)?)
+    }
+}
+
+#[async_trait]
+impl NoteRepository for Encrypted<dyn NoteRepository> {
+    async fn create(&self, actor: &str, owner: &str, mut note: CreateNote) -> Result<Option<Note>> {
+        note.content = self.cipher.encrypt(&note.content);
+        Ok(self.inner.create(actor, owner, note).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Note>> {
+        Ok(self.inner.read(id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, mut note: CreateNote)
+        -> Result<Option<Note>>
+    {
+        note.content = self.cipher.encrypt(&note.content);
+        Ok(self.inner.update(actor, id, owner, version, note).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Note>>> {
+        Ok(self.inner.history(id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Note>>
+    {
+        Ok(self.inner.revert(actor, id, owner, version, to).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Note>> {
+        Ok(self.inner.delete(actor, id, owner).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<Note>> {
+        Ok(self.inner.list(owner, profile).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list_dose_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<DoseNote>> {
+        Ok(self.inner.list_dose_notes(owner, profile).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list_notes_for_dose(&self, owner: &str, dose: &str) -> Result<Vec<DoseNote>> {
+        Ok(self.inner.list_notes_for_dose(owner, dose).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list_medication_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<MedicationNote>> {
+        Ok(self.inner.list_medication_notes(owner, profile).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list_notes_for_medication(&self, owner: &str, medication: &str) -> Result<Vec<MedicationNote>> {
+        Ok(self.inner.list_notes_for_medication(owner, medication).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list_store_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<StoreNote>> {
+        Ok(self.inner.list_store_notes(owner, profile).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn list_notes_for_store(&self, owner: &str, store: &str) -> Result<Vec<StoreNote>> {
+        Ok(self.inner.list_notes_for_store(owner, store).await?.decrypt(&self.cipher)?)
+    }
+}
+
+/// Decrypts the encrypted fields of a record of `table`, see `ENCRYPTED_FIELDS`.
+fn decrypt_record(cipher: &Cipher, table: &str, mut record: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    for (_, field) in ENCRYPTED_FIELDS.iter().filter(|(encrypted, _)| *encrypted == table) {
+        if let Some(serde_json::Value::String(value)) = record.get_mut(*field) {
+            *value = cipher.decrypt(value)?;
+        }
+    }
+    Ok(record)
+}
+
+impl Decrypt for TrashItem {
+    fn decrypt(self, cipher: &Cipher) -> anyhow::Result<Self> {
+        let record = decrypt_record(cipher, &self.table, self.record)?;
+        Ok(Self { record, ..self })
+    }
+}
+
+#[async_trait]
+impl TrashRepository for Encrypted<dyn TrashRepository> {
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<TrashItem>> {
+        Ok(self.inner.list(owner, profile).await?.decrypt(&self.cipher)?)
+    }
+
+    async fn restore(&self, actor: &str, table: &str, id: &str, owner: &str, cascade: bool)
+        -> Result<Option<serde_json::Value>>
+    {
+        let record = self.inner.restore(actor, table, id, owner, cascade).await?;
+        Ok(record.map(|record| decrypt_record(&self.cipher, table, record)).transpose()?)
+    }
+
+    async fn purge(&self, actor: &str, table: &str, id: &str, owner: &str, cascade: bool)
+        -> Result<Option<serde_json::Value>>
+    {
+        let record = self.inner.purge(actor, table, id, owner, cascade).await?;
+        Ok(record.map(|record| decrypt_record(&self.cipher, table, record)).transpose()?)
+    }
+}
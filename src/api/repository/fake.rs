@@ -0,0 +1,1524 @@
+use async_trait::async_trait;
+use std::sync::{Mutex, MutexGuard};
+use surrealdb::sql::{ Datetime, Id, Thing };
+
+use crate::api::error::Error;
+use crate::api::Result;
+use crate::api::repository::dose::{CreateDose, Dose, DoseList, DoseRepository};
+use crate::api::repository::history::Version;
+use crate::api::repository::medication::{CreateMedication, Medication, MedicationRepository};
+use crate::api::repository::note::{CreateNote, DoseNote, MedicationNote, Note, NoteRepository, StoreNote};
+use crate::api::repository::reference::REFERENCES;
+use crate::api::repository::reminder::{CreateReminder, Reminder, ReminderRepository};
+use crate::api::repository::store::{CreateStore, RefillStore, Store, StoreList, StoreRepository};
+use crate::api::repository::trash::{TrashItem, TrashRepository};
+use crate::api::repository::uom::{CreateUnitOfMeasure, UnitOfMeasure, UnitOfMeasureRepository};
+
+/// The repositories of all the aggregates in memory, for unit tests.
+///
+/// It fills in the fields SurrealDB would, the ids, timestamps, defaults and the profile taken from the linked
+/// record, and joins the list views the same way as the `fn::list_*` functions of the schema. Nothing is
+/// validated beyond that and the stock of the stores, and nothing is written to the audit log. Deleted records
+/// are kept in a trash of their own, with their dependents, following the same rules as `reference`, and the
+/// updates keep the earlier versions in a history like that of `history`.
+#[derive(Default)]
+pub struct FakeRepository {
+    tables: Mutex<Tables>,
+}
+
+#[derive(Default)]
+struct Tables {
+    medications: Vec<Medication>,
+    stores: Vec<Store>,
+    doses: Vec<Dose>,
+    reminders: Vec<Reminder>,
+    notes: Vec<Note>,
+    units: Vec<UnitOfMeasure>,
+    trash: Vec<Trashed>,
+    history: Vec<Revision>,
+}
+
+/// A record of any of the aggregates, as kept in the trash.
+#[derive(Clone)]
+enum Record {
+    Medication(Medication),
+    Store(Store),
+    Dose(Dose),
+    Reminder(Reminder),
+    Note(Note),
+    Unit(UnitOfMeasure),
+}
+
+/// A record in the trash, with when it was deleted.
+struct Trashed {
+    deleted_at: Datetime,
+    record: Record,
+}
+
+/// An earlier version of a record, with when the next one replaced it.
+struct Revision {
+    replaced: Datetime,
+    record: Record,
+}
+
+/// A model the fake keeps as a `Record`.
+trait FromRecord: Sized {
+    fn from_record(record: Record) -> Option<Self>;
+}
+
+impl FromRecord for Medication {
+    fn from_record(record: Record) -> Option<Self> {
+        match record { Record::Medication(m) => Some(m), _ => None }
+    }
+}
+
+impl FromRecord for Store {
+    fn from_record(record: Record) -> Option<Self> {
+        match record { Record::Store(s) => Some(s), _ => None }
+    }
+}
+
+impl FromRecord for Dose {
+    fn from_record(record: Record) -> Option<Self> {
+        match record { Record::Dose(d) => Some(d), _ => None }
+    }
+}
+
+impl FromRecord for Reminder {
+    fn from_record(record: Record) -> Option<Self> {
+        match record { Record::Reminder(r) => Some(r), _ => None }
+    }
+}
+
+impl FromRecord for Note {
+    fn from_record(record: Record) -> Option<Self> {
+        match record { Record::Note(n) => Some(n), _ => None }
+    }
+}
+
+impl Record {
+    fn table(&self) -> &'static str {
+        match self {
+            Record::Medication(_) => "medication",
+            Record::Store(_) => "store",
+            Record::Dose(_) => "dose",
+            Record::Reminder(_) => "reminder",
+            Record::Note(_) => "note",
+            Record::Unit(_) => "unit_of_measure",
+        }
+    }
+
+    fn id(&self) -> Option<&Thing> {
+        match self {
+            Record::Medication(m) => Some(&m.id),
+            Record::Store(s) => Some(&s.id),
+            Record::Dose(d) => Some(&d.id),
+            Record::Reminder(r) => Some(&r.id),
+            Record::Note(n) => n.id.as_ref(),
+            Record::Unit(u) => u.id.as_ref(),
+        }
+    }
+
+    fn user(&self) -> Option<&Thing> {
+        match self {
+            Record::Medication(m) => Some(&m.user),
+            Record::Store(s) => Some(&s.user),
+            Record::Dose(d) => Some(&d.user),
+            Record::Reminder(r) => r.user.as_ref(),
+            Record::Note(n) => n.user.as_ref(),
+            Record::Unit(u) => Some(&u.user),
+        }
+    }
+
+    /// The version of the record, its `updated`.
+    fn version(&self) -> &Datetime {
+        match self {
+            Record::Medication(m) => &m.updated,
+            Record::Store(s) => &s.updated,
+            Record::Dose(d) => &d.updated,
+            Record::Reminder(r) => &r.updated,
+            Record::Note(n) => &n.updated,
+            Record::Unit(u) => &u.updated,
+        }
+    }
+
+    /// Makes the record a new version.
+    fn touch(&mut self) {
+        let updated = now();
+        match self {
+            Record::Medication(m) => m.updated = updated,
+            Record::Store(s) => s.updated = updated,
+            Record::Dose(d) => d.updated = updated,
+            Record::Reminder(r) => r.updated = updated,
+            Record::Note(n) => n.updated = updated,
+            Record::Unit(u) => u.updated = updated,
+        }
+    }
+
+    fn profile(&self) -> Option<&Thing> {
+        match self {
+            Record::Medication(m) => Some(&m.profile),
+            Record::Store(s) => Some(&s.profile),
+            Record::Dose(d) => Some(&d.profile),
+            Record::Reminder(r) => r.profile.as_ref(),
+            Record::Note(n) => n.profile.as_ref(),
+            Record::Unit(_) => None,
+        }
+    }
+
+    /// The record this one references, see `crate::api::repository::reference`, or the one a note is about.
+    fn parent(&self) -> Option<Thing> {
+        match self {
+            Record::Store(s) => Some(s.medication.clone()),
+            Record::Dose(d) => Some(d.store.clone()),
+            Record::Reminder(r) => Some(r.medication.clone()),
+            Record::Note(n) => Some(thing(&n.note_table, &n.note_thing)),
+            Record::Medication(_) | Record::Unit(_) => None,
+        }
+    }
+
+    /// The record as JSON, with the `deleted_at` it has in the trash.
+    fn to_json(&self, deleted_at: Option<&Datetime>) -> Result<serde_json::Value> {
+        let mut json = match self {
+            Record::Medication(m) => serde_json::to_value(m),
+            Record::Store(s) => serde_json::to_value(s),
+            Record::Dose(d) => serde_json::to_value(d),
+            Record::Reminder(r) => serde_json::to_value(r),
+            Record::Note(n) => serde_json::to_value(n),
+            Record::Unit(u) => serde_json::to_value(u),
+        }.map_err(anyhow::Error::from)?;
+        if let (Some(deleted_at), serde_json::Value::Object(fields)) = (deleted_at, &mut json) {
+            fields.insert("deleted_at".to_owned(), serde_json::to_value(deleted_at).map_err(anyhow::Error::from)?);
+        }
+        Ok(json)
+    }
+}
+
+impl FakeRepository {
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        // A test that panicked while holding the lock has failed already, the records are still usable.
+        self.tables.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Tables {
+    fn medication(&self, id: &Thing) -> Option<&Medication> {
+        self.medications.iter().find(|m| &m.id == id)
+    }
+
+    fn store(&self, id: &Thing) -> Option<&Store> {
+        self.stores.iter().find(|s| &s.id == id)
+    }
+
+    fn dose(&self, id: &Thing) -> Option<&Dose> {
+        self.doses.iter().find(|d| &d.id == id)
+    }
+
+    /// The profile of the record a note relates to, if it is one the fake keeps.
+    fn profile_of(&self, table: &str, id: &str) -> Option<Thing> {
+        let id = thing(table, id);
+        match table {
+            "medication" => self.medication(&id).map(|m| m.profile.clone()),
+            "store" => self.store(&id).map(|s| s.profile.clone()),
+            "dose" => self.dose(&id).map(|d| d.profile.clone()),
+            _ => None,
+        }
+    }
+
+    fn store_list(&self, store: &Store) -> Option<StoreList> {
+        let medication = self.medication(&store.medication)?;
+        Some(StoreList {
+            medication_id: medication.id.clone(),
+            medication_name: medication.name.clone(),
+            store_active: store.active,
+            store_created: store.created.clone(),
+            store_expiration_date: store.expiration_date.clone(),
+            store_id: store.id.clone(),
+            store_lot_number: store.lot_number.clone(),
+            store_production_date: store.production_date.clone(),
+            store_start_quantity: store.quantity,
+            store_unit: store.unit.clone(),
+            store_updated: store.updated.clone(),
+            user: store.user.clone(),
+        })
+    }
+
+    fn dose_list(&self, dose: &Dose) -> Option<DoseList> {
+        let store = self.store(&dose.store)?;
+        let medication = self.medication(&store.medication)?;
+        Some(DoseList {
+            created: dose.created.clone(),
+            id: dose.id.clone(),
+            dose_quantity: dose.quantity,
+            dose_unit: dose.unit.clone(),
+            medication_id: medication.id.clone(),
+            medication_name: medication.name.clone(),
+            store_active: store.active,
+            store_created: store.created.clone(),
+            store_id: store.id.clone(),
+            store_production_date: store.production_date.clone(),
+            store_start_quantity: store.quantity,
+            store_unit: store.unit.clone(),
+            store_updated: store.updated.clone(),
+            updated: dose.updated.clone(),
+            user: dose.user.clone(),
+        })
+    }
+
+    fn dose_note(&self, note: &Note) -> Option<DoseNote> {
+        let dose = self.dose(&thing(&note.note_table, &note.note_thing))?;
+        let store = self.store(&dose.store)?;
+        let medication = self.medication(&store.medication)?;
+        Some(DoseNote {
+            content: note.content.clone(),
+            created: note.created.clone()?,
+            dose_created: dose.created.to_raw(),
+            dose_id: dose.id.clone(),
+            dose_quantity: dose.quantity,
+            dose_updated: dose.updated.to_raw(),
+            id: note.id.clone()?,
+            medication_id: medication.id.clone(),
+            medication_name: medication.name.clone(),
+            note_table: note.note_table.clone(),
+            note_thing: note.note_thing.clone(),
+            store_id: store.id.clone(),
+            store_production_date: store.production_date.clone(),
+            store_start_quantity: store.quantity,
+            unit: dose.unit.clone(),
+            updated: note.updated.clone(),
+            user: dose.user.clone(),
+        })
+    }
+
+    fn medication_note(&self, note: &Note) -> Option<MedicationNote> {
+        let medication = self.medication(&thing(&note.note_table, &note.note_thing))?;
+        Some(MedicationNote {
+            id: note.id.clone()?,
+            content: note.content.clone(),
+            created: note.created.clone()?,
+            medication_active: medication.active.unwrap_or(true),
+            medication_id: medication.id.clone(),
+            medication_name: medication.name.clone(),
+            note_table: note.note_table.clone(),
+            note_thing: note.note_thing.clone(),
+            updated: note.updated.clone(),
+            user: medication.user.clone(),
+        })
+    }
+
+    fn store_note(&self, note: &Note) -> Option<StoreNote> {
+        let store = self.store(&thing(&note.note_table, &note.note_thing))?;
+        let medication = self.medication(&store.medication)?;
+        Some(StoreNote {
+            id: note.id.clone()?,
+            content: note.content.clone(),
+            created: note.created.clone()?,
+            medication_id: medication.id.clone(),
+            medication_name: medication.name.clone(),
+            note_table: note.note_table.clone(),
+            note_thing: note.note_thing.clone(),
+            store_active: store.active,
+            store_created: store.created.to_raw(),
+            store_id: store.id.clone(),
+            store_production_date: store.production_date.clone(),
+            store_start_quantity: store.quantity,
+            store_updated: store.updated.to_raw(),
+            unit: store.unit.clone(),
+            updated: note.updated.clone(),
+            user: store.user.clone(),
+        })
+    }
+
+    /// The notes on one table, of a profile or of all profiles of `owner`, oldest first.
+    fn notes_on(&self, table: &str, owner: &str, profile: Option<&str>) -> Vec<&Note> {
+        let user = user(owner);
+        let profile = profile.map(|p| thing("profile", p));
+        let mut notes: Vec<&Note> = self.notes.iter()
+            .filter(|n| n.note_table == table)
+            .filter(|n| match &profile {
+                Some(profile) => n.profile.as_ref() == Some(profile),
+                None => n.user.as_ref() == Some(&user),
+            })
+            .collect();
+        notes.sort_by(|a, b| a.created.cmp(&b.created));
+        notes
+    }
+
+    /// The records depending on `record`, and on each other, except the notes, see
+    /// `crate::api::repository::reference`.
+    fn dependents(&self, record: &Thing) -> Vec<Thing> {
+        let mut found = vec![record.clone()];
+        let mut index = 0;
+        while let Some(parent) = found.get(index).cloned() {
+            found.extend(self.stores.iter().filter(|s| s.medication == parent).map(|s| s.id.clone()));
+            found.extend(self.reminders.iter().filter(|r| r.medication == parent).map(|r| r.id.clone()));
+            found.extend(self.doses.iter().filter(|d| d.store == parent).map(|d| d.id.clone()));
+            index += 1;
+        }
+        found.split_off(1)
+    }
+
+    /// The records in the trash depending on `record`, and on each other, notes included, limited to the ones
+    /// deleted at `deleted_at` if it is given.
+    fn trashed_dependents(&self, record: &Thing, deleted_at: Option<&Datetime>) -> Vec<Thing> {
+        let mut found = vec![record.clone()];
+        let mut index = 0;
+        while let Some(parent) = found.get(index).cloned() {
+            found.extend(self.trash.iter()
+                .filter(|t| deleted_at.is_none_or(|d| &t.deleted_at == d))
+                .filter(|t| t.record.parent().as_ref() == Some(&parent))
+                .filter_map(|t| t.record.id().cloned()));
+            index += 1;
+        }
+        found.split_off(1)
+    }
+
+    /// Takes a record out of its table.
+    fn take(&mut self, id: &Thing) -> Option<Record> {
+        fn remove<T>(records: &mut Vec<T>, found: impl Fn(&T) -> bool) -> Option<T> {
+            records.iter().position(found).map(|i| records.remove(i))
+        }
+        match id.tb.as_str() {
+            "medication" => remove(&mut self.medications, |m| &m.id == id).map(Record::Medication),
+            "store" => remove(&mut self.stores, |s| &s.id == id).map(Record::Store),
+            "dose" => remove(&mut self.doses, |d| &d.id == id).map(Record::Dose),
+            "reminder" => remove(&mut self.reminders, |r| &r.id == id).map(Record::Reminder),
+            "note" => remove(&mut self.notes, |n| n.id.as_ref() == Some(id)).map(Record::Note),
+            "unit_of_measure" => remove(&mut self.units, |u| u.id.as_ref() == Some(id)).map(Record::Unit),
+            _ => None,
+        }
+    }
+
+    /// A copy of a record outside the trash.
+    fn find(&self, id: &Thing) -> Option<Record> {
+        match id.tb.as_str() {
+            "medication" => self.medication(id).cloned().map(Record::Medication),
+            "store" => self.store(id).cloned().map(Record::Store),
+            "dose" => self.dose(id).cloned().map(Record::Dose),
+            "reminder" => self.reminders.iter().find(|r| &r.id == id).cloned().map(Record::Reminder),
+            "note" => self.notes.iter().find(|n| n.id.as_ref() == Some(id)).cloned().map(Record::Note),
+            "unit_of_measure" => self.units.iter().find(|u| u.id.as_ref() == Some(id)).cloned().map(Record::Unit),
+            _ => None,
+        }
+    }
+
+    /// Puts a record back into its table.
+    fn put(&mut self, record: Record) {
+        match record {
+            Record::Medication(m) => self.medications.push(m),
+            Record::Store(s) => self.stores.push(s),
+            Record::Dose(d) => self.doses.push(d),
+            Record::Reminder(r) => self.reminders.push(r),
+            Record::Note(n) => self.notes.push(n),
+            Record::Unit(u) => self.units.push(u),
+        }
+    }
+
+    /// Moves `record`, its dependents and the notes about any of them to the trash, unless it has dependents
+    /// and `cascade` is off.
+    fn move_to_trash(&mut self, record: &Thing, cascade: bool) -> Result<()> {
+        let mut deleted = self.dependents(record);
+        if !cascade && !deleted.is_empty() {
+            return Err(Error::Conflict { dependents: deleted });
+        }
+        deleted.push(record.clone());
+        let notes: Vec<Thing> = self.notes.iter()
+            .filter(|n| deleted.contains(&thing(&n.note_table, &n.note_thing)))
+            .filter_map(|n| n.id.clone())
+            .collect();
+        deleted.extend(notes);
+
+        let deleted_at = now();
+        for id in deleted {
+            if let Some(record) = self.take(&id) {
+                self.trash.push(Trashed { deleted_at: deleted_at.clone(), record });
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the version an update replaced in the history, if the update found the record.
+    fn keep(&mut self, before: Option<Record>) {
+        if let Some(record) = before {
+            self.history.push(Revision { replaced: now(), record });
+        }
+    }
+
+    /// The earlier versions of a record of `owner`, oldest first.
+    fn versions<T: FromRecord>(&self, id: &Thing, owner: &str) -> Vec<Version<T>> {
+        let user = user(owner);
+        let mut versions: Vec<Version<T>> = self.history.iter()
+            .filter(|r| r.record.id() == Some(id) && r.record.user() == Some(&user))
+            .filter_map(|r| Some(Version {
+                version: r.record.version().clone(),
+                replaced: r.replaced.clone(),
+                record: T::from_record(r.record.clone())?,
+            }))
+            .collect();
+        versions.sort_by(|a, b| a.version.cmp(&b.version));
+        versions
+    }
+
+    /// Reverts a record of `owner` to its version `to` if it is still at `version`, like `revert_record()` of the
+    /// `SurrealRepository`: the records the version references must still exist, a dose must fit in its store,
+    /// and a reminder keeps when it was acknowledged.
+    fn revert<T: FromRecord>(&mut self, id: &Thing, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<T>> {
+        let user = user(owner);
+        let mut reverted = self.history.iter()
+            .map(|r| &r.record)
+            .find(|r| r.id() == Some(id) && r.user() == Some(&user) && r.version() == to)
+            .cloned()
+            .ok_or(Error::NotFound)?;
+        let Some(current) = self.find(id).filter(|r| r.user() == Some(&user) && r.version() == version) else {
+            return Ok(None);
+        };
+        if let Some(parent) = reverted.parent() {
+            if self.find(&parent).is_none_or(|p| p.user() != Some(&user)) {
+                let field = REFERENCES.iter()
+                    .find(|reference| reference.table == reverted.table())
+                    .map_or("note_thing", |reference| reference.field);
+                return Err(Error::unprocessable_entity([(field, "was not found")]));
+            }
+        }
+        match (&mut reverted, &current) {
+            (Record::Dose(dose), Record::Dose(taken_by)) => {
+                self.take_from_store(&dose.store, Some(taken_by), dose.quantity, &dose.unit)?;
+                self.give_back_to_store(&dose.store, taken_by);
+            }
+            (Record::Reminder(reminder), Record::Reminder(current)) => {
+                reminder.acknowledged = current.acknowledged.clone();
+            }
+            _ => {}
+        }
+        reverted.touch();
+
+        self.take(id);
+        self.put(reverted.clone());
+        self.keep(Some(current));
+        Ok(T::from_record(reverted))
+    }
+
+    /// The index of a record of `owner` in the trash.
+    fn trashed(&self, table: &str, id: &str, owner: &str) -> Option<usize> {
+        let (id, user) = (thing(table, id), user(owner));
+        self.trash.iter().position(|t| t.record.id() == Some(&id) && t.record.user() == Some(&user))
+    }
+
+    /// Takes the records out of the trash.
+    fn take_trashed(&mut self, ids: &[Thing]) -> Vec<Record> {
+        let (taken, kept) = std::mem::take(&mut self.trash)
+            .into_iter()
+            .partition(|t| t.record.id().is_some_and(|id| ids.contains(id)));
+        self.trash = kept;
+        taken.into_iter().map(|t: Trashed| t.record).collect()
+    }
+
+    /// Takes a dose from its store like `take_from_store()` of the `SurrealRepository`, leaving out what the
+    /// dose `taken_by`, being updated or restored, took before.
+    fn take_from_store(&mut self, store: &Thing, taken_by: Option<&Dose>, quantity: f32, unit: &str) -> Result<()> {
+        let taken: f32 = self.doses.iter()
+            .filter(|d| &d.store == store && Some(&d.id) != taken_by.map(|t| &t.id))
+            .map(|d| d.quantity)
+            .sum();
+        let own_store = taken_by.is_some_and(|t| &t.store == store);
+        let store = self.stores.iter_mut()
+            .find(|s| &s.id == store)
+            .ok_or_else(|| Error::unprocessable_entity([("store", "was not found")]))?;
+        if !store.active && !own_store {
+            return Err(Error::unprocessable_entity([("store", "is not active")]));
+        }
+        if unit != store.unit {
+            return Err(Error::unprocessable_entity([("unit", "must be the unit of the store")]));
+        }
+        if quantity > store.quantity - taken {
+            return Err(Error::unprocessable_entity([("quantity", "is more than is left in the store")]));
+        }
+        if quantity >= store.quantity - taken {
+            store.active = false;
+        }
+        Ok(())
+    }
+
+    /// Gives back to its store what the dose `taken_by` took, if it is moved to `store`, like
+    /// `give_back_to_store()` of the `SurrealRepository`.
+    fn give_back_to_store(&mut self, store: &Thing, taken_by: &Dose) {
+        if &taken_by.store == store {
+            return;
+        }
+        let taken: f32 = self.doses.iter()
+            .filter(|d| d.store == taken_by.store && d.id != taken_by.id)
+            .map(|d| d.quantity)
+            .sum();
+        if let Some(former) = self.stores.iter_mut().find(|s| s.id == taken_by.store) {
+            let left = former.quantity - taken;
+            if !former.active && left - taken_by.quantity <= 0.0 && left > 0.0 {
+                former.active = true;
+            }
+        }
+    }
+
+    /// The notes of `owner` on one record, oldest first.
+    fn notes_for(&self, table: &str, id: &str, owner: &str) -> Vec<&Note> {
+        let user = user(owner);
+        let mut notes: Vec<&Note> = self.notes.iter()
+            .filter(|n| n.note_table == table && n.note_thing == id && n.user.as_ref() == Some(&user))
+            .collect();
+        notes.sort_by(|a, b| a.created.cmp(&b.created));
+        notes
+    }
+}
+
+fn thing(table: &str, id: &str) -> Thing {
+    Thing::from((table, id))
+}
+
+fn user(owner: &str) -> Thing {
+    thing("user", owner)
+}
+
+fn new_id(table: &str) -> Thing {
+    Thing::from((table, Id::rand()))
+}
+
+fn now() -> Datetime {
+    Datetime::default()
+}
+
+/// Whether a record of `owner` is in the given profile, or in any of them if none is given.
+fn in_scope(record_user: &Thing, record_profile: Option<&Thing>, owner: &str, profile: Option<&str>) -> bool {
+    *record_user == user(owner) && profile.is_none_or(|p| record_profile == Some(&thing("profile", p)))
+}
+
+#[async_trait]
+impl MedicationRepository for FakeRepository {
+    async fn create(&self, _actor: &str, owner: &str, profile: &str, medication: CreateMedication)
+        -> Result<Option<Medication>> {
+        let created = now();
+        let medication = Medication {
+            id: new_id("medication"),
+            user: user(owner),
+            profile: thing("profile", profile),
+            name: medication.name,
+            created: Some(created.clone()),
+            updated: created,
+            active: Some(true),
+        };
+        self.tables().medications.push(medication.clone());
+        Ok(Some(medication))
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Medication>> {
+        let (id, user) = (thing("medication", id), user(owner));
+        Ok(self.tables().medications.iter().find(|m| m.id == id && m.user == user).cloned())
+    }
+
+    async fn update(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, medication: CreateMedication)
+        -> Result<Option<Medication>> {
+        let (id, user) = (thing("medication", id), user(owner));
+        let mut tables = self.tables();
+        let found = tables.medications.iter_mut()
+            .find(|m| m.id == id && m.user == user && m.updated == *version);
+        let before = found.as_deref().cloned().map(Record::Medication);
+        let updated = found.map(|m| {
+            m.name = medication.name;
+            m.updated = now();
+            m.clone()
+        });
+        tables.keep(before);
+        Ok(updated)
+    }
+
+    async fn deactivate(&self, _actor: &str, id: &str, owner: &str, version: &Datetime)
+        -> Result<Option<Medication>> {
+        let (id, user) = (thing("medication", id), user(owner));
+        let mut tables = self.tables();
+        let found = tables.medications.iter_mut()
+            .find(|m| m.id == id && m.user == user && m.updated == *version);
+        let before = found.as_deref().cloned().map(Record::Medication);
+        let updated = found.map(|m| {
+            m.active = Some(false);
+            m.updated = now();
+            m.clone()
+        });
+        tables.keep(before);
+        Ok(updated)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Medication>>> {
+        Ok(self.tables().versions(&thing("medication", id), owner))
+    }
+
+    async fn revert(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Medication>> {
+        self.tables().revert(&thing("medication", id), owner, version, to)
+    }
+
+    async fn delete(&self, _actor: &str, id: &str, owner: &str, cascade: bool) -> Result<Option<Medication>> {
+        let (id, user) = (thing("medication", id), user(owner));
+        let mut tables = self.tables();
+        let Some(medication) = tables.medications.iter().find(|m| m.id == id && m.user == user).cloned() else {
+            return Ok(None);
+        };
+        tables.move_to_trash(&id, cascade)?;
+        Ok(Some(medication))
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>, active: Option<bool>) -> Result<Vec<Medication>> {
+        let tables = self.tables();
+        // Like the schema functions, a profile alone selects the records, its owner was checked by the caller.
+        let mut medications: Vec<Medication> = tables.medications.iter()
+            .filter(|m| match profile {
+                Some(profile) => m.profile == thing("profile", profile),
+                None => m.user == user(owner),
+            })
+            .filter(|m| active.is_none_or(|a| m.active == Some(a)))
+            .cloned()
+            .collect();
+        medications.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(medications)
+    }
+}
+
+#[async_trait]
+impl StoreRepository for FakeRepository {
+    async fn create(&self, _actor: &str, owner: &str, store: CreateStore) -> Result<Option<Store>> {
+        let mut tables = self.tables();
+        let medication = thing("medication", &store.medication);
+        let profile = tables.medication(&medication).ok_or(Error::NotFound)?.profile.clone();
+        let created = now();
+        let store = Store {
+            id: new_id("store"),
+            user: user(owner),
+            profile,
+            medication,
+            production_date: store.production_date,
+            expiration_date: store.expiration_date,
+            lot_number: store.lot_number,
+            quantity: store.quantity,
+            unit: store.unit,
+            created: created.clone(),
+            updated: created,
+            active: true,
+        };
+        tables.stores.push(store.clone());
+        Ok(Some(store))
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Store>> {
+        let (id, user) = (thing("store", id), user(owner));
+        Ok(self.tables().stores.iter().find(|s| s.id == id && s.user == user).cloned())
+    }
+
+    async fn update(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, store: CreateStore)
+        -> Result<Option<Store>> {
+        let (id, user) = (thing("store", id), user(owner));
+        let mut tables = self.tables();
+        let medication = thing("medication", &store.medication);
+        let profile = tables.medication(&medication).ok_or(Error::NotFound)?.profile.clone();
+        let found = tables.stores.iter_mut().find(|s| s.id == id && s.user == user && s.updated == *version);
+        let before = found.as_deref().cloned().map(Record::Store);
+        let updated = found.map(|s| {
+            s.medication = medication;
+            s.profile = profile;
+            s.production_date = store.production_date;
+            s.expiration_date = store.expiration_date;
+            s.lot_number = store.lot_number;
+            s.quantity = store.quantity;
+            s.unit = store.unit;
+            s.updated = now();
+            s.clone()
+        });
+        tables.keep(before);
+        Ok(updated)
+    }
+
+    async fn deactivate(&self, _actor: &str, id: &str, owner: &str, version: &Datetime) -> Result<Option<Store>> {
+        let (id, user) = (thing("store", id), user(owner));
+        let mut tables = self.tables();
+        let found = tables.stores.iter_mut().find(|s| s.id == id && s.user == user && s.updated == *version);
+        let before = found.as_deref().cloned().map(Record::Store);
+        let updated = found.map(|s| {
+            s.active = false;
+            s.updated = now();
+            s.clone()
+        });
+        tables.keep(before);
+        Ok(updated)
+    }
+
+    async fn refill(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, refill: RefillStore)
+        -> Result<Option<Store>> {
+        let (id, user) = (thing("store", id), user(owner));
+        let mut tables = self.tables();
+        let Some(old) = tables.stores.iter_mut().find(|s| s.id == id && s.user == user && s.updated == *version) else {
+            return Ok(None);
+        };
+        let before = Record::Store(old.clone());
+        old.active = false;
+        old.updated = now();
+        let created = now();
+        let store = Store {
+            id: new_id("store"),
+            production_date: refill.production_date,
+            expiration_date: refill.expiration_date,
+            lot_number: refill.lot_number,
+            quantity: refill.quantity,
+            unit: refill.unit,
+            created: created.clone(),
+            updated: created,
+            active: true,
+            ..old.clone()
+        };
+        tables.stores.push(store.clone());
+        tables.keep(Some(before));
+        Ok(Some(store))
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Store>>> {
+        Ok(self.tables().versions(&thing("store", id), owner))
+    }
+
+    async fn revert(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Store>> {
+        self.tables().revert(&thing("store", id), owner, version, to)
+    }
+
+    async fn delete(&self, _actor: &str, id: &str, owner: &str, cascade: bool) -> Result<Option<Store>> {
+        let (id, user) = (thing("store", id), user(owner));
+        let mut tables = self.tables();
+        let Some(store) = tables.stores.iter().find(|s| s.id == id && s.user == user).cloned() else {
+            return Ok(None);
+        };
+        tables.move_to_trash(&id, cascade)?;
+        Ok(Some(store))
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<Store>> {
+        let tables = self.tables();
+        let mut stores: Vec<Store> = tables.stores.iter()
+            .filter(|s| in_scope(&s.user, Some(&s.profile), owner, profile))
+            .cloned()
+            .collect();
+        stores.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(stores)
+    }
+
+    async fn list_for_medication(&self, owner: &str, medication: &str, active: Option<bool>)
+        -> Result<Vec<StoreList>> {
+        let (medication, user) = (thing("medication", medication), user(owner));
+        let tables = self.tables();
+        let mut stores: Vec<&Store> = tables.stores.iter()
+            .filter(|s| s.medication == medication && s.user == user)
+            .filter(|s| active.is_none_or(|a| s.active == a))
+            .collect();
+        stores.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(stores.into_iter().filter_map(|s| tables.store_list(s)).collect())
+    }
+}
+
+#[async_trait]
+impl DoseRepository for FakeRepository {
+    async fn create(&self, _actor: &str, owner: &str, dose: CreateDose) -> Result<Option<Dose>> {
+        let mut tables = self.tables();
+        let store = thing("store", &dose.store);
+        let profile = tables.store(&store).ok_or(Error::NotFound)?.profile.clone();
+        tables.take_from_store(&store, None, dose.quantity, &dose.unit)?;
+        let created = now();
+        let dose = Dose {
+            id: new_id("dose"),
+            user: user(owner),
+            profile,
+            store,
+            quantity: dose.quantity,
+            unit: dose.unit,
+            created: created.clone(),
+            updated: created,
+        };
+        tables.doses.push(dose.clone());
+        Ok(Some(dose))
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Dose>> {
+        let (id, user) = (thing("dose", id), user(owner));
+        Ok(self.tables().doses.iter().find(|d| d.id == id && d.user == user).cloned())
+    }
+
+    async fn update(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, dose: CreateDose)
+        -> Result<Option<Dose>> {
+        let (id, user) = (thing("dose", id), user(owner));
+        let mut tables = self.tables();
+        let store = thing("store", &dose.store);
+        let profile = tables.store(&store).ok_or(Error::NotFound)?.profile.clone();
+        let taken_by = tables.doses.iter().find(|d| d.id == id && d.user == user && d.updated == *version).cloned();
+        if let Some(taken_by) = taken_by {
+            tables.take_from_store(&store, Some(&taken_by), dose.quantity, &dose.unit)?;
+            tables.give_back_to_store(&store, &taken_by);
+        }
+        let found = tables.doses.iter_mut().find(|d| d.id == id && d.user == user && d.updated == *version);
+        let before = found.as_deref().cloned().map(Record::Dose);
+        let updated = found.map(|d| {
+            d.store = store;
+            d.profile = profile;
+            d.quantity = dose.quantity;
+            d.unit = dose.unit;
+            d.updated = now();
+            d.clone()
+        });
+        tables.keep(before);
+        Ok(updated)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Dose>>> {
+        Ok(self.tables().versions(&thing("dose", id), owner))
+    }
+
+    async fn revert(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Dose>> {
+        self.tables().revert(&thing("dose", id), owner, version, to)
+    }
+
+    async fn delete(&self, _actor: &str, id: &str, owner: &str) -> Result<Option<Dose>> {
+        let (id, user) = (thing("dose", id), user(owner));
+        let mut tables = self.tables();
+        let Some(dose) = tables.doses.iter().find(|d| d.id == id && d.user == user).cloned() else {
+            return Ok(None);
+        };
+        tables.move_to_trash(&id, false)?;
+        Ok(Some(dose))
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<DoseList>> {
+        let tables = self.tables();
+        let mut doses: Vec<&Dose> = tables.doses.iter()
+            .filter(|d| match profile {
+                Some(profile) => d.profile == thing("profile", profile),
+                None => d.user == user(owner),
+            })
+            .collect();
+        doses.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(doses.into_iter().filter_map(|d| tables.dose_list(d)).collect())
+    }
+
+    async fn list_for_medication(&self, owner: &str, medication: &str) -> Result<Vec<DoseList>> {
+        let (medication, user) = (thing("medication", medication), user(owner));
+        let tables = self.tables();
+        let mut doses: Vec<&Dose> = tables.doses.iter()
+            .filter(|d| d.user == user && tables.store(&d.store).is_some_and(|s| s.medication == medication))
+            .collect();
+        doses.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(doses.into_iter().filter_map(|d| tables.dose_list(d)).collect())
+    }
+
+    async fn list_for_store(&self, owner: &str, store: &str) -> Result<Vec<DoseList>> {
+        let (store, user) = (thing("store", store), user(owner));
+        let tables = self.tables();
+        let mut doses: Vec<&Dose> = tables.doses.iter()
+            .filter(|d| d.store == store && d.user == user)
+            .collect();
+        doses.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(doses.into_iter().filter_map(|d| tables.dose_list(d)).collect())
+    }
+}
+
+#[async_trait]
+impl ReminderRepository for FakeRepository {
+    async fn create(&self, _actor: &str, owner: &str, reminder: CreateReminder) -> Result<Option<Reminder>> {
+        let mut tables = self.tables();
+        let medication = thing("medication", &reminder.medication);
+        let profile = tables.medication(&medication).ok_or(Error::NotFound)?.profile.clone();
+        let created = now();
+        let reminder = Reminder {
+            acknowledged: None,
+            active: true,
+            created: created.clone(),
+            days: reminder.days,
+            end: reminder.end,
+            id: new_id("reminder"),
+            medication,
+            start: created.clone(),
+            times: reminder.times,
+            updated: created,
+            user: Some(user(owner)),
+            profile: Some(profile),
+        };
+        tables.reminders.push(reminder.clone());
+        Ok(Some(reminder))
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Reminder>> {
+        let (id, user) = (thing("reminder", id), Some(user(owner)));
+        Ok(self.tables().reminders.iter().find(|r| r.id == id && r.user == user).cloned())
+    }
+
+    async fn update(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, reminder: CreateReminder)
+        -> Result<Option<Reminder>> {
+        let (id, user) = (thing("reminder", id), Some(user(owner)));
+        let mut tables = self.tables();
+        let medication = thing("medication", &reminder.medication);
+        let profile = tables.medication(&medication).ok_or(Error::NotFound)?.profile.clone();
+        let found = tables.reminders.iter_mut().find(|r| r.id == id && r.user == user && r.updated == *version);
+        let before = found.as_deref().cloned().map(Record::Reminder);
+        let updated = found.map(|r| {
+            r.medication = medication;
+            r.profile = Some(profile);
+            r.end = reminder.end;
+            r.days = reminder.days;
+            r.times = reminder.times;
+            r.updated = now();
+            r.clone()
+        });
+        tables.keep(before);
+        Ok(updated)
+    }
+
+    async fn deactivate(&self, _actor: &str, id: &str, owner: &str, version: &Datetime) -> Result<Option<Reminder>> {
+        let (id, user) = (thing("reminder", id), Some(user(owner)));
+        let mut tables = self.tables();
+        let found = tables.reminders.iter_mut().find(|r| r.id == id && r.user == user && r.updated == *version);
+        let before = found.as_deref().cloned().map(Record::Reminder);
+        let updated = found.map(|r| {
+            r.active = false;
+            r.updated = now();
+            r.clone()
+        });
+        tables.keep(before);
+        Ok(updated)
+    }
+
+    async fn acknowledge(&self, _actor: &str, id: &str, owner: &str) -> Result<Option<Reminder>> {
+        let (id, user) = (thing("reminder", id), Some(user(owner)));
+        let mut tables = self.tables();
+        Ok(tables.reminders.iter_mut().find(|r| r.id == id && r.user == user).map(|r| {
+            r.acknowledged = Some(now());
+            r.clone()
+        }))
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Reminder>>> {
+        Ok(self.tables().versions(&thing("reminder", id), owner))
+    }
+
+    async fn revert(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Reminder>> {
+        self.tables().revert(&thing("reminder", id), owner, version, to)
+    }
+
+    async fn delete(&self, _actor: &str, id: &str, owner: &str) -> Result<Option<Reminder>> {
+        let (id, user) = (thing("reminder", id), Some(user(owner)));
+        let mut tables = self.tables();
+        let Some(reminder) = tables.reminders.iter().find(|r| r.id == id && r.user == user).cloned() else {
+            return Ok(None);
+        };
+        tables.move_to_trash(&reminder.id, false)?;
+        Ok(Some(reminder))
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>, active: Option<bool>) -> Result<Vec<Reminder>> {
+        let tables = self.tables();
+        Ok(tables.reminders.iter()
+            .filter(|r| r.user.as_ref().is_some_and(|u| in_scope(u, r.profile.as_ref(), owner, profile)))
+            .filter(|r| active.is_none_or(|a| r.active == a))
+            .cloned()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl NoteRepository for FakeRepository {
+    async fn create(&self, _actor: &str, owner: &str, note: CreateNote) -> Result<Option<Note>> {
+        let mut tables = self.tables();
+        let created = now();
+        let note = Note {
+            id: Some(new_id("note")),
+            user: Some(user(owner)),
+            profile: tables.profile_of(&note.note_table, &note.note_thing),
+            note_table: note.note_table,
+            note_thing: note.note_thing,
+            content: note.content,
+            created: Some(created.clone()),
+            updated: created,
+        };
+        tables.notes.push(note.clone());
+        Ok(Some(note))
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Note>> {
+        let (id, user) = (Some(thing("note", id)), Some(user(owner)));
+        Ok(self.tables().notes.iter().find(|n| n.id == id && n.user == user).cloned())
+    }
+
+    async fn update(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, note: CreateNote)
+        -> Result<Option<Note>> {
+        let (id, user) = (Some(thing("note", id)), Some(user(owner)));
+        let mut tables = self.tables();
+        let profile = tables.profile_of(&note.note_table, &note.note_thing);
+        let found = tables.notes.iter_mut()
+            .find(|n| n.id == id && n.user == user && n.updated == *version);
+        let before = found.as_deref().cloned().map(Record::Note);
+        let updated = found.map(|n| {
+            n.profile = profile;
+            n.note_table = note.note_table;
+            n.note_thing = note.note_thing;
+            n.content = note.content;
+            n.updated = now();
+            n.clone()
+        });
+        tables.keep(before);
+        Ok(updated)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Note>>> {
+        Ok(self.tables().versions(&thing("note", id), owner))
+    }
+
+    async fn revert(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Note>> {
+        self.tables().revert(&thing("note", id), owner, version, to)
+    }
+
+    async fn delete(&self, _actor: &str, id: &str, owner: &str) -> Result<Option<Note>> {
+        let (id, user) = (thing("note", id), Some(user(owner)));
+        let mut tables = self.tables();
+        let Some(note) = tables.notes.iter().find(|n| n.id.as_ref() == Some(&id) && n.user == user).cloned() else {
+            return Ok(None);
+        };
+        tables.move_to_trash(&id, false)?;
+        Ok(Some(note))
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<Note>> {
+        let tables = self.tables();
+        let mut notes: Vec<Note> = tables.notes.iter()
+            .filter(|n| n.user.as_ref().is_some_and(|u| in_scope(u, n.profile.as_ref(), owner, profile)))
+            .cloned()
+            .collect();
+        notes.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(notes)
+    }
+
+    async fn list_dose_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<DoseNote>> {
+        let tables = self.tables();
+        Ok(tables.notes_on("dose", owner, profile).into_iter().filter_map(|n| tables.dose_note(n)).collect())
+    }
+
+    async fn list_notes_for_dose(&self, owner: &str, dose: &str) -> Result<Vec<DoseNote>> {
+        let tables = self.tables();
+        Ok(tables.notes_for("dose", dose, owner).into_iter().filter_map(|n| tables.dose_note(n)).collect())
+    }
+
+    async fn list_medication_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<MedicationNote>> {
+        let tables = self.tables();
+        Ok(tables.notes_on("medication", owner, profile).into_iter()
+            .filter_map(|n| tables.medication_note(n))
+            .collect())
+    }
+
+    async fn list_notes_for_medication(&self, owner: &str, medication: &str) -> Result<Vec<MedicationNote>> {
+        let tables = self.tables();
+        Ok(tables.notes_for("medication", medication, owner).into_iter()
+            .filter_map(|n| tables.medication_note(n))
+            .collect())
+    }
+
+    async fn list_store_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<StoreNote>> {
+        let tables = self.tables();
+        Ok(tables.notes_on("store", owner, profile).into_iter().filter_map(|n| tables.store_note(n)).collect())
+    }
+
+    async fn list_notes_for_store(&self, owner: &str, store: &str) -> Result<Vec<StoreNote>> {
+        let tables = self.tables();
+        Ok(tables.notes_for("store", store, owner).into_iter().filter_map(|n| tables.store_note(n)).collect())
+    }
+}
+
+#[async_trait]
+impl UnitOfMeasureRepository for FakeRepository {
+    async fn create(&self, _actor: &str, owner: &str, unit: CreateUnitOfMeasure) -> Result<Option<UnitOfMeasure>> {
+        let created = now();
+        let unit = UnitOfMeasure {
+            id: Some(new_id("unit_of_measure")),
+            user: user(owner),
+            name: unit.name,
+            abbreviation: unit.abbreviation,
+            created: Some(created.clone()),
+            updated: created,
+            active: Some(true),
+        };
+        self.tables().units.push(unit.clone());
+        Ok(Some(unit))
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<UnitOfMeasure>> {
+        let (id, user) = (Some(thing("unit_of_measure", id)), user(owner));
+        Ok(self.tables().units.iter().find(|u| u.id == id && u.user == user).cloned())
+    }
+
+    async fn update(&self, _actor: &str, id: &str, owner: &str, version: &Datetime, unit: CreateUnitOfMeasure)
+        -> Result<Option<UnitOfMeasure>> {
+        let (id, user) = (Some(thing("unit_of_measure", id)), user(owner));
+        let mut tables = self.tables();
+        let found = tables.units.iter_mut()
+            .find(|u| u.id == id && u.user == user && u.updated == *version);
+        Ok(found.map(|u| {
+            u.name = unit.name;
+            u.abbreviation = unit.abbreviation;
+            u.active = unit.active;
+            u.updated = now();
+            u.clone()
+        }))
+    }
+
+    async fn delete(&self, _actor: &str, id: &str, owner: &str) -> Result<Option<UnitOfMeasure>> {
+        let (id, user) = (thing("unit_of_measure", id), user(owner));
+        let mut tables = self.tables();
+        let Some(unit) = tables.units.iter().find(|u| u.id.as_ref() == Some(&id) && u.user == user).cloned() else {
+            return Ok(None);
+        };
+        tables.move_to_trash(&id, false)?;
+        Ok(Some(unit))
+    }
+
+    async fn list(&self, owner: &str) -> Result<Vec<UnitOfMeasure>> {
+        let user = user(owner);
+        let mut units: Vec<UnitOfMeasure> = self.tables().units.iter()
+            .filter(|u| u.user == user)
+            .cloned()
+            .collect();
+        units.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(units)
+    }
+}
+
+#[async_trait]
+impl TrashRepository for FakeRepository {
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<TrashItem>> {
+        let (user, profile) = (user(owner), profile.map(|p| thing("profile", p)));
+        let tables = self.tables();
+        let mut items = tables.trash.iter()
+            .filter(|t| t.record.user() == Some(&user))
+            .filter(|t| profile.is_none() || t.record.profile().is_none() || t.record.profile() == profile.as_ref())
+            .map(|t| Ok(TrashItem {
+                table: t.record.table().to_owned(),
+                deleted_at: t.deleted_at.clone(),
+                record: t.record.to_json(Some(&t.deleted_at))?,
+            }))
+            .collect::<Result<Vec<_>>>()?;
+        items.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at));
+        Ok(items)
+    }
+
+    async fn restore(&self, _actor: &str, table: &str, id: &str, owner: &str, cascade: bool)
+        -> Result<Option<serde_json::Value>> {
+        let mut tables = self.tables();
+        let Some(index) = tables.trashed(table, id, owner) else {
+            return Ok(None);
+        };
+        let Trashed { deleted_at, record } = &tables.trash[index];
+        let (deleted_at, json, record_id) = (deleted_at.clone(), record.to_json(None)?, thing(table, id));
+        if let Some(parent) = record.parent().filter(|p| tables.trash.iter().any(|t| t.record.id() == Some(p))) {
+            return Err(Error::Conflict { dependents: vec![parent] });
+        }
+        // A restored dose takes from its store again, as if it were logged anew.
+        if let Record::Dose(dose) = record.clone() {
+            tables.take_from_store(&dose.store, Some(&dose), dose.quantity, &dose.unit)?;
+        }
+
+        // The records deleted along with this one have the same `deleted_at`.
+        let mut restored = match cascade {
+            true => tables.trashed_dependents(&record_id, Some(&deleted_at)),
+            false => tables.trash.iter()
+                .filter(|t| t.deleted_at == deleted_at && matches!(t.record, Record::Note(_)))
+                .filter(|t| t.record.parent().as_ref() == Some(&record_id))
+                .filter_map(|t| t.record.id().cloned())
+                .collect(),
+        };
+        restored.push(record_id);
+        for record in tables.take_trashed(&restored) {
+            tables.put(record);
+        }
+        Ok(Some(json))
+    }
+
+    async fn purge(&self, _actor: &str, table: &str, id: &str, owner: &str, cascade: bool)
+        -> Result<Option<serde_json::Value>> {
+        let mut tables = self.tables();
+        let Some(index) = tables.trashed(table, id, owner) else {
+            return Ok(None);
+        };
+        let record = tables.trash[index].record.to_json(None)?;
+        let record_id = thing(table, id);
+        let mut purged = tables.trashed_dependents(&record_id, None);
+        let restricted: Vec<Thing> = purged.iter().filter(|p| p.tb != "note").cloned().collect();
+        if !cascade && !restricted.is_empty() {
+            return Err(Error::Conflict { dependents: restricted });
+        }
+        purged.push(record_id);
+        tables.take_trashed(&purged);
+        // Deleting a record for good deletes its history.
+        tables.history.retain(|r| r.record.id().is_none_or(|id| !purged.contains(id)));
+        Ok(Some(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use surrealdb::sql::{Datetime, Thing};
+
+    use crate::api::error::Error;
+    use crate::api::repository::dose::CreateDose;
+    use crate::api::repository::medication::{CreateMedication, Medication};
+    use crate::api::repository::note::CreateNote;
+    use crate::api::repository::reminder::CreateReminder;
+    use crate::api::repository::store::{CreateStore, Store};
+    use crate::api::repository::{FakeRepository, Repositories};
+
+    const ANN: &str = "ann";
+    const PROFILE: &str = "ann";
+
+    /// Runs each scenario against the fake and against a `SurrealRepository`, so the fake is checked to
+    /// follow the same rules.
+    macro_rules! scenarios {
+        ($($scenario:ident),* $(,)?) => {
+            mod fake {
+                $(#[tokio::test]
+                async fn $scenario() {
+                    super::$scenario(super::Repositories::new(super::FakeRepository::default())).await;
+                })*
+            }
+
+            mod surreal {
+                $(#[tokio::test]
+                async fn $scenario() {
+                    super::$scenario(crate::api::testing::TestApp::new().await.ctx.repos).await;
+                })*
+            }
+        };
+    }
+
+    scenarios!(
+        deleting_a_record_with_dependents_needs_cascade,
+        restoring_needs_the_referenced_records_back_first,
+        purging_a_record_with_dependents_needs_cascade,
+        a_dose_must_fit_in_an_active_store,
+        moving_a_dose_gives_back_to_its_former_store,
+        reverting_restores_every_field_of_the_version,
+        reverting_a_reminder_keeps_when_it_was_acknowledged,
+        reverting_needs_the_referenced_records_and_the_stock,
+    );
+
+    fn key(record: &Thing) -> String {
+        record.id.to_raw()
+    }
+
+    async fn medication(repos: &Repositories, name: &str) -> Medication {
+        let medication = CreateMedication { name: name.to_owned(), created: None, updated: None, active: None };
+        repos.medications.create(ANN, ANN, PROFILE, medication).await.unwrap().unwrap()
+    }
+
+    async fn store(repos: &Repositories, medication: &Medication, quantity: f32) -> Store {
+        let store = CreateStore {
+            medication: key(&medication.id),
+            production_date: Datetime::default(),
+            expiration_date: None,
+            lot_number: "A1".to_owned(),
+            quantity,
+            unit: "mg".to_owned(),
+        };
+        repos.stores.create(ANN, ANN, store).await.unwrap().unwrap()
+    }
+
+    fn dose(store: &Store, quantity: f32, unit: &str) -> CreateDose {
+        CreateDose { id: None, store: key(&store.id), quantity, unit: unit.to_owned() }
+    }
+
+    fn note(table: &str, record: &Thing) -> CreateNote {
+        CreateNote {
+            note_table: table.to_owned(),
+            note_thing: key(record),
+            content: "taken with food".to_owned(),
+            created: None,
+            updated: None,
+        }
+    }
+
+    fn unprocessable(result: Result<impl Sized, Error>, field: &str) -> bool {
+        matches!(result, Err(Error::UnprocessableEntity { errors }) if errors.contains_key(field))
+    }
+
+    async fn deleting_a_record_with_dependents_needs_cascade(repos: Repositories) {
+        let aspirin = medication(&repos, "Aspirin").await;
+        let store = store(&repos, &aspirin, 10.0).await;
+        let dose = repos.doses.create(ANN, ANN, dose(&store, 1.0, "mg")).await.unwrap().unwrap();
+        repos.notes.create(ANN, ANN, note("dose", &dose.id)).await.unwrap();
+
+        let result = repos.medications.delete(ANN, &key(&aspirin.id), ANN, false).await;
+        let Err(Error::Conflict { dependents }) = result else { panic!("deleted with dependents") };
+        assert!(dependents.contains(&store.id) && dependents.contains(&dose.id));
+        assert!(repos.medications.read(&key(&aspirin.id), ANN).await.unwrap().is_some());
+
+        repos.medications.delete(ANN, &key(&aspirin.id), ANN, true).await.unwrap().unwrap();
+        let tables: Vec<String> = repos.trash.list(ANN, None).await.unwrap().into_iter().map(|t| t.table).collect();
+        assert_eq!(tables.len(), 4);
+        for table in ["medication", "store", "dose", "note"] {
+            assert!(tables.iter().any(|t| t == table), "no {table} in the trash");
+        }
+        assert!(repos.doses.read(&key(&dose.id), ANN).await.unwrap().is_none());
+        assert!(repos.notes.list(ANN, None).await.unwrap().is_empty());
+    }
+
+    async fn restoring_needs_the_referenced_records_back_first(repos: Repositories) {
+        let aspirin = medication(&repos, "Aspirin").await;
+        let store = store(&repos, &aspirin, 10.0).await;
+        let dose = repos.doses.create(ANN, ANN, dose(&store, 1.0, "mg")).await.unwrap().unwrap();
+        repos.medications.delete(ANN, &key(&aspirin.id), ANN, true).await.unwrap();
+
+        let result = repos.trash.restore(ANN, "dose", &key(&dose.id), ANN, false).await;
+        assert!(matches!(result, Err(Error::Conflict { dependents }) if dependents == [store.id.clone()]));
+
+        // Without cascade only the medication comes back, with it the store and the dose as well.
+        repos.trash.restore(ANN, "medication", &key(&aspirin.id), ANN, false).await.unwrap().unwrap();
+        assert!(repos.stores.read(&key(&store.id), ANN).await.unwrap().is_none());
+        repos.trash.restore(ANN, "store", &key(&store.id), ANN, true).await.unwrap().unwrap();
+        assert!(repos.doses.read(&key(&dose.id), ANN).await.unwrap().is_some());
+        assert!(repos.trash.list(ANN, None).await.unwrap().is_empty());
+
+        // A record that isn't in the trash isn't restored again.
+        let restored = repos.trash.restore(ANN, "dose", &key(&dose.id), ANN, false).await.unwrap();
+        assert!(restored.is_none());
+    }
+
+    async fn purging_a_record_with_dependents_needs_cascade(repos: Repositories) {
+        let aspirin = medication(&repos, "Aspirin").await;
+        let store = store(&repos, &aspirin, 10.0).await;
+        repos.notes.create(ANN, ANN, note("medication", &aspirin.id)).await.unwrap();
+        repos.medications.delete(ANN, &key(&aspirin.id), ANN, true).await.unwrap();
+
+        let result = repos.trash.purge(ANN, "medication", &key(&aspirin.id), ANN, false).await;
+        assert!(matches!(result, Err(Error::Conflict { dependents }) if dependents == [store.id.clone()]));
+
+        repos.trash.purge(ANN, "medication", &key(&aspirin.id), ANN, true).await.unwrap().unwrap();
+        assert!(repos.trash.list(ANN, None).await.unwrap().is_empty());
+        let restored = repos.trash.restore(ANN, "store", &key(&store.id), ANN, false).await.unwrap();
+        assert!(restored.is_none());
+    }
+
+    async fn a_dose_must_fit_in_an_active_store(repos: Repositories) {
+        let aspirin = medication(&repos, "Aspirin").await;
+        let store = store(&repos, &aspirin, 10.0).await;
+
+        assert!(unprocessable(repos.doses.create(ANN, ANN, dose(&store, 1.0, "ml")).await, "unit"));
+        assert!(unprocessable(repos.doses.create(ANN, ANN, dose(&store, 11.0, "mg")).await, "quantity"));
+
+        let first = repos.doses.create(ANN, ANN, dose(&store, 4.0, "mg")).await.unwrap().unwrap();
+        assert!(unprocessable(repos.doses.create(ANN, ANN, dose(&store, 7.0, "mg")).await, "quantity"));
+
+        // A restored dose takes from its store again, so it must still fit.
+        repos.doses.delete(ANN, &key(&first.id), ANN).await.unwrap().unwrap();
+        repos.doses.create(ANN, ANN, dose(&store, 7.0, "mg")).await.unwrap().unwrap();
+        let result = repos.trash.restore(ANN, "dose", &key(&first.id), ANN, false).await;
+        assert!(unprocessable(result, "quantity"));
+
+        // A dose using up the store deactivates it.
+        let last = repos.doses.create(ANN, ANN, dose(&store, 3.0, "mg")).await.unwrap().unwrap();
+        assert!(!repos.stores.read(&key(&store.id), ANN).await.unwrap().unwrap().active);
+        assert!(unprocessable(repos.doses.create(ANN, ANN, dose(&store, 0.0, "mg")).await, "store"));
+        // Its own dose may still be changed, giving back what it took first.
+        let updated = repos.doses.update(ANN, &key(&last.id), ANN, &last.updated, dose(&store, 3.5, "mg")).await;
+        assert!(unprocessable(updated, "quantity"));
+        let updated = repos.doses.update(ANN, &key(&last.id), ANN, &last.updated, dose(&store, 0.5, "mg")).await;
+        assert!(updated.unwrap().is_some());
+    }
+
+    async fn moving_a_dose_gives_back_to_its_former_store(repos: Repositories) {
+        let aspirin = medication(&repos, "Aspirin").await;
+        let (first, second) = (store(&repos, &aspirin, 10.0).await, store(&repos, &aspirin, 10.0).await);
+        repos.doses.create(ANN, ANN, dose(&first, 6.0, "mg")).await.unwrap().unwrap();
+        let moved = repos.doses.create(ANN, ANN, dose(&first, 4.0, "mg")).await.unwrap().unwrap();
+        assert!(!repos.stores.read(&key(&first.id), ANN).await.unwrap().unwrap().active);
+
+        let moved = repos.doses.update(ANN, &key(&moved.id), ANN, &moved.updated, dose(&second, 4.0, "mg"))
+            .await.unwrap().unwrap();
+        assert_eq!(moved.store, second.id);
+        let reactivated = repos.stores.read(&key(&first.id), ANN).await.unwrap().unwrap();
+        assert!(reactivated.active);
+        assert_eq!(reactivated.updated, first.updated, "reactivating changed the version");
+        assert!(unprocessable(repos.doses.create(ANN, ANN, dose(&second, 7.0, "mg")).await, "quantity"));
+
+        // Reverting it back uses up the first store again, and leaving the second gives back to it.
+        repos.doses.update(ANN, &key(&moved.id), ANN, &moved.updated, dose(&second, 10.0, "mg"))
+            .await.unwrap().unwrap();
+        assert!(!repos.stores.read(&key(&second.id), ANN).await.unwrap().unwrap().active);
+        let history = repos.doses.history(&key(&moved.id), ANN).await.unwrap();
+        let current = repos.doses.read(&key(&moved.id), ANN).await.unwrap().unwrap();
+        repos.doses.revert(ANN, &key(&moved.id), ANN, &current.updated, &history[0].version)
+            .await.unwrap().unwrap();
+        assert!(!repos.stores.read(&key(&first.id), ANN).await.unwrap().unwrap().active);
+        assert!(repos.stores.read(&key(&second.id), ANN).await.unwrap().unwrap().active);
+    }
+
+    async fn reverting_restores_every_field_of_the_version(repos: Repositories) {
+        let aspirin = medication(&repos, "Aspirin").await;
+        let first = aspirin.updated.clone();
+        let renamed = CreateMedication { name: "Ibuprofen".to_owned(), created: None, updated: None, active: None };
+        let renamed = repos.medications.update(ANN, &key(&aspirin.id), ANN, &first, renamed).await.unwrap().unwrap();
+        let deactivated = repos.medications.deactivate(ANN, &key(&aspirin.id), ANN, &renamed.updated)
+            .await.unwrap().unwrap();
+
+        let history = repos.medications.history(&key(&aspirin.id), ANN).await.unwrap();
+        let versions: Vec<&Datetime> = history.iter().map(|v| &v.version).collect();
+        assert_eq!(versions, [&first, &renamed.updated]);
+
+        let version = &deactivated.updated;
+        let stale = repos.medications.revert(ANN, &key(&aspirin.id), ANN, &first, &first).await.unwrap();
+        assert!(stale.is_none());
+        let unknown = repos.medications.revert(ANN, &key(&aspirin.id), ANN, version, version).await;
+        assert!(matches!(unknown, Err(Error::NotFound)));
+
+        let reverted = repos.medications.revert(ANN, &key(&aspirin.id), ANN, version, &first).await.unwrap().unwrap();
+        assert_eq!(reverted.name, "Aspirin");
+        assert_eq!(reverted.active, Some(true));
+        // The version it replaced can be reverted to in turn.
+        assert_eq!(repos.medications.history(&key(&aspirin.id), ANN).await.unwrap().len(), 3);
+    }
+
+    async fn reverting_a_reminder_keeps_when_it_was_acknowledged(repos: Repositories) {
+        let aspirin = medication(&repos, "Aspirin").await;
+        let reminder = CreateReminder {
+            medication: key(&aspirin.id),
+            end: Datetime::default(),
+            days: "1111111".to_owned(),
+            times: vec!["08:00".to_owned()],
+        };
+        let reminder = repos.reminders.create(ANN, ANN, reminder).await.unwrap().unwrap();
+        let deactivated = repos.reminders.deactivate(ANN, &key(&reminder.id), ANN, &reminder.updated)
+            .await.unwrap().unwrap();
+        let acknowledged = repos.reminders.acknowledge(ANN, &key(&reminder.id), ANN).await.unwrap().unwrap();
+
+        let reverted = repos.reminders.revert(ANN, &key(&reminder.id), ANN, &deactivated.updated, &reminder.updated)
+            .await.unwrap().unwrap();
+        assert!(reverted.active);
+        assert_eq!(reverted.acknowledged, acknowledged.acknowledged);
+    }
+
+    async fn reverting_needs_the_referenced_records_and_the_stock(repos: Repositories) {
+        let aspirin = medication(&repos, "Aspirin").await;
+        let ibuprofen = medication(&repos, "Ibuprofen").await;
+        let store = store(&repos, &ibuprofen, 10.0).await;
+
+        let about_aspirin = repos.notes.create(ANN, ANN, note("medication", &aspirin.id)).await.unwrap().unwrap();
+        let about_ibuprofen = repos.notes.update(ANN, &key(about_aspirin.id.as_ref().unwrap()), ANN,
+            &about_aspirin.updated, note("medication", &ibuprofen.id)).await.unwrap().unwrap();
+        repos.medications.delete(ANN, &key(&aspirin.id), ANN, false).await.unwrap().unwrap();
+        let result = repos.notes.revert(ANN, &key(about_aspirin.id.as_ref().unwrap()), ANN,
+            &about_ibuprofen.updated, &about_aspirin.updated).await;
+        assert!(unprocessable(result, "note_thing"));
+
+        let large = repos.doses.create(ANN, ANN, dose(&store, 2.0, "mg")).await.unwrap().unwrap();
+        let small = repos.doses.update(ANN, &key(&large.id), ANN, &large.updated, dose(&store, 1.0, "mg"))
+            .await.unwrap().unwrap();
+        repos.doses.create(ANN, ANN, dose(&store, 8.5, "mg")).await.unwrap().unwrap();
+        let result = repos.doses.revert(ANN, &key(&large.id), ANN, &small.updated, &large.updated).await;
+        assert!(unprocessable(result, "quantity"));
+    }
+}
@@ -0,0 +1,25 @@
+//! The version history of the medications, stores, doses, reminders and notes, see `021_history.surql`.
+//!
+//! Every update of the user keeps the record as it was before, under its version, the `updated` it had. The
+//! repositories of these aggregates list the versions of a record and revert it to one of them.
+//!
+//! Reverting replaces every field of the record with the ones of the version, except those the application
+//! writes on its own, like when a reminder was acknowledged. The version is checked again like an update: the
+//! records it references must still exist, and a dose must still fit in its store. The version it replaces
+//! goes to the history like that of any other update, so a revert can be reverted.
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::Datetime;
+
+/// A struct representing an earlier version of a record
+///
+/// # Fields
+///
+/// * `version` - A `Datetime` representing the version, the `ETag` the record had, to revert to it by
+/// * `replaced` - A `Datetime` representing when the next version replaced it
+/// * `record` - The record as it was
+#[derive(Serialize, Deserialize)]
+pub struct Version<T> {
+    pub version: Datetime,
+    pub replaced: Datetime,
+    pub record: T,
+}
@@ -0,0 +1,74 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::Result;
+use crate::api::repository::history::Version;
+
+/// A struct representing a medication
+///
+/// # Fields
+///
+/// * `id` - An optional `Thing` representing the ID of the medication
+/// * `user` - A `Thing` representing the ID of the user who created the medication
+/// * `profile` - A `Thing` representing the ID of the profile the medication belongs to
+/// * `name` - A `String` representing the name of the medication
+/// * `created` - An optional `Datetime` representing the date and time the medication was created
+/// * `updated` - A `Datetime` representing the date and time the medication was last updated, its version
+/// * `active` - An optional `bool` representing whether the medication is currently active or not
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Medication {
+    pub id: Thing,
+    pub user: Thing,
+    pub profile: Thing,
+    pub name: String,
+    pub created: Option<Datetime>,
+    pub updated: Datetime,
+    pub active: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateMedication {
+    pub name: String,
+    pub created: Option<Datetime>,
+    pub updated: Option<Datetime>,
+    pub active: Option<bool>,
+}
+
+/// The medications of a user.
+#[async_trait]
+pub trait MedicationRepository: Send + Sync {
+    /// Creates a medication of `owner` in the given profile.
+    async fn create(&self, actor: &str, owner: &str, profile: &str, medication: CreateMedication)
+        -> Result<Option<Medication>>;
+
+    /// Returns the medication, or `None` if it doesn't exist or belongs to another user.
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Medication>>;
+
+    /// Renames the medication if it is still at `version`, its `updated`. Returns `None` if it doesn't exist,
+    /// belongs to another user or was changed since.
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, medication: CreateMedication)
+        -> Result<Option<Medication>>;
+
+    /// Marks the medication as inactive if it is still at `version`, like `update()`.
+    async fn deactivate(&self, actor: &str, id: &str, owner: &str, version: &Datetime)
+        -> Result<Option<Medication>>;
+
+    /// Lists the earlier versions of the medication, oldest first, see `super::history`. Empty if it has none or
+    /// belongs to another user.
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Medication>>>;
+
+    /// Reverts the medication to its earlier version `to` if it is still at `version`, returning `None` like
+    /// `update()`. Fails with `Error::NotFound` if it has no such version.
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Medication>>;
+
+    /// Moves the medication to the trash and returns it as it was, see `crate::db::trash`. The notes about it go along.
+    /// If it has its stores, their doses and its reminders, they go along with `cascade`, otherwise it fails with `Error::Conflict`.
+    async fn delete(&self, actor: &str, id: &str, owner: &str, cascade: bool) -> Result<Option<Medication>>;
+
+    /// Lists the medications of a profile, or of all profiles of `owner`, oldest first. With `active`,
+    /// only the active or inactive ones.
+    async fn list(&self, owner: &str, profile: Option<&str>, active: Option<bool>) -> Result<Vec<Medication>>;
+}
@@ -0,0 +1,151 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::Result;
+use crate::api::repository::history::Version;
+
+/// A struct representing a note that can relate to other objects. Used to store notes on
+/// medications, stores, and other objects. The `note_table` and `note_thing` fields are used to
+/// identify the object the note relates to. The `content` field is used to store the note itself.
+///
+/// # Fields
+///
+/// * `id` - The unique identifier of the note
+/// * `profile` - The profile the note belongs to, the same as that of the thing it relates to
+/// * `note_table` - The table the note relates to
+/// * `note_thing` - The thing the note relates to
+/// * `content` - The content of the note
+/// * `created` - The date the note was created
+/// * `updated` - The date the note was last updated, its version
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Note {
+    pub id: Option<Thing>,
+    pub user: Option<Thing>,
+    pub profile: Option<Thing>,
+    pub note_table: String,
+    pub note_thing: String,
+    pub content: String,
+    pub created: Option<Datetime>,
+    pub updated: Datetime,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateNote {
+    pub note_table: String,
+    pub note_thing: String,
+    pub content: String,
+    pub created: Option<Datetime>,
+    pub updated: Option<Datetime>,
+}
+
+/// A note on a dose, joined with the dose, its store and its medication.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DoseNote {
+    pub content: String,
+    pub created: Datetime,
+    pub dose_created: String,
+    pub dose_id: Thing,
+    pub dose_quantity: f32,
+    pub dose_updated: String,
+    pub id: Thing,
+    pub medication_id: Thing,
+    pub medication_name: String,
+    pub note_table: String,
+    pub note_thing: String,
+    pub store_id: Thing,
+    pub store_production_date: Datetime,
+    pub store_start_quantity: f32,
+    pub unit: String,
+    pub updated: Datetime,
+    pub user: Thing,
+}
+
+/// A note on a medication, joined with the medication.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MedicationNote {
+    pub id: Thing,
+    pub content: String,
+    pub created: Datetime,
+    pub medication_active: bool,
+    pub medication_id: Thing,
+    pub medication_name: String,
+    pub note_table: String,
+    pub note_thing: String,
+    pub updated: Datetime,
+    pub user: Thing,
+}
+
+/// A note on a store, joined with the store and its medication.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreNote {
+    pub id: Thing,
+    pub content: String,
+    pub created: Datetime,
+    pub medication_id: Thing,
+    pub medication_name: String,
+    pub note_table: String,
+    pub note_thing: String,
+    pub store_active: bool,
+    pub store_created: String,
+    pub store_id: Thing,
+    pub store_production_date: Datetime,
+    pub store_start_quantity: f32,
+    pub store_updated: String,
+    pub unit: String,
+    pub updated: Datetime,
+    pub user: Thing,
+}
+
+/// The notes of a user on their medications, stores and doses.
+///
+/// A note takes the profile of the record it relates to, which the caller must have checked belongs to the
+/// owner.
+#[async_trait]
+pub trait NoteRepository: Send + Sync {
+    /// Creates a note of `owner`.
+    async fn create(&self, actor: &str, owner: &str, note: CreateNote) -> Result<Option<Note>>;
+
+    /// Returns the note, or `None` if it doesn't exist or belongs to another user.
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Note>>;
+
+    /// Replaces the fields of the note if it is still at `version`, its `updated`. Returns `None` if it doesn't
+    /// exist, belongs to another user or was changed since.
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, note: CreateNote)
+        -> Result<Option<Note>>;
+
+    /// Lists the earlier versions of the note, oldest first, see `super::history`. Empty if it has none or
+    /// belongs to another user.
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Note>>>;
+
+    /// Reverts the note to its earlier version `to` if it is still at `version`, returning `None` like
+    /// `update()`. Fails with `Error::NotFound` if it has no such version. Fails with
+    /// `Error::UnprocessableEntity` if the record it is about no longer exists.
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Note>>;
+
+    /// Moves the note to the trash and returns it as it was, see `crate::db::trash`.
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Note>>;
+
+    /// Lists the notes of `owner`, of one profile or of all of them, oldest first.
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<Note>>;
+
+    /// Lists the notes on doses of a profile, or of all profiles of `owner`, oldest first.
+    async fn list_dose_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<DoseNote>>;
+
+    /// Lists the notes of `owner` on one dose, oldest first.
+    async fn list_notes_for_dose(&self, owner: &str, dose: &str) -> Result<Vec<DoseNote>>;
+
+    /// Lists the notes on medications of a profile, or of all profiles of `owner`, oldest first.
+    async fn list_medication_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<MedicationNote>>;
+
+    /// Lists the notes of `owner` on one medication, oldest first.
+    async fn list_notes_for_medication(&self, owner: &str, medication: &str) -> Result<Vec<MedicationNote>>;
+
+    /// Lists the notes on stores of a profile, or of all profiles of `owner`, oldest first.
+    async fn list_store_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<StoreNote>>;
+
+    /// Lists the notes of `owner` on one store, oldest first.
+    async fn list_notes_for_store(&self, owner: &str, store: &str) -> Result<Vec<StoreNote>>;
+}
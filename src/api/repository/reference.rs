@@ -0,0 +1,112 @@
+//! The references between the aggregates, and what deleting a referenced record does to the records
+//! referencing it.
+//!
+//! A store references its medication, a reminder its medication and a dose its store. These restrict: a
+//! record with such dependents is only deleted with `cascade`, which deletes the dependents along with it,
+//! otherwise it fails with `Error::Conflict` listing them. Notes reference the medication, store or dose they
+//! are about by `note_table` and `note_thing`, and always go along with it.
+//!
+//! The same rules apply to the records in the trash, see `crate::db::trash`: a record is restored only after
+//! the records it references, and purged only together with the ones referencing it.
+
+/// A field of `table` holding a record of `target`.
+pub struct Reference {
+    pub table: &'static str,
+    pub field: &'static str,
+    pub target: &'static str,
+}
+
+/// Every reference between the aggregates except those of notes, see `NOTE_TABLES`. None of them is
+/// optional.
+pub const REFERENCES: [Reference; 3] = [
+    Reference { table: "store", field: "medication", target: "medication" },
+    Reference { table: "reminder", field: "medication", target: "medication" },
+    Reference { table: "dose", field: "store", target: "store" },
+];
+
+/// The tables of the records a note can be about.
+pub const NOTE_TABLES: [&str; 3] = ["medication", "store", "dose"];
+
+/// Builds the SurrealQL that collects the records depending on the ones in `$<table>`, an array of record
+/// IDs of `table` the caller sets, and on each other, limited to the ones matching `filter`, e.g.
+/// `deleted_at = NONE`. It sets `$<dependent table>` for each table found on the way, `$restricted` to the
+/// dependents that keep the records from being deleted without cascading, and `$dependents` to all of them,
+/// notes included.
+pub fn collect_dependents(table: &str, filter: &str) -> String {
+    let mut sql = String::new();
+    let mut tables = vec![table];
+    let mut restricted = Vec::new();
+
+    let mut index = 0;
+    while let Some(&parent) = tables.get(index) {
+        for reference in REFERENCES.iter().filter(|reference| reference.target == parent) {
+            let Reference { table, field, .. } = reference;
+            sql += &format!("LET ${table} = SELECT VALUE id FROM {table} WHERE {field} INSIDE ${parent} AND {filter};\n");
+            tables.push(table);
+            restricted.push(format!("${table}"));
+        }
+        index += 1;
+    }
+
+    sql += &format!(
+        "LET $restricted = array::flatten([{}]);
+        LET $dependents = array::concat($restricted, {});\n",
+        restricted.join(", "),
+        notes_about(&tables, filter));
+    sql
+}
+
+/// Builds the SurrealQL that sets `$dependents` to the notes about the records in `$<table>`, matching
+/// `filter`, leaving out the records that depend on them otherwise. See `collect_dependents()`.
+pub fn collect_notes(table: &str, filter: &str) -> String {
+    format!("LET $dependents = {};\n", notes_about(&[table], filter))
+}
+
+/// Builds the SurrealQL that sets `$references` to the records the ones in `$<table>` reference, notes
+/// included. A referenced record that no longer exists is left out.
+pub fn collect_references(table: &str) -> String {
+    let mut fields: Vec<String> = REFERENCES
+        .iter()
+        .filter(|reference| reference.table == table)
+        .map(|Reference { field, .. }| format!("(SELECT VALUE {field} FROM ${table})"))
+        .collect();
+    if table == "note" {
+        fields.push("(SELECT VALUE type::thing(note_table, note_thing) FROM $note)".to_owned());
+    }
+    format!("LET $references = SELECT VALUE id FROM array::flatten([{}]);\n", fields.join(", "))
+}
+
+/// Builds the SurrealQL that checks, if `condition` holds, that the records referenced by `$fields`, the fields
+/// of a record of `table`, are records of `$user` outside the trash, and `THROW`s like a failed validation of
+/// the field otherwise.
+pub fn check_references(table: &str, condition: &str) -> String {
+    let mut fields: Vec<(&str, String)> = REFERENCES
+        .iter()
+        .filter(|reference| reference.table == table)
+        .map(|Reference { field, .. }| (*field, format!("$fields.{field}")))
+        .collect();
+    if table == "note" {
+        fields.push(("note_thing", "type::thing($fields.note_table, $fields.note_thing)".to_owned()));
+    }
+    fields
+        .into_iter()
+        .map(|(field, record)| format!(
+            "IF {condition} AND (SELECT VALUE id FROM {record}
+                WHERE user = type::thing('user', $user) AND deleted_at = NONE)[0] = NONE {{
+                THROW '{field}: was not found'
+            }};\n"))
+        .collect()
+}
+
+/// The expression selecting the notes about the records in `$<table>` for each of `tables`.
+fn notes_about(tables: &[&str], filter: &str) -> String {
+    let about: Vec<String> = tables
+        .iter()
+        .filter(|table| NOTE_TABLES.contains(table))
+        .map(|table| format!("(note_table = '{table}' AND type::thing('{table}', note_thing) INSIDE ${table})"))
+        .collect();
+    match about.is_empty() {
+        true => "[]".to_owned(),
+        false => format!("(SELECT VALUE id FROM note WHERE ({}) AND {filter})", about.join(" OR ")),
+    }
+}
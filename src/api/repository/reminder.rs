@@ -0,0 +1,86 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::Result;
+use crate::api::repository::history::Version;
+
+/// A struct representing a reminder for taking a medication with the following fields:
+/// * `id`: A unique identifier for the reminder
+/// * `medication`: The medication for which the reminder is set
+/// * `start`: The start date and time of the reminder
+/// * `end`: The end date and time of the reminder
+/// * `days`: A string representing the days on which the reminder should be active
+/// * `times`: A vector of strings representing the times at which the reminder should be active
+/// * `active`: A boolean indicating whether the reminder is currently active
+/// * `user`: An optional string representing the user who created the reminder
+/// * `profile`: The profile the reminder belongs to, the same as that of its medication
+/// * `acknowledged`: The date and time when the reminder was last acknowledged, if ever
+/// * `created`: The date and time when the reminder was created
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Reminder {
+    #[serde(default)]
+    pub acknowledged: Option<Datetime>,
+    pub active: bool,
+    pub created: Datetime,
+    pub days: String,
+    pub end: Datetime,
+    pub id: Thing,
+    pub medication: Thing,
+    pub start: Datetime,
+    pub times: Vec<String>,
+    pub updated: Datetime,
+    pub user: Option<Thing>,
+    pub profile: Option<Thing>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateReminder {
+    pub medication: String,
+    // start: String,
+    pub end: Datetime,
+    pub days: String,
+    pub times: Vec<String>,
+}
+
+/// The reminders to take medications of a user.
+///
+/// A reminder takes the profile of its medication, which the caller must have checked belongs to the owner.
+#[async_trait]
+pub trait ReminderRepository: Send + Sync {
+    /// Creates a reminder of `owner`, starting now.
+    async fn create(&self, actor: &str, owner: &str, reminder: CreateReminder) -> Result<Option<Reminder>>;
+
+    /// Returns the reminder, or `None` if it doesn't exist or belongs to another user.
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Reminder>>;
+
+    /// Replaces the fields of the reminder if it is still at `version`, its `updated`. Returns `None` if it
+    /// doesn't exist, belongs to another user or was changed since.
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, reminder: CreateReminder)
+        -> Result<Option<Reminder>>;
+
+    /// Marks the reminder as inactive if it is still at `version`, like `update()`.
+    async fn deactivate(&self, actor: &str, id: &str, owner: &str, version: &Datetime) -> Result<Option<Reminder>>;
+
+    /// Sets the time the reminder was acknowledged to now. This isn't an edit of the reminder, so it keeps its
+    /// version, `updated`, and isn't recorded in its history.
+    async fn acknowledge(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Reminder>>;
+
+    /// Lists the earlier versions of the reminder, oldest first, see `super::history`. Empty if it has none or
+    /// belongs to another user.
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Reminder>>>;
+
+    /// Reverts the reminder to its earlier version `to` if it is still at `version`, returning `None` like
+    /// `update()`. Fails with `Error::NotFound` if it has no such version. Fails with
+    /// `Error::UnprocessableEntity` if its medication no longer exists.
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Reminder>>;
+
+    /// Moves the reminder to the trash and returns it as it was, see `crate::db::trash`.
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Reminder>>;
+
+    /// Lists the reminders of `owner`, of one profile or of all of them. With `active`, only the active or
+    /// inactive ones.
+    async fn list(&self, owner: &str, profile: Option<&str>, active: Option<bool>) -> Result<Vec<Reminder>>;
+}
@@ -0,0 +1,136 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::Result;
+use crate::api::repository::history::Version;
+
+/// A struct representing a store of medication
+///
+/// # Fields
+///
+/// * `id` - The unique identifier of the store
+/// * `profile` - The profile the store belongs to, the same as that of its medication
+/// * `medication` - The medication stored in the store
+/// * `production_date` - The date the medication was produced
+/// * `expiration_date` - The date the medication will expire
+/// * `lot_number` - The lot number of the medication
+/// * `quantity` - The quantity of medication stored
+/// * `unit` - The unit of measurement for the quantity
+/// * `created` - The date the store was created
+/// * `updated` - The date the store was last updated
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Store {
+    pub id: Thing,
+    pub user: Thing,
+    pub profile: Thing,
+    pub medication: Thing,
+    pub production_date: Datetime,
+    pub expiration_date: Option<Datetime>,
+    pub lot_number: String,
+    pub quantity: f32,
+    pub unit: String,
+    pub created: Datetime,
+    pub updated: Datetime,
+    pub active: bool,
+}
+
+/// A struct representing the creation of a store with the following fields:
+///
+/// * `medication` - a `String` representing the name of the medication
+/// * `production_date` - a `Datetime` representing the date of production
+/// * `expiration_date` - a `Datetime` representing the date of expiration
+/// * `lot_number` - a `String` representing the lot number of the medication
+/// * `quantity` - a `f32` representing the quantity of the medication
+/// * `unit` - a `String` representing the unit of measurement for the medication quantity.
+#[derive(Serialize, Deserialize)]
+pub struct CreateStore {
+    pub medication: String,
+    pub production_date: Datetime,
+    pub expiration_date: Option<Datetime>,
+    pub lot_number: String,
+    pub quantity: f32,
+    pub unit: String,
+}
+
+/// A struct representing a new batch of the medication of a store, replacing it, with the following fields:
+///
+/// * `production_date` - a `Datetime` representing the date of production
+/// * `expiration_date` - a `Datetime` representing the date of expiration
+/// * `lot_number` - a `String` representing the lot number of the new batch
+/// * `quantity` - a `f32` representing the quantity of the new batch
+/// * `unit` - a `String` representing the unit of measurement for the quantity.
+#[derive(Serialize, Deserialize)]
+pub struct RefillStore {
+    pub production_date: Datetime,
+    pub expiration_date: Option<Datetime>,
+    pub lot_number: String,
+    pub quantity: f32,
+    pub unit: String,
+}
+
+/// A store joined with the name of its medication.
+#[derive(Serialize, Deserialize)]
+pub struct StoreList {
+    pub medication_id: Thing,
+    pub medication_name: String,
+    pub store_active: bool,
+    pub store_created: Datetime,
+    pub store_expiration_date: Option<Datetime>,
+    pub store_id: Thing,
+    pub store_lot_number: String,
+    pub store_production_date: Datetime,
+    pub store_start_quantity: f32,
+    pub store_unit: String,
+    pub store_updated: Datetime,
+    pub user: Thing,
+}
+
+/// The stores of medication of a user.
+///
+/// A store takes the profile of its medication, which the caller must have checked belongs to the owner.
+#[async_trait]
+pub trait StoreRepository: Send + Sync {
+    /// Creates a store of `owner`.
+    async fn create(&self, actor: &str, owner: &str, store: CreateStore) -> Result<Option<Store>>;
+
+    /// Returns the store, or `None` if it doesn't exist or belongs to another user.
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Store>>;
+
+    /// Replaces the fields of the store if it is still at `version`, its `updated`. Returns `None` if it doesn't
+    /// exist, belongs to another user or was changed since.
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, store: CreateStore)
+        -> Result<Option<Store>>;
+
+    /// Marks the store as inactive if it is still at `version`, like `update()`.
+    async fn deactivate(&self, actor: &str, id: &str, owner: &str, version: &Datetime) -> Result<Option<Store>>;
+
+    /// Replaces the store with a new one of the same medication, if it is still at `version`: it is deactivated
+    /// and the new one created in one transaction, see `crate::api::transaction`. Returns the new store, or
+    /// `None` like `update()`.
+    async fn refill(&self, actor: &str, id: &str, owner: &str, version: &Datetime, refill: RefillStore)
+        -> Result<Option<Store>>;
+
+    /// Lists the earlier versions of the store, oldest first, see `super::history`. Empty if it has none or
+    /// belongs to another user.
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Store>>>;
+
+    /// Reverts the store to its earlier version `to` if it is still at `version`, returning `None` like
+    /// `update()`. Fails with `Error::NotFound` if it has no such version. Fails with
+    /// `Error::UnprocessableEntity` if its medication no longer exists.
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Store>>;
+
+    /// Moves the store to the trash and returns it as it was, see `crate::db::trash`. The notes about it go along.
+    /// If it has its doses, they go along with `cascade`, otherwise it fails with `Error::Conflict`.
+    async fn delete(&self, actor: &str, id: &str, owner: &str, cascade: bool) -> Result<Option<Store>>;
+
+    /// Lists the stores of `owner`, of one profile or of all of them, oldest first.
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<Store>>;
+
+    /// Lists the stores of a medication of `owner`, oldest first. With `active`, only the active or
+    /// inactive ones.
+    async fn list_for_medication(&self, owner: &str, medication: &str, active: Option<bool>)
+        -> Result<Vec<StoreList>>;
+}
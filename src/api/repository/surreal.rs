@@ -0,0 +1,829 @@
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use surrealdb::Surreal;
+use surrealdb::engine::any::Any;
+use surrealdb::sql::{Datetime, Thing};
+
+use crate::api::error::Error;
+use crate::api::Result;
+use crate::api::repository::dose::{CreateDose, Dose, DoseList, DoseRepository};
+use crate::api::repository::history::Version;
+use crate::api::repository::medication::{CreateMedication, Medication, MedicationRepository};
+use crate::api::repository::note::{CreateNote, DoseNote, MedicationNote, Note, NoteRepository, StoreNote};
+use crate::api::repository::reference::{check_references, collect_dependents, collect_notes, collect_references};
+use crate::api::repository::reminder::{CreateReminder, Reminder, ReminderRepository};
+use crate::api::repository::store::{CreateStore, RefillStore, Store, StoreList, StoreRepository};
+use crate::api::repository::trash::{TrashItem, TrashRepository};
+use crate::api::repository::uom::{CreateUnitOfMeasure, UnitOfMeasure, UnitOfMeasureRepository};
+use crate::api::transaction::Transaction;
+use crate::db::trash::TRASH_TABLES;
+
+/// The repositories of all the aggregates on SurrealDB.
+///
+/// Every write binds `$actor`, which the events of `015_audit.surql` record as the user behind the change.
+#[derive(Clone)]
+pub struct SurrealRepository {
+    db: Surreal<Any>,
+}
+
+impl SurrealRepository {
+    pub fn new(db: Surreal<Any>) -> Self {
+        Self { db }
+    }
+
+    /// Moves the record to the trash, together with the records depending on it, and returns it as it was.
+    ///
+    /// Without `cascade` it fails with `Error::Conflict` if any of them restricts the deletion, see
+    /// `reference`. All of them get the same `deleted_at`, so the trash can tell which were deleted together.
+    async fn delete_record<T: DeserializeOwned>(&self, actor: &str, table: &str, id: &str, owner: &str, cascade: bool)
+        -> Result<Option<T>> {
+        let mut sql = Transaction::new(&self.db, format!(
+            "LET $now = time::now();
+            LET ${table} = SELECT VALUE id FROM type::thing($table, $id)
+                WHERE user = type::thing('user', $user) AND deleted_at = NONE;
+            {dependents}
+            LET $delete = $cascade OR array::len($restricted) = 0;
+            RETURN $restricted;
+            UPDATE $dependents SET deleted_at = $now WHERE $delete;
+            UPDATE ${table} SET deleted_at = $now WHERE $delete RETURN BEFORE;",
+            dependents = collect_dependents(table, "deleted_at = NONE")))
+            .bind(("actor", actor))
+            .bind(("table", table))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("cascade", cascade))
+            .commit()
+            .await?;
+
+        let last = sql.num_statements() - 1;
+        let restricted: Vec<Thing> = sql.take(last - 2)?;
+        if !cascade && !restricted.is_empty() {
+            return Err(Error::Conflict { dependents: restricted });
+        }
+        Ok(sql.take(last)?)
+    }
+
+    /// Lists the earlier versions of the record, oldest first, see `021_history.surql`.
+    async fn history_of<T: DeserializeOwned>(&self, table: &str, id: &str, owner: &str) -> Result<Vec<Version<T>>> {
+        let mut sql = self.db.query(
+            "SELECT version, replaced, content AS record FROM history
+                WHERE record = type::thing($table, $id) AND user = type::thing('user', $user) ORDER BY version;")
+            .bind(("table", table))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    /// Replaces every field of the record with the ones of its version `to` if it is still at `version`, and
+    /// returns it, see `super::history`. It takes the fields as stored, so the encrypted ones stay encrypted.
+    ///
+    /// Fails with `Error::NotFound` if there is no such version, or like an update if the version no longer is
+    /// valid: the records it references must still exist, and a dose must fit in its store.
+    async fn revert_record<T: DeserializeOwned>(&self, actor: &str, table: &str, id: &str, owner: &str,
+        version: &Datetime, to: &Datetime) -> Result<Option<T>> {
+        let kept = match table {
+            // When it was acknowledged isn't part of a version, see `ReminderRepository::acknowledge()`.
+            "reminder" => ", $record.acknowledged AS acknowledged",
+            _ => "",
+        };
+        let checks = match table {
+            // The version takes from its store again, leaving out what the dose takes now.
+            "dose" => format!(
+                "LET $store = IF $revert THEN meta::id($fields.store) END;
+                LET $quantity = $fields.quantity;
+                LET $unit = $fields.unit;
+                {take}
+                {give_back}",
+                take = take_from_store("$revert", "$record.id"),
+                give_back = give_back_to_store("$revert", "$record")),
+            _ => String::new(),
+        };
+        let mut sql = Transaction::new(&self.db, format!(
+            "LET $record = (SELECT * FROM type::thing($table, $id)
+                WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version)[0];
+            LET $fields = (SELECT *{kept} OMIT updated FROM (SELECT VALUE content FROM history
+                WHERE record = type::thing($table, $id) AND user = type::thing('user', $user) AND version = $to))[0];
+            LET $revert = $record != NONE AND $fields != NONE;
+            RETURN $fields != NONE;
+            {references}
+            {checks}
+            UPDATE type::thing($table, $id) CONTENT $fields WHERE $revert RETURN AFTER;",
+            references = check_references(table, "$revert")))
+            .bind(("actor", actor))
+            .bind(("table", table))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .bind(("to", to))
+            .commit()
+            .await?;
+
+        let last = sql.num_statements() - 1;
+        let found: Option<bool> = sql.take(0)?;
+        if found != Some(true) {
+            return Err(Error::NotFound);
+        }
+        Ok(sql.take(last)?)
+    }
+}
+
+#[async_trait]
+impl MedicationRepository for SurrealRepository {
+    async fn create(&self, actor: &str, owner: &str, profile: &str, medication: CreateMedication)
+        -> Result<Option<Medication>> {
+        let mut sql = self.db.query(
+            "CREATE medication SET user = type::thing('user', $user), profile = type::thing('profile', $profile), name = $name;")
+            .bind(("actor", actor))
+            .bind(("user", owner))
+            .bind(("profile", profile))
+            .bind(("name", medication.name))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Medication>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM type::thing('medication', $id) WHERE user = type::thing('user', $user) AND deleted_at = NONE;")
+            .bind(("id", id))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, medication: CreateMedication)
+        -> Result<Option<Medication>> {
+        let mut sql = self.db.query(
+            "UPDATE type::thing('medication', $id) SET name = $name
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version;")
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .bind(("name", medication.name))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn deactivate(&self, actor: &str, id: &str, owner: &str, version: &Datetime) -> Result<Option<Medication>> {
+        let mut sql = self.db.query(
+            "UPDATE type::thing('medication', $id) SET active = false
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version;")
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Medication>>> {
+        self.history_of("medication", id, owner).await
+    }
+
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Medication>> {
+        self.revert_record(actor, "medication", id, owner, version, to).await
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str, cascade: bool) -> Result<Option<Medication>> {
+        self.delete_record(actor, "medication", id, owner, cascade).await
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>, active: Option<bool>) -> Result<Vec<Medication>> {
+        let mut sql = match (profile, active) {
+            (Some(profile), None) => self.db.query("RETURN fn::list_profile_medications($profile);")
+                .bind(("profile", profile)),
+            (Some(profile), Some(active)) => self.db.query("RETURN fn::list_profile_medications_by_status($active, $profile);")
+                .bind(("active", active))
+                .bind(("profile", profile)),
+            (None, None) => self.db.query("RETURN fn::list_user_medications($user);")
+                .bind(("user", owner)),
+            (None, Some(active)) => self.db.query("RETURN fn::list_user_medications_by_status($active, $user);")
+                .bind(("active", active))
+                .bind(("user", owner)),
+        }
+        .await?;
+        Ok(sql.take(0)?)
+    }
+}
+
+#[async_trait]
+impl StoreRepository for SurrealRepository {
+    async fn create(&self, actor: &str, owner: &str, store: CreateStore) -> Result<Option<Store>> {
+        //TODO: Evaluate if the <decimal> function here on quantity is necessary
+        let mut sql = self.db.query(
+            "CREATE store SET  user = type::thing('user', $user), medication = type::thing('medication', $medication),
+            profile = type::thing('medication', $medication).profile, production_date = $production_date,
+            expiration_date = $expiration_date, lot_number = $lot_number , quantity = <decimal> $quantity, unit = $unit;")
+            .bind(("actor", actor))
+            .bind(("user", owner))
+            .bind(("medication", store.medication))
+            .bind(("production_date", store.production_date))
+            .bind(("expiration_date", store.expiration_date))
+            .bind(("lot_number", store.lot_number))
+            .bind(("quantity", store.quantity))
+            .bind(("unit", store.unit))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Store>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM type::thing('store', $id) WHERE user = type::thing('user', $user) AND deleted_at = NONE;")
+            .bind(("id", id))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, store: CreateStore) -> Result<Option<Store>> {
+        let mut sql = self.db.query(
+            "UPDATE type::thing('store', $id) SET medication = type::thing('medication', $medication),
+            profile = type::thing('medication', $medication).profile, production_date = $production_date,
+            expiration_date = $expiration_date, lot_number = $lot_number , quantity = <decimal> $quantity, unit = $unit
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version;")
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .bind(("medication", store.medication))
+            .bind(("production_date", store.production_date))
+            .bind(("expiration_date", store.expiration_date))
+            .bind(("lot_number", store.lot_number))
+            .bind(("quantity", store.quantity))
+            .bind(("unit", store.unit))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn deactivate(&self, actor: &str, id: &str, owner: &str, version: &Datetime) -> Result<Option<Store>> {
+        let mut sql = self.db.query(
+            "UPDATE type::thing('store', $id) SET active = false
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version;")
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn refill(&self, actor: &str, id: &str, owner: &str, version: &Datetime, refill: RefillStore)
+        -> Result<Option<Store>> {
+        // Nothing is inserted if the old store doesn't match.
+        let mut sql = Transaction::new(&self.db,
+            "LET $old = UPDATE type::thing('store', $id) SET active = false
+                WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version RETURN BEFORE;
+            INSERT INTO store (SELECT user, profile, medication, $production_date AS production_date,
+                $expiration_date AS expiration_date, $lot_number AS lot_number, <decimal> $quantity AS quantity,
+                $unit AS unit FROM $old);")
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .bind(("production_date", refill.production_date))
+            .bind(("expiration_date", refill.expiration_date))
+            .bind(("lot_number", refill.lot_number))
+            .bind(("quantity", refill.quantity))
+            .bind(("unit", refill.unit))
+            .commit()
+            .await?;
+        Ok(sql.take(sql.num_statements() - 1)?)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Store>>> {
+        self.history_of("store", id, owner).await
+    }
+
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Store>> {
+        self.revert_record(actor, "store", id, owner, version, to).await
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str, cascade: bool) -> Result<Option<Store>> {
+        self.delete_record(actor, "store", id, owner, cascade).await
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<Store>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM store WHERE user = type::thing('user', $user)
+            AND ($profile = NONE OR profile = type::thing('profile', $profile)) AND deleted_at = NONE ORDER BY created;")
+            .bind(("user", owner))
+            .bind(("profile", profile))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn list_for_medication(&self, owner: &str, medication: &str, active: Option<bool>)
+        -> Result<Vec<StoreList>> {
+        let mut sql = match active {
+            Some(active) => self.db.query("RETURN fn::list_stores_for_medication($id, $bool, $user);")
+                .bind(("bool", active)),
+            None => self.db.query("RETURN fn::list_all_stores_for_medication($id, $user);"),
+        }
+        .bind(("id", medication))
+        .bind(("user", owner))
+        .await?;
+        Ok(sql.take(0)?)
+    }
+}
+
+/// Builds the statements taking a dose from its store, run before writing it if `condition` holds: they check
+/// that the store is active, the unit and what is left in the store, and deactivate the store the dose uses up,
+/// keeping its version, see `024_keep_version.surql`.
+/// `dose` is the ID of a dose being updated or restored, to leave out what it took before, or `NONE`. Its own
+/// store may be inactive, e.g. because the dose used it up.
+fn take_from_store(condition: &str, dose: &str) -> String {
+    format!(
+        "LET $source = (SELECT * FROM type::thing('store', $store) WHERE deleted_at = NONE)[0];
+        LET $taken = math::sum((SELECT VALUE quantity FROM dose
+            WHERE store = $source.id AND deleted_at = NONE AND id != {dose}));
+        IF {condition} AND $source = NONE {{ THROW 'store: was not found' }};
+        IF {condition} AND !$source.active AND $source.id != {dose}.store {{ THROW 'store: is not active' }};
+        IF {condition} AND $unit != $source.unit {{ THROW 'unit: must be the unit of the store' }};
+        IF {condition} AND $quantity > $source.quantity - $taken {{
+            THROW 'quantity: is more than is left in the store'
+        }};
+        LET $keep_version = true;
+        IF {condition} AND $quantity >= $source.quantity - $taken {{ UPDATE $source.id SET active = false }};
+        LET $keep_version = false;")
+}
+
+/// Builds the statements giving back to its former store what the dose `dose` took, if `condition` holds and the
+/// dose is moved to another store: the former store is reactivated, keeping its version, if the dose had used it up
+/// and something is left in it again. Run after `take_from_store()` and before the dose is written.
+fn give_back_to_store(condition: &str, dose: &str) -> String {
+    format!(
+        "LET $former = IF {condition} AND {dose}.store != type::thing('store', $store) THEN
+            (SELECT * FROM {dose}.store WHERE deleted_at = NONE)[0]
+        END;
+        LET $left = IF $former != NONE THEN $former.quantity - math::sum((SELECT VALUE quantity FROM dose
+            WHERE store = $former.id AND deleted_at = NONE AND id != {dose}.id)) END;
+        LET $keep_version = true;
+        IF $former != NONE AND !$former.active AND $left - {dose}.quantity <= 0 AND $left > 0 {{
+            UPDATE $former.id SET active = true
+        }};
+        LET $keep_version = false;")
+}
+
+#[async_trait]
+impl DoseRepository for SurrealRepository {
+    async fn create(&self, actor: &str, owner: &str, dose: CreateDose) -> Result<Option<Dose>> {
+        let mut sql = Transaction::new(&self.db, format!(
+            "{take}
+            CREATE dose SET user = type::thing('user', $user), store = type::thing('store', $store),
+            profile = type::thing('store', $store).profile, quantity = $quantity, unit = $unit;",
+            take = take_from_store("true", "NONE")))
+            .bind(("actor", actor))
+            .bind(("user", owner))
+            .bind(("store", dose.store))
+            .bind(("quantity", dose.quantity))
+            .bind(("unit", dose.unit))
+            .commit()
+            .await?;
+        Ok(sql.take(sql.num_statements() - 1)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Dose>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM type::thing('dose', $id) WHERE user = type::thing('user', $user) AND deleted_at = NONE;")
+            .bind(("id", id))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, dose: CreateDose) -> Result<Option<Dose>> {
+        let mut sql = Transaction::new(&self.db, format!(
+            "LET $dose = (SELECT VALUE id FROM type::thing('dose', $id)
+                WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version)[0];
+            {take}
+            {give_back}
+            UPDATE type::thing('dose', $id) SET quantity = $quantity, unit = $unit, store = type::thing('store', $store),
+            profile = type::thing('store', $store).profile
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version;",
+            // A dose that doesn't match takes nothing, and the update returns None.
+            take = take_from_store("$dose != NONE", "$dose"),
+            give_back = give_back_to_store("$dose != NONE", "$dose")))
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("quantity", dose.quantity))
+            .bind(("unit", dose.unit))
+            .bind(("store", dose.store))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .commit()
+            .await?;
+        Ok(sql.take(sql.num_statements() - 1)?)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Dose>>> {
+        self.history_of("dose", id, owner).await
+    }
+
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Dose>> {
+        self.revert_record(actor, "dose", id, owner, version, to).await
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Dose>> {
+        // Nothing restricts the deletion, the notes about it always go along.
+        self.delete_record(actor, "dose", id, owner, false).await
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<DoseList>> {
+        let mut sql = match profile {
+            Some(profile) => self.db.query("RETURN fn::list_doses_for_profile($profile);").bind(("profile", profile)),
+            None => self.db.query("RETURN fn::list_doses_for_user($user);").bind(("user", owner)),
+        }
+        .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn list_for_medication(&self, owner: &str, medication: &str) -> Result<Vec<DoseList>> {
+        let mut sql = self.db.query(
+            "RETURN fn::list_doses_for_medication($id, $user);")
+            .bind(("id", medication))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn list_for_store(&self, owner: &str, store: &str) -> Result<Vec<DoseList>> {
+        let mut sql = self.db.query(
+            "RETURN fn::list_doses_for_store($id, $user);")
+            .bind(("id", store))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+}
+
+#[async_trait]
+impl ReminderRepository for SurrealRepository {
+    async fn create(&self, actor: &str, owner: &str, reminder: CreateReminder) -> Result<Option<Reminder>> {
+        let mut sql = self.db.query(
+            "CREATE reminder SET user = type::thing('user', $user), medication = type::thing('medication', $medication),
+            profile = type::thing('medication', $medication).profile, end = $end, days = $days, times = $times;")
+            .bind(("actor", actor))
+            .bind(("user", owner))
+            .bind(("medication", reminder.medication))
+            .bind(("end", reminder.end))
+            .bind(("days", reminder.days))
+            .bind(("times", reminder.times))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Reminder>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM type::thing('reminder', $id) WHERE user = type::thing('user', $user) AND deleted_at = NONE;")
+            .bind(("id", id))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, reminder: CreateReminder) -> Result<Option<Reminder>> {
+        let mut sql = self.db.query(
+            "UPDATE type::thing('reminder', $id) SET medication = type::thing('medication', $medication),
+            profile = type::thing('medication', $medication).profile, end = $end, days = $days, times = $times
+           
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version;")
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .bind(("medication", reminder.medication))
+            .bind(("end", reminder.end))
+            .bind(("days", reminder.days))
+            .bind(("times", reminder.times))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn deactivate(&self, actor: &str, id: &str, owner: &str, version: &Datetime) -> Result<Option<Reminder>> {
+        let mut sql = self.db.query(
+            "UPDATE type::thing('reminder', $id) SET active = false
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version;")
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn acknowledge(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Reminder>> {
+        let mut sql = self.db.query(
+            "UPDATE type::thing('reminder', $id) SET acknowledged = time::now() WHERE user = type::thing('user', $user) AND deleted_at = NONE;")
+            .bind(("actor", actor))
+            .bind(("keep_version", true))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Reminder>>> {
+        self.history_of("reminder", id, owner).await
+    }
+
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Reminder>> {
+        self.revert_record(actor, "reminder", id, owner, version, to).await
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Reminder>> {
+        self.delete_record(actor, "reminder", id, owner, false).await
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>, active: Option<bool>) -> Result<Vec<Reminder>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM reminder WHERE user = type::thing('user', $user)
+            AND ($profile = NONE OR profile = type::thing('profile', $profile))
+            AND ($active = NONE OR active = $active) AND deleted_at = NONE;")
+            .bind(("user", owner))
+            .bind(("profile", profile))
+            .bind(("active", active))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+}
+
+#[async_trait]
+impl NoteRepository for SurrealRepository {
+    async fn create(&self, actor: &str, owner: &str, note: CreateNote) -> Result<Option<Note>> {
+        let mut sql = self.db.query(
+            "CREATE note SET user = type::thing('user', $user), note_table = $note_table, note_thing = $note_thing,
+            profile = type::thing($note_table, $note_thing).profile, content = $content;")
+            .bind(("actor", actor))
+            .bind(("user", owner))
+            .bind(("note_table", note.note_table))
+            .bind(("note_thing", note.note_thing))
+            .bind(("content", note.content))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<Note>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM type::thing('note', $id) WHERE user = type::thing('user', $user) AND deleted_at = NONE;")
+            .bind(("id", id))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, note: CreateNote) -> Result<Option<Note>> {
+        let mut sql = self.db.query(
+            "UPDATE type::thing('note', $id) SET note_table = $note_table, note_thing = $note_thing,
+            profile = type::thing($note_table, $note_thing).profile, content = $content
+           
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version;")
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .bind(("note_table", note.note_table))
+            .bind(("note_thing", note.note_thing))
+            .bind(("content", note.content))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn history(&self, id: &str, owner: &str) -> Result<Vec<Version<Note>>> {
+        self.history_of("note", id, owner).await
+    }
+
+    async fn revert(&self, actor: &str, id: &str, owner: &str, version: &Datetime, to: &Datetime)
+        -> Result<Option<Note>> {
+        self.revert_record(actor, "note", id, owner, version, to).await
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<Note>> {
+        self.delete_record(actor, "note", id, owner, false).await
+    }
+
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<Note>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM note WHERE user = type::thing('user', $user)
+            AND ($profile = NONE OR profile = type::thing('profile', $profile)) AND deleted_at = NONE ORDER BY created;")
+            .bind(("user", owner))
+            .bind(("profile", profile))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn list_dose_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<DoseNote>> {
+        let mut sql = match profile {
+            Some(profile) => self.db.query("RETURN fn::list_all_dose_notes_for_profile($profile);")
+                .bind(("profile", profile)),
+            None => self.db.query("RETURN fn::list_all_dose_notes($user);").bind(("user", owner)),
+        }
+        .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn list_notes_for_dose(&self, owner: &str, dose: &str) -> Result<Vec<DoseNote>> {
+        let mut sql = self.db.query(
+            "RETURN fn::list_notes_for_dose($id, $user);")
+            .bind(("id", dose))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn list_medication_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<MedicationNote>> {
+        let mut sql = match profile {
+            Some(profile) => self.db.query("RETURN fn::list_all_medication_notes_for_profile($profile);")
+                .bind(("profile", profile)),
+            None => self.db.query("RETURN fn::list_all_medication_notes($user);").bind(("user", owner)),
+        }
+        .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn list_notes_for_medication(&self, owner: &str, medication: &str) -> Result<Vec<MedicationNote>> {
+        let mut sql = self.db.query(
+            "RETURN fn::list_notes_for_medication($id, $user);")
+            .bind(("id", medication))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn list_store_notes(&self, owner: &str, profile: Option<&str>) -> Result<Vec<StoreNote>> {
+        let mut sql = match profile {
+            Some(profile) => self.db.query("RETURN fn::list_all_store_notes_for_profile($profile);")
+                .bind(("profile", profile)),
+            None => self.db.query("RETURN fn::list_all_store_notes($user);").bind(("user", owner)),
+        }
+        .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn list_notes_for_store(&self, owner: &str, store: &str) -> Result<Vec<StoreNote>> {
+        let mut sql = self.db.query(
+            "RETURN fn::list_notes_for_store($id, $user);")
+            .bind(("id", store))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+}
+
+#[async_trait]
+impl UnitOfMeasureRepository for SurrealRepository {
+    async fn create(&self, actor: &str, owner: &str, unit: CreateUnitOfMeasure) -> Result<Option<UnitOfMeasure>> {
+        let mut sql = self.db.query(
+            "CREATE unit_of_measure set user = type::thing('user', $user), name = $name, abbreviation = $abbreviation;")
+            .bind(("actor", actor))
+            .bind(("user", owner))
+            .bind(("name", unit.name))
+            .bind(("abbreviation", unit.abbreviation))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<UnitOfMeasure>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM type::thing('unit_of_measure', $id) WHERE user = type::thing('user', $user) AND deleted_at = NONE;")
+            .bind(("id", id))
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, unit: CreateUnitOfMeasure)
+        -> Result<Option<UnitOfMeasure>> {
+        let mut sql = self.db.query(
+            "UPDATE type::thing('unit_of_measure', $id) SET name = $name, abbreviation = $abbreviation, active = $active
+           
+            WHERE user = type::thing('user', $user) AND deleted_at = NONE AND updated = $version;")
+            .bind(("actor", actor))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("version", version))
+            .bind(("name", unit.name))
+            .bind(("abbreviation", unit.abbreviation))
+            .bind(("active", unit.active))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<UnitOfMeasure>> {
+        self.delete_record(actor, "unit_of_measure", id, owner, false).await
+    }
+
+    async fn list(&self, owner: &str) -> Result<Vec<UnitOfMeasure>> {
+        let mut sql = self.db.query(
+            "SELECT * FROM unit_of_measure WHERE user = type::thing('user', $user) AND deleted_at = NONE ORDER BY name;")
+            .bind(("user", owner))
+            .await?;
+        Ok(sql.take(0)?)
+    }
+}
+
+#[async_trait]
+impl TrashRepository for SurrealRepository {
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<TrashItem>> {
+        let select: String = TRASH_TABLES
+            .iter()
+            .map(|table| format!(
+                "SELECT * FROM {table} WHERE user = type::thing('user', $owner) AND deleted_at != NONE
+                    AND ($profile = NONE OR profile = NONE OR profile = type::thing('profile', $profile));\n"))
+            .collect();
+        let mut sql = self.db.query(select)
+            .bind(("owner", owner))
+            .bind(("profile", profile))
+            .await?;
+
+        let mut items = Vec::new();
+        for (index, table) in TRASH_TABLES.iter().enumerate() {
+            let records: Vec<serde_json::Value> = sql.take(index)?;
+            for record in records {
+                let deleted_at = record.get("deleted_at")
+                    .and_then(|value| value.as_str())
+                    .and_then(|value| Datetime::try_from(value).ok())
+                    .ok_or_else(|| anyhow::anyhow!("{table} in the trash has no valid deleted_at"))?;
+                items.push(TrashItem { table: table.to_string(), deleted_at, record });
+            }
+        }
+        items.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at));
+        Ok(items)
+    }
+
+    async fn restore(&self, actor: &str, table: &str, id: &str, owner: &str, cascade: bool)
+        -> Result<Option<serde_json::Value>> {
+        // The records deleted along with this one have the same `deleted_at`.
+        let dependents = match cascade {
+            true => collect_dependents(table, "deleted_at = $deleted_at"),
+            false => collect_notes(table, "deleted_at = $deleted_at"),
+        };
+        let mut sql = Transaction::new(&self.db, format!(
+            "LET ${table} = SELECT VALUE id FROM type::thing($table, $id)
+                WHERE user = type::thing('user', $user) AND deleted_at != NONE;
+            LET $deleted_at = (SELECT VALUE deleted_at FROM ${table})[0];
+            {references}
+            LET $parents = SELECT VALUE id FROM $references WHERE deleted_at != NONE;
+            {dependents}
+            LET $restore = array::len($parents) = 0;
+            RETURN $parents;
+            {stock}
+            UPDATE $dependents SET deleted_at = NONE WHERE $restore;
+            UPDATE ${table} SET deleted_at = NONE WHERE $restore RETURN AFTER;",
+            references = collect_references(table),
+            stock = match table {
+                // A restored dose takes from its store again, as if it were logged anew.
+                "dose" => format!(
+                    "LET $store = IF $dose[0] != NONE THEN meta::id($dose[0].store) END;
+                    LET $quantity = $dose[0].quantity;
+                    LET $unit = $dose[0].unit;
+                    {take}",
+                    take = take_from_store("$restore AND $dose[0] != NONE", "$dose[0]")),
+                _ => String::new(),
+            }))
+            .bind(("actor", actor))
+            .bind(("table", table))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .commit()
+            .await?;
+
+        let last = sql.num_statements() - 1;
+        let parents: Vec<Thing> = sql.take(0)?;
+        if !parents.is_empty() {
+            return Err(Error::Conflict { dependents: parents });
+        }
+        Ok(sql.take(last)?)
+    }
+
+    async fn purge(&self, actor: &str, table: &str, id: &str, owner: &str, cascade: bool)
+        -> Result<Option<serde_json::Value>> {
+        let mut sql = Transaction::new(&self.db, format!(
+            "LET ${table} = SELECT VALUE id FROM type::thing($table, $id)
+                WHERE user = type::thing('user', $user) AND deleted_at != NONE;
+            {dependents}
+            LET $purge = $cascade OR array::len($restricted) = 0;
+            RETURN $restricted;
+            DELETE $dependents WHERE $purge;
+            DELETE ${table} WHERE $purge RETURN BEFORE;",
+            dependents = collect_dependents(table, "true")))
+            .bind(("actor", actor))
+            .bind(("table", table))
+            .bind(("id", id))
+            .bind(("user", owner))
+            .bind(("cascade", cascade))
+            .commit()
+            .await?;
+
+        let last = sql.num_statements() - 1;
+        let restricted: Vec<Thing> = sql.take(last - 2)?;
+        if !cascade && !restricted.is_empty() {
+            return Err(Error::Conflict { dependents: restricted });
+        }
+        Ok(sql.take(last)?)
+    }
+}
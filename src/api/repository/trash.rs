@@ -0,0 +1,43 @@
+use async_trait::async_trait;
+use serde::Serialize;
+use surrealdb::sql::Datetime;
+
+use crate::api::Result;
+
+/// A struct representing a deleted record in the trash
+///
+/// # Fields
+///
+/// * `table` - The table of the record
+/// * `deleted_at` - A `Datetime` representing when the record was deleted
+/// * `record` - The record as it was deleted
+#[derive(Serialize)]
+pub struct TrashItem {
+    pub table: String,
+    pub deleted_at: Datetime,
+    pub record: serde_json::Value,
+}
+
+/// The deleted records of a user, of any of the tables in `crate::db::trash::TRASH_TABLES`, which the caller
+/// must have checked `table` is one of.
+///
+/// The records are returned as JSON, as they come from different tables.
+#[async_trait]
+pub trait TrashRepository: Send + Sync {
+    /// Lists the records of `owner` in the trash, of a profile or of all of them, most recently deleted first.
+    /// Units of measure don't belong to a profile, so they are listed for every profile.
+    async fn list(&self, owner: &str, profile: Option<&str>) -> Result<Vec<TrashItem>>;
+
+    /// Restores a record of `owner` from the trash, along with the notes about it that were deleted with it, and
+    /// with `cascade` the other records deleted along with it, see `reference`. Returns `None` if it isn't in the
+    /// trash. Fails with `Error::Conflict` listing the records it references that are in the trash, or with
+    /// `Error::UnprocessableEntity` if a record with the same name was created since it was deleted.
+    async fn restore(&self, actor: &str, table: &str, id: &str, owner: &str, cascade: bool)
+        -> Result<Option<serde_json::Value>>;
+
+    /// Deletes a record of `owner` in the trash for good, along with the notes about it, and with `cascade` the
+    /// records depending on it. Returns `None` if it isn't in the trash. Fails with `Error::Conflict` listing the
+    /// records depending on it if there are any and `cascade` isn't set.
+    async fn purge(&self, actor: &str, table: &str, id: &str, owner: &str, cascade: bool)
+        -> Result<Option<serde_json::Value>>;
+}
@@ -0,0 +1,57 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde::Serialize;
+use surrealdb::sql::{ Thing, Datetime };
+
+use crate::api::Result;
+
+/// A unit of measure to be used by the client for medications and doses.
+///
+/// # Fields
+///
+/// * `id` - An optional unique identifier for the unit of measure.
+/// * `user` - The user who created the unit of measure.
+/// * `name` - The name of the unit of measure.
+/// * `abbreviation` - The abbreviation of the unit of measure.
+/// * `created` - An optional timestamp indicating when the unit of measure was created.
+/// * `updated` - A timestamp indicating when the unit of measure was last updated, its version.
+/// * `active` - An optional boolean indicating whether the unit of measure is currently active.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UnitOfMeasure {
+    pub id: Option<Thing>,
+    pub user: Thing,
+    pub name: String,
+    pub abbreviation: String,
+    pub created: Option<Datetime>,
+    pub updated: Datetime,
+    pub active: Option<bool>,
+}
+
+/// A unit of measure to be created or updated by the client. The owning user is taken from the login token.
+#[derive(Serialize, Deserialize)]
+pub struct CreateUnitOfMeasure {
+    pub name: String,
+    pub abbreviation: String,
+    pub active: Option<bool>,
+}
+
+/// The units of measure of a user. They are never shared through grants.
+#[async_trait]
+pub trait UnitOfMeasureRepository: Send + Sync {
+    /// Creates a unit of measure of `owner`.
+    async fn create(&self, actor: &str, owner: &str, unit: CreateUnitOfMeasure) -> Result<Option<UnitOfMeasure>>;
+
+    /// Returns the unit of measure, or `None` if it doesn't exist or belongs to another user.
+    async fn read(&self, id: &str, owner: &str) -> Result<Option<UnitOfMeasure>>;
+
+    /// Replaces the fields of the unit of measure if it is still at `version`, its `updated`. Returns `None` if
+    /// it doesn't exist, belongs to another user or was changed since.
+    async fn update(&self, actor: &str, id: &str, owner: &str, version: &Datetime, unit: CreateUnitOfMeasure)
+        -> Result<Option<UnitOfMeasure>>;
+
+    /// Moves the unit of measure to the trash and returns it as it was, see `crate::db::trash`.
+    async fn delete(&self, actor: &str, id: &str, owner: &str) -> Result<Option<UnitOfMeasure>>;
+
+    /// Lists the units of measure of `owner` by name.
+    async fn list(&self, owner: &str) -> Result<Vec<UnitOfMeasure>>;
+}
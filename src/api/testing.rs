@@ -0,0 +1,166 @@
+//! The API on an in-memory database, for the tests of the handlers.
+use axum::body::{Body, HttpBody};
+use axum::extract::ConnectInfo;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH};
+use axum::http::{HeaderMap, HeaderName, Method, Request, StatusCode};
+use axum::Router;
+use clap::Parser;
+use serde_json::{json, Value};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use surrealdb::engine::any::Any;
+use surrealdb::Surreal;
+use tower::ServiceExt;
+
+use crate::api::handlers::user;
+use crate::api::repository::{Repositories, SurrealRepository};
+use crate::api::{api_router, ApiContext};
+use crate::config::Config;
+use crate::db::encryption::{self, Cipher, Secret};
+use crate::db::{self, migration};
+
+/// The address the requests come from unless a test gives another one. It isn't a loopback address, so
+/// failed logins are tracked by IP as well, see `handlers::login_attempt`.
+pub(crate) const CLIENT: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 40000));
+
+/// The password of the users made with `TestApp::sign_up()`.
+pub(crate) const PASSWORD: &str = "correct horse";
+
+/// The API on a database of its own, migrated, with the cheapest argon2 settings for the passwords. It is
+/// unencrypted unless the arguments set a key.
+pub(crate) struct TestApp {
+    /// The context the router runs on, for tests of the repositories behind it.
+    pub(crate) ctx: ApiContext,
+    router: Router,
+}
+
+impl TestApp {
+    pub(crate) async fn new() -> Self {
+        Self::with_args(&[]).await
+    }
+
+    /// The API with more command line arguments for its `Config`.
+    pub(crate) async fn with_args(args: &[&str]) -> Self {
+        let config = Config::parse_from([
+            "medoxido",
+            "--db-engine", "mem",
+            "--db-namespace", "test",
+            "--db-name", "test",
+            "--hmac-key", "test",
+            "--argon2-memory-kib", "8",
+            "--argon2-iterations", "1",
+            "--argon2-parallelism", "1",
+        ].iter().chain(args));
+        let db = db::connect(&config).await.expect("failed to open the in-memory database");
+        migration::migrate(&db).await.expect("failed to migrate the database");
+        let cipher = encryption::unlock(&db, Secret::from_config(&config)).await.expect("failed to unlock the database");
+
+        let dummy_password_hash = user::dummy_password_hash(&config).expect("invalid argon2 settings");
+        let ctx = ApiContext {
+            config: Arc::new(config),
+            repos: Repositories::new(SurrealRepository::new(db.clone())).encrypted(cipher.clone()),
+            db,
+            cipher,
+            dummy_password_hash: dummy_password_hash.into(),
+        };
+        Self { router: api_router(ctx.clone()), ctx }
+    }
+
+    /// The database behind the API, to check what is stored.
+    pub(crate) fn db(&self) -> &Surreal<Any> {
+        &self.ctx.db
+    }
+
+    /// The cipher of the database, see `crate::db::encryption`.
+    pub(crate) fn cipher(&self) -> &Cipher {
+        &self.ctx.cipher
+    }
+
+    /// Sends a request from `CLIENT`, and returns the status and the body, as JSON if it is.
+    pub(crate) async fn request(&self, method: Method, uri: &str, token: Option<&str>, body: Option<Value>)
+        -> (StatusCode, Value) {
+        self.request_from(CLIENT, method, uri, token, body).await
+    }
+
+    /// Sends a request from `client`, see `request()`.
+    pub(crate) async fn request_from(&self, client: SocketAddr, method: Method, uri: &str, token: Option<&str>,
+        body: Option<Value>) -> (StatusCode, Value) {
+        let authorization = token.map(|token| format!("Bearer {token}"));
+        let headers: Vec<_> = authorization.iter().map(|value| (AUTHORIZATION, value.as_str())).collect();
+        let (status, _, body) = self.send(client, method, uri, &headers, body).await;
+        (status, body)
+    }
+
+    /// Sends a request from `CLIENT` with the given headers, e.g. cookies, and returns the headers of the
+    /// response along with the status and the body.
+    pub(crate) async fn request_with_headers(&self, method: Method, uri: &str, headers: &[(HeaderName, &str)],
+        body: Option<Value>) -> (StatusCode, HeaderMap, Value) {
+        self.send(CLIENT, method, uri, headers, body).await
+    }
+
+    /// Reads the record at `uri` and returns its `ETag`, for the `If-Match` header of a change to it.
+    pub(crate) async fn etag(&self, uri: &str, token: &str) -> String {
+        let authorization = format!("Bearer {token}");
+        let (status, headers, body) =
+            self.request_with_headers(Method::GET, uri, &[(AUTHORIZATION, &authorization)], None).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        headers.get(ETAG).unwrap_or_else(|| panic!("no ETag for {uri}")).to_str().unwrap().to_owned()
+    }
+
+    /// Sends a request from `CLIENT` with the `ETag` of the record in `If-Match`, see `request()`.
+    pub(crate) async fn request_if_match(&self, method: Method, uri: &str, token: &str, etag: &str,
+        body: Option<Value>) -> (StatusCode, Value) {
+        let authorization = format!("Bearer {token}");
+        let headers = [(AUTHORIZATION, authorization.as_str()), (IF_MATCH, etag)];
+        let (status, _, body) = self.request_with_headers(method, uri, &headers, body).await;
+        (status, body)
+    }
+
+    async fn send(&self, client: SocketAddr, method: Method, uri: &str, headers: &[(HeaderName, &str)],
+        body: Option<Value>) -> (StatusCode, HeaderMap, Value) {
+        let mut request = Request::builder().method(method).uri(uri).header(CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        let mut request = request
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .expect("invalid request");
+        request.extensions_mut().insert(ConnectInfo(client));
+
+        let response = self.router.clone().oneshot(request).await.expect("the router failed");
+        let status = response.status();
+        let headers = response.headers().clone();
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.expect("failed to read the response"));
+        }
+        let body = serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+        (status, headers, body)
+    }
+
+    /// Signs up a user with `PASSWORD` and returns its token.
+    pub(crate) async fn sign_up(&self, username: &str) -> String {
+        let user = json!({ "user": { "username": username, "email": format!("{username}@example.com"), "password": PASSWORD } });
+        let (status, body) = self.request(Method::POST, "/users", None, Some(user)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        token(&body)
+    }
+
+    /// Logs a user in from `client` with `password`.
+    pub(crate) async fn log_in_from(&self, client: SocketAddr, username: &str, password: &str) -> (StatusCode, Value) {
+        let user = json!({ "user": { "username": username, "password": password } });
+        self.request_from(client, Method::POST, "/users/login", None, Some(user)).await
+    }
+}
+
+/// The token in the body of a login.
+pub(crate) fn token(body: &Value) -> String {
+    body["user"]["token"].as_str().unwrap_or_else(|| panic!("no token in {body}")).to_owned()
+}
+
+/// The ID of a record in a response, without its table.
+pub(crate) fn id(record: &Value) -> String {
+    record["id"]["id"]["String"].as_str().unwrap_or_else(|| panic!("no ID in {record}")).to_owned()
+}
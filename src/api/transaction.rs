@@ -0,0 +1,65 @@
+//! Statements that must apply together or not at all, e.g. logging a dose and using up its store.
+//!
+//! A `Transaction` wraps the statements in `BEGIN TRANSACTION` and `COMMIT TRANSACTION`. When one of them
+//! fails, SurrealDB rolls back the others and reports each of them as not executed, so `commit()` looks for
+//! the statement that failed and turns it into an `Error`:
+//!
+//! * `THROW "<field>: <problem>"` is how a statement refuses the request, e.g. `THROW "quantity: is more than
+//!   is left in the store"`. It becomes `Error::UnprocessableEntity` with the problem under the field, or
+//!   under `record` for a message without one.
+//! * A field refusing its value, or a unique index a record, becomes `Error::UnprocessableEntity` too.
+//! * Anything else is a database error, as for a single statement.
+//!
+//! A `RETURN` drops the results of the statements before it, errors included, so it is the first result of
+//! the transaction, and a statement that may `THROW` has to come after it.
+use std::fmt::Display;
+
+use serde::Serialize;
+use surrealdb::engine::any::Any;
+use surrealdb::method::Query;
+use surrealdb::{Response, Surreal};
+
+use crate::api::error::Error;
+use crate::api::Result;
+use crate::db::migration::first_error;
+
+/// A transaction to bind parameters to and then commit, see the module documentation.
+pub struct Transaction<'a> {
+    query: Query<'a, Any>,
+}
+
+impl<'a> Transaction<'a> {
+    /// Wraps `statements` in a transaction. `BEGIN` and `COMMIT` return no results, so the results in the
+    /// response are those of `statements`.
+    pub fn new(db: &'a Surreal<Any>, statements: impl Display) -> Self {
+        let query = db.query(format!("BEGIN TRANSACTION;\n{statements}\nCOMMIT TRANSACTION;"));
+        Self { query }
+    }
+
+    /// Binds a parameter, like `Query::bind()`.
+    pub fn bind(self, binding: impl Serialize) -> Self {
+        Self { query: self.query.bind(binding) }
+    }
+
+    /// Runs the transaction and returns the results of the statements, or the error of the one that rolled
+    /// it back.
+    pub async fn commit(self) -> Result<Response> {
+        let mut response = self.query.await?;
+        match first_error(&mut response) {
+            None => Ok(response),
+            Some(surrealdb::Error::Db(surrealdb::error::Db::Thrown(message))) => {
+                let (field, problem) = message.split_once(": ").unwrap_or(("record", &message));
+                Err(Error::unprocessable_entity([(field.to_owned(), problem.to_owned())]))
+            }
+            Some(surrealdb::Error::Db(
+                surrealdb::error::Db::FieldValue { field, .. } | surrealdb::error::Db::FieldCheck { field, .. },
+            )) => {
+                Err(Error::unprocessable_entity([(field.to_string(), "is not valid")]))
+            }
+            Some(surrealdb::Error::Db(surrealdb::error::Db::IndexExists { .. })) => {
+                Err(Error::unprocessable_entity([("record", "has the same name as another one")]))
+            }
+            Some(error) => Err(error.into()),
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 /// The configuration parameters for the application.
 ///
 /// For development convenience, these can also be read from a `.env` file in the working
@@ -6,21 +8,34 @@
 /// See `.env.sample` in the repository root for details.
 #[derive(clap::Parser)]
 pub struct Config {
-    /// The connection URL for the SurrealDB connection.
-    #[clap(long, env)]
+    /// A command to run instead of serving the API.
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// The SurrealDB engine to use: a remote server over a websocket, an embedded database in a local
+    /// file, or an in-memory database that is gone when the application stops.
+    #[clap(long, env, value_enum, default_value_t = DbEngine::Ws)]
+    pub db_engine: DbEngine,
+
+    /// The directory of the embedded database for the `file` engine.
+    #[clap(long, env, default_value = "medoxido.db")]
+    pub db_path: String,
+
+    /// The host of the SurrealDB server for the `ws` engine.
+    #[clap(long, env, default_value = "localhost")]
     pub db_host: String,
 
-    /// The port for the SurrealDB connection.
-    #[clap(long, env)]
+    /// The port of the SurrealDB server for the `ws` engine.
+    #[clap(long, env, default_value = "8000")]
     pub db_port: String,
 
-    /// The user for the SurrealDB connection.
+    /// The root user to sign in to SurrealDB with, if any. The embedded engines don't need one.
     #[clap(long, env)]
-    pub db_user: String,
+    pub db_user: Option<String>,
 
-    /// The password for the SurrealDB connection.
+    /// The password of `db_user`.
     #[clap(long, env)]
-    pub db_password: String,
+    pub db_password: Option<String>,
 
     /// The namespace for the SurrealDB connection.
     #[clap(long, env)]
@@ -30,8 +45,122 @@ pub struct Config {
     #[clap(long, env)]
     pub db_name: String,
 
+    /// What to do with the schema migrations that haven't been applied to the database yet.
+    #[clap(long, env, value_enum, default_value_t = MigrateMode::Startup)]
+    pub db_migrate: MigrateMode,
+
     /// The HMAC signing and verification key used for login tokens (JWTs).
 
     #[clap(long, env)]
     pub hmac_key: String,
+
+    /// Whether the session cookies are marked `Secure`, i.e. only sent over HTTPS.
+    ///
+    /// Leave this off when serving plain HTTP to a local desktop frontend.
+    #[clap(long, env)]
+    pub secure_cookies: bool,
+
+    /// The memory cost of new argon2 password hashes, in KiB.
+    #[clap(long, env, default_value_t = argon2::Params::DEFAULT_M_COST)]
+    pub argon2_memory_kib: u32,
+
+    /// The number of iterations of new argon2 password hashes.
+    #[clap(long, env, default_value_t = argon2::Params::DEFAULT_T_COST)]
+    pub argon2_iterations: u32,
+
+    /// The degree of parallelism of new argon2 password hashes.
+    ///
+    /// Existing hashes made with lower settings than these three are replaced on the next login.
+    #[clap(long, env, default_value_t = argon2::Params::DEFAULT_P_COST)]
+    pub argon2_parallelism: u32,
+
+    /// How many minutes a session unlocked with the quick-unlock PIN lasts without being used.
+    #[clap(long, env, default_value_t = 15)]
+    pub pin_idle_timeout_minutes: i64,
+
+    /// The key that authorizes `GET /backup` and `POST /backup/restore`, sent as a bearer token.
+    ///
+    /// These routes read and replace the records of every user, so they are disabled unless a key is set.
+    #[clap(long, env)]
+    pub backup_key: Option<String>,
+
+    /// The largest backup `POST /backup/restore` accepts, in MiB.
+    #[clap(long, env, default_value_t = 256, value_parser = clap::value_parser!(u64).range(1..))]
+    pub backup_max_mib: u64,
+
+    /// The directory for the scheduled backups. Scheduled backups are off unless it is set.
+    #[clap(long, env)]
+    pub backup_dir: Option<PathBuf>,
+
+    /// How many hours apart the scheduled backups are. The first one is made at startup.
+    #[clap(long, env, default_value_t = 24, value_parser = clap::value_parser!(u64).range(1..))]
+    pub backup_interval_hours: u64,
+
+    /// How many scheduled backups are kept in `backup_dir`, older ones are deleted.
+    #[clap(long, env, default_value_t = 7, value_parser = clap::value_parser!(u64).range(1..))]
+    pub backup_retention: u64,
+
+    /// How many days deleted records stay in the trash before they are purged for good.
+    #[clap(long, env, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub trash_retention_days: u64,
+
+    /// The passphrase the sensitive fields are encrypted with, see `db::encryption`.
+    ///
+    /// Setting it, or `encryption_key_file`, on an unencrypted database encrypts it at startup. An encrypted
+    /// database doesn't start without its key.
+    #[clap(long, env, conflicts_with = "encryption_key_file")]
+    pub encryption_passphrase: Option<String>,
+
+    /// A file whose contents the sensitive fields are encrypted with, instead of a passphrase.
+    #[clap(long, env)]
+    pub encryption_key_file: Option<PathBuf>,
+}
+
+/// The commands that run instead of the server, see `Config::command`.
+#[derive(clap::Subcommand, Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Write a backup of the whole database to a file, then exit.
+    Backup {
+        /// The file to write the backup to.
+        path: PathBuf,
+    },
+    /// Restore a backup file into the empty database, then exit.
+    Restore {
+        /// The backup file to restore.
+        path: PathBuf,
+    },
+    /// Re-encrypt the database, unlocked with the current key, with a new passphrase or key file, then exit.
+    #[clap(group(clap::ArgGroup::new("new_key").required(true).args(["new_passphrase", "new_key_file"])))]
+    RotateKey {
+        /// The new passphrase.
+        #[clap(long, env = "NEW_ENCRYPTION_PASSPHRASE")]
+        new_passphrase: Option<String>,
+        /// The new key file.
+        #[clap(long, env = "NEW_ENCRYPTION_KEY_FILE")]
+        new_key_file: Option<PathBuf>,
+    },
+}
+
+/// The SurrealDB engines the application can run on, see `Config::db_engine`.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DbEngine {
+    /// A SurrealDB server at `db_host`:`db_port`.
+    Ws,
+    /// An embedded RocksDB database in the directory `db_path`. Needs the `kv-rocksdb` feature.
+    File,
+    /// An embedded in-memory database, e.g. for tests and demos.
+    Mem,
+}
+
+/// When the embedded schema migrations are applied, see `Config::db_migrate`.
+///
+/// In every mode the application refuses to start if a migration was edited after it was applied.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MigrateMode {
+    /// Apply the pending migrations, then start the server.
+    Startup,
+    /// Apply the pending migrations and exit.
+    Only,
+    /// Apply nothing and refuse to start while migrations are pending.
+    Check,
 }
@@ -0,0 +1,50 @@
+use anyhow::Context;
+use surrealdb::engine::any::{self, Any};
+use surrealdb::opt::auth::Root;
+use surrealdb::Surreal;
+
+use crate::config::{Config, DbEngine};
+
+pub mod backup;
+pub mod encryption;
+pub mod migration;
+pub mod trash;
+
+/// Connects to the database with the engine chosen in the `Config` and selects its namespace and
+/// database.
+///
+/// All engines share the `Surreal<Any>` type, so the rest of the application doesn't care which
+/// one it runs on.
+///
+/// # Arguments
+///
+/// * `config` - The `Config` with the engine and its connection settings
+///
+/// # Returns
+///
+/// The connected `Surreal<Any>` client, or an `anyhow::Error` if the database can't be reached or
+/// the engine isn't part of this build.
+pub async fn connect(config: &Config) -> anyhow::Result<Surreal<Any>> {
+    let endpoint = match config.db_engine {
+        DbEngine::Ws => format!("ws://{}:{}", config.db_host, config.db_port),
+        DbEngine::File => format!("rocksdb://{}", config.db_path),
+        DbEngine::Mem => "mem://".to_owned(),
+    };
+
+    let db = any::connect(&endpoint)
+        .await
+        .with_context(|| format!("failed to connect to the database at {endpoint}"))?;
+
+    if let (Some(username), Some(password)) = (&config.db_user, &config.db_password) {
+        db.signin(Root { username, password })
+            .await
+            .context("failed to sign in to the database")?;
+    }
+
+    db.use_ns(&config.db_namespace)
+        .use_db(&config.db_name)
+        .await
+        .context("failed to select the database namespace")?;
+
+    Ok(db)
+}
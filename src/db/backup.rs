@@ -0,0 +1,269 @@
+//! Backup and restore of the whole database.
+//!
+//! # Backup format
+//!
+//! A backup is a single JSON document:
+//!
+//! ```json
+//! {
+//!   "format": "medoxido-backup",
+//!   "version": 1,
+//!   "created": "2024-01-31T12:00:00Z",
+//!   "schema_version": 17,
+//!   "checksum": "9f86d081884c7d65...",
+//!   "tables": { "dose": "[{ id: dose:abc, ... }]", "user": "[...]", ... }
+//! }
+//! ```
+//!
+//! `tables` has every table defined in `src/api/schema` but those in `LEFT_OUT`, each holding all its records
+//! as a SurrealQL array, which keeps the record IDs and datetimes that JSON would turn into strings. `schema_version`
+//! is the last migration applied to the database it was made from. `checksum` is the hex SHA-256 of the
+//! schema version and the tables, see `Backup::digest()`, to catch a backup that was damaged or edited.
+//!
+//! A backup is restored into an empty database only, it never merges with existing records.
+//!
+//! # Secrets
+//!
+//! A backup is written in plain text, the fields in `crate::db::encryption::ENCRYPTED_FIELDS` aside. So it
+//! leaves out the credentials that only last a while, see `LEFT_OUT`: after a restore, everyone signs in
+//! again. The ones an account can't do without are kept, as the database stores them: the passwords, PINs,
+//! recovery codes and access tokens as hashes only, but the TOTP secrets of two-factor authentication as
+//! they are. A backup must be kept as safe as the database itself.
+
+use anyhow::{bail, ensure, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use surrealdb::engine::any::Any;
+use surrealdb::sql::{Datetime, Id, Thing, Value};
+use surrealdb::Surreal;
+
+use crate::db::migration::{self, first_error, MIGRATIONS};
+
+/// Identifies a medoxido backup document.
+const BACKUP_FORMAT: &str = "medoxido-backup";
+
+/// The version of the backup format. Bump it when the format changes incompatibly.
+const BACKUP_VERSION: u32 = 1;
+
+/// The tables of sessions, refresh tokens, sign-in challenges, password recovery codes and failed sign-ins,
+/// which are left out of a backup, see the module documentation.
+const LEFT_OUT: [&str; 5] = ["session", "refresh_token", "login_challenge", "password_recovery_code", "login_attempt"];
+
+/// A backup of every table of the database, see the module documentation for the format.
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    format: String,
+    version: u32,
+    created: Datetime,
+    schema_version: u32,
+    checksum: String,
+    tables: BTreeMap<String, String>,
+}
+
+impl Backup {
+    /// The SHA-256 of the schema version and of each table name and its records, hex encoded.
+    fn digest(schema_version: u32, tables: &BTreeMap<String, String>) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{schema_version}\n"));
+        for (table, records) in tables {
+            hasher.update(format!("{table}\n{records}\n"));
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// The name of the file the backup is stored in, e.g. `medoxido-20240131T120000Z.backup`.
+    pub fn file_name(&self) -> String {
+        format!("medoxido-{}.backup", self.created.0.format("%Y%m%dT%H%M%SZ"))
+    }
+
+    /// Whether the backup was made of an encrypted database, see `crate::db::encryption`. It is restored
+    /// as it is, and needs the same key.
+    pub fn is_encrypted(&self) -> bool {
+        self.tables.get("encryption").is_some_and(|records| records != "[]")
+    }
+
+    /// Checks the backup and returns the records of each table.
+    ///
+    /// Fails if the backup isn't a supported medoxido backup, its checksum doesn't match, it was made
+    /// with a newer schema than this build has, or it holds anything other than plain records of the
+    /// known tables. The records are only ever inserted as values, but a backup is not trusted to
+    /// contain nothing else.
+    pub fn records(&self) -> anyhow::Result<Vec<(&str, Value)>> {
+        ensure!(self.format == BACKUP_FORMAT, "not a medoxido backup");
+        ensure!(self.version == BACKUP_VERSION, "backup version {} is not supported", self.version);
+        ensure!(
+            self.checksum == Self::digest(self.schema_version, &self.tables),
+            "the checksum doesn't match, the backup is damaged"
+        );
+        ensure!(
+            self.schema_version as usize <= MIGRATIONS.len(),
+            "the backup was made with schema version {}, which this version of medoxido doesn't know",
+            self.schema_version
+        );
+
+        let known = migration::tables();
+        let mut records = Vec::with_capacity(self.tables.len());
+        for (table, sql) in &self.tables {
+            ensure!(known.contains(&table.as_str()), "table {table} is not part of the schema");
+
+            let value = surrealdb::sql::value(sql).with_context(|| format!("table {table} can't be parsed"))?;
+            let Value::Array(array) = &value else {
+                bail!("table {table} is not a list of records");
+            };
+            for record in array.iter() {
+                let Value::Object(object) = record else {
+                    bail!("table {table} has a value that is not a record");
+                };
+                ensure!(
+                    matches!(object.get("id"), Some(Value::Thing(thing)) if thing.tb == *table),
+                    "table {table} has a record without an ID of that table"
+                );
+                ensure!(is_data(record), "table {table} has a record with something other than data");
+            }
+            records.push((table.as_str(), value));
+        }
+        Ok(records)
+    }
+}
+
+/// Whether the value is plain data, rather than an expression that would be evaluated.
+fn is_data(value: &Value) -> bool {
+    match value {
+        Value::None | Value::Null | Value::Bool(_) | Value::Number(_) | Value::Strand(_) | Value::Duration(_)
+        | Value::Datetime(_) | Value::Uuid(_) | Value::Geometry(_) | Value::Bytes(_) => true,
+        Value::Array(array) => array.iter().all(is_data),
+        Value::Object(object) => object.values().all(is_data),
+        Value::Thing(Thing { id, .. }) => match id {
+            Id::Number(_) | Id::String(_) => true,
+            Id::Array(array) => array.iter().all(is_data),
+            Id::Object(object) => object.values().all(is_data),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Makes a backup of every table defined in the schema files but those in `LEFT_OUT`.
+///
+/// The tables are read in one transaction, so the backup is consistent even while the API is in use.
+pub async fn create(db: &Surreal<Any>) -> anyhow::Result<Backup> {
+    let schema_version = migration::version(db).await?;
+    let tables: Vec<&str> = migration::tables().into_iter().filter(|table| !LEFT_OUT.contains(table)).collect();
+
+    let select: String = tables.iter().map(|table| format!("SELECT * FROM {table};\n")).collect();
+    let mut response = db.query(format!("BEGIN TRANSACTION;\n{select}COMMIT TRANSACTION;"))
+        .await
+        .context("failed to read the tables for the backup")?;
+
+    let mut records = BTreeMap::new();
+    for (index, table) in tables.iter().enumerate() {
+        let value: Value = response.take(index).with_context(|| format!("failed to read table {table}"))?;
+        records.insert(table.to_string(), value.to_string());
+    }
+
+    Ok(Backup {
+        format: BACKUP_FORMAT.to_owned(),
+        version: BACKUP_VERSION,
+        created: Datetime::default(),
+        schema_version,
+        checksum: Backup::digest(schema_version, &records),
+        tables: records,
+    })
+}
+
+/// Returns whether none of the tables defined in the schema files has a record.
+pub async fn is_empty(db: &Surreal<Any>) -> anyhow::Result<bool> {
+    let tables = migration::tables();
+    let select: String = tables.iter().map(|table| format!("SELECT VALUE id FROM {table} LIMIT 1;\n")).collect();
+    let mut response = db.query(select).await.context("failed to check whether the database is empty")?;
+
+    for index in 0..tables.len() {
+        let ids: Vec<Thing> = response.take(index)?;
+        if !ids.is_empty() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Restores the records returned by `Backup::records()` into an empty database, and returns how many
+/// were restored.
+///
+/// Applies the pending migrations first, so the schema is the current one even for a backup made with an
+/// older one. The records are inserted in one transaction as they were, with their IDs and timestamps:
+/// the insert binds `$verbatim`, which keeps the computed fields as given and the audit log from recording
+/// every restored record again, see `023_verbatim_writes.surql`.
+pub async fn restore(db: &Surreal<Any>, records: Vec<(&str, Value)>) -> anyhow::Result<usize> {
+    migration::migrate(db).await?;
+    ensure!(is_empty(db).await?, "the database is not empty, a backup can only be restored into an empty one");
+
+    insert(db, records).await
+}
+
+/// Inserts the records of every table in one transaction, and returns how many were inserted.
+async fn insert(db: &Surreal<Any>, records: Vec<(&str, Value)>) -> anyhow::Result<usize> {
+    let mut sql = String::from("BEGIN TRANSACTION;\n");
+    let mut count = 0;
+    for (index, (table, value)) in records.iter().enumerate() {
+        if let Value::Array(array) = value {
+            count += array.len();
+        }
+        sql += &format!("INSERT INTO {table} $table{index};\n");
+    }
+    sql += "COMMIT TRANSACTION;";
+
+    let mut query = db.query(sql).bind(("verbatim", true));
+    for (index, (_, value)) in records.into_iter().enumerate() {
+        query = query.bind((format!("table{index}"), value));
+    }
+
+    let mut response = query.await.context("failed to restore the records")?;
+    if let Some(error) = first_error(&mut response) {
+        return Err(error).context("failed to restore the records");
+    }
+    Ok(count)
+}
+
+/// Writes a backup of the database into `dir`, then deletes the oldest backups there beyond the
+/// `retention` most recent ones. Returns the path of the new backup.
+pub async fn write(db: &Surreal<Any>, dir: &Path, retention: usize) -> anyhow::Result<PathBuf> {
+    let backup = create(db).await?;
+
+    std::fs::create_dir_all(dir).with_context(|| format!("failed to create the backup directory {}", dir.display()))?;
+    let path = dir.join(backup.file_name());
+    std::fs::write(&path, serde_json::to_vec(&backup)?)
+        .with_context(|| format!("failed to write the backup {}", path.display()))?;
+
+    // The file names sort by the time the backups were made.
+    let mut backups: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("medoxido-") && name.ends_with(".backup"))
+        })
+        .collect();
+    backups.sort();
+    let expired = backups.len().saturating_sub(retention);
+    for old in &backups[..expired] {
+        std::fs::remove_file(old).with_context(|| format!("failed to delete the old backup {}", old.display()))?;
+    }
+
+    Ok(path)
+}
+
+/// Writes a backup into `dir` right away and then every `interval`, keeping the `retention` most recent
+/// ones. Runs until the application stops; a failed backup is logged and tried again next time.
+pub async fn schedule(db: Surreal<Any>, dir: PathBuf, interval: Duration, retention: usize) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match write(&db, &dir, retention).await {
+            Ok(path) => log::info!("wrote backup {}", path.display()),
+            Err(error) => log::error!("scheduled backup failed: {error:#}"),
+        }
+    }
+}
@@ -0,0 +1,382 @@
+//! Encryption of the sensitive fields at rest.
+//!
+//! The fields in `ENCRYPTED_FIELDS`, and their copies in the `before` and `after` of the audit log and in the
+//! `content` of the version history, are stored as `enc:v1:` followed by the base64 of a nonce and their
+//! AES-256-GCM-SIV ciphertext. The other fields stay readable, as the queries filter, join and sort by them:
+//! IDs, links, dates and quantities.
+//!
+//! The key is derived with argon2id from a passphrase or the contents of a key file, and the salt in the
+//! `encryption:key` record, see `018_encryption.surql`. That record also holds a known text encrypted with
+//! the key, so a wrong key is refused at startup rather than showing up as unreadable records.
+//!
+//! The nonce is derived from the value, so equal values encrypt to the same text. This keeps the unique
+//! index on the medication names working, and lets the stock check compare the unit of a dose with that of its
+//! store. The price is that anyone who can read the database file sees which values are equal: that two
+//! medications, of the same user or of different users, have the same name, that two profiles have the same
+//! patient name, or which doses are in the same unit. It also sees the plain fields, when each dose was taken and
+//! how much, though not of what.
+//!
+//! Setting a key on an unencrypted database encrypts it at startup, and so does unlocking an encrypted one for
+//! the fields added to `ENCRYPTED_FIELDS` since. `rotate()` re-encrypts every field with a new key.
+
+use aes_gcm_siv::aead::{Aead, KeyInit};
+use aes_gcm_siv::{Aes256GcmSiv, Nonce};
+use anyhow::{anyhow, ensure, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::PathBuf;
+use std::sync::Arc;
+use surrealdb::engine::any::Any;
+use surrealdb::sql::Thing;
+use surrealdb::Surreal;
+
+use crate::config::Config;
+use crate::db::migration::first_error;
+
+/// The encrypted fields, as table and field.
+///
+/// These are the free text the user writes, the names of the patients, and the units of the stores and doses, which
+/// the stock check only compares with each other. The rest of a dose stays readable, as the database works with
+/// it: the lists join its store, the stock check sums its quantity, and the lists sort by its dates. The same goes
+/// for the quantities of the stores, and for the schedules of the reminders, which the schema validates and
+/// indexes.
+pub const ENCRYPTED_FIELDS: [(&str, &str); 6] = [
+    ("medication", "name"),
+    ("store", "lot_number"),
+    ("store", "unit"),
+    ("dose", "unit"),
+    ("note", "content"),
+    ("profile", "name"),
+];
+
+/// Marks an encrypted value, and the version of its format.
+const PREFIX: &str = "enc:v1:";
+
+/// The text encrypted in `key_check` to tell whether a key is the right one.
+const KEY_CHECK: &str = "medoxido";
+
+/// Where the key comes from, see `Config::encryption_passphrase` and `Config::encryption_key_file`.
+pub enum Secret {
+    Passphrase(String),
+    KeyFile(PathBuf),
+}
+
+impl Secret {
+    /// The secret set in the `Config`, if any.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        match (&config.encryption_passphrase, &config.encryption_key_file) {
+            (Some(passphrase), _) => Some(Self::Passphrase(passphrase.clone())),
+            (None, Some(path)) => Some(Self::KeyFile(path.clone())),
+            (None, None) => None,
+        }
+    }
+
+    fn bytes(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::Passphrase(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+            Self::KeyFile(path) => {
+                std::fs::read(path).with_context(|| format!("failed to read the key file {}", path.display()))
+            }
+        }
+    }
+}
+
+/// Why the database couldn't be unlocked at startup.
+#[derive(thiserror::Error, Debug)]
+pub enum KeyError {
+    #[error("the database is encrypted, set ENCRYPTION_PASSPHRASE or ENCRYPTION_KEY_FILE to its key")]
+    Missing,
+
+    #[error("the encryption key is wrong, it is not the one the database was encrypted with")]
+    Wrong,
+
+    #[error(transparent)]
+    Db(#[from] surrealdb::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+struct Keys {
+    aead: Aes256GcmSiv,
+    nonce: [u8; 32],
+}
+
+/// Encrypts and decrypts the values of the encrypted fields. Without a key, the default, it leaves them
+/// as they are.
+#[derive(Clone, Default)]
+pub struct Cipher {
+    keys: Option<Arc<Keys>>,
+}
+
+impl Cipher {
+    /// Derives the keys for encrypting the values and for their nonces from the secret and the salt.
+    fn derive(secret: &Secret, salt: &[u8]) -> anyhow::Result<Self> {
+        let mut key = [0u8; 64];
+        argon2::Argon2::default()
+            .hash_password_into(&secret.bytes()?, salt, &mut key)
+            .map_err(|error| anyhow!("failed to derive the encryption key: {error}"))?;
+        let (aead, nonce) = key.split_at(32);
+
+        Ok(Self {
+            keys: Some(Arc::new(Keys {
+                aead: Aes256GcmSiv::new_from_slice(aead)?,
+                nonce: nonce.try_into()?,
+            })),
+        })
+    }
+
+    /// Whether the database is encrypted.
+    pub fn is_enabled(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    /// Encrypts the value, or returns it as it is without a key.
+    pub fn encrypt(&self, value: &str) -> String {
+        let Some(keys) = &self.keys else {
+            return value.to_owned();
+        };
+
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&keys.nonce).expect("HMAC takes a key of any length");
+        mac.update(value.as_bytes());
+        let nonce = &mac.finalize().into_bytes()[..12];
+        let ciphertext = keys.aead
+            .encrypt(Nonce::from_slice(nonce), value.as_bytes())
+            .expect("encrypting into a Vec doesn't fail");
+
+        format!("{PREFIX}{}", STANDARD.encode([nonce, &ciphertext].concat()))
+    }
+
+    /// Decrypts the value. A value that isn't encrypted is returned as it is.
+    pub fn decrypt(&self, value: &str) -> anyhow::Result<String> {
+        let Some(encoded) = value.strip_prefix(PREFIX) else {
+            return Ok(value.to_owned());
+        };
+        let keys = self.keys.as_ref().context("the value is encrypted, but no key is set")?;
+
+        let bytes = STANDARD.decode(encoded).context("the encrypted value is damaged")?;
+        ensure!(bytes.len() > 12, "the encrypted value is damaged");
+        let (nonce, ciphertext) = bytes.split_at(12);
+        let plaintext = keys.aead
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("the value can't be decrypted with this key"))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+}
+
+#[derive(Deserialize)]
+struct KeyRecord {
+    salt: String,
+    key_check: String,
+    fields: Option<Vec<String>>,
+}
+
+/// `ENCRYPTED_FIELDS` as stored in the `fields` of the key record.
+fn field_names() -> Vec<String> {
+    ENCRYPTED_FIELDS.iter().map(|(table, field)| format!("{table}.{field}")).collect()
+}
+
+/// Returns the cipher for the database, checking the secret against its key.
+///
+/// Without a secret the database must not be encrypted. With one, an unencrypted database is encrypted
+/// first, which rewrites every encrypted field.
+pub async fn unlock(db: &Surreal<Any>, secret: Option<Secret>) -> Result<Cipher, KeyError> {
+    let record: Option<KeyRecord> = db.query("SELECT salt, key_check, fields FROM encryption:key").await?.take(0)?;
+
+    match (record, secret) {
+        (None, None) => Ok(Cipher::default()),
+        (Some(_), None) => Err(KeyError::Missing),
+        (Some(record), Some(secret)) => {
+            let salt = STANDARD.decode(&record.salt).context("the salt of the encryption key is damaged")?;
+            let cipher = Cipher::derive(&secret, &salt)?;
+            match cipher.decrypt(&record.key_check) {
+                Ok(check) if check == KEY_CHECK => {}
+                _ => return Err(KeyError::Wrong),
+            }
+            // Encrypting a value again gives the same text, so only the fields that were still plain change.
+            if record.fields != Some(field_names()) {
+                log::info!("encrypting the fields added since the database was encrypted");
+                reencrypt(db, &cipher, &cipher, &record.salt).await?;
+            }
+            Ok(cipher)
+        }
+        (None, Some(secret)) => {
+            log::info!("encrypting the database");
+            Ok(rekey(db, &Cipher::default(), &secret).await?)
+        }
+    }
+}
+
+/// Re-encrypts the database, unlocked with `current`, with a key derived from a new secret and salt, and
+/// returns the new cipher.
+pub async fn rotate(db: &Surreal<Any>, current: &Cipher, secret: &Secret) -> anyhow::Result<Cipher> {
+    ensure!(current.is_enabled(), "the database is not encrypted, set a key to encrypt it at startup instead");
+    rekey(db, current, secret).await
+}
+
+async fn rekey(db: &Surreal<Any>, current: &Cipher, secret: &Secret) -> anyhow::Result<Cipher> {
+    let salt: [u8; 16] = rand::random();
+    let cipher = Cipher::derive(secret, &salt)?;
+    reencrypt(db, current, &cipher, &STANDARD.encode(salt)).await?;
+    Ok(cipher)
+}
+
+/// An encrypted field of a record.
+#[derive(Serialize, Deserialize)]
+struct Field {
+    id: Thing,
+    value: Option<String>,
+}
+
+/// An encrypted field in the copies of a record in an audit log entry.
+#[derive(Serialize, Deserialize)]
+struct AuditField {
+    id: Thing,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+/// Decrypts every encrypted field with `from` and encrypts it with `to`, and stores the key check of `to`
+/// with its salt and the list of encrypted fields, all in one transaction. A field that is still plain is
+/// decrypted as it is.
+///
+/// The records are written with `$verbatim`, so they keep their `updated`, and neither the audit log nor the
+/// version history records the change, see `023_verbatim_writes.surql`.
+async fn reencrypt(db: &Surreal<Any>, from: &Cipher, to: &Cipher, salt: &str) -> anyhow::Result<()> {
+    let convert = |value: Option<String>| -> anyhow::Result<Option<String>> {
+        value.map(|value| Ok(to.encrypt(&from.decrypt(&value)?))).transpose()
+    };
+
+    let select: String = ENCRYPTED_FIELDS
+        .iter()
+        .map(|(table, field)| format!(
+            "SELECT id, {field} AS value FROM {table};
+            SELECT id, before.{field} AS before, after.{field} AS after FROM audit WHERE table = '{table}';
+            SELECT id, content.{field} AS value FROM history WHERE table = '{table}';\n"))
+        .collect();
+    let mut response = db.query(select).await.context("failed to read the encrypted fields")?;
+
+    let mut sql = String::from("BEGIN TRANSACTION;\nLET $verbatim = true;\n");
+    let mut fields = Vec::new();
+    let mut audit_fields = Vec::new();
+    let mut history_fields = Vec::new();
+    for (index, (table, field)) in ENCRYPTED_FIELDS.iter().enumerate() {
+        let records: Vec<Field> = response.take(3 * index)?;
+        let entries: Vec<AuditField> = response.take(3 * index + 1)?;
+        let versions: Vec<Field> = response.take(3 * index + 2)?;
+
+        fields.push(records
+            .into_iter()
+            .map(|record| Ok(Field { value: convert(record.value)?, ..record }))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .with_context(|| format!("failed to decrypt {table}.{field}"))?);
+        audit_fields.push(entries
+            .into_iter()
+            .map(|entry| Ok(AuditField { before: convert(entry.before)?, after: convert(entry.after)?, ..entry }))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .with_context(|| format!("failed to decrypt {table}.{field} in the audit log"))?);
+        history_fields.push(versions
+            .into_iter()
+            .map(|version| Ok(Field { value: convert(version.value)?, ..version }))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .with_context(|| format!("failed to decrypt {table}.{field} in the version history"))?);
+
+        sql += &format!(
+            "FOR $r IN $fields{index} {{ UPDATE $r.id SET {field} = $r.value; }};
+            FOR $r IN $audit{index} {{
+                IF $r.before != NONE {{ UPDATE $r.id SET before.{field} = $r.before; }};
+                IF $r.after != NONE {{ UPDATE $r.id SET after.{field} = $r.after; }};
+            }};
+            FOR $r IN $history{index} {{ UPDATE $r.id SET content.{field} = $r.value; }};\n");
+    }
+    // The key record is a write of its own, its `updated` is when the key was last rotated.
+    sql += "LET $verbatim = false;
+        UPDATE encryption:key SET salt = $salt, key_check = $key_check, fields = $names;
+        COMMIT TRANSACTION;";
+
+    let mut query = db.query(sql)
+        .bind(("salt", salt))
+        .bind(("key_check", to.encrypt(KEY_CHECK)))
+        .bind(("names", field_names()));
+    let fields = fields.into_iter().zip(audit_fields).zip(history_fields);
+    for (index, ((fields, audit_fields), history_fields)) in fields.enumerate() {
+        query = query
+            .bind((format!("fields{index}"), fields))
+            .bind((format!("audit{index}"), audit_fields))
+            .bind((format!("history{index}"), history_fields));
+    }
+
+    let mut response = query.await.context("failed to re-encrypt the fields")?;
+    first_error(&mut response).map_or(Ok(()), Err).context("failed to re-encrypt the fields")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{Method, StatusCode};
+    use serde_json::json;
+
+    use super::{unlock, Secret, PREFIX};
+    use crate::api::testing::{id, TestApp};
+
+    const KEY: [&str; 2] = ["--encryption-passphrase", "secret"];
+
+    #[tokio::test]
+    async fn the_sensitive_fields_are_stored_encrypted() {
+        let app = TestApp::with_args(&KEY).await;
+        let token = app.sign_up("ann").await;
+
+        let (_, medication) =
+            app.request(Method::POST, "/medications", Some(&token), Some(json!({ "name": "Aspirin" }))).await;
+        let store = json!({
+            "medication": id(&medication),
+            "production_date": "2026-01-01T00:00:00Z",
+            "lot_number": "A1",
+            "quantity": 10.0,
+            "unit": "mg",
+        });
+        let (_, store) = app.request(Method::POST, "/stores", Some(&token), Some(store)).await;
+        let dose = json!({ "store": id(&store), "quantity": 1.0, "unit": "mg" });
+        let (status, dose) = app.request(Method::POST, "/doses", Some(&token), Some(dose)).await;
+        // The stock check compares the encrypted units.
+        assert_eq!(status, StatusCode::OK, "{dose}");
+        assert_eq!(dose["unit"], "mg");
+        let other_unit = json!({ "store": id(&store), "quantity": 1.0, "unit": "ml" });
+        let (status, _) = app.request(Method::POST, "/doses", Some(&token), Some(other_unit)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let (_, profiles) = app.request(Method::GET, "/profiles", Some(&token), None).await;
+        assert_eq!(profiles[0]["name"], "ann");
+
+        let mut stored = app.db().query(
+            "SELECT VALUE name FROM profile;
+            SELECT VALUE [lot_number, unit] FROM store;
+            SELECT VALUE unit FROM dose;
+            SELECT VALUE after.unit FROM audit WHERE table = 'dose';").await.unwrap();
+        let values: Vec<String> = [
+            stored.take::<Vec<String>>(0).unwrap(),
+            stored.take::<Vec<Vec<String>>>(1).unwrap().concat(),
+            stored.take::<Vec<String>>(2).unwrap(),
+            stored.take::<Vec<String>>(3).unwrap(),
+        ].concat();
+        assert_eq!(values.len(), 5);
+        assert!(values.iter().all(|value| value.starts_with(PREFIX)), "{values:?}");
+    }
+
+    #[tokio::test]
+    async fn unlocking_encrypts_the_fields_added_since() {
+        let app = TestApp::with_args(&KEY).await;
+        app.sign_up("ann").await;
+        // As encrypted by a version that didn't encrypt the profile names yet.
+        app.db().query("UPDATE profile SET name = 'ann'; UPDATE encryption:key SET fields = NONE;")
+            .await.unwrap().check().unwrap();
+
+        unlock(app.db(), Some(Secret::Passphrase("secret".to_owned()))).await.unwrap();
+        let name: Option<String> = app.db().query("SELECT VALUE name FROM profile").await.unwrap().take(0).unwrap();
+        let name = name.unwrap();
+        assert!(name.starts_with(PREFIX));
+        assert_eq!(app.cipher().decrypt(&name).unwrap(), "ann");
+    }
+}
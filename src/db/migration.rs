@@ -0,0 +1,166 @@
+use anyhow::{bail, Context};
+use sha2::{Digest, Sha256};
+use surrealdb::engine::any::Any;
+use surrealdb::{Response, Surreal};
+
+/// One numbered schema file from `src/api/schema`, embedded in the binary.
+pub struct Migration {
+    /// The number the file name starts with, which is also the order the files are applied in.
+    pub version: u32,
+    /// The file name, for log and error messages.
+    pub file: &'static str,
+    sql: &'static str,
+}
+
+impl Migration {
+    /// The SHA-256 of the file, hex encoded, to notice when an applied file was edited afterwards.
+    fn checksum(&self) -> String {
+        format!("{:x}", Sha256::digest(self.sql.as_bytes()))
+    }
+}
+
+macro_rules! migration {
+    ($version:literal, $file:literal) => {
+        Migration {
+            version: $version,
+            file: $file,
+            sql: include_str!(concat!("../api/schema/", $file)),
+        }
+    };
+}
+
+/// Every schema file, in order. A new file goes at the end of this list with the next number, and
+/// files that were released are never edited again: changes go into a new file instead.
+pub const MIGRATIONS: [Migration; 26] = [
+    migration!(1, "001_base.surql"),
+    migration!(2, "002_unit_of_measure.surql"),
+    migration!(3, "003_medication.surql"),
+    migration!(4, "004_store.surql"),
+    migration!(5, "005_dose.surql"),
+    migration!(6, "006_reminder.surql"),
+    migration!(7, "007_note.surql"),
+    migration!(8, "008_session.surql"),
+    migration!(9, "009_refresh_token.surql"),
+    migration!(10, "010_two_factor.surql"),
+    migration!(11, "011_care_grant.surql"),
+    migration!(12, "012_profile.surql"),
+    migration!(13, "013_access_token.surql"),
+    migration!(14, "014_password_recovery.surql"),
+    migration!(15, "015_audit.surql"),
+    migration!(16, "016_login_attempt.surql"),
+    migration!(17, "017_quick_unlock.surql"),
+    migration!(18, "018_encryption.surql"),
+    migration!(19, "019_trash.surql"),
+    migration!(20, "020_references.surql"),
+    migration!(21, "021_history.surql"),
+    migration!(22, "022_optional_dates.surql"),
+    migration!(23, "023_verbatim_writes.surql"),
+    migration!(24, "024_keep_version.surql"),
+    migration!(25, "025_encrypted_fields.surql"),
+    migration!(26, "026_required_versions.surql"),
+];
+
+/// Returns the names of the tables the schema files define, in the order they are defined.
+///
+/// The `migration` table itself isn't one of them, it belongs to the database rather than the
+/// application.
+pub fn tables() -> Vec<&'static str> {
+    MIGRATIONS
+        .iter()
+        .flat_map(|migration| migration.sql.lines())
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let define = words.next()?.eq_ignore_ascii_case("DEFINE") && words.next()?.eq_ignore_ascii_case("TABLE");
+            define.then(|| words.next()).flatten()
+        })
+        .collect()
+}
+
+/// A row of the `migration` table, one for each applied schema file, keyed by its version.
+#[derive(serde::Deserialize)]
+struct AppliedMigration {
+    version: u32,
+    file: String,
+    checksum: String,
+}
+
+/// Returns the schema files that haven't been applied to the database yet, in order.
+///
+/// Creates the `migration` table if needed. Fails if an applied file was edited since, or if the
+/// database has a file applied that this build doesn't know about, i.e. it was last run by a newer
+/// version of the application.
+pub async fn pending(db: &Surreal<Any>) -> anyhow::Result<Vec<&'static Migration>> {
+    let mut sql = db.query(
+        "DEFINE TABLE migration SCHEMAFULL;
+        DEFINE FIELD version ON TABLE migration TYPE int;
+        DEFINE FIELD file ON TABLE migration TYPE string;
+        DEFINE FIELD checksum ON TABLE migration TYPE string;
+        DEFINE FIELD applied ON TABLE migration VALUE $before OR time::now();
+        SELECT version, file, checksum FROM migration ORDER BY version;")
+        .await
+        .context("failed to read the applied migrations")?;
+    let applied: Vec<AppliedMigration> = sql.take(5)?;
+
+    for record in &applied {
+        let Some(migration) = MIGRATIONS.iter().find(|m| m.version == record.version) else {
+            bail!("the database has migration {} applied, which this version of medoxido doesn't know", record.file);
+        };
+        if migration.checksum() != record.checksum {
+            bail!("migration {} was edited after it was applied, put the change in a new file instead", migration.file);
+        }
+    }
+
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|m| !applied.iter().any(|record| record.version == m.version))
+        .collect())
+}
+
+/// Returns the version of the last schema file applied to the database, 0 if there is none.
+pub async fn version(db: &Surreal<Any>) -> anyhow::Result<u32> {
+    let pending = pending(db).await?;
+    Ok(pending.first().map_or(MIGRATIONS.len() as u32, |migration| migration.version - 1))
+}
+
+/// Applies the pending schema files in order, each in its own transaction together with its row in
+/// the `migration` table, and returns how many were applied.
+pub async fn migrate(db: &Surreal<Any>) -> anyhow::Result<usize> {
+    let pending = pending(db).await?;
+
+    for migration in &pending {
+        log::info!("applying migration {}", migration.file);
+
+        let mut response = db.query(format!(
+            "BEGIN TRANSACTION;
+            {}
+            CREATE type::thing('migration', $version) SET version = $version, file = $file, checksum = $checksum;
+            COMMIT TRANSACTION;",
+            migration.sql))
+            .bind(("version", migration.version))
+            .bind(("file", migration.file))
+            .bind(("checksum", migration.checksum()))
+            .await
+            .with_context(|| format!("failed to apply migration {}", migration.file))?;
+
+        if let Some(error) = first_error(&mut response) {
+            return Err(error).with_context(|| format!("failed to apply migration {}", migration.file));
+        }
+    }
+
+    Ok(pending.len())
+}
+
+/// Returns the error of the statement that failed, if any.
+///
+/// When a statement in a transaction fails, every other statement reports that it wasn't executed,
+/// which says nothing about the cause.
+pub fn first_error(response: &mut Response) -> Option<surrealdb::Error> {
+    let mut errors: Vec<_> = response.take_errors().into_iter().collect();
+    errors.sort_by_key(|(index, _)| *index);
+
+    let cause = errors
+        .iter()
+        .position(|(_, error)| !matches!(error, surrealdb::Error::Db(surrealdb::error::Db::QueryNotExecuted)))
+        .unwrap_or_default();
+    (!errors.is_empty()).then(|| errors.swap_remove(cause).1)
+}
@@ -0,0 +1,47 @@
+//! The trash of deleted records, see `019_trash.surql`.
+//!
+//! Deleting a record of one of the `TRASH_TABLES` sets its `deleted_at`, and the records in the trash can be
+//! restored or purged through `/trash`. `schedule()` purges the ones deleted longer ago than the retention.
+
+use anyhow::Context;
+use std::time::Duration;
+use surrealdb::engine::any::Any;
+use surrealdb::Surreal;
+
+/// The tables whose records are moved to the trash when they are deleted.
+pub const TRASH_TABLES: [&str; 6] = ["unit_of_measure", "medication", "store", "dose", "reminder", "note"];
+
+/// How often `schedule()` looks for expired records.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Deletes for good every record that has been in the trash longer than `retention`, and returns how many
+/// there were.
+///
+/// The audit log records each of them as deleted, without an actor.
+pub async fn purge_expired(db: &Surreal<Any>, retention: Duration) -> anyhow::Result<usize> {
+    let delete: Vec<String> = TRASH_TABLES
+        .iter()
+        .map(|table| format!(
+            "(DELETE {table} WHERE deleted_at != NONE AND deleted_at < time::now() - $retention RETURN BEFORE)"))
+        .collect();
+    let mut response = db.query(format!("RETURN array::len(array::flatten([{}]));", delete.join(", ")))
+        .bind(("retention", surrealdb::sql::Duration::from(retention)))
+        .await
+        .context("failed to purge the trash")?;
+    let purged: Option<usize> = response.take(0).context("failed to purge the trash")?;
+    Ok(purged.unwrap_or_default())
+}
+
+/// Purges the records that have been in the trash longer than `retention`, at startup and then every hour.
+/// Runs until the application stops; a failed purge is logged and tried again next time.
+pub async fn schedule(db: Surreal<Any>, retention: Duration) {
+    let mut ticker = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        ticker.tick().await;
+        match purge_expired(&db, retention).await {
+            Ok(0) => (),
+            Ok(purged) => log::info!("purged {purged} records from the trash"),
+            Err(error) => log::error!("purging the trash failed: {error:#}"),
+        }
+    }
+}
@@ -6,3 +6,6 @@ pub mod config;
 /// Contains the setup code for the API build with Axum.
 ///
 pub mod api;
+
+/// Connects to SurrealDB with the engine chosen in the [`config::Config`].
+pub mod db;
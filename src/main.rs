@@ -8,12 +8,10 @@
 //! and uses a local built-in database engine and local file.
 //!
 use clap::Parser;
-use surrealdb::engine::remote::ws::Ws;
-use surrealdb::opt::auth::Root;
-use surrealdb::Surreal;
-use medoxido::config::Config;
-use medoxido::api;
-
+use std::time::Duration;
+use medoxido::config::{Command, Config, MigrateMode};
+use medoxido::db::encryption::{KeyError, Secret};
+use medoxido::{api, db};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -26,20 +24,76 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // This will exit with a help message if something is wrong.
     let config = Config::parse();
 
-    // let db = Surreal::new::<Ws>("localhost:8000").await?;
-    let db_address = format!("{}:{}", &config.db_host, &config.db_port);
+    let db = db::connect(&config).await?;
+
+    match &config.command {
+        Some(Command::Backup { path }) => {
+            let backup = db::backup::create(&db).await?;
+            std::fs::write(path, serde_json::to_vec(&backup)?)?;
+            log::info!("wrote backup {}", path.display());
+            return Ok(());
+        }
+        Some(Command::Restore { path }) => {
+            let backup: db::backup::Backup = serde_json::from_slice(&std::fs::read(path)?)?;
+            let restored = db::backup::restore(&db, backup.records()?).await?;
+            log::info!("restored {} records from {}", restored, path.display());
+            return Ok(());
+        }
+        Some(Command::RotateKey { .. }) | None => (),
+    }
+
+    match config.db_migrate {
+        MigrateMode::Startup => {
+            db::migration::migrate(&db).await?;
+        }
+        MigrateMode::Only => {
+            let applied = db::migration::migrate(&db).await?;
+            log::info!("applied {} migrations", applied);
+            return Ok(());
+        }
+        MigrateMode::Check => {
+            if let Some(migration) = db::migration::pending(&db).await?.first() {
+                return Err(format!("migration {} is pending, run with --db-migrate only first", migration.file).into());
+            }
+        }
+    }
+
+    // A missing or wrong key is the likely mistake here, so it gets a plain message rather than a trace.
+    let cipher = match db::encryption::unlock(&db, Secret::from_config(&config)).await {
+        Ok(cipher) => cipher,
+        Err(error @ (KeyError::Missing | KeyError::Wrong)) => {
+            eprintln!("medoxido: {error}");
+            std::process::exit(2);
+        }
+        Err(error) => return Err(error.into()),
+    };
 
-    let db = Surreal::new::<Ws>(db_address).await?;
+    if let Some(Command::RotateKey { new_passphrase, new_key_file }) = &config.command {
+        let secret = match (new_passphrase, new_key_file) {
+            (Some(passphrase), _) => Secret::Passphrase(passphrase.clone()),
+            (None, Some(path)) => Secret::KeyFile(path.clone()),
+            (None, None) => unreachable!("clap requires one of them"),
+        };
+        db::encryption::rotate(&db, &cipher, &secret).await?;
+        log::info!("re-encrypted the database with the new key");
+        return Ok(());
+    }
 
-    db.signin(Root {
-        username: &config.db_user,
-        password: &config.db_password,
-    })
-    .await?;
+    if let Some(dir) = &config.backup_dir {
+        tokio::spawn(db::backup::schedule(
+            db.clone(),
+            dir.clone(),
+            Duration::from_secs(config.backup_interval_hours * 60 * 60),
+            config.backup_retention as usize,
+        ));
+    }
 
-    db.use_ns(&config.db_namespace).use_db(&config.db_name).await?;
+    tokio::spawn(db::trash::schedule(
+        db.clone(),
+        Duration::from_secs(config.trash_retention_days * 24 * 60 * 60),
+    ));
 
-    api::serve(config, db).await?;
+    api::serve(config, db, cipher).await?;
 
     Ok(())
 }